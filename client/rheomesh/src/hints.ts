@@ -0,0 +1,38 @@
+export type SdpHints = {
+  version: string | null;
+  controlChannelLabel: string | null;
+};
+
+const HINT_PREFIX = "a=x-rheomesh:";
+
+export function parseHints(sdp: string | undefined): SdpHints {
+  const hints: SdpHints = {
+    version: null,
+    controlChannelLabel: null,
+  };
+  if (!sdp) {
+    return hints;
+  }
+
+  for (const line of sdp.split(/\r?\n/)) {
+    if (line.startsWith("m=")) {
+      break;
+    }
+    if (!line.startsWith(HINT_PREFIX)) {
+      continue;
+    }
+    const hint = line.slice(HINT_PREFIX.length);
+    const index = hint.indexOf("=");
+    const key = index < 0 ? hint : hint.slice(0, index);
+    const value = index < 0 ? null : hint.slice(index + 1);
+    switch (key) {
+      case "version":
+        hints.version = value;
+        break;
+      case "control-channel":
+        hints.controlChannelLabel = value;
+        break;
+    }
+  }
+  return hints;
+}
@@ -1,4 +1,6 @@
+import { parseHints, SdpHints } from "./hints";
 import { PublishTransport } from "./publishTransport";
 import { SubscribeTransport } from "./subscribeTransport";
 
-export { PublishTransport, SubscribeTransport };
+export { PublishTransport, SubscribeTransport, parseHints };
+export type { SdpHints };
@@ -1,6 +1,7 @@
 import { EventEmitter } from "events";
 import * as sdpTransform from "sdp-transform";
 import { findExtmapOrder } from "./config";
+import { parseHints, SdpHints } from "./hints";
 
 const offerOptions: RTCOfferOptions = {
   offerToReceiveVideo: false,
@@ -10,6 +11,7 @@ const offerOptions: RTCOfferOptions = {
 export class PublishTransport extends EventEmitter {
   private _peerConnection: RTCPeerConnection;
   private _signalingLock: boolean;
+  private _hints: SdpHints;
 
   constructor(config: RTCConfiguration) {
     super();
@@ -17,6 +19,7 @@ export class PublishTransport extends EventEmitter {
 
     this._peerConnection = peer;
     this._signalingLock = false;
+    this._hints = parseHints(undefined);
 
     this._peerConnection.onicecandidate = (event) => {
       if (event.candidate) {
@@ -70,6 +73,7 @@ export class PublishTransport extends EventEmitter {
   }
 
   public async setAnswer(answer: RTCSessionDescription): Promise<void> {
+    this._hints = parseHints(answer.sdp);
     await this._peerConnection.setRemoteDescription(answer);
   }
 
@@ -94,6 +98,10 @@ export class PublishTransport extends EventEmitter {
     return [channel, offer];
   }
 
+  public hints(): SdpHints {
+    return this._hints;
+  }
+
   public close() {
     this._peerConnection.close();
   }
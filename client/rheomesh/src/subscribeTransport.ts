@@ -1,4 +1,5 @@
 import { EventEmitter } from "events";
+import { parseHints, SdpHints } from "./hints";
 
 export class SubscribeTransport extends EventEmitter {
   private _peerConnection: RTCPeerConnection;
@@ -6,6 +7,7 @@ export class SubscribeTransport extends EventEmitter {
   private _track: { [publisherId: string]: MediaStreamTrack };
   private _channel: { [publisherId: string]: RTCDataChannel };
   private _signalingLock: boolean;
+  private _hints: SdpHints;
 
   constructor(config: RTCConfiguration) {
     super();
@@ -16,6 +18,7 @@ export class SubscribeTransport extends EventEmitter {
     this._track = {};
     this._channel = {};
     this._signalingLock = false;
+    this._hints = parseHints(undefined);
 
     this._peerConnection.onicecandidate = (event) => {
       if (event.candidate) {
@@ -54,6 +57,7 @@ export class SubscribeTransport extends EventEmitter {
       await new Promise((resolve) => setTimeout(resolve, 100));
     }
     this._signalingLock = true;
+    this._hints = parseHints(sdp.sdp);
     await this._peerConnection.setRemoteDescription(sdp);
     const answer = await this._peerConnection.createAnswer();
     await this._peerConnection.setLocalDescription(answer);
@@ -102,6 +106,10 @@ export class SubscribeTransport extends EventEmitter {
     });
   }
 
+  public hints(): SdpHints {
+    return this._hints;
+  }
+
   public close() {
     this._peerConnection.close();
   }
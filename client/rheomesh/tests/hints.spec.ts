@@ -0,0 +1,27 @@
+import { parseHints } from "../src/hints";
+
+describe("parseHints", () => {
+  it("hints should be parsed from session attributes", () => {
+    const sdp = [
+      "v=0",
+      "s=-",
+      "t=0 0",
+      "a=x-rheomesh:version=0.1.6",
+      "m=audio 9 UDP/TLS/RTP/SAVPF 111",
+      "a=x-rheomesh:control-channel=control",
+      "",
+    ].join("\r\n");
+
+    expect(parseHints(sdp)).toEqual({
+      version: "0.1.6",
+      controlChannelLabel: null,
+    });
+  });
+
+  it("defaults should be returned without hints", () => {
+    expect(parseHints(undefined)).toEqual({
+      version: null,
+      controlChannelLabel: null,
+    });
+  });
+});
@@ -0,0 +1,209 @@
+use std::net::IpAddr;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use rheomesh::config::{
+    self, CodecConfig, CongestionFeedback, FindingSeverity, HeaderExtensionConfig, IceServerConfig,
+    MediaConfig, PortRange, WebRTCTransportConfig,
+};
+use rheomesh::net::probe_ice_servers;
+use serde::Deserialize;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters};
+use webrtc::rtp_transceiver::RTCPFeedback;
+
+// Usage: cargo run --example rheomesh-doctor -- config.json
+//
+// {
+//   "announcedIps": ["192.168.10.10"],
+//   "iceServers": [{ "urls": ["stun:stun.l.google.com:19302"] }],
+//   "portRange": { "min": 12000, "max": 15000 },
+//   "congestionFeedback": "twcc",
+//   "codecs": {
+//     "audio": [{ "mimeType": "audio/opus", "clockRate": 48000, "channels": 2, "payloadType": 111 }],
+//     "video": [{ "mimeType": "video/VP8", "clockRate": 90000, "payloadType": 96, "rtcpFeedback": ["nack", "nack pli", "goog-remb"] }]
+//   }
+// }
+#[actix_web::main]
+async fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: rheomesh-doctor <config.json>");
+        return ExitCode::FAILURE;
+    };
+    let config: DoctorConfig = match std::fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+    {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("failed to load {}: {}", path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let media_config = config.media_config();
+    let transport_config = config.transport_config();
+
+    println!("Validating configuration...");
+    let findings = config::validate(&media_config, &transport_config);
+    for finding in findings.iter() {
+        println!("  {}", finding);
+    }
+    if findings.is_empty() {
+        println!("  no problems found");
+    }
+
+    println!("Probing ICE servers...");
+    let probes = probe_ice_servers(
+        &transport_config.configuration.ice_servers,
+        Duration::from_secs(3),
+    )
+    .await;
+    for probe in probes.iter() {
+        match (&probe.error, probe.mapped_address) {
+            (Some(error), _) => println!("  {}: unreachable, {}", probe.url, error),
+            (None, Some(mapped)) => println!(
+                "  {}: reachable in {:?}, mapped address {}",
+                probe.url,
+                probe.rtt.unwrap_or_default(),
+                mapped
+            ),
+            (None, None) => println!(
+                "  {}: reachable in {:?}",
+                probe.url,
+                probe.rtt.unwrap_or_default()
+            ),
+        }
+    }
+
+    let failed = findings
+        .iter()
+        .any(|f| f.severity == FindingSeverity::Error)
+        || probes.iter().any(|p| !p.is_reachable());
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct DoctorConfig {
+    announced_ips: Vec<IpAddr>,
+    ice_servers: Vec<IceServerConfig>,
+    port_range: Option<DoctorPortRange>,
+    ice_username_fragment: Option<String>,
+    ice_password: Option<String>,
+    congestion_feedback: Option<DoctorCongestionFeedback>,
+    codecs: DoctorCodecs,
+    header_extensions: Option<DoctorHeaderExtensions>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DoctorPortRange {
+    min: u16,
+    max: u16,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum DoctorCongestionFeedback {
+    Twcc,
+    Remb,
+    Both,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct DoctorCodecs {
+    audio: Vec<DoctorCodec>,
+    video: Vec<DoctorCodec>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DoctorCodec {
+    mime_type: String,
+    clock_rate: u32,
+    #[serde(default)]
+    channels: u16,
+    #[serde(default)]
+    sdp_fmtp_line: String,
+    payload_type: u8,
+    /// Each value is "<type>" or "<type> <parameter>", for example "nack pli".
+    #[serde(default)]
+    rtcp_feedback: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DoctorHeaderExtensions {
+    audio: Vec<String>,
+    video: Vec<String>,
+}
+
+impl DoctorConfig {
+    fn media_config(&self) -> MediaConfig {
+        let codecs = |codecs: &[DoctorCodec]| -> Vec<RTCRtpCodecParameters> {
+            codecs.iter().map(DoctorCodec::parameters).collect()
+        };
+        MediaConfig {
+            codec: CodecConfig {
+                audio: codecs(&self.codecs.audio),
+                video: codecs(&self.codecs.video),
+            },
+            header_extension: match &self.header_extensions {
+                Some(extensions) => HeaderExtensionConfig {
+                    audio: extensions.audio.clone(),
+                    video: extensions.video.clone(),
+                },
+                None => HeaderExtensionConfig::default(),
+            },
+            congestion_feedback: match self.congestion_feedback {
+                Some(DoctorCongestionFeedback::Twcc) => CongestionFeedback::Twcc,
+                Some(DoctorCongestionFeedback::Remb) => CongestionFeedback::Remb,
+                Some(DoctorCongestionFeedback::Both) | None => CongestionFeedback::Both,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn transport_config(&self) -> WebRTCTransportConfig {
+        let mut config = WebRTCTransportConfig::default();
+        config.announced_ips = self.announced_ips.clone();
+        config.configuration.ice_servers =
+            self.ice_servers.iter().cloned().map(Into::into).collect();
+        config.port_range = self.port_range.as_ref().map(|range| PortRange {
+            min: range.min,
+            max: range.max,
+        });
+        config.ice_username_fragment = self.ice_username_fragment.clone();
+        config.ice_password = self.ice_password.clone();
+        config
+    }
+}
+
+impl DoctorCodec {
+    fn parameters(&self) -> RTCRtpCodecParameters {
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: self.mime_type.clone(),
+                clock_rate: self.clock_rate,
+                channels: self.channels,
+                sdp_fmtp_line: self.sdp_fmtp_line.clone(),
+                rtcp_feedback: self
+                    .rtcp_feedback
+                    .iter()
+                    .map(|fb| {
+                        let (typ, parameter) = fb.split_once(' ').unwrap_or((fb.as_str(), ""));
+                        RTCPFeedback {
+                            typ: typ.to_string(),
+                            parameter: parameter.to_string(),
+                        }
+                    })
+                    .collect(),
+            },
+            payload_type: self.payload_type,
+            ..Default::default()
+        }
+    }
+}
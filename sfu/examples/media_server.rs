@@ -8,6 +8,7 @@ use actix_web::web::{Data, Query};
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_actors::ws;
 use rheomesh::config::MediaConfig;
+use rheomesh::integrations::actix::TransportActorBridge;
 use rheomesh::publisher::Publisher;
 use rheomesh::subscriber::Subscriber;
 use rheomesh::transport::Transport;
@@ -16,7 +17,7 @@ use tokio::sync::Mutex;
 use tracing_actix_web::TracingLogger;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
@@ -186,12 +187,12 @@ impl Handler<ReceivedMessage> for WebSocket {
             }
             ReceivedMessage::PublisherInit => {
                 let publish_transport = self.publish_transport.clone();
+                let bridge = TransportActorBridge::new(&address);
                 tokio::spawn(async move {
-                    let addr = address.clone();
                     publish_transport
-                        .on_ice_candidate(Box::new(move |candidate| {
+                        .on_ice_candidate(bridge.callback(|candidate: RTCIceCandidate| {
                             let init = candidate.to_json().expect("failed to parse candidate");
-                            addr.do_send(SendingMessage::PublisherIce { candidate: init });
+                            SendingMessage::PublisherIce { candidate: init }
                         }))
                         .await;
                 });
@@ -199,19 +200,18 @@ impl Handler<ReceivedMessage> for WebSocket {
             ReceivedMessage::SubscriberInit => {
                 let subscribe_transport = self.subscribe_transport.clone();
                 let room = self.room.clone();
+                let bridge = TransportActorBridge::new(&address);
                 tokio::spawn(async move {
-                    let addr = address.clone();
-                    let addr2 = address.clone();
                     subscribe_transport
-                        .on_ice_candidate(Box::new(move |candidate| {
+                        .on_ice_candidate(bridge.callback(|candidate: RTCIceCandidate| {
                             let init = candidate.to_json().expect("failed to parse candidate");
-                            addr.do_send(SendingMessage::SubscriberIce { candidate: init });
+                            SendingMessage::SubscriberIce { candidate: init }
                         }))
                         .await;
                     subscribe_transport
-                        .on_negotiation_needed(Box::new(move |offer| {
-                            addr2.do_send(SendingMessage::Offer { sdp: offer });
-                        }))
+                        .on_negotiation_needed(
+                            bridge.callback(|offer| SendingMessage::Offer { sdp: offer }),
+                        )
                         .await;
 
                     let router = room.router.lock().await;
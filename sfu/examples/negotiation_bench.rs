@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use rheomesh::config::{MediaConfig, WebRTCTransportConfig};
+use rheomesh::publish_transport::PublishTransport;
+use rheomesh::router::Router;
+use rheomesh::transport::Transport;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_VP8};
+use webrtc::api::APIBuilder;
+use webrtc::interceptor::registry::Registry;
+use webrtc::media::Sample;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+
+// Usage: cargo run --release --example negotiation_bench -- [transports]
+//
+// This publishes one video track from a local peer connection, and then many SubscribeTransports subscribe it at once like a join storm.
+// The report is printed as JSON, so it can be compared between versions.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Report {
+    transports: usize,
+    offers: usize,
+    failures: usize,
+    elapsed_ms: f64,
+    offers_per_sec: f64,
+    latency_ms: Latency,
+}
+
+#[derive(Serialize, Debug)]
+struct Latency {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let transports: usize = std::env::args()
+        .nth(1)
+        .map(|n| n.parse())
+        .transpose()?
+        .unwrap_or(50);
+
+    let router = Router::new(MediaConfig::default());
+    let (publisher_id, _client, publish_transport) = publish(&router).await?;
+
+    let started = Instant::now();
+    let mut handles = Vec::new();
+    for _ in 0..transports {
+        let router = router.clone();
+        let publisher_id = publisher_id.clone();
+        handles.push(tokio::spawn(async move {
+            let transport = router
+                .lock()
+                .await
+                .create_subscribe_transport(WebRTCTransportConfig::default())
+                .await;
+            let started = Instant::now();
+            let res = transport.subscribe(publisher_id).await;
+            let latency = started.elapsed();
+            (transport, res.is_ok(), latency)
+        }));
+    }
+
+    let mut latencies = Vec::new();
+    let mut subscribe_transports = Vec::new();
+    let mut failures = 0;
+    for handle in handles {
+        let (transport, ok, latency) = handle.await?;
+        if ok {
+            latencies.push(latency.as_secs_f64() * 1000.0);
+        } else {
+            failures += 1;
+        }
+        subscribe_transports.push(transport);
+    }
+    let elapsed = started.elapsed();
+
+    latencies.sort_by(|a, b| a.total_cmp(b));
+    let percentile = |p: f64| -> f64 {
+        if latencies.is_empty() {
+            return 0.0;
+        }
+        let index = ((latencies.len() - 1) as f64 * p).round() as usize;
+        latencies[index]
+    };
+    let report = Report {
+        transports,
+        offers: latencies.len(),
+        failures,
+        elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+        offers_per_sec: latencies.len() as f64 / elapsed.as_secs_f64(),
+        latency_ms: Latency {
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: latencies.last().copied().unwrap_or_default(),
+        },
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    for transport in subscribe_transports {
+        let _ = transport.close().await;
+    }
+    let _ = publish_transport.close().await;
+    router.lock().await.close();
+    Ok(())
+}
+
+/// Publish a video track from a local peer connection, and return the publisher ID when the router receives the track.
+async fn publish(
+    router: &Arc<tokio::sync::Mutex<Router>>,
+) -> Result<(String, Arc<RTCPeerConnection>, PublishTransport), Box<dyn std::error::Error>> {
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs()?;
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+    let api = APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .build();
+    let client = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
+
+    let track = Arc::new(TrackLocalStaticSample::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_VP8.to_owned(),
+            ..Default::default()
+        },
+        "bench-video".to_owned(),
+        "bench".to_owned(),
+    ));
+    client.add_track(track.clone()).await?;
+
+    let publish_transport = router
+        .lock()
+        .await
+        .create_publish_transport(WebRTCTransportConfig::default())
+        .await;
+    let (candidate_sender, mut candidate_receiver) = mpsc::unbounded_channel();
+    publish_transport
+        .on_ice_candidate(Box::new(move |candidate| {
+            if let Ok(init) = candidate.to_json() {
+                let _ = candidate_sender.send(init);
+            }
+        }))
+        .await;
+
+    // The offer contains all candidates of the client, so only candidates of the server are trickled.
+    let offer = client.create_offer(None).await?;
+    let mut gathering_complete = client.gathering_complete_promise().await;
+    client.set_local_description(offer).await?;
+    let _ = gathering_complete.recv().await;
+    let offer = client
+        .local_description()
+        .await
+        .ok_or("no local description")?;
+
+    let answer = publish_transport.get_answer(offer).await?;
+    client.set_remote_description(answer).await?;
+    {
+        let client = client.clone();
+        tokio::spawn(async move {
+            while let Some(candidate) = candidate_receiver.recv().await {
+                let _ = client.add_ice_candidate(candidate).await;
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let sample = Sample {
+                data: Bytes::from_static(&[0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a]),
+                duration: Duration::from_millis(33),
+                ..Default::default()
+            };
+            if track.write_sample(&sample).await.is_err() {
+                break;
+            }
+            sleep(Duration::from_millis(33)).await;
+        }
+    });
+
+    let publisher = tokio::time::timeout(
+        Duration::from_secs(10),
+        publish_transport.publish("bench-video".to_owned()),
+    )
+    .await??;
+    Ok((publisher.id.clone(), client, publish_transport))
+}
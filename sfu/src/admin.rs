@@ -0,0 +1,405 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+};
+
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::{
+    router::Router,
+    stats::{PublisherStats, SubscriberStats},
+};
+
+/// Administrative API for operators, which lists routers, publishers and subscribers, pulls stats and closes publishers and routers.
+/// Handlers don't depend on any HTTP framework. [`AdminApi::handle`] takes a method and a path, and returns a status code and a JSON body, so it can be mounted on any server. An adapter for axum is provided by the `axum` feature, please refer [`crate::integrations`].
+///
+/// | method | path                                         | response                                        |
+/// |--------|----------------------------------------------|-------------------------------------------------|
+/// | GET    | `/routers`                                   | [`RouterSummary`] of all routers                |
+/// | GET    | `/routers/{router_id}`                       | [`crate::stats::RouterStatsSnapshot`]           |
+/// | DELETE | `/routers/{router_id}`                       | Close the router                                |
+/// | GET    | `/routers/{router_id}/publishers`            | [`PublisherStats`] of the router                |
+/// | DELETE | `/routers/{router_id}/publishers/{publisher_id}` | Close the publisher, which kicks it from the router |
+/// | GET    | `/routers/{router_id}/subscribers`           | [`AdminSubscriber`] of the router               |
+///
+/// The API doesn't authenticate requests. Please protect it with the middleware of your server, or don't expose it to public networks.
+#[derive(Clone, Debug, Default)]
+pub struct AdminApi {
+    // Weak references, so the API doesn't keep closed routers alive.
+    routers: Arc<std::sync::Mutex<HashMap<String, Weak<Mutex<Router>>>>>,
+}
+
+/// Response of [`AdminApi::handle`]. The body is `None` when the status is 204 No Content.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdminResponse {
+    pub status: u16,
+    pub body: Option<Value>,
+}
+
+impl AdminResponse {
+    fn ok(body: impl Serialize) -> Self {
+        match serde_json::to_value(body) {
+            Ok(body) => Self {
+                status: 200,
+                body: Some(body),
+            },
+            Err(err) => Self::error(500, err.to_string()),
+        }
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            body: None,
+        }
+    }
+
+    fn error(status: u16, message: String) -> Self {
+        Self {
+            status,
+            body: Some(json!({ "error": message })),
+        }
+    }
+}
+
+/// Summary of a router in [`AdminApi`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouterSummary {
+    pub id: String,
+    pub publishers: usize,
+    pub data_publishers: usize,
+    pub transports: usize,
+    pub subscribers: usize,
+}
+
+/// Stats of a subscriber with the transport which it belongs to.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminSubscriber {
+    pub transport_id: String,
+    #[serde(flatten)]
+    pub stats: SubscriberStats,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Route {
+    ListRouters,
+    GetRouter(String),
+    CloseRouter(String),
+    ListPublishers(String),
+    ClosePublisher(String, String),
+    ListSubscribers(String),
+}
+
+impl Route {
+    fn parse(method: &str, path: &str) -> Result<Self, AdminResponse> {
+        // IDs may contain reserved characters, for example `{` and `}` of track ids which are generated by browsers, so each segment is decoded after splitting.
+        let decoded = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                percent_decode_str(s)
+                    .decode_utf8()
+                    .map(|s| s.into_owned())
+                    .map_err(|err| AdminResponse::error(400, err.to_string()))
+            })
+            .collect::<Result<Vec<String>, AdminResponse>>()?;
+        let segments: Vec<&str> = decoded.iter().map(String::as_str).collect();
+        let method = method.to_ascii_uppercase();
+        let route = match (method.as_str(), segments.as_slice()) {
+            ("GET", ["routers"]) => Route::ListRouters,
+            ("GET", ["routers", router_id]) => Route::GetRouter(router_id.to_string()),
+            ("DELETE", ["routers", router_id]) => Route::CloseRouter(router_id.to_string()),
+            ("GET", ["routers", router_id, "publishers"]) => {
+                Route::ListPublishers(router_id.to_string())
+            }
+            ("DELETE", ["routers", router_id, "publishers", publisher_id]) => {
+                Route::ClosePublisher(router_id.to_string(), publisher_id.to_string())
+            }
+            ("GET", ["routers", router_id, "subscribers"]) => {
+                Route::ListSubscribers(router_id.to_string())
+            }
+            (_, ["routers"])
+            | (_, ["routers", _])
+            | (_, ["routers", _, "publishers"])
+            | (_, ["routers", _, "publishers", _])
+            | (_, ["routers", _, "subscribers"]) => {
+                return Err(AdminResponse::error(
+                    405,
+                    format!("method {} is not allowed", method),
+                ))
+            }
+            _ => return Err(AdminResponse::error(404, format!("{} is not found", path))),
+        };
+        Ok(route)
+    }
+}
+
+impl AdminApi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the router to the API. Routers are removed when they are dropped or closed with the API.
+    pub async fn register(&self, router: &Arc<Mutex<Router>>) {
+        let id = router.lock().await.id.clone();
+        self.routers
+            .lock()
+            .unwrap()
+            .insert(id, Arc::downgrade(router));
+    }
+
+    pub fn unregister(&self, router_id: &str) {
+        self.routers.lock().unwrap().remove(router_id);
+    }
+
+    /// Handle a request. `path` is relative to the mount point of the API, for example `/routers`.
+    pub async fn handle(&self, method: &str, path: &str) -> AdminResponse {
+        let route = match Route::parse(method, path) {
+            Ok(route) => route,
+            Err(res) => return res,
+        };
+        match route {
+            Route::ListRouters => {
+                let mut summaries = Vec::new();
+                for router in self.routers() {
+                    summaries.push(Self::summary(&*router.lock().await));
+                }
+                AdminResponse::ok(summaries)
+            }
+            Route::GetRouter(router_id) => match self.find(&router_id) {
+                Some(router) => AdminResponse::ok(router.lock().await.stats_snapshot()),
+                None => Self::router_not_found(&router_id),
+            },
+            Route::CloseRouter(router_id) => match self.find(&router_id) {
+                Some(router) => {
+                    router.lock().await.close();
+                    self.unregister(&router_id);
+                    tracing::info!("Router {} is closed by the admin API", router_id);
+                    AdminResponse::no_content()
+                }
+                None => Self::router_not_found(&router_id),
+            },
+            Route::ListPublishers(router_id) => match self.find(&router_id) {
+                Some(router) => {
+                    let publishers: Vec<PublisherStats> =
+                        router.lock().await.stats_snapshot().publishers;
+                    AdminResponse::ok(publishers)
+                }
+                None => Self::router_not_found(&router_id),
+            },
+            Route::ClosePublisher(router_id, publisher_id) => {
+                let Some(router) = self.find(&router_id) else {
+                    return Self::router_not_found(&router_id);
+                };
+                let publisher = router.lock().await.publisher(&publisher_id);
+                match publisher {
+                    Some(publisher) => {
+                        publisher.close().await;
+                        tracing::info!(
+                            "Publisher {} in router {} is closed by the admin API",
+                            publisher_id,
+                            router_id
+                        );
+                        AdminResponse::no_content()
+                    }
+                    None => AdminResponse::error(
+                        404,
+                        format!("publisher {} is not found", publisher_id),
+                    ),
+                }
+            }
+            Route::ListSubscribers(router_id) => match self.find(&router_id) {
+                Some(router) => {
+                    let subscribers: Vec<AdminSubscriber> = router
+                        .lock()
+                        .await
+                        .stats_snapshot()
+                        .transports
+                        .into_iter()
+                        .flat_map(|transport| {
+                            let transport_id = transport.id;
+                            transport
+                                .subscribers
+                                .into_iter()
+                                .map(move |stats| AdminSubscriber {
+                                    transport_id: transport_id.clone(),
+                                    stats,
+                                })
+                        })
+                        .collect();
+                    AdminResponse::ok(subscribers)
+                }
+                None => Self::router_not_found(&router_id),
+            },
+        }
+    }
+
+    /// Routers which are alive. Dropped routers are removed here.
+    fn routers(&self) -> Vec<Arc<Mutex<Router>>> {
+        let mut routers = Vec::new();
+        self.routers
+            .lock()
+            .unwrap()
+            .retain(|_, router| match router.upgrade() {
+                Some(router) => {
+                    routers.push(router);
+                    true
+                }
+                None => false,
+            });
+        routers
+    }
+
+    fn find(&self, router_id: &str) -> Option<Arc<Mutex<Router>>> {
+        self.routers
+            .lock()
+            .unwrap()
+            .get(router_id)
+            .and_then(|router| router.upgrade())
+    }
+
+    fn summary(router: &Router) -> RouterSummary {
+        let snapshot = router.stats_snapshot();
+        RouterSummary {
+            id: router.id.clone(),
+            publishers: snapshot.publishers.len(),
+            data_publishers: router.data_publisher_ids().len(),
+            transports: snapshot.transports.len(),
+            subscribers: snapshot
+                .transports
+                .iter()
+                .map(|transport| transport.subscribers.len())
+                .sum(),
+        }
+    }
+
+    fn router_not_found(router_id: &str) -> AdminResponse {
+        AdminResponse::error(404, format!("router {} is not found", router_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{config::MediaConfig, media_source::SilentSource};
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(Route::parse("GET", "/routers"), Ok(Route::ListRouters));
+        assert_eq!(
+            Route::parse("get", "/routers/r1/"),
+            Ok(Route::GetRouter("r1".to_string()))
+        );
+        assert_eq!(
+            Route::parse("DELETE", "/routers/r1/publishers/p1"),
+            Ok(Route::ClosePublisher("r1".to_string(), "p1".to_string()))
+        );
+        assert_eq!(
+            Route::parse("GET", "/routers/r1/subscribers"),
+            Ok(Route::ListSubscribers("r1".to_string()))
+        );
+        assert_eq!(Route::parse("POST", "/routers").unwrap_err().status, 405);
+        assert_eq!(
+            Route::parse("GET", "/routers/r1/publishers/p1")
+                .unwrap_err()
+                .status,
+            405
+        );
+        assert_eq!(Route::parse("GET", "/unknown").unwrap_err().status, 404);
+    }
+
+    #[test]
+    fn test_parse_encoded_route() {
+        assert_eq!(
+            Route::parse("DELETE", "/routers/r1/publishers/%7Bp1%7D"),
+            Ok(Route::ClosePublisher("r1".to_string(), "{p1}".to_string()))
+        );
+        assert_eq!(
+            Route::parse("DELETE", "/routers/r1/publishers/p%2F1"),
+            Ok(Route::ClosePublisher("r1".to_string(), "p/1".to_string()))
+        );
+        assert_eq!(Route::parse("GET", "/routers/%FF").unwrap_err().status, 400);
+    }
+
+    async fn wait_for_publishers(router: &Arc<Mutex<Router>>, ids: Vec<&str>) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while router.lock().await.publisher_ids() != ids {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_routers() {
+        let api = AdminApi::new();
+        let router = Router::new(MediaConfig::default());
+        api.register(&router).await;
+        let router_id = router.lock().await.id.clone();
+        router
+            .lock()
+            .await
+            .publish_source(SilentSource::new("{first}", 1))
+            .unwrap();
+        wait_for_publishers(&router, vec!["{first}"]).await;
+
+        let res = api.handle("GET", "/routers").await;
+        assert_eq!(res.status, 200);
+        assert_eq!(
+            res.body,
+            Some(
+                serde_json::to_value(vec![RouterSummary {
+                    id: router_id.clone(),
+                    publishers: 1,
+                    data_publishers: 0,
+                    transports: 0,
+                    subscribers: 0,
+                }])
+                .unwrap()
+            )
+        );
+
+        let res = api
+            .handle(
+                "DELETE",
+                &format!("/routers/{}/publishers/%7Bfirst%7D", router_id),
+            )
+            .await;
+        assert_eq!(res.status, 204);
+        wait_for_publishers(&router, vec![]).await;
+
+        let res = api
+            .handle(
+                "DELETE",
+                &format!("/routers/{}/publishers/unknown", router_id),
+            )
+            .await;
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_handle_close_router() {
+        let api = AdminApi::new();
+        let router = Router::new(MediaConfig::default());
+        api.register(&router).await;
+        let router_id = router.lock().await.id.clone();
+
+        let res = api
+            .handle("DELETE", &format!("/routers/{}", router_id))
+            .await;
+        assert_eq!(res, AdminResponse::no_content());
+
+        let res = api.handle("GET", &format!("/routers/{}", router_id)).await;
+        assert_eq!(res.status, 404);
+        let res = api.handle("GET", "/routers").await;
+        assert_eq!(res.body, Some(json!([])));
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use tokio::sync::{broadcast, watch};
+
+use crate::{publisher::Publisher, runtime};
+
+/// Weight of the latest interval in the smoothed loudness. Smoothing keeps the ranking stable between words.
+const LOUDNESS_SMOOTHING: f32 = 0.5;
+/// Smoothed loudness below this is treated as silence.
+const MIN_LOUDNESS: f32 = 0.5;
+
+/// Configuration of [`AudioLevelObserver`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioLevelObserverConfig {
+    /// Interval to update the ranking of speakers.
+    pub interval: Duration,
+    /// Audio level in -dBov. Packets which are quieter than this are treated as silence. The range is from 0 (loudest) to 127 (silence).
+    pub threshold: u8,
+}
+
+impl Default for AudioLevelObserverConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(500),
+            threshold: 80,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Loudness {
+    sum: u64,
+    packets: u64,
+    smoothed: f32,
+}
+
+impl Loudness {
+    fn record(&mut self, level: u8, threshold: u8) {
+        self.packets += 1;
+        if level < threshold {
+            self.sum += (threshold - level) as u64;
+        }
+    }
+
+    fn update(&mut self) -> f32 {
+        let current = if self.packets > 0 {
+            self.sum as f32 / self.packets as f32
+        } else {
+            0.0
+        };
+        self.smoothed = self.smoothed * (1.0 - LOUDNESS_SMOOTHING) + current * LOUDNESS_SMOOTHING;
+        self.sum = 0;
+        self.packets = 0;
+        self.smoothed
+    }
+}
+
+/// AudioLevelObserver ranks audio [`crate::publisher::Publisher`]s of a [`crate::router::Router`] by the audio level header extension (RFC 6464).
+/// Payloads are not decoded, so publishers which don't send the extension are ranked as silent.
+#[derive(Clone, Debug)]
+pub struct AudioLevelObserver {
+    config: AudioLevelObserverConfig,
+    loudness: Arc<Mutex<Vec<(String, Loudness)>>>,
+    ranking: Arc<watch::Sender<Vec<String>>>,
+}
+
+impl AudioLevelObserver {
+    pub(crate) fn new(config: AudioLevelObserverConfig) -> Self {
+        let (ranking, _) = watch::channel(Vec::new());
+        let observer = Self {
+            config,
+            loudness: Arc::new(Mutex::new(Vec::new())),
+            ranking: Arc::new(ranking),
+        };
+        let interval = observer.config.interval;
+        let loudness = Arc::downgrade(&observer.loudness);
+        let ranking = Arc::downgrade(&observer.ranking);
+        runtime::spawn(async move {
+            Self::ranking_loop(interval, loudness, ranking).await;
+        });
+        observer
+    }
+
+    /// Observe audio levels of the publisher until it is closed.
+    pub(crate) fn observe(&self, publisher: &Publisher) {
+        let id = publisher.id.clone();
+        {
+            let mut loudness = self.loudness.lock().unwrap();
+            loudness.retain(|(publisher_id, _)| *publisher_id != id);
+            loudness.push((id.clone(), Loudness::default()));
+        }
+        let mut metadata = publisher.metadata_tap();
+        let threshold = self.config.threshold;
+        let loudness = Arc::downgrade(&self.loudness);
+        runtime::spawn(async move {
+            loop {
+                match metadata.recv().await {
+                    Ok(packet) => {
+                        let Some(level) = packet.audio_level else {
+                            continue;
+                        };
+                        let Some(loudness) = loudness.upgrade() else {
+                            break;
+                        };
+                        let mut loudness = loudness.lock().unwrap();
+                        if let Some((_, l)) = loudness.iter_mut().find(|(i, _)| *i == id) {
+                            l.record(level, threshold);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            if let Some(loudness) = loudness.upgrade() {
+                loudness.lock().unwrap().retain(|(i, _)| *i != id);
+            }
+            tracing::debug!("AudioLevelObserver stops observing publisher {}", id);
+        });
+    }
+
+    async fn ranking_loop(
+        interval: Duration,
+        loudness: Weak<Mutex<Vec<(String, Loudness)>>>,
+        ranking: Weak<watch::Sender<Vec<String>>>,
+    ) {
+        loop {
+            runtime::sleep(interval).await;
+            let (Some(loudness), Some(ranking)) = (loudness.upgrade(), ranking.upgrade()) else {
+                break;
+            };
+            let new_ranking = {
+                let mut loudness = loudness.lock().unwrap();
+                rank(&mut loudness)
+            };
+            ranking.send_if_modified(|current| {
+                if *current == new_ranking {
+                    return false;
+                }
+                *current = new_ranking;
+                true
+            });
+        }
+    }
+
+    /// This returns IDs of all observed publishers from the loudest. Silent publishers follow in the order they are published.
+    pub fn ranking(&self) -> Vec<String> {
+        self.ranking.borrow().clone()
+    }
+
+    /// This returns a receiver which is updated when the ranking is changed.
+    pub fn watch(&self) -> watch::Receiver<Vec<String>> {
+        self.ranking.subscribe()
+    }
+}
+
+/// Compute the audio level in -dBov (RFC 6464) from 16 bit PCM samples, from 0 (loudest) to 127 (silence).
+/// It is useful for [`crate::media_source::MediaSource::audio_level`] of mixed or generated audio.
+pub fn compute_audio_level(samples: &[i16]) -> u8 {
+    if samples.is_empty() {
+        return 127;
+    }
+    let square_sum: f64 = samples
+        .iter()
+        .map(|s| {
+            let s = *s as f64 / 32768.0;
+            s * s
+        })
+        .sum();
+    let rms = (square_sum / samples.len() as f64).sqrt();
+    if rms <= 0.0 {
+        return 127;
+    }
+    let dbov = 20.0 * rms.log10();
+    (-dbov).round().clamp(0.0, 127.0) as u8
+}
+
+fn rank(loudness: &mut [(String, Loudness)]) -> Vec<String> {
+    let mut speakers: Vec<(usize, f32)> = loudness
+        .iter_mut()
+        .enumerate()
+        .map(|(i, (_, l))| {
+            let smoothed = l.update();
+            (
+                i,
+                if smoothed < MIN_LOUDNESS {
+                    0.0
+                } else {
+                    smoothed
+                },
+            )
+        })
+        .collect();
+    // The sort is stable, so silent publishers keep the published order.
+    speakers.sort_by(|a, b| b.1.total_cmp(&a.1));
+    speakers
+        .into_iter()
+        .map(|(i, _)| loudness[i].0.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rank() {
+        let mut loudness = vec![
+            ("a".to_string(), Loudness::default()),
+            ("b".to_string(), Loudness::default()),
+            ("c".to_string(), Loudness::default()),
+        ];
+        loudness[1].1.record(30, 80);
+        loudness[2].1.record(60, 80);
+        loudness[2].1.record(127, 80);
+        assert_eq!(rank(&mut loudness), vec!["b", "c", "a"]);
+
+        // Without new packets, the smoothed loudness decays but keeps the order.
+        assert_eq!(rank(&mut loudness), vec!["b", "c", "a"]);
+
+        loudness[0].1.record(0, 80);
+        assert_eq!(rank(&mut loudness), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_compute_audio_level() {
+        assert_eq!(compute_audio_level(&[]), 127);
+        assert_eq!(compute_audio_level(&[0; 960]), 127);
+        assert_eq!(compute_audio_level(&[i16::MIN; 960]), 0);
+        // A tenth of the full scale is -20 dBov.
+        let samples: Vec<i16> = (0..960)
+            .map(|i| if i % 2 == 0 { 3277 } else { -3277 })
+            .collect();
+        assert_eq!(compute_audio_level(&samples), 20);
+    }
+}
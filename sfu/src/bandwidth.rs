@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{RembPolicy, UpgradePolicy},
+    publisher::MediaType,
+    subscriber::SubscriberPriority,
+};
+
+const MEASURE_INTERVAL: Duration = Duration::from_millis(500);
+// Measured bitrate is only a lower bound of what a stream wants, so allow it to grow.
+const DEMAND_HEADROOM: f32 = 1.5;
+const MIN_VIDEO_DEMAND: f32 = 150_000.0;
+
+/// BandwidthAllocator shares the bandwidth estimate of one [`crate::subscribe_transport::SubscribeTransport`] among its subscribers.
+/// Audio is served first, and the rest is split among video subscribers with weighted max-min fairness, so one high bitrate track can't starve the others.
+///
+/// This is not a weighted fair queuing pacer. webrtc-rs writes packets of each track to the transport directly, so the SFU has no egress queue to schedule. Instead, the share of each subscriber caps the REMB which is forwarded to its publisher by [`RembShaper`], and publishers lower their bitrates to fit in the shares.
+#[derive(Debug, Default)]
+pub(crate) struct BandwidthAllocator {
+    entries: HashMap<String, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    media_type: MediaType,
+    priority: SubscriberPriority,
+    sent_bytes: Arc<AtomicU64>,
+    last_bytes: u64,
+    last_measured: Instant,
+    bitrate: f32,
+    bandwidth_hint: Option<f32>,
+}
+
+impl Entry {
+    /// Bitrate which the subscriber wants. The bandwidth hint is used as the initial demand until the bitrate is measured, and it caps the demand.
+    fn demand(&self) -> f32 {
+        let measured = (self.bitrate * DEMAND_HEADROOM).max(MIN_VIDEO_DEMAND);
+        match self.bandwidth_hint {
+            Some(hint) if self.bitrate == 0.0 => hint,
+            Some(hint) => measured.min(hint),
+            None => measured,
+        }
+    }
+}
+
+impl BandwidthAllocator {
+    /// Register a subscriber and return the counter which the subscriber has to increase with forwarded bytes.
+    pub(crate) fn register(&mut self, id: String, media_type: MediaType) -> Arc<AtomicU64> {
+        let sent_bytes = Arc::new(AtomicU64::new(0));
+        self.entries.insert(
+            id,
+            Entry {
+                media_type,
+                priority: SubscriberPriority::default(),
+                sent_bytes: sent_bytes.clone(),
+                last_bytes: 0,
+                last_measured: Instant::now(),
+                bitrate: 0.0,
+                bandwidth_hint: None,
+            },
+        );
+        sent_bytes
+    }
+
+    pub(crate) fn unregister(&mut self, id: &str) {
+        self.entries.remove(id);
+    }
+
+    pub(crate) fn set_priority(&mut self, id: &str, priority: SubscriberPriority) {
+        if let Some(entry) = self.entries.get_mut(id) {
+            entry.priority = priority;
+        }
+    }
+
+    /// Set the bitrate in bps which is announced for the subscriber with `b=TIAS` or `b=AS`.
+    pub(crate) fn set_bandwidth_hint(&mut self, id: &str, bandwidth_hint: Option<u32>) {
+        if let Some(entry) = self.entries.get_mut(id) {
+            entry.bandwidth_hint = bandwidth_hint.map(|hint| hint as f32);
+        }
+    }
+
+    /// This returns the bitrate which the subscriber is allowed to request to its publisher, from the whole estimate of the transport.
+    pub(crate) fn share(&mut self, id: &str, estimate: f32) -> f32 {
+        self.measure();
+
+        let Some(target) = self.entries.get(id) else {
+            return estimate;
+        };
+
+        let audio_demand: f32 = self
+            .entries
+            .values()
+            .filter(|e| e.media_type == MediaType::Audio)
+            .map(|e| e.bitrate)
+            .sum();
+        if target.media_type == MediaType::Audio {
+            return estimate;
+        }
+
+        let mut ids = Vec::new();
+        let mut flows = Vec::new();
+        for (entry_id, entry) in self.entries.iter() {
+            if entry.media_type != MediaType::Video {
+                continue;
+            }
+            ids.push(entry_id.as_str());
+            flows.push((entry.priority.weight(), entry.demand()));
+        }
+
+        let capacity = (estimate - audio_demand).max(0.0);
+        let allocation = allocate(capacity, &flows);
+        ids.iter()
+            .position(|i| *i == id)
+            .map(|index| allocation[index])
+            .unwrap_or(estimate)
+    }
+
+    /// This returns the bitrate which is forwarded to all subscribers of the transport.
+    pub(crate) fn total_bitrate(&mut self) -> f32 {
+        self.measure();
+        self.entries.values().map(|e| e.bitrate).sum()
+    }
+
+    fn measure(&mut self) {
+        let now = Instant::now();
+        for entry in self.entries.values_mut() {
+            let elapsed = now.duration_since(entry.last_measured);
+            if elapsed < MEASURE_INTERVAL {
+                continue;
+            }
+            let bytes = entry.sent_bytes.load(Ordering::Relaxed);
+            let diff = bytes.saturating_sub(entry.last_bytes);
+            entry.bitrate = diff as f32 * 8.0 / elapsed.as_secs_f32();
+            entry.last_bytes = bytes;
+            entry.last_measured = now;
+        }
+    }
+}
+
+/// RembShaper adjusts REMB values which a subscriber forwards to its publisher, using the fair share of the transport and [`RembPolicy`].
+#[derive(Debug)]
+pub(crate) struct RembShaper {
+    subscriber_id: String,
+    media_type: MediaType,
+    allocator: Arc<std::sync::Mutex<BandwidthAllocator>>,
+    policy: RembPolicy,
+    started_at: Instant,
+    upgrade_scheduler: Option<UpgradeScheduler>,
+}
+
+impl RembShaper {
+    pub(crate) fn new(
+        subscriber_id: String,
+        media_type: MediaType,
+        allocator: Arc<std::sync::Mutex<BandwidthAllocator>>,
+        policy: RembPolicy,
+    ) -> Self {
+        let upgrade_scheduler = policy.upgrade.clone().map(UpgradeScheduler::new);
+        Self {
+            subscriber_id,
+            media_type,
+            allocator,
+            policy,
+            started_at: Instant::now(),
+            upgrade_scheduler,
+        }
+    }
+
+    pub(crate) fn shape(&mut self, bitrate: f32) -> f32 {
+        let mut bitrate = self
+            .allocator
+            .lock()
+            .unwrap()
+            .share(&self.subscriber_id, bitrate);
+        if let Some(scheduler) = self.upgrade_scheduler.as_mut() {
+            bitrate = scheduler.schedule(bitrate, Instant::now());
+        }
+        self.policy
+            .clamp(self.media_type, self.started_at.elapsed(), bitrate)
+    }
+}
+
+impl Drop for RembShaper {
+    fn drop(&mut self) {
+        self.allocator
+            .lock()
+            .unwrap()
+            .unregister(&self.subscriber_id);
+    }
+}
+
+/// UpgradeScheduler decides when the bitrate can be raised again after congestion, with hold-down timers and exponential backoff.
+#[derive(Debug)]
+pub(crate) struct UpgradeScheduler {
+    policy: UpgradePolicy,
+    current: Option<f32>,
+    hold_down: Duration,
+    hold_until: Option<Instant>,
+    upgraded_at: Option<Instant>,
+}
+
+impl UpgradeScheduler {
+    pub(crate) fn new(policy: UpgradePolicy) -> Self {
+        let hold_down = policy.hold_down;
+        Self {
+            policy,
+            current: None,
+            hold_down,
+            hold_until: None,
+            upgraded_at: None,
+        }
+    }
+
+    /// This returns the bitrate which should be forwarded for the estimate.
+    pub(crate) fn schedule(&mut self, estimate: f32, now: Instant) -> f32 {
+        let Some(current) = self.current else {
+            self.current = Some(estimate);
+            return estimate;
+        };
+
+        if estimate < current * self.policy.congestion_ratio {
+            if let Some(upgraded_at) = self.upgraded_at.take() {
+                if now.duration_since(upgraded_at) < self.policy.probe_duration {
+                    self.hold_down = (self.hold_down * 2).min(self.policy.max_hold_down);
+                    tracing::debug!(
+                        "upgrade failed, hold-down is backed off to {:?}",
+                        self.hold_down
+                    );
+                }
+            }
+            self.hold_until = Some(now + self.hold_down);
+            self.current = Some(estimate);
+            return estimate;
+        }
+
+        if estimate <= current {
+            self.current = Some(estimate);
+            return estimate;
+        }
+
+        if let Some(hold_until) = self.hold_until {
+            if now < hold_until {
+                return current;
+            }
+            self.hold_until = None;
+            self.upgraded_at = Some(now);
+        }
+        if let Some(upgraded_at) = self.upgraded_at {
+            if now.duration_since(upgraded_at) >= self.policy.probe_duration {
+                self.upgraded_at = None;
+                self.hold_down = self.policy.hold_down;
+            }
+        }
+        self.current = Some(estimate);
+        estimate
+    }
+}
+
+/// Weighted max-min fair allocation (water-filling). Each flow is `(weight, demand)`.
+/// Flows demanding less than their fair share get their demand, and the rest is shared by the others in proportion to their weights.
+pub(crate) fn allocate(capacity: f32, flows: &[(f32, f32)]) -> Vec<f32> {
+    let mut result = vec![0.0; flows.len()];
+    let mut remaining = capacity;
+    let mut active: Vec<usize> = (0..flows.len()).collect();
+
+    while !active.is_empty() && remaining > 0.0 {
+        let total_weight: f32 = active.iter().map(|i| flows[*i].0).sum();
+        if total_weight <= 0.0 {
+            break;
+        }
+        let satisfied: Vec<usize> = active
+            .iter()
+            .copied()
+            .filter(|i| flows[*i].1 <= remaining * flows[*i].0 / total_weight)
+            .collect();
+
+        if satisfied.is_empty() {
+            for i in active.iter() {
+                result[*i] = remaining * flows[*i].0 / total_weight;
+            }
+            break;
+        }
+
+        for i in satisfied.iter() {
+            result[*i] = flows[*i].1;
+            remaining -= flows[*i].1;
+        }
+        active.retain(|i| !satisfied.contains(i));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allocate_equal_weights() {
+        let res = allocate(3_000_000.0, &[(1.0, 10_000_000.0), (1.0, 10_000_000.0)]);
+        assert_eq!(res, vec![1_500_000.0, 1_500_000.0]);
+    }
+
+    #[test]
+    fn test_allocate_redistributes_unused_share() {
+        let res = allocate(3_000_000.0, &[(1.0, 10_000_000.0), (1.0, 500_000.0)]);
+        assert_eq!(res, vec![2_500_000.0, 500_000.0]);
+    }
+
+    #[test]
+    fn test_upgrade_scheduler_backoff() {
+        let policy = UpgradePolicy::default();
+        let mut scheduler = UpgradeScheduler::new(policy.clone());
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert_eq!(scheduler.schedule(2_000_000.0, at(0)), 2_000_000.0);
+        // Congestion is forwarded immediately, and a recovery is held down.
+        assert_eq!(scheduler.schedule(1_000_000.0, at(1)), 1_000_000.0);
+        assert_eq!(scheduler.schedule(2_000_000.0, at(2)), 1_000_000.0);
+        assert_eq!(scheduler.schedule(2_000_000.0, at(3)), 2_000_000.0);
+
+        // Congestion right after the upgrade doubles the hold-down.
+        assert_eq!(scheduler.schedule(1_000_000.0, at(4)), 1_000_000.0);
+        assert_eq!(scheduler.schedule(2_000_000.0, at(7)), 1_000_000.0);
+        assert_eq!(scheduler.schedule(2_000_000.0, at(8)), 2_000_000.0);
+
+        // The hold-down is reset after the upgrade is stable.
+        assert_eq!(scheduler.schedule(2_100_000.0, at(20)), 2_100_000.0);
+        assert_eq!(scheduler.schedule(1_000_000.0, at(30)), 1_000_000.0);
+        assert_eq!(scheduler.schedule(2_000_000.0, at(32)), 2_000_000.0);
+    }
+
+    #[test]
+    fn test_bandwidth_hint_demand() {
+        let mut allocator = BandwidthAllocator::default();
+        allocator.register("a".to_string(), MediaType::Video);
+        allocator.register("b".to_string(), MediaType::Video);
+        allocator.set_bandwidth_hint("a", Some(2_000_000));
+
+        // The hint is used as the initial demand before the bitrate is measured.
+        assert_eq!(allocator.share("a", 3_000_000.0), 2_000_000.0);
+        assert_eq!(allocator.share("b", 3_000_000.0), MIN_VIDEO_DEMAND);
+
+        allocator.entries.get_mut("a").unwrap().bitrate = 1_000_000.0;
+        assert_eq!(allocator.share("a", 3_000_000.0), 1_500_000.0);
+    }
+
+    #[test]
+    fn test_allocate_weighted() {
+        let res = allocate(3_000_000.0, &[(2.0, 10_000_000.0), (1.0, 10_000_000.0)]);
+        assert_eq!(res, vec![2_000_000.0, 1_000_000.0]);
+    }
+}
@@ -0,0 +1,109 @@
+use std::net::{IpAddr, SocketAddr};
+
+use rheomesh::config::{IceServerConfig, MediaConfig, PortRange, WebRTCTransportConfig};
+use serde::Deserialize;
+
+/// Configuration file of rheomesh-server.
+///
+/// ```json
+/// {
+///   "listen": "0.0.0.0:4000",
+///   "adminListen": "127.0.0.1:4001",
+///   "logLevel": "info",
+///   "announcedIps": ["192.168.10.10"],
+///   "iceServers": [{ "urls": ["stun:stun.l.google.com:19302"] }],
+///   "portRange": { "min": 12000, "max": 15000 }
+/// }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ServerConfig {
+    /// Address of the HTTP server which accepts signaling WebSocket connections.
+    pub listen: SocketAddr,
+    /// Address of the admin API. It is disabled when this is not set. The API is not authenticated, so please bind it to a private address.
+    pub admin_listen: Option<SocketAddr>,
+    /// Filter of tracing events. `RUST_LOG` takes precedence over it.
+    pub log_level: String,
+    pub announced_ips: Vec<IpAddr>,
+    pub ice_servers: Vec<IceServerConfig>,
+    pub port_range: Option<ServerPortRange>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ServerPortRange {
+    min: u16,
+    max: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 4000)),
+            admin_listen: None,
+            log_level: "info".to_string(),
+            announced_ips: Vec::new(),
+            ice_servers: Vec::new(),
+            port_range: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&content).map_err(|e| e.to_string())
+    }
+
+    pub fn media_config(&self) -> MediaConfig {
+        MediaConfig::default()
+    }
+
+    pub fn transport_config(&self) -> WebRTCTransportConfig {
+        let mut config = WebRTCTransportConfig::default();
+        config.announced_ips = self.announced_ips.clone();
+        config.configuration.ice_servers =
+            self.ice_servers.iter().cloned().map(Into::into).collect();
+        config.port_range = self.port_range.as_ref().map(|range| PortRange {
+            min: range.min,
+            max: range.max,
+        });
+        config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_documented_config() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{
+  "listen": "0.0.0.0:4000",
+  "adminListen": "127.0.0.1:4001",
+  "logLevel": "info",
+  "announcedIps": ["192.168.10.10"],
+  "iceServers": [{ "urls": ["stun:stun.l.google.com:19302"] }],
+  "portRange": { "min": 12000, "max": 15000 }
+}"#,
+        )
+        .unwrap();
+        let transport_config = config.transport_config();
+        assert_eq!(
+            transport_config.configuration.ice_servers[0].urls,
+            vec!["stun:stun.l.google.com:19302".to_string()]
+        );
+        assert_eq!(transport_config.configuration.ice_servers[0].username, "");
+        assert_eq!(
+            transport_config.port_range,
+            Some(PortRange {
+                min: 12000,
+                max: 15000
+            })
+        );
+        assert_eq!(
+            config.admin_listen,
+            Some(SocketAddr::from(([127, 0, 0, 1], 4001)))
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Standalone SFU server which embeds rheomesh.
+//!
+//! Usage: rheomesh-server [config.json]
+//!
+//! It serves the signaling WebSocket on `/socket?room=<room id>` and a health check on `/`. Please refer [`config::ServerConfig`] for the configuration file.
+//! When `adminListen` is configured, [`rheomesh::admin::AdminApi`] is served on the address. It is not authenticated, so please bind it to a private address.
+//! WHIP/WHEP endpoints and a metrics exporter are not served yet.
+use std::process::ExitCode;
+
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder};
+use rheomesh::admin::AdminApi;
+use rheomesh::config::FindingSeverity;
+use tracing_actix_web::TracingLogger;
+use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+mod config;
+mod signaling;
+
+#[actix_web::main]
+async fn main() -> ExitCode {
+    let server_config = match std::env::args().nth(1) {
+        Some(path) => match config::ServerConfig::load(&path) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("failed to load {}: {}", path, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => config::ServerConfig::default(),
+    };
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| server_config.log_level.clone().into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let media_config = server_config.media_config();
+    let transport_config = server_config.transport_config();
+    let findings = rheomesh::config::validate(&media_config, &transport_config);
+    for finding in findings.iter() {
+        match finding.severity {
+            FindingSeverity::Error => tracing::error!("{}", finding.message),
+            FindingSeverity::Warning => tracing::warn!("{}", finding.message),
+        }
+    }
+    if findings
+        .iter()
+        .any(|f| f.severity == FindingSeverity::Error)
+    {
+        return ExitCode::FAILURE;
+    }
+
+    let admin = AdminApi::new();
+    if let Some(admin_listen) = server_config.admin_listen {
+        let admin = Data::new(admin.clone());
+        let server = match HttpServer::new(move || {
+            App::new()
+                .wrap(TracingLogger::default())
+                .app_data(admin.clone())
+                .default_service(web::to(admin_api))
+        })
+        .bind(admin_listen)
+        {
+            Ok(server) => server,
+            Err(err) => {
+                tracing::error!("failed to bind {}: {}", admin_listen, err);
+                return ExitCode::FAILURE;
+            }
+        };
+        tracing::info!("Admin API is listening on {}", admin_listen);
+        actix_web::rt::spawn(server.run());
+    }
+
+    let rooms = Data::new(signaling::Rooms::new(media_config, transport_config, admin));
+    tracing::info!("Listening on {}", server_config.listen);
+    let server = match HttpServer::new(move || {
+        App::new()
+            .wrap(TracingLogger::default())
+            .app_data(rooms.clone())
+            .route("/", web::get().to(health))
+            .route("/socket", web::get().to(signaling::socket))
+    })
+    .bind(server_config.listen)
+    {
+        Ok(server) => server,
+        Err(err) => {
+            tracing::error!("failed to bind {}: {}", server_config.listen, err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match server.run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tracing::error!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn health() -> impl Responder {
+    HttpResponse::Ok().body("healthy")
+}
+
+async fn admin_api(req: HttpRequest, admin: Data<AdminApi>) -> HttpResponse {
+    let res = admin.handle(req.method().as_str(), req.path()).await;
+    let status = StatusCode::from_u16(res.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    match res.body {
+        Some(body) => HttpResponse::build(status).json(body),
+        None => HttpResponse::build(status).finish(),
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix::{Actor, Addr, AsyncContext, Handler, Message, StreamHandler};
+use actix_web::web::{self, Data, Query};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use rheomesh::admin::AdminApi;
+use rheomesh::config::{MediaConfig, WebRTCTransportConfig};
+use rheomesh::integrations::actix::TransportActorBridge;
+use rheomesh::publisher::Publisher;
+use rheomesh::router::Router;
+use rheomesh::subscriber::Subscriber;
+use rheomesh::transport::Transport;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+/// Rooms of the server. Each room has a router, and it is created when the first user joins it.
+/// The room is removed and its router is closed when the last user leaves it.
+pub struct Rooms {
+    media_config: MediaConfig,
+    transport_config: WebRTCTransportConfig,
+    // Rooms with the number of users who joined them.
+    rooms: Mutex<HashMap<String, (Arc<Room>, usize)>>,
+    admin: AdminApi,
+}
+
+impl Rooms {
+    /// Routers of rooms are registered to `admin`.
+    pub fn new(
+        media_config: MediaConfig,
+        transport_config: WebRTCTransportConfig,
+        admin: AdminApi,
+    ) -> Self {
+        Self {
+            media_config,
+            transport_config,
+            rooms: Mutex::new(HashMap::new()),
+            admin,
+        }
+    }
+
+    async fn join(&self, id: &str) -> Arc<Room> {
+        let mut rooms = self.rooms.lock().await;
+        if let Some((room, users)) = rooms.get_mut(id) {
+            *users += 1;
+            return room.clone();
+        }
+        tracing::info!("Creating a room: {}", id);
+        let router = Router::new(self.media_config.clone());
+        self.admin.register(&router).await;
+        let room = Arc::new(Room::new(router));
+        rooms.insert(id.to_string(), (room.clone(), 1));
+        room
+    }
+
+    async fn leave(&self, id: &str) {
+        let mut rooms = self.rooms.lock().await;
+        let Some((room, users)) = rooms.get_mut(id) else {
+            return;
+        };
+        *users -= 1;
+        if *users > 0 {
+            return;
+        }
+        tracing::info!("Removing a room: {}", id);
+        let router = room.router.lock().await;
+        router.close();
+        self.admin.unregister(&router.id);
+        drop(router);
+        rooms.remove(id);
+    }
+}
+
+/// Signaling WebSocket, which speaks the same protocol as the client SDK examples.
+/// Users join the room which is specified with the `room` query parameter.
+pub async fn socket(
+    req: HttpRequest,
+    rooms: Data<Rooms>,
+    stream: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let parameters = match Query::<HashMap<String, String>>::from_query(req.query_string()) {
+        Ok(parameters) => parameters,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+    };
+    let Some(room_id) = parameters.get("room") else {
+        return Ok(HttpResponse::BadRequest().body("room is required"));
+    };
+    let room = rooms.join(room_id).await;
+    let server = WebSocket::new(
+        rooms.clone(),
+        room_id.to_string(),
+        room,
+        rooms.transport_config.clone(),
+    )
+    .await;
+    let res = ws::start(server, &req, stream);
+    if res.is_err() {
+        rooms.leave(room_id).await;
+    }
+    res
+}
+
+pub struct WebSocket {
+    rooms: Data<Rooms>,
+    room_id: String,
+    room: Arc<Room>,
+    publish_transport: Arc<rheomesh::publish_transport::PublishTransport>,
+    subscribe_transport: Arc<rheomesh::subscribe_transport::SubscribeTransport>,
+    publishers: Arc<Mutex<HashMap<String, Arc<Publisher>>>>,
+    subscribers: Arc<Mutex<HashMap<String, Arc<Subscriber>>>>,
+}
+
+impl WebSocket {
+    pub async fn new(
+        rooms: Data<Rooms>,
+        room_id: String,
+        room: Arc<Room>,
+        config: WebRTCTransportConfig,
+    ) -> Self {
+        let r = room.router.clone();
+        let router = r.lock().await;
+        let publish_transport = router.create_publish_transport(config.clone()).await;
+        let subscribe_transport = router.create_subscribe_transport(config).await;
+        Self {
+            rooms,
+            room_id,
+            room,
+            publish_transport: Arc::new(publish_transport),
+            subscribe_transport: Arc::new(subscribe_transport),
+            publishers: Arc::new(Mutex::new(HashMap::new())),
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Actor for WebSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        tracing::info!("New WebSocket connection is started");
+        let address = ctx.address();
+        self.room.add_user(address.clone());
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        tracing::info!("The WebSocket connection is stopped");
+        let address = ctx.address();
+        let subscribe_transport = self.subscribe_transport.clone();
+        let publish_transport = self.publish_transport.clone();
+        let rooms = self.rooms.clone();
+        let room_id = self.room_id.clone();
+        self.room.remove_user(address);
+        actix::spawn(async move {
+            if let Err(err) = subscribe_transport.close().await {
+                tracing::error!("failed to close subscribe_transport: {}", err);
+            }
+            if let Err(err) = publish_transport.close().await {
+                tracing::error!("failed to close publish_transport: {}", err);
+            }
+            rooms.leave(&room_id).await;
+        });
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
+    fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match item {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<ReceivedMessage>(&text) {
+                Ok(message) => {
+                    ctx.address().do_send(message);
+                }
+                Err(error) => {
+                    tracing::error!("failed to parse client message: {}\n{}", error, text);
+                }
+            },
+            Ok(ws::Message::Close(reason)) => ctx.close(reason),
+            _ => (),
+        }
+    }
+}
+
+impl Handler<ReceivedMessage> for WebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReceivedMessage, ctx: &mut Self::Context) -> Self::Result {
+        let address = ctx.address();
+        tracing::debug!("received message: {:?}", msg);
+
+        match msg {
+            ReceivedMessage::Ping => {
+                address.do_send(SendingMessage::Pong);
+            }
+            ReceivedMessage::PublisherInit => {
+                let publish_transport = self.publish_transport.clone();
+                let bridge = TransportActorBridge::new(&address);
+                actix::spawn(async move {
+                    publish_transport
+                        .on_ice_candidate(bridge.callback(|candidate: RTCIceCandidate| {
+                            let init = candidate.to_json().expect("failed to parse candidate");
+                            SendingMessage::PublisherIce { candidate: init }
+                        }))
+                        .await;
+                });
+            }
+            ReceivedMessage::SubscriberInit => {
+                let subscribe_transport = self.subscribe_transport.clone();
+                let room = self.room.clone();
+                let bridge = TransportActorBridge::new(&address);
+                actix::spawn(async move {
+                    subscribe_transport
+                        .on_ice_candidate(bridge.callback(|candidate: RTCIceCandidate| {
+                            let init = candidate.to_json().expect("failed to parse candidate");
+                            SendingMessage::SubscriberIce { candidate: init }
+                        }))
+                        .await;
+                    subscribe_transport
+                        .on_negotiation_needed(
+                            bridge.callback(|offer| SendingMessage::Offer { sdp: offer }),
+                        )
+                        .await;
+
+                    let ids = room.router.lock().await.publisher_ids();
+                    address.do_send(SendingMessage::Published { publisher_ids: ids });
+                });
+            }
+
+            ReceivedMessage::RequestPublish => address.do_send(SendingMessage::StartAsPublisher),
+            ReceivedMessage::PublisherIce { candidate } => {
+                let publish_transport = self.publish_transport.clone();
+                actix::spawn(async move {
+                    if let Err(err) = publish_transport.add_ice_candidate(candidate).await {
+                        tracing::error!("failed to add ICE candidate: {}", err);
+                    }
+                });
+            }
+            ReceivedMessage::SubscriberIce { candidate } => {
+                let subscribe_transport = self.subscribe_transport.clone();
+                actix::spawn(async move {
+                    if let Err(err) = subscribe_transport.add_ice_candidate(candidate).await {
+                        tracing::error!("failed to add ICE candidate: {}", err);
+                    }
+                });
+            }
+            ReceivedMessage::Offer { sdp } => {
+                let publish_transport = self.publish_transport.clone();
+                actix::spawn(async move {
+                    match publish_transport.get_answer(sdp).await {
+                        Ok(answer) => address.do_send(SendingMessage::Answer { sdp: answer }),
+                        Err(err) => tracing::error!("failed to connect publish_transport: {}", err),
+                    }
+                });
+            }
+            ReceivedMessage::Subscribe {
+                publisher_id: track_id,
+            } => {
+                let subscribe_transport = self.subscribe_transport.clone();
+                let subscribers = self.subscribers.clone();
+                actix::spawn(async move {
+                    let (subscriber, offer) = match subscribe_transport.subscribe(track_id).await {
+                        Ok(result) => result,
+                        Err(err) => {
+                            tracing::error!("failed to connect subscribe_transport: {}", err);
+                            return;
+                        }
+                    };
+
+                    let id = subscriber.id.clone();
+                    let mut s = subscribers.lock().await;
+                    s.insert(subscriber.id.clone(), Arc::new(subscriber));
+                    address.do_send(SendingMessage::Offer { sdp: offer });
+                    address.do_send(SendingMessage::Subscribed { subscriber_id: id })
+                });
+            }
+            ReceivedMessage::Answer { sdp } => {
+                let subscribe_transport = self.subscribe_transport.clone();
+                actix::spawn(async move {
+                    if let Err(err) = subscribe_transport.set_answer(sdp).await {
+                        tracing::error!("failed to set answer: {}", err);
+                    }
+                });
+            }
+            ReceivedMessage::Publish { track_id } => {
+                let room = self.room.clone();
+                let publish_transport = self.publish_transport.clone();
+                let publishers = self.publishers.clone();
+                actix::spawn(async move {
+                    match publish_transport.publish(track_id).await {
+                        Ok(publisher) => {
+                            tracing::debug!("published a track: {}", publisher.id);
+                            let mut p = publishers.lock().await;
+                            p.insert(publisher.id.clone(), publisher.clone());
+                            room.get_peers(&address).iter().for_each(|peer| {
+                                peer.do_send(SendingMessage::Published {
+                                    publisher_ids: vec![publisher.id.clone()],
+                                });
+                            });
+                        }
+                        Err(err) => {
+                            tracing::error!("{}", err);
+                        }
+                    }
+                });
+            }
+            ReceivedMessage::StopPublish { publisher_id } => {
+                let publishers = self.publishers.clone();
+                actix::spawn(async move {
+                    let mut p = publishers.lock().await;
+                    if let Some(publisher) = p.remove(&publisher_id) {
+                        publisher.close().await;
+                    }
+                });
+            }
+            ReceivedMessage::StopSubscribe { subscriber_id } => {
+                let subscribers = self.subscribers.clone();
+                actix::spawn(async move {
+                    let mut s = subscribers.lock().await;
+                    if let Some(subscriber) = s.remove(&subscriber_id) {
+                        subscriber.close().await;
+                    }
+                });
+            }
+        }
+    }
+}
+
+impl Handler<SendingMessage> for WebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendingMessage, ctx: &mut Self::Context) -> Self::Result {
+        tracing::debug!("sending message: {:?}", msg);
+        ctx.text(serde_json::to_string(&msg).expect("failed to parse SendingMessage"));
+    }
+}
+
+#[derive(Deserialize, Message, Debug)]
+#[serde(tag = "action")]
+#[rtype(result = "()")]
+enum ReceivedMessage {
+    #[serde(rename_all = "camelCase")]
+    Ping,
+    #[serde(rename_all = "camelCase")]
+    PublisherInit,
+    #[serde(rename_all = "camelCase")]
+    SubscriberInit,
+    #[serde(rename_all = "camelCase")]
+    RequestPublish,
+    // Seems like client-side (JS) RTCIceCandidate struct is equal RTCIceCandidateInit.
+    #[serde(rename_all = "camelCase")]
+    PublisherIce { candidate: RTCIceCandidateInit },
+    #[serde(rename_all = "camelCase")]
+    SubscriberIce { candidate: RTCIceCandidateInit },
+    #[serde(rename_all = "camelCase")]
+    Offer { sdp: RTCSessionDescription },
+    #[serde(rename_all = "camelCase")]
+    Subscribe { publisher_id: String },
+    #[serde(rename_all = "camelCase")]
+    Answer { sdp: RTCSessionDescription },
+    #[serde(rename_all = "camelCase")]
+    Publish { track_id: String },
+    #[serde(rename_all = "camelCase")]
+    StopPublish { publisher_id: String },
+    #[serde(rename_all = "camelCase")]
+    StopSubscribe { subscriber_id: String },
+}
+
+#[derive(Serialize, Message, Debug)]
+#[serde(tag = "action")]
+#[rtype(result = "()")]
+enum SendingMessage {
+    #[serde(rename_all = "camelCase")]
+    Pong,
+    #[serde(rename_all = "camelCase")]
+    StartAsPublisher,
+    #[serde(rename_all = "camelCase")]
+    Answer { sdp: RTCSessionDescription },
+    #[serde(rename_all = "camelCase")]
+    Offer { sdp: RTCSessionDescription },
+    #[serde(rename_all = "camelCase")]
+    PublisherIce { candidate: RTCIceCandidateInit },
+    #[serde(rename_all = "camelCase")]
+    SubscriberIce { candidate: RTCIceCandidateInit },
+    #[serde(rename_all = "camelCase")]
+    Published { publisher_ids: Vec<String> },
+    #[serde(rename_all = "camelCase")]
+    Subscribed { subscriber_id: String },
+}
+
+pub struct Room {
+    router: Arc<Mutex<Router>>,
+    users: std::sync::Mutex<Vec<Addr<WebSocket>>>,
+}
+
+impl Room {
+    fn new(router: Arc<Mutex<Router>>) -> Self {
+        Self {
+            router,
+            users: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn add_user(&self, user: Addr<WebSocket>) {
+        let mut users = self.users.lock().unwrap();
+        users.push(user);
+    }
+
+    fn remove_user(&self, user: Addr<WebSocket>) {
+        let mut users = self.users.lock().unwrap();
+        users.retain(|u| u != &user);
+    }
+
+    fn get_peers(&self, user: &Addr<WebSocket>) -> Vec<Addr<WebSocket>> {
+        let users = self.users.lock().unwrap();
+        users.iter().filter(|u| u != &user).cloned().collect()
+    }
+}
@@ -1,19 +1,39 @@
-use std::{collections::HashMap, fmt::Debug, net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    net::IpAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
+use crate::audio_level::AudioLevelObserverConfig;
+use crate::keyframe::KeyframeDetectors;
+use crate::memory::MemoryBudget;
+use crate::net::{DscpConfig, SharedUdpSocket};
+use crate::publisher::MediaType;
+use crate::runtime::Runtime;
 use derivative::Derivative;
+use serde::Deserialize;
 use webrtc::{
-    api::setting_engine::SettingEngine, peer_connection::configuration::RTCConfiguration,
-    rtp_transceiver::rtp_codec::RTCRtpCodecParameters, sdp::extmap,
+    api::setting_engine::SettingEngine,
+    ice_transport::ice_server::RTCIceServer,
+    peer_connection::configuration::RTCConfiguration,
+    rtp_transceiver::{
+        rtp_codec::RTCRtpCodecParameters, TYPE_RTCP_FB_GOOG_REMB, TYPE_RTCP_FB_TRANSPORT_CC,
+    },
+    sdp::extmap,
 };
+
 use webrtc_ice::{
     network_type::NetworkType,
     udp_network::{EphemeralUDP, UDPNetwork},
 };
+use webrtc_util::ifaces::ifaces;
 
 const EXT_TOFFSET: &str = "urn:ietf:params:rtp-hdrext:toffset";
 
 /// PortRange for [`WebRTCTransportConfig`]. In server side, random ports within this range are assigned for UDP connections.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PortRange {
     /// Min port for UDP connections. It should be less than max.
     pub min: u16,
@@ -21,6 +41,25 @@ pub struct PortRange {
     pub max: u16,
 }
 
+/// ICE server which is loaded from configuration files. Unlike [`RTCIceServer`], `username` and `credential` can be omitted, for example for STUN servers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+
+impl From<IceServerConfig> for RTCIceServer {
+    fn from(config: IceServerConfig) -> Self {
+        Self {
+            urls: config.urls,
+            username: config.username,
+            credential: config.credential,
+        }
+    }
+}
+
 /// Configuration for [`crate::publish_transport::PublishTransport`] and [`crate::subscribe_transport::SubscribeTransport`].
 #[derive(Derivative)]
 #[derivative(Clone, Debug)]
@@ -35,6 +74,17 @@ pub struct WebRTCTransportConfig {
     pub ice_username_fragment: Option<String>,
     pub ice_password: Option<String>,
     pub port_range: Option<PortRange>,
+    /// Context which is attached to all tracing events of the transport.
+    pub log_context: LogContext,
+    /// Feature hints which are embedded in SDP for the client SDK. They are not embedded if it is `None`.
+    pub sdp_hints: Option<SdpHints>,
+    /// UDP socket which is shared by all transports. If it is set, `port_range` is ignored.
+    pub udp_socket: Option<SharedUdpSocket>,
+    /// Replay protection of SRTP and SRTCP.
+    pub replay_protection: ReplayProtection,
+    /// Code points which are marked on packets per media kind. If any of them is set, each transport binds its own IPv4 UDP socket to mark packets.
+    /// It is ignored when `udp_socket` is set, please use [`crate::net::UdpSocketOptions::dscp`] in that case.
+    pub dscp: DscpConfig,
 }
 
 impl Default for WebRTCTransportConfig {
@@ -51,11 +101,172 @@ impl Default for WebRTCTransportConfig {
             ice_username_fragment: None,
             ice_password: None,
             port_range: None,
+            log_context: LogContext::default(),
+            sdp_hints: None,
+            udp_socket: None,
+            replay_protection: ReplayProtection::default(),
+            dscp: DscpConfig::default(),
+        }
+    }
+}
+
+/// Window of SRTP and SRTCP replay protection in packets. Packets which are older than the window, or which have been received in the window, are dropped as replays.
+/// Legitimate packets which are reordered more than the window are also dropped, so relay links with high jitter, such as cascading between SFUs, need a larger window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplayProtection {
+    /// The default window of webrtc, which is 64 packets.
+    #[default]
+    Default,
+    /// [`LARGE_REPLAY_WINDOW`] packets for both of SRTP and SRTCP.
+    Large,
+    Window {
+        srtp: usize,
+        srtcp: usize,
+    },
+    /// Replayed packets are not dropped. This should be used only in trusted networks.
+    Disabled,
+}
+
+/// Replay window of [`ReplayProtection::Large`]. It covers about 1 second of a 10 Mbps video stream.
+pub const LARGE_REPLAY_WINDOW: usize = 1024;
+
+impl ReplayProtection {
+    fn apply(&self, setting_engine: &mut SettingEngine) {
+        match self {
+            ReplayProtection::Default => {}
+            ReplayProtection::Large => {
+                setting_engine.set_srtp_replay_protection_window(LARGE_REPLAY_WINDOW);
+                setting_engine.set_srtcp_replay_protection_window(LARGE_REPLAY_WINDOW);
+            }
+            ReplayProtection::Window { srtp, srtcp } => {
+                setting_engine.set_srtp_replay_protection_window(*srtp);
+                setting_engine.set_srtcp_replay_protection_window(*srtcp);
+            }
+            ReplayProtection::Disabled => {
+                setting_engine.disable_srtp_replay_protection(true);
+                setting_engine.disable_srtcp_replay_protection(true);
+            }
+        }
+    }
+}
+
+/// Features of the SFU which are advertised to the client SDK as `a=x-rheomesh:<feature>` session attributes, so the SDK can enable them progressively without extra signaling messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SdpHints {
+    /// Label of the data channel which is used for control messages.
+    pub control_channel_label: Option<String>,
+}
+
+impl SdpHints {
+    pub(crate) fn attributes(&self) -> Vec<String> {
+        let mut attributes = vec![format!("version={}", env!("CARGO_PKG_VERSION"))];
+        if let Some(label) = &self.control_channel_label {
+            attributes.push(format!("control-channel={}", label));
+        }
+        attributes
+            .into_iter()
+            .map(|attribute| format!("a=x-rheomesh:{}", attribute))
+            .collect()
+    }
+}
+
+/// Key-value pairs such as user id and session id, which are attached to all tracing events emitted by a transport and its publishers and subscribers.
+/// It is useful to filter server logs per end user.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogContext {
+    fields: Vec<(String, String)>,
+}
+
+impl LogContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.fields.push((key.into(), value.into()));
+        self
+    }
+
+    pub(crate) fn span(&self, transport: &str, transport_id: &str) -> tracing::Span {
+        tracing::info_span!("rheomesh", transport, transport_id, context = %self)
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Deployment profile which sets a documented combination of ICE servers, network types, ports and timeouts for [`WebRTCTransportConfig`].
+/// Profiles can be layered with [`WebRTCTransportConfig::apply_profile`], and fields can be overridden after that.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// The server has a public IP address and clients connect from the internet. A public STUN server is used, both of IPv4 and IPv6 are gathered, and timeouts are tolerant of lossy networks.
+    PublicInternet,
+    /// Clients and the server are in the same private network. Only host candidates are used without STUN, and failures are detected quickly.
+    PrivateNetwork,
+    /// The server runs in a Kubernetes pod. Only IPv4 is used, and UDP ports are limited to the range which is exposed with hostPort or NodePort, so the range must match the manifest.
+    /// The public address of the node is discovered with a public STUN server.
+    Kubernetes { port_range: PortRange },
+}
+
+const PUBLIC_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+
+impl Profile {
+    /// Apply the profile to the config. Fields which the profile doesn't care are kept.
+    pub fn apply(&self, config: &mut WebRTCTransportConfig) {
+        match self {
+            Profile::PublicInternet => {
+                config.configuration.ice_servers = vec![RTCIceServer {
+                    urls: vec![PUBLIC_STUN_SERVER.to_string()],
+                    ..Default::default()
+                }];
+                config.network_types = vec![NetworkType::Udp4, NetworkType::Udp6];
+                config.ice_disconnected_timeout = Some(Duration::from_secs(5));
+                config.ice_failed_timeout = Some(Duration::from_secs(25));
+                config.ice_keep_alive_interval = Some(Duration::from_secs(2));
+            }
+            Profile::PrivateNetwork => {
+                config.configuration.ice_servers = vec![];
+                config.network_types = vec![NetworkType::Udp4];
+                config.ice_disconnected_timeout = Some(Duration::from_secs(3));
+                config.ice_failed_timeout = Some(Duration::from_secs(10));
+                config.ice_keep_alive_interval = Some(Duration::from_secs(1));
+            }
+            Profile::Kubernetes { port_range } => {
+                config.configuration.ice_servers = vec![RTCIceServer {
+                    urls: vec![PUBLIC_STUN_SERVER.to_string()],
+                    ..Default::default()
+                }];
+                config.network_types = vec![NetworkType::Udp4];
+                config.port_range = Some(port_range.clone());
+                config.ice_disconnected_timeout = Some(Duration::from_secs(5));
+                config.ice_failed_timeout = Some(Duration::from_secs(25));
+                config.ice_keep_alive_interval = Some(Duration::from_secs(2));
+            }
         }
     }
 }
 
 impl WebRTCTransportConfig {
+    /// Create a config from the default values and the profile.
+    pub fn from_profile(profile: Profile) -> Self {
+        Self::default().apply_profile(profile)
+    }
+
+    /// Apply a profile on top of this config.
+    pub fn apply_profile(mut self, profile: Profile) -> Self {
+        profile.apply(&mut self);
+        self
+    }
+
     pub fn configuration(&self) -> RTCConfiguration {
         self.configuration.clone()
     }
@@ -92,7 +303,16 @@ impl WebRTCTransportConfig {
             setting_engine.set_ice_credentials(username, password);
         }
 
-        if let Some(port_range) = &self.port_range {
+        if let Some(udp_socket) = &self.udp_socket {
+            let bind_ip = udp_socket.local_addr().ip();
+            if !bind_ip.is_unspecified() {
+                let announced_ips = self.announced_ips.clone();
+                setting_engine.set_ip_filter(Box::new(move |ip| {
+                    ip == bind_ip && (announced_ips.is_empty() || announced_ips.contains(&ip))
+                }));
+            }
+            setting_engine.set_udp_network(UDPNetwork::Muxed(udp_socket.mux.clone()));
+        } else if let Some(port_range) = &self.port_range {
             let ephemeral = EphemeralUDP::new(port_range.min, port_range.max)
                 .expect("failed to define ephemeral UDP");
 
@@ -100,6 +320,8 @@ impl WebRTCTransportConfig {
             setting_engine.set_udp_network(udp_network);
         }
 
+        self.replay_protection.apply(&mut setting_engine);
+
         setting_engine
     }
 }
@@ -109,6 +331,19 @@ impl WebRTCTransportConfig {
 pub struct MediaConfig {
     pub codec: CodecConfig,
     pub header_extension: HeaderExtensionConfig,
+    pub remb_policy: RembPolicy,
+    /// Max number of CPU heavy tasks which run at the same time in the router. If it is `None`, the number of CPUs is used.
+    pub max_blocking_tasks: Option<usize>,
+    pub congestion_feedback: CongestionFeedback,
+    /// Detectors to find keyframes in RTP packets. Register a detector here to support codecs which are not built in.
+    pub keyframe_detectors: KeyframeDetectors,
+    pub e2ee_policy: E2eePolicy,
+    /// Runtime which runs RTP and RTCP forwarding loops of publishers and subscribers in the router. If it is `None`, the global runtime is used.
+    /// For example, [`crate::runtime::PinnedRuntime`] keeps forwarding of the router on specific cores.
+    pub forwarding_runtime: Option<Arc<dyn Runtime>>,
+    pub audio_level_observer: AudioLevelObserverConfig,
+    /// Budget of memory for buffered RTP packets and data channel messages. Share the same budget with all routers to bound the whole process. If it is `None`, buffers are not limited.
+    pub memory_budget: Option<Arc<MemoryBudget>>,
 }
 
 impl Default for MediaConfig {
@@ -116,10 +351,144 @@ impl Default for MediaConfig {
         Self {
             codec: Default::default(),
             header_extension: Default::default(),
+            remb_policy: Default::default(),
+            max_blocking_tasks: None,
+            congestion_feedback: CongestionFeedback::default(),
+            keyframe_detectors: KeyframeDetectors::default(),
+            e2ee_policy: E2eePolicy::default(),
+            forwarding_runtime: None,
+            audio_level_observer: AudioLevelObserverConfig::default(),
+            memory_budget: None,
+        }
+    }
+}
+
+/// Policy for end-to-end encrypted media, like insertable streams or SFrame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum E2eePolicy {
+    /// Media payloads may be read by the SFU.
+    #[default]
+    Disabled,
+    /// Media payloads are encrypted, so the SFU must forward them blindly. [`validate`] reports features which parse media payloads as errors.
+    Required,
+}
+
+/// Congestion control feedback which is negotiated with clients.
+/// Some client stacks are confused when both of them are negotiated, and the unused one wastes header bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CongestionFeedback {
+    /// Transport-wide congestion control. The transport-cc header extension is registered and the SFU generates TWCC feedback for publishers, but REMB from subscribers is not forwarded.
+    Twcc,
+    /// Receiver estimated maximum bitrate. The transport-cc header extension is not registered, and REMB from subscribers is forwarded to publishers.
+    Remb,
+    /// Negotiate both of them.
+    #[default]
+    Both,
+}
+
+impl CongestionFeedback {
+    pub(crate) fn twcc(&self) -> bool {
+        *self != CongestionFeedback::Remb
+    }
+
+    pub(crate) fn remb(&self) -> bool {
+        *self != CongestionFeedback::Twcc
+    }
+
+    /// This returns false if the rtcp-fb type or the header extension is not used in this mode.
+    pub(crate) fn allows(&self, feedback_or_extension: &str) -> bool {
+        match feedback_or_extension {
+            TYPE_RTCP_FB_TRANSPORT_CC | extmap::TRANSPORT_CC_URI => self.twcc(),
+            TYPE_RTCP_FB_GOOG_REMB => self.remb(),
+            _ => true,
         }
     }
 }
 
+/// Clamping policy of REMB values which subscribers send to publishers.
+/// While `ramp_duration` has not passed since subscribing, `initial_bitrate` of each media kind is used as the floor, so publishers don't drop the quality before the bandwidth estimation converges.
+#[derive(Clone, Debug)]
+pub struct RembPolicy {
+    pub ramp_duration: Duration,
+    pub video: RembBitrateRange,
+    pub audio: RembBitrateRange,
+    /// Scheduling of bitrate increases after congestion. If it is `None`, increases are forwarded immediately.
+    pub upgrade: Option<UpgradePolicy>,
+}
+
+impl Default for RembPolicy {
+    fn default() -> Self {
+        Self {
+            ramp_duration: Duration::from_secs(30),
+            video: RembBitrateRange {
+                initial_bitrate: 128_000.0,
+                min_bitrate: 0.0,
+                max_bitrate: None,
+            },
+            audio: RembBitrateRange {
+                initial_bitrate: 64_000.0,
+                min_bitrate: 0.0,
+                max_bitrate: None,
+            },
+            upgrade: Some(UpgradePolicy::default()),
+        }
+    }
+}
+
+/// Policy to raise the bitrate again after congestion forces a downswitch. A decrease is forwarded to the publisher immediately, but an increase is held down for a while, so a momentary recovery of the estimate doesn't make the quality oscillate.
+/// If congestion happens again soon after an upgrade, the hold-down time is doubled up to `max_hold_down`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpgradePolicy {
+    /// Time to keep the reduced bitrate after congestion.
+    pub hold_down: Duration,
+    pub max_hold_down: Duration,
+    /// Congestion within this time after an upgrade means the upgrade failed. If no congestion happens in this time, the hold-down time is reset.
+    pub probe_duration: Duration,
+    /// A drop of the estimate below this ratio of the current bitrate is regarded as congestion.
+    pub congestion_ratio: f32,
+}
+
+impl Default for UpgradePolicy {
+    fn default() -> Self {
+        Self {
+            hold_down: Duration::from_secs(2),
+            max_hold_down: Duration::from_secs(32),
+            probe_duration: Duration::from_secs(10),
+            congestion_ratio: 0.85,
+        }
+    }
+}
+
+/// Bitrate range in bps for [`RembPolicy`].
+#[derive(Clone, Debug)]
+pub struct RembBitrateRange {
+    /// Floor during the ramp duration.
+    pub initial_bitrate: f32,
+    /// Floor after the ramp duration.
+    pub min_bitrate: f32,
+    /// Ceiling which is always applied.
+    pub max_bitrate: Option<f32>,
+}
+
+impl RembPolicy {
+    pub(crate) fn clamp(&self, media_type: MediaType, elapsed: Duration, bitrate: f32) -> f32 {
+        let range = match media_type {
+            MediaType::Video => &self.video,
+            MediaType::Audio => &self.audio,
+        };
+        let floor = if elapsed < self.ramp_duration {
+            range.initial_bitrate.max(range.min_bitrate)
+        } else {
+            range.min_bitrate
+        };
+        let mut bitrate = bitrate.max(floor);
+        if let Some(max) = range.max_bitrate {
+            bitrate = bitrate.min(max);
+        }
+        bitrate
+    }
+}
+
 /// Media codec configuration for audio and video.
 #[derive(Clone, Debug)]
 pub struct CodecConfig {
@@ -163,22 +532,435 @@ impl Default for HeaderExtensionConfig {
     }
 }
 
-fn extmap_order() -> HashMap<u16, String> {
-    HashMap::from([
-        (1, extmap::AUDIO_LEVEL_URI.to_owned()),
-        (2, extmap::ABS_SEND_TIME_URI.to_owned()),
-        (3, extmap::TRANSPORT_CC_URI.to_owned()),
-        (4, extmap::SDES_MID_URI.to_owned()),
-        (10, extmap::SDES_RTP_STREAM_ID_URI.to_owned()),
-        (11, extmap::SDES_REPAIR_RTP_STREAM_ID_URI.to_owned()),
-        (13, extmap::VIDEO_ORIENTATION_URI.to_owned()),
-        (14, EXT_TOFFSET.to_string()),
-    ])
+/// Header extension IDs which are written to offers. The table is built once, because it is looked up for each extmap line of each offer.
+fn extmap_order() -> &'static HashMap<u16, String> {
+    static EXTMAP_ORDER: OnceLock<HashMap<u16, String>> = OnceLock::new();
+    EXTMAP_ORDER.get_or_init(|| {
+        HashMap::from([
+            (1, extmap::AUDIO_LEVEL_URI.to_owned()),
+            (2, extmap::ABS_SEND_TIME_URI.to_owned()),
+            (3, extmap::TRANSPORT_CC_URI.to_owned()),
+            (4, extmap::SDES_MID_URI.to_owned()),
+            (10, extmap::SDES_RTP_STREAM_ID_URI.to_owned()),
+            (11, extmap::SDES_REPAIR_RTP_STREAM_ID_URI.to_owned()),
+            (13, extmap::VIDEO_ORIENTATION_URI.to_owned()),
+            (14, EXT_TOFFSET.to_string()),
+        ])
+    })
 }
 
 pub(crate) fn find_extmap_order(uri: &str) -> Option<u16> {
     extmap_order()
-        .into_iter()
-        .find(|(_, v)| v == uri)
-        .map(|(k, _)| k)
+        .iter()
+        .find(|(_, v)| *v == uri)
+        .map(|(k, _)| *k)
+}
+
+/// Severity of a [`ConfigFinding`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FindingSeverity {
+    /// The configuration doesn't work.
+    Error,
+    /// The configuration works, but it may not behave as expected.
+    Warning,
+}
+
+/// A problem which is found by [`validate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigFinding {
+    pub severity: FindingSeverity,
+    pub message: String,
+}
+
+impl ConfigFinding {
+    fn error(message: String) -> Self {
+        Self {
+            severity: FindingSeverity::Error,
+            message,
+        }
+    }
+
+    fn warning(message: String) -> Self {
+        Self {
+            severity: FindingSeverity::Warning,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            FindingSeverity::Error => write!(f, "error: {}", self.message),
+            FindingSeverity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+/// Validate consistency of codecs, header extensions and network settings before creating a [`crate::router::Router`].
+/// Announced IPs are compared with addresses of local network interfaces, because they are used to filter ICE candidates.
+pub fn validate(
+    media_config: &MediaConfig,
+    transport_config: &WebRTCTransportConfig,
+) -> Vec<ConfigFinding> {
+    let mut findings = validate_media(media_config);
+    findings.extend(validate_transport(transport_config));
+    findings.extend(validate_announced_ips(&transport_config.announced_ips));
+    findings
+}
+
+fn validate_media(config: &MediaConfig) -> Vec<ConfigFinding> {
+    let mut findings = Vec::new();
+    let feedback = config.congestion_feedback;
+
+    let mut registered: Vec<RTCRtpCodecParameters> = Vec::new();
+    for (kind, codecs) in [
+        ("audio", &config.codec.audio),
+        ("video", &config.codec.video),
+    ] {
+        for codec in codecs.iter() {
+            let mime_type = &codec.capability.mime_type;
+            if !mime_type.to_lowercase().starts_with(&format!("{}/", kind)) {
+                findings.push(ConfigFinding::error(format!(
+                    "{} is registered as {} codec",
+                    mime_type, kind
+                )));
+            }
+            if codec.payload_type > 127 {
+                findings.push(ConfigFinding::error(format!(
+                    "payload type {} of {} is out of range",
+                    codec.payload_type, mime_type
+                )));
+            }
+            match find_payload_type_owner(&registered, codec) {
+                Some(other) if same_codec(other, codec) => {
+                    findings.push(ConfigFinding::warning(format!(
+                        "{} with payload type {} is duplicated",
+                        codec_name(codec),
+                        codec.payload_type
+                    )));
+                }
+                Some(other) => {
+                    findings.push(ConfigFinding::error(format!(
+                        "payload type {} is used by both {} and {}",
+                        codec.payload_type,
+                        codec_name(other),
+                        codec_name(codec)
+                    )));
+                }
+                None => registered.push(codec.clone()),
+            }
+            for fb in codec.capability.rtcp_feedback.iter() {
+                if !feedback.allows(&fb.typ) {
+                    findings.push(ConfigFinding::warning(format!(
+                        "rtcp-fb {} of {} is removed, because congestion feedback is {:?}",
+                        fb.typ, mime_type, feedback
+                    )));
+                }
+            }
+        }
+    }
+
+    if config.e2ee_policy == E2eePolicy::Required {
+        let mime_types = config.keyframe_detectors.mime_types();
+        if !mime_types.is_empty() {
+            findings.push(ConfigFinding::error(format!(
+                "keyframe detectors for {} parse media payloads, which are encrypted with E2EE, please use KeyframeDetectors::empty()",
+                mime_types.join(", ")
+            )));
+        }
+    }
+
+    for (kind, extensions) in [
+        ("audio", &config.header_extension.audio),
+        ("video", &config.header_extension.video),
+    ] {
+        for (i, uri) in extensions.iter().enumerate() {
+            if extensions[..i].contains(uri) {
+                findings.push(ConfigFinding::warning(format!(
+                    "{} header extension {} is duplicated",
+                    kind, uri
+                )));
+            }
+            if find_extmap_order(uri).is_none() {
+                findings.push(ConfigFinding::warning(format!(
+                    "{} header extension {} is not offered to subscribers, because it doesn't have a fixed id",
+                    kind, uri
+                )));
+            }
+            if !feedback.allows(uri) {
+                findings.push(ConfigFinding::warning(format!(
+                    "{} header extension {} is not registered, because congestion feedback is {:?}",
+                    kind, uri, feedback
+                )));
+            }
+        }
+    }
+
+    findings
+}
+
+/// This returns the codec which has already been registered with the payload type of `codec`.
+/// Only the first codec of a payload type is registered to the media engine, because a payload type must identify one codec in a session.
+pub(crate) fn find_payload_type_owner<'a>(
+    registered: &'a [RTCRtpCodecParameters],
+    codec: &RTCRtpCodecParameters,
+) -> Option<&'a RTCRtpCodecParameters> {
+    registered
+        .iter()
+        .find(|c| c.payload_type == codec.payload_type)
+}
+
+pub(crate) fn same_codec(a: &RTCRtpCodecParameters, b: &RTCRtpCodecParameters) -> bool {
+    a.capability
+        .mime_type
+        .eq_ignore_ascii_case(&b.capability.mime_type)
+        && a.capability.clock_rate == b.capability.clock_rate
+        && a.capability.channels == b.capability.channels
+        && a.capability.sdp_fmtp_line == b.capability.sdp_fmtp_line
+}
+
+pub(crate) fn codec_name(codec: &RTCRtpCodecParameters) -> String {
+    if codec.capability.sdp_fmtp_line.is_empty() {
+        codec.capability.mime_type.clone()
+    } else {
+        format!(
+            "{} ({})",
+            codec.capability.mime_type, codec.capability.sdp_fmtp_line
+        )
+    }
+}
+
+fn validate_transport(config: &WebRTCTransportConfig) -> Vec<ConfigFinding> {
+    let mut findings = Vec::new();
+
+    if let Some(port_range) = &config.port_range {
+        if port_range.min > port_range.max {
+            findings.push(ConfigFinding::error(format!(
+                "port range min {} is greater than max {}",
+                port_range.min, port_range.max
+            )));
+        }
+    }
+
+    if config.udp_socket.is_some() {
+        if config.port_range.is_some() {
+            findings.push(ConfigFinding::warning(
+                "port range is ignored, because a shared UDP socket is configured".to_string(),
+            ));
+        }
+        if !config.dscp.is_empty() {
+            findings.push(ConfigFinding::warning(
+                "DSCP per media kind is ignored, because a shared UDP socket is configured"
+                    .to_string(),
+            ));
+        }
+        // The shared socket demultiplexes transports with the username fragment.
+        if config.ice_username_fragment.is_some() {
+            findings.push(ConfigFinding::error(
+                "ICE username fragment must not be fixed with a shared UDP socket".to_string(),
+            ));
+        }
+    }
+
+    // ICE requires at least 4 characters for ufrag and 22 characters for password.
+    if let Some(ufrag) = &config.ice_username_fragment {
+        if ufrag.len() < 4 {
+            findings.push(ConfigFinding::error(
+                "ICE username fragment must be at least 4 characters".to_string(),
+            ));
+        }
+    }
+    if let Some(password) = &config.ice_password {
+        if password.len() < 22 {
+            findings.push(ConfigFinding::error(
+                "ICE password must be at least 22 characters".to_string(),
+            ));
+        }
+    }
+
+    match config.replay_protection {
+        ReplayProtection::Window { srtp, srtcp } if srtp == 0 || srtcp == 0 => {
+            findings.push(ConfigFinding::error(
+                "replay protection window must not be 0, please use ReplayProtection::Disabled to disable it".to_string(),
+            ));
+        }
+        ReplayProtection::Disabled => {
+            findings.push(ConfigFinding::warning(
+                "SRTP replay protection is disabled".to_string(),
+            ));
+        }
+        _ => {}
+    }
+
+    if config.configuration.ice_servers.is_empty() {
+        findings.push(ConfigFinding::warning(
+            "no ICE servers are configured, so clients behind NAT may not be able to connect"
+                .to_string(),
+        ));
+    }
+
+    findings
+}
+
+fn validate_announced_ips(announced_ips: &[IpAddr]) -> Vec<ConfigFinding> {
+    if announced_ips.is_empty() {
+        return vec![];
+    }
+    let local_ips: Vec<IpAddr> = match ifaces() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .filter_map(|i| i.addr.map(|addr| addr.ip()))
+            .collect(),
+        Err(err) => {
+            return vec![ConfigFinding::warning(format!(
+                "failed to list network interfaces: {}",
+                err
+            ))]
+        }
+    };
+
+    announced_ips
+        .iter()
+        .filter(|ip| !local_ips.contains(ip))
+        .map(|ip| {
+            ConfigFinding::error(format!(
+                "announced IP {} is not assigned to any local interface, so no ICE candidates are gathered for it",
+                ip
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use webrtc::rtp_transceiver::{rtp_codec::RTCRtpCodecCapability, RTCPFeedback};
+
+    fn codec(mime_type: &str, payload_type: u8) -> RTCRtpCodecParameters {
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: mime_type.to_string(),
+                clock_rate: 90000,
+                rtcp_feedback: vec![RTCPFeedback {
+                    typ: TYPE_RTCP_FB_GOOG_REMB.to_string(),
+                    parameter: "".to_string(),
+                }],
+                ..Default::default()
+            },
+            payload_type,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_media() {
+        let config = MediaConfig {
+            codec: CodecConfig {
+                audio: vec![codec("video/VP8", 96)],
+                video: vec![codec("video/VP9", 96)],
+            },
+            congestion_feedback: CongestionFeedback::Twcc,
+            ..Default::default()
+        };
+
+        let findings = validate_media(&config);
+        let errors: Vec<&ConfigFinding> = findings
+            .iter()
+            .filter(|f| f.severity == FindingSeverity::Error)
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].message,
+            "video/VP8 is registered as audio codec".to_string()
+        );
+        assert_eq!(
+            errors[1].message,
+            "payload type 96 is used by both video/VP8 and video/VP9".to_string()
+        );
+        // goog-remb of each codec is dropped, and transport-cc is kept with TWCC.
+        let warnings: Vec<&str> = findings
+            .iter()
+            .filter(|f| f.severity == FindingSeverity::Warning)
+            .map(|f| f.message.as_str())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "rtcp-fb goog-remb of video/VP8 is removed, because congestion feedback is Twcc",
+                "rtcp-fb goog-remb of video/VP9 is removed, because congestion feedback is Twcc",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_default_media() {
+        assert_eq!(validate_media(&MediaConfig::default()), vec![]);
+    }
+
+    #[test]
+    fn test_validate_e2ee() {
+        let mut config = MediaConfig {
+            e2ee_policy: E2eePolicy::Required,
+            ..Default::default()
+        };
+        let findings = validate_media(&config);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, FindingSeverity::Error);
+
+        config.keyframe_detectors = KeyframeDetectors::empty();
+        assert_eq!(validate_media(&config), vec![]);
+    }
+
+    #[test]
+    fn test_validate_replay_protection() {
+        let config = WebRTCTransportConfig {
+            replay_protection: ReplayProtection::Window { srtp: 0, srtcp: 64 },
+            ..Default::default()
+        };
+        let errors: Vec<ConfigFinding> = validate_transport(&config)
+            .into_iter()
+            .filter(|f| f.severity == FindingSeverity::Error)
+            .collect();
+        assert_eq!(errors.len(), 1);
+
+        let config = WebRTCTransportConfig {
+            replay_protection: ReplayProtection::Large,
+            ..Default::default()
+        };
+        assert!(validate_transport(&config)
+            .iter()
+            .all(|f| f.severity != FindingSeverity::Error));
+    }
+
+    #[test]
+    fn test_validate_payload_type_conflicts() {
+        let mut baseline = codec("video/H264", 102);
+        baseline.capability.sdp_fmtp_line = "profile-level-id=42001f".to_string();
+        let mut high = codec("video/H264", 102);
+        high.capability.sdp_fmtp_line = "profile-level-id=640032".to_string();
+        let config = MediaConfig {
+            codec: CodecConfig {
+                audio: vec![],
+                video: vec![baseline.clone(), baseline, high],
+            },
+            congestion_feedback: CongestionFeedback::Both,
+            ..Default::default()
+        };
+
+        let findings = validate_media(&config);
+        assert_eq!(
+            findings,
+            vec![
+                ConfigFinding::warning(
+                    "video/H264 (profile-level-id=42001f) with payload type 102 is duplicated"
+                        .to_string()
+                ),
+                ConfigFinding::error(
+                    "payload type 102 is used by both video/H264 (profile-level-id=42001f) and video/H264 (profile-level-id=640032)"
+                        .to_string()
+                ),
+            ]
+        );
+    }
 }
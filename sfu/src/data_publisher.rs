@@ -1,47 +1,122 @@
 use std::{fmt::Debug, sync::Arc};
 
 use enclose::enc;
-use tokio::sync::{broadcast, mpsc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::Instrument;
 use uuid::Uuid;
-use webrtc::data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel};
+use webrtc::data_channel::{
+    data_channel_init::RTCDataChannelInit, data_channel_message::DataChannelMessage, RTCDataChannel,
+};
 
-use crate::router::RouterEvent;
+use crate::{memory::Reservation, router::RouterEvent};
+
+/// Number of messages which are buffered for data subscribers.
+pub(crate) const DATA_CAPACITY: usize = 1024;
+/// Each buffered message is accounted at this size in [`crate::memory::MemoryBudget`]. Most messages fit in an SCTP packet.
+pub(crate) const DATA_MESSAGE_SIZE: usize = 1200;
+
+/// Separator between the group and the name in data channel labels, for example `whiteboard:page1`.
+pub const DATA_GROUP_SEPARATOR: char = ':';
+
+/// Ordered and reliability semantics of a data channel.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataChannelReliability {
+    pub ordered: bool,
+    /// Time in milliseconds to retransmit a message. The channel is unreliable if it or `max_retransmits` is set.
+    pub max_packet_lifetime: Option<u16>,
+    pub max_retransmits: Option<u16>,
+    pub protocol: String,
+}
+
+impl Default for DataChannelReliability {
+    fn default() -> Self {
+        Self {
+            ordered: true,
+            max_packet_lifetime: None,
+            max_retransmits: None,
+            protocol: String::new(),
+        }
+    }
+}
+
+impl DataChannelReliability {
+    fn new(data_channel: &RTCDataChannel) -> Self {
+        Self {
+            ordered: data_channel.ordered(),
+            max_packet_lifetime: data_channel.max_packet_lifetime(),
+            max_retransmits: data_channel.max_retransmits(),
+            protocol: data_channel.protocol().to_string(),
+        }
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.max_packet_lifetime.is_none() && self.max_retransmits.is_none()
+    }
+
+    pub(crate) fn init(&self) -> RTCDataChannelInit {
+        RTCDataChannelInit {
+            ordered: Some(self.ordered),
+            max_packet_life_time: self.max_packet_lifetime,
+            max_retransmits: self.max_retransmits,
+            protocol: Some(self.protocol.clone()),
+            negotiated: None,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct DataPublisher {
     pub id: String,
     pub channel_id: u16,
     pub label: String,
+    /// Delivery semantics of the data channel which the client has opened. Data channels of subscribers are created with the same semantics.
+    pub reliability: DataChannelReliability,
     pub(crate) data_sender: broadcast::Sender<DataChannelMessage>,
     data_channel: Arc<RTCDataChannel>,
+    closed_sender: Arc<watch::Sender<bool>>,
+    // The reservation is released when the last clone is dropped.
+    _memory_reservation: Option<Arc<Reservation>>,
 }
 
 impl DataPublisher {
     pub(crate) fn new(
         data_channel: Arc<RTCDataChannel>,
         router_sender: mpsc::UnboundedSender<RouterEvent>,
+        memory_reservation: Option<Reservation>,
     ) -> Self {
         let channel_id = data_channel.id();
         let label = data_channel.label().to_string();
+        let reliability = DataChannelReliability::new(&data_channel);
 
         let id = Uuid::new_v4().to_string();
         let cloned_id = id.clone();
-        data_channel.on_close(Box::new(enc!((router_sender, cloned_id) move || {
-            tracing::debug!("DataChannel {} has been closed", cloned_id);
-            Box::pin(enc!((router_sender, cloned_id) async move {
-                let _ = router_sender.send(RouterEvent::DataRemoved(cloned_id));
-            }))
-        })));
+        // Callbacks are called out of the transport's span, so the span is entered explicitly to keep the log context.
+        let span = tracing::Span::current();
+        let closed_sender = Arc::new(watch::channel(false).0);
+        data_channel.on_close(Box::new(
+            enc!((router_sender, cloned_id, span, closed_sender) move || {
+                let _enter = span.enter();
+                tracing::debug!("DataChannel {} has been closed", cloned_id);
+                // Data subscribers close their channels, so subscribing clients know the channel has gone.
+                closed_sender.send_replace(true);
+                Box::pin(enc!((router_sender, cloned_id) async move {
+                    let _ = router_sender.send(RouterEvent::DataRemoved(cloned_id));
+                }))
+            }),
+        ));
 
-        data_channel.on_error(Box::new(move |err| {
+        data_channel.on_error(Box::new(enc!((span) move |err| {
             Box::pin(async move {
                 tracing::debug!("Error on DataChannel: {}", err);
-            })
-        }));
+            }.instrument(span.clone()))
+        })));
 
-        let (data_sender, _data_receiver) = broadcast::channel(1024);
+        let (data_sender, _data_receiver) = broadcast::channel(DATA_CAPACITY);
         let sender = data_sender.clone();
         data_channel.on_message(Box::new(move |msg| {
+            let _enter = span.enter();
             tracing::debug!("message: {:#?}", msg.data);
             let data_sender = sender.clone();
             Box::pin(async move {
@@ -49,25 +124,55 @@ impl DataPublisher {
             })
         }));
 
-        tracing::debug!("DataPublisher {} is created, label={}", id, label);
+        tracing::debug!(
+            "DataPublisher {} is created, label={}, reliability={:?}",
+            id,
+            label,
+            reliability
+        );
 
         let publisher = Self {
             id,
             channel_id,
             label,
+            reliability,
             data_sender,
             data_channel,
+            closed_sender,
+            _memory_reservation: memory_reservation.map(Arc::new),
         };
 
         publisher
     }
 
+    /// This returns the group of the data channel, which is the part of the label before [`DATA_GROUP_SEPARATOR`]. A label without the separator is a group by itself, for example `chat`.
+    pub fn group(&self) -> &str {
+        data_group(&self.label)
+    }
+
+    /// This returns true if the data channel has been closed by the client or by [`DataPublisher::close`].
+    pub fn is_closed(&self) -> bool {
+        *self.closed_sender.borrow()
+    }
+
+    pub(crate) fn subscribe_closed(&self) -> watch::Receiver<bool> {
+        self.closed_sender.subscribe()
+    }
+
     pub async fn close(&self) {
         tracing::debug!("DataPublisher is closed");
+        self.closed_sender.send_replace(true);
         let _ = self.data_channel.close().await;
     }
 }
 
+pub(crate) fn data_group(label: &str) -> &str {
+    label
+        .split_once(DATA_GROUP_SEPARATOR)
+        .map(|(group, _)| group)
+        .unwrap_or(label)
+}
+
 impl Drop for DataPublisher {
     fn drop(&mut self) {
         tracing::debug!("DataPublisher {} is dropped", self.id);
@@ -79,6 +184,20 @@ impl Debug for DataPublisher {
         f.debug_struct("DataPublisher")
             .field("id", &self.id)
             .field("channel_id", &self.channel_id)
+            .field("reliability", &self.reliability)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_data_group() {
+        assert_eq!(data_group("chat"), "chat");
+        assert_eq!(data_group("whiteboard:page1"), "whiteboard");
+        assert_eq!(data_group("whiteboard:page1:layer2"), "whiteboard");
+        assert_eq!(data_group(":page1"), "");
+    }
+}
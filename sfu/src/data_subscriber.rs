@@ -1,13 +1,24 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 
+use bytes::Bytes;
 use derivative::Derivative;
-use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tracing::Instrument;
 use uuid::Uuid;
 use webrtc::data_channel::{
     data_channel_message::DataChannelMessage, data_channel_state::RTCDataChannelState,
     RTCDataChannel,
 };
 
+use crate::error::{Error, SubscriberErrorKind};
+use crate::runtime;
+
+/// Callback which is called with the [`crate::data_publisher::DataPublisher`] ID when the publishing client closes the data channel.
+pub type OnPublisherClosedFn = Box<dyn Fn(String) + Send + Sync>;
+
 #[derive(Derivative)]
 #[derivative(Clone, Debug)]
 pub struct DataSubscriber {
@@ -15,6 +26,9 @@ pub struct DataSubscriber {
     closed_sender: Arc<mpsc::UnboundedSender<bool>>,
     #[derivative(Debug = "ignore")]
     data_channel: Arc<RTCDataChannel>,
+    max_message_size: Arc<AtomicUsize>,
+    #[derivative(Debug = "ignore")]
+    on_publisher_closed_fn: Arc<Mutex<OnPublisherClosedFn>>,
 }
 
 impl DataSubscriber {
@@ -22,41 +36,81 @@ impl DataSubscriber {
         data_publisher_id: String,
         data_channel: Arc<RTCDataChannel>,
         data_sender: broadcast::Sender<DataChannelMessage>,
+        publisher_closed: watch::Receiver<bool>,
         transport_closed: Arc<Mutex<mpsc::UnboundedReceiver<bool>>>,
+        max_message_size: Arc<AtomicUsize>,
     ) -> Self {
         let id = Uuid::new_v4().to_string();
         let (tx, rx) = mpsc::unbounded_channel();
         let closed_receiver = Arc::new(Mutex::new(rx));
+        let on_publisher_closed_fn: Arc<Mutex<OnPublisherClosedFn>> =
+            Arc::new(Mutex::new(Box::new(|_| {})));
 
         let channel = data_channel.clone();
 
-        tokio::spawn(async move {
-            let receiver = data_sender.subscribe();
+        {
+            let max_message_size = max_message_size.clone();
+            let on_publisher_closed = on_publisher_closed_fn.clone();
+            runtime::spawn(
+                async move {
+                    let receiver = data_sender.subscribe();
 
-            Self::data_event_loop(
-                data_publisher_id,
-                channel,
-                receiver,
-                transport_closed,
-                closed_receiver,
-            )
-            .await;
-        });
+                    let publisher_closed = Self::data_event_loop(
+                        data_publisher_id.clone(),
+                        channel.clone(),
+                        receiver,
+                        publisher_closed,
+                        transport_closed,
+                        closed_receiver,
+                        max_message_size,
+                    )
+                    .await;
+                    if publisher_closed {
+                        tracing::debug!(
+                            "DataPublisher {} has been closed, so the data channel of the subscriber is closed",
+                            data_publisher_id
+                        );
+                        let _ = channel.close().await;
+                        let locked = on_publisher_closed.lock().await;
+                        (locked)(data_publisher_id);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
 
         Self {
             id,
             closed_sender: Arc::new(tx),
             data_channel,
+            max_message_size,
+            on_publisher_closed_fn,
         }
     }
 
+    /// This returns the max message size which the remote peer accepts. `0` means there is no limit.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.load(Ordering::Relaxed)
+    }
+
+    /// Send a message to the subscriber. This returns an error if the message exceeds the negotiated max message size.
+    pub async fn send(&self, data: &Bytes) -> Result<usize, Error> {
+        check_message_size(data.len(), self.max_message_size())?;
+        let size = self.data_channel.send(data).await?;
+        Ok(size)
+    }
+
+    /// This returns true if the loop has finished because the data publisher is closed.
     pub(crate) async fn data_event_loop(
         source_channel_id: String,
         data_channel: Arc<RTCDataChannel>,
         mut data_receiver: broadcast::Receiver<DataChannelMessage>,
+        mut publisher_closed: watch::Receiver<bool>,
         transport_closed: Arc<Mutex<mpsc::UnboundedReceiver<bool>>>,
         subscriber_closed: Arc<Mutex<mpsc::UnboundedReceiver<bool>>>,
-    ) {
+        max_message_size: Arc<AtomicUsize>,
+    ) -> bool {
+        let mut closed_by_publisher = false;
         tracing::debug!(
             "DataSubscriber event loop has started for {}",
             source_channel_id
@@ -72,6 +126,10 @@ impl DataSubscriber {
                 _closed = subscriber_closed.recv() => {
                     break;
                 }
+                _closed = publisher_closed.wait_for(|closed| *closed) => {
+                    closed_by_publisher = true;
+                    break;
+                }
                 res = data_receiver.recv() => {
                     match res {
                         Ok(res) => {
@@ -79,6 +137,10 @@ impl DataSubscriber {
                             match state {
                                 RTCDataChannelState::Open => {
                                     let data = res.data;
+                                    if let Err(err) = check_message_size(data.len(), max_message_size.load(Ordering::Relaxed)) {
+                                        tracing::warn!("DataSubscriber drops a message: {}", err);
+                                        continue;
+                                    }
                                     let _ = data_channel.send(&data).await;
                                 }
                                 _ => {
@@ -99,6 +161,18 @@ impl DataSubscriber {
             "DataSubscriber event loop has finished for {}",
             source_channel_id
         );
+        closed_by_publisher
+    }
+
+    /// Set callback function when the publishing client closes the data channel. The data channel of the subscriber is closed before it is called.
+    pub async fn on_publisher_closed(&self, f: OnPublisherClosedFn) {
+        let mut callback = self.on_publisher_closed_fn.lock().await;
+        *callback = f;
+    }
+
+    /// This returns true if the subscriber has been closed, including when the data publisher is closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed_sender.is_closed()
     }
 
     pub async fn close(&self) {
@@ -109,6 +183,69 @@ impl DataSubscriber {
     }
 }
 
+/// DataGroupSubscriber subscribes every data channel of a group, including data channels which are published to the group later. It is created by [`crate::subscribe_transport::SubscribeTransport::data_subscribe_group`].
+#[derive(Clone, Debug)]
+pub struct DataGroupSubscriber {
+    pub group: String,
+    data_subscribers: Arc<std::sync::Mutex<Vec<DataSubscriber>>>,
+    closed_sender: Arc<watch::Sender<bool>>,
+}
+
+impl DataGroupSubscriber {
+    pub(crate) fn new(group: String) -> Self {
+        Self {
+            group,
+            data_subscribers: Arc::new(std::sync::Mutex::new(Vec::new())),
+            closed_sender: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub(crate) fn push(&self, data_subscriber: DataSubscriber) {
+        self.data_subscribers.lock().unwrap().push(data_subscriber);
+    }
+
+    pub(crate) fn subscribe_closed(&self) -> watch::Receiver<bool> {
+        self.closed_sender.subscribe()
+    }
+
+    /// This returns data subscribers of the group which have been created so far. Data subscribers whose data publishers are closed are removed.
+    pub fn data_subscribers(&self) -> Vec<DataSubscriber> {
+        let mut data_subscribers = self.data_subscribers.lock().unwrap();
+        data_subscribers.retain(|data_subscriber| !data_subscriber.is_closed());
+        data_subscribers.clone()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed_sender.borrow()
+    }
+
+    /// Stop following the group and close every data subscriber of the group.
+    pub async fn close(&self) {
+        self.closed_sender.send_replace(true);
+        let data_subscribers: Vec<DataSubscriber> =
+            self.data_subscribers.lock().unwrap().drain(..).collect();
+        for data_subscriber in data_subscribers {
+            data_subscriber.close().await;
+        }
+    }
+}
+
+fn check_message_size(size: usize, max_message_size: usize) -> Result<(), Error> {
+    if max_message_size > 0 && size > max_message_size {
+        return Err(Error::new_subscriber(
+            format!(
+                "Message size {} exceeds max message size {}",
+                size, max_message_size
+            ),
+            SubscriberErrorKind::MessageTooLargeError {
+                size,
+                max_message_size,
+            },
+        ));
+    }
+    Ok(())
+}
+
 impl Drop for DataSubscriber {
     fn drop(&mut self) {
         tracing::debug!("DataSubscriber {} is dropped", self.id);
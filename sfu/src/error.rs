@@ -9,6 +9,12 @@ pub enum Error {
     #[error(transparent)]
     SdpInternalError(#[from] webrtc_sdp::error::SdpParserInternalError),
     #[error(transparent)]
+    JoinError(#[from] tokio::task::JoinError),
+    #[error(transparent)]
+    SerializeError(#[from] serde_json::Error),
+    #[error(transparent)]
+    StorageError(#[from] Box<dyn std::error::Error + Send + Sync>),
+    #[error(transparent)]
     TransportError(#[from] TransportError),
     #[error(transparent)]
     SubscriberError(#[from] SubscriberError),
@@ -49,6 +55,11 @@ pub enum TransportErrorKind {
     SignalingStateInvalidError,
     #[error("extmap parse error")]
     ExtmapParseError,
+    #[error("plan-b not supported error: mid={mid:?}, track_ids={track_ids:?}")]
+    PlanBNotSupportedError {
+        mid: Option<String>,
+        track_ids: Vec<String>,
+    },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -57,14 +68,31 @@ pub enum SubscriberErrorKind {
     TrackNotFoundError,
     #[error("data channel not found error")]
     DataChannelNotFoundError,
+    #[error("forwarding not allowed error")]
+    ForwardingNotAllowedError,
+    #[error("message too large error: size={size}, max_message_size={max_message_size}")]
+    MessageTooLargeError {
+        size: usize,
+        max_message_size: usize,
+    },
+    #[error("audio program not found error")]
+    AudioProgramNotFoundError,
+    #[error("codec mismatch error")]
+    CodecMismatchError,
+    #[error("subscription not granted error")]
+    SubscriptionNotGrantedError,
 }
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Clone, Debug, thiserror::Error)]
 pub enum PublisherErrorKind {
     #[error("track not published error")]
     TrackNotPublishedError,
     #[error("data channel not published error")]
     DataChannelNotPublishedError,
+    #[error("memory budget exceeded error")]
+    MemoryBudgetExceededError,
+    #[error("data label rejected error")]
+    DataLabelRejectedError,
 }
 
 impl Error {
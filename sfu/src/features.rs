@@ -0,0 +1,66 @@
+use serde::Serialize;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Optional subsystem which is compiled in with a cargo feature.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Feature {
+    /// Name of the cargo feature.
+    pub name: &'static str,
+    pub enabled: bool,
+    /// Version of the subsystem. It is `None` when the feature is not compiled in.
+    pub version: Option<&'static str>,
+}
+
+/// Subsystems which are compiled in this build. Please refer [`features`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Features {
+    /// Version of rheomesh.
+    pub version: &'static str,
+    pub features: Vec<Feature>,
+}
+
+impl Features {
+    /// Returns true if the feature is compiled in. Unknown features, such as ones which are added in newer versions, are false.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.features
+            .iter()
+            .any(|feature| feature.name == name && feature.enabled)
+    }
+}
+
+/// This returns which optional subsystems are compiled in this build.
+/// Nodes of a fleet may be built with different features, so signaling servers can route work to nodes which support it, and admin APIs can advertise capabilities.
+pub fn features() -> Features {
+    let feature = |name, enabled| Feature {
+        name,
+        enabled,
+        version: enabled.then_some(VERSION),
+    };
+    Features {
+        version: VERSION,
+        features: vec![
+            feature("actix", cfg!(feature = "actix")),
+            feature("axum", cfg!(feature = "axum")),
+            feature("cluster", cfg!(feature = "cluster")),
+            feature("cpu-affinity", cfg!(feature = "cpu-affinity")),
+            feature("server", cfg!(feature = "server")),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_features() {
+        let features = features();
+        assert_eq!(features.is_enabled("cluster"), cfg!(feature = "cluster"));
+        assert_eq!(features.is_enabled("server"), cfg!(feature = "server"));
+        // Features which are not declared are never enabled.
+        assert!(!features.is_enabled("unknown"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use webrtc::{
+    interceptor::{
+        self, stream_info::StreamInfo, Attributes, Interceptor, InterceptorBuilder, RTCPReader,
+        RTCPWriter, RTPReader, RTPWriter,
+    },
+    rtcp::{
+        self,
+        header::{PacketType, FORMAT_REMB, HEADER_LENGTH},
+        raw_packet::RawPacket,
+    },
+};
+
+/// Feedback message type of Layer Refresh Request (draft-ietf-avtext-lrr).
+pub(crate) const FORMAT_LRR: u8 = 10;
+/// Unique identifier of loss notifications (goog-lntf) in application layer feedback messages.
+const LNTF_IDENTIFIER: &[u8] = b"LNTF";
+/// Size of an FCI entry of layer refresh requests, which starts with the SSRC of the stream.
+const LRR_ENTRY_LENGTH: usize = 8;
+
+type RtcpPackets = Vec<Box<dyn rtcp::packet::Packet + Send + Sync>>;
+
+/// This returns true if the packet is a loss notification. It is an application layer feedback message, which has the same format as REMB.
+pub(crate) fn is_loss_notification(raw: &[u8]) -> bool {
+    raw.len() >= 16
+        && raw[1] == PacketType::PayloadSpecificFeedback as u8
+        && raw[0] & 0x1f == FORMAT_REMB
+        && &raw[12..16] == LNTF_IDENTIFIER
+}
+
+/// Rewrite the media source SSRC of a payload-specific feedback message, so it points to the stream of the publisher.
+/// Each FCI entry of layer refresh requests has the SSRC of the stream too, so they are rewritten as well.
+pub(crate) fn rewrite_media_ssrc(raw: &RawPacket, media_ssrc: u32) -> Option<RawPacket> {
+    // Header, sender SSRC and media source SSRC.
+    if raw.0.len() < 12 {
+        return None;
+    }
+    let mut buf = raw.0.to_vec();
+    let ssrc = media_ssrc.to_be_bytes();
+    buf[8..12].copy_from_slice(&ssrc);
+    if buf[0] & 0x1f == FORMAT_LRR {
+        for entry in buf[12..].chunks_exact_mut(LRR_ENTRY_LENGTH) {
+            entry[..4].copy_from_slice(&ssrc);
+        }
+    }
+    Some(RawPacket(buf.into()))
+}
+
+/// Parse a compound RTCP packet, and keep loss notifications as [`RawPacket`].
+fn unmarshal_with_loss_notifications(raw: &[u8]) -> Result<RtcpPackets, rtcp::Error> {
+    let mut packets: RtcpPackets = Vec::new();
+    let mut rest = raw;
+    // The buffer is longer than the compound packet, and it is filled with zeros after the packet.
+    while rest.len() >= HEADER_LENGTH && rest[0] >> 6 == 2 {
+        let length = (u16::from_be_bytes([rest[2], rest[3]]) as usize + 1) * 4;
+        if length > rest.len() {
+            return Err(rtcp::Error::PacketTooShort);
+        }
+        let (packet, next) = rest.split_at(length);
+        if is_loss_notification(packet) {
+            packets.push(Box::new(RawPacket(Bytes::copy_from_slice(packet))));
+        } else {
+            packets.extend(rtcp::packet::unmarshal(&mut &packet[..])?);
+        }
+        rest = next;
+    }
+    if packets.is_empty() {
+        return Err(rtcp::Error::PacketTooShort);
+    }
+    Ok(packets)
+}
+
+/// Interceptor which reads loss notifications from clients. rtcp parses them as REMB and fails, and the whole compound packet is dropped, so they are parsed again here.
+/// It has to be registered before other interceptors, so it wraps the reader which parses packets at first.
+#[derive(Debug)]
+pub(crate) struct LossNotificationInterceptorBuilder;
+
+impl InterceptorBuilder for LossNotificationInterceptorBuilder {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, interceptor::Error> {
+        Ok(Arc::new(LossNotificationInterceptor))
+    }
+}
+
+#[derive(Debug)]
+struct LossNotificationInterceptor;
+
+#[async_trait]
+impl Interceptor for LossNotificationInterceptor {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        Arc::new(LossNotificationReader { reader })
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        _info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn unbind_local_stream(&self, _info: &StreamInfo) {}
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<(), interceptor::Error> {
+        Ok(())
+    }
+}
+
+struct LossNotificationReader {
+    reader: Arc<dyn RTCPReader + Send + Sync>,
+}
+
+#[async_trait]
+impl RTCPReader for LossNotificationReader {
+    async fn read(
+        &self,
+        buf: &mut [u8],
+        attributes: &Attributes,
+    ) -> Result<(RtcpPackets, Attributes), interceptor::Error> {
+        match self.reader.read(buf, attributes).await {
+            // The packet has already been decrypted into the buffer when parsing fails.
+            Err(interceptor::Error::Rtcp(err)) => match unmarshal_with_loss_notifications(buf) {
+                Ok(packets) => Ok((packets, attributes.clone())),
+                Err(_) => Err(interceptor::Error::Rtcp(err)),
+            },
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn loss_notification() -> Vec<u8> {
+        vec![
+            0x8f, 206, 0, 4, // FMT=15, PSFB, length=4
+            0, 0, 0, 1, // sender SSRC
+            0, 0, 0, 2, // media source SSRC
+            b'L', b'N', b'T', b'F', // unique identifier
+            0, 10, 0, 12, // last decoded and last received sequence numbers
+        ]
+    }
+
+    fn layer_refresh_request() -> Vec<u8> {
+        vec![
+            0x8a, 206, 0, 4, // FMT=10, PSFB, length=4
+            0, 0, 0, 1, // sender SSRC
+            0, 0, 0, 0, // media source SSRC, which is not used
+            0, 0, 0, 2, // SSRC of the stream
+            3, 0x60, 0, 0x21, // sequence number, payload type and layer indices
+        ]
+    }
+
+    #[test]
+    fn test_rewrite_layer_refresh_request() {
+        let raw = RawPacket(layer_refresh_request().into());
+        let rewritten = rewrite_media_ssrc(&raw, 0xaabbccdd).unwrap();
+        assert_eq!(&rewritten.0[8..12], &[0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(&rewritten.0[12..16], &[0xaa, 0xbb, 0xcc, 0xdd]);
+        // The sender SSRC and the layer indices are kept.
+        assert_eq!(&rewritten.0[4..8], &raw.0[4..8]);
+        assert_eq!(&rewritten.0[16..], &raw.0[16..]);
+    }
+
+    #[test]
+    fn test_rewrite_loss_notification() {
+        let raw = RawPacket(loss_notification().into());
+        let rewritten = rewrite_media_ssrc(&raw, 0xaabbccdd).unwrap();
+        assert_eq!(&rewritten.0[8..12], &[0xaa, 0xbb, 0xcc, 0xdd]);
+        // The identifier is not an SSRC.
+        assert_eq!(&rewritten.0[12..], &raw.0[12..]);
+    }
+
+    #[test]
+    fn test_unmarshal_with_loss_notifications() {
+        let mut buf = loss_notification();
+        assert!(rtcp::packet::unmarshal(&mut &buf[..]).is_err());
+
+        buf.extend(layer_refresh_request());
+        buf.extend([0; 16]);
+        let packets = unmarshal_with_loss_notifications(&buf).unwrap();
+        assert_eq!(packets.len(), 2);
+        let lntf = packets[0].as_any().downcast_ref::<RawPacket>().unwrap();
+        assert!(is_loss_notification(&lntf.0));
+        assert_eq!(packets[1].header().count, FORMAT_LRR);
+
+        assert!(unmarshal_with_loss_notifications(&[0; 16]).is_err());
+    }
+}
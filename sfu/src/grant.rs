@@ -0,0 +1,111 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    error::{Error, SubscriberErrorKind},
+    publisher::Publisher,
+    router::RouterEvent,
+};
+
+/// Publishers which a [`crate::subscribe_transport::SubscribeTransport`] is allowed to subscribe.
+///
+/// The SFU doesn't issue or verify tokens. Please embed the grant in claims of your join token, verify the token in the signaling server, and set the grant with [`crate::subscribe_transport::SubscribeTransport::set_grant`].
+/// For example, spectators of a webinar can be granted the `speaker` role, so they can't subscribe to publishers of the `backstage` role.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SubscribeGrant {
+    /// IDs of [`crate::publisher::Publisher`]s which can be subscribed.
+    pub publisher_ids: HashSet<String>,
+    /// Publishers whose role is one of them can be subscribed. Please refer [`crate::publisher::Publisher::set_role`].
+    pub roles: HashSet<String>,
+}
+
+impl SubscribeGrant {
+    /// Returns true if the publisher is listed by the ID or the role.
+    pub fn allows(&self, publisher_id: &str, role: Option<&str>) -> bool {
+        self.publisher_ids.contains(publisher_id)
+            || role.is_some_and(|role| self.roles.contains(role))
+    }
+}
+
+/// Grant of a transport which is shared with its subscribers, so publishers are checked when subscribers are switched too.
+#[derive(Clone, Debug)]
+pub(crate) struct GrantGuard {
+    transport_id: String,
+    grant: Arc<Mutex<Option<SubscribeGrant>>>,
+    router_event_sender: mpsc::UnboundedSender<RouterEvent>,
+}
+
+impl GrantGuard {
+    pub(crate) fn new(
+        transport_id: String,
+        router_event_sender: mpsc::UnboundedSender<RouterEvent>,
+    ) -> Self {
+        Self {
+            transport_id,
+            grant: Arc::new(Mutex::new(None)),
+            router_event_sender,
+        }
+    }
+
+    pub(crate) fn set(&self, grant: Option<SubscribeGrant>) {
+        *self.grant.lock().unwrap() = grant;
+    }
+
+    pub(crate) fn get(&self) -> Option<SubscribeGrant> {
+        self.grant.lock().unwrap().clone()
+    }
+
+    /// Denied attempts are recorded in the journal of the router for audit logs.
+    pub(crate) fn check(&self, publisher: &Publisher) -> Result<(), Error> {
+        let allowed = match self.grant.lock().unwrap().as_ref() {
+            Some(grant) => grant.allows(&publisher.id, publisher.role().as_deref()),
+            None => true,
+        };
+        if allowed {
+            return Ok(());
+        }
+
+        tracing::warn!(
+            "SubscribeTransport {} is not granted to subscribe publisher {}",
+            self.transport_id,
+            publisher.id
+        );
+        let _ = self
+            .router_event_sender
+            .send(RouterEvent::SubscriptionDenied {
+                transport_id: self.transport_id.clone(),
+                publisher_id: publisher.id.clone(),
+            });
+        Err(Error::new_subscriber(
+            format!(
+                "Publisher {} is not granted to {}",
+                publisher.id, self.transport_id
+            ),
+            SubscriberErrorKind::SubscriptionNotGrantedError,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_grant_allows() {
+        let grant = SubscribeGrant {
+            publisher_ids: HashSet::from(["screen".to_string()]),
+            roles: HashSet::from(["speaker".to_string()]),
+        };
+        assert!(grant.allows("screen", None));
+        assert!(grant.allows("camera", Some("speaker")));
+        assert!(!grant.allows("camera", Some("backstage")));
+        assert!(!grant.allows("camera", None));
+        assert!(!SubscribeGrant::default().allows("camera", Some("speaker")));
+    }
+}
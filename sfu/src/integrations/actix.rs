@@ -0,0 +1,111 @@
+use std::fmt;
+
+use ::actix::{dev::ToEnvelope, Actor, Addr, Handler, MailboxError, Message, WeakAddr};
+use tokio::sync::mpsc;
+
+/// Default number of messages which can be queued per callback before they are dropped.
+pub const DEFAULT_BRIDGE_CAPACITY: usize = 256;
+
+/// TransportActorBridge forwards callbacks of transports, for example [`crate::publish_transport::PublishTransport::on_ice_candidate`] and [`crate::subscribe_transport::SubscribeTransport::on_negotiation_needed`], into an actor.
+///
+/// Callbacks don't hold [`Addr`] of the actor, so they don't keep a stopped actor alive. Messages are delivered in order and each message waits for the mailbox of the actor, so a slow actor doesn't overflow its mailbox. If the actor can't catch up and the queue of a callback is full, messages are dropped.
+///
+/// ```ignore
+/// let bridge = TransportActorBridge::new(&ctx.address());
+/// subscribe_transport
+///     .on_ice_candidate(bridge.callback(|candidate: RTCIceCandidate| {
+///         SendingMessage::SubscriberIce { candidate: candidate.to_json().unwrap() }
+///     }))
+///     .await;
+/// ```
+pub struct TransportActorBridge<A: Actor> {
+    addr: WeakAddr<A>,
+    capacity: usize,
+}
+
+impl<A: Actor> TransportActorBridge<A> {
+    pub fn new(addr: &Addr<A>) -> Self {
+        Self::with_capacity(addr, DEFAULT_BRIDGE_CAPACITY)
+    }
+
+    /// Create a bridge which queues up to `capacity` messages per callback.
+    pub fn with_capacity(addr: &Addr<A>, capacity: usize) -> Self {
+        Self {
+            addr: addr.downgrade(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Returns true while the actor is running.
+    pub fn is_connected(&self) -> bool {
+        self.addr.upgrade().is_some_and(|addr| addr.connected())
+    }
+
+    /// Create a callback which converts the argument into a message with `f` and sends it to the actor.
+    /// The returned callback can be passed to `on_ice_candidate`, `on_negotiation_needed` and so on. It must be created in a tokio runtime.
+    pub fn callback<T, M, F>(&self, f: F) -> Box<dyn Fn(T) + Send + Sync>
+    where
+        T: 'static,
+        F: Fn(T) -> M + Send + Sync + 'static,
+        M: Message + Send + 'static,
+        M::Result: Send,
+        A: Handler<M>,
+        A::Context: ToEnvelope<A, M>,
+    {
+        let (tx, rx) = mpsc::channel::<M>(self.capacity);
+        tokio::spawn(Self::forward(self.addr.clone(), rx));
+
+        Box::new(move |arg: T| match tx.try_send(f(arg)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::warn!("TransportActorBridge drops a message, because the actor is busy");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::debug!(
+                    "TransportActorBridge drops a message, because the actor has stopped"
+                );
+            }
+        })
+    }
+
+    async fn forward<M>(addr: WeakAddr<A>, mut rx: mpsc::Receiver<M>)
+    where
+        M: Message + Send + 'static,
+        M::Result: Send,
+        A: Handler<M>,
+        A::Context: ToEnvelope<A, M>,
+    {
+        while let Some(message) = rx.recv().await {
+            // Upgrade for each message, so this task doesn't keep the actor alive.
+            let Some(addr) = addr.upgrade() else {
+                break;
+            };
+            match addr.send(message).await {
+                Ok(_) => {}
+                Err(MailboxError::Closed) => break,
+                Err(MailboxError::Timeout) => {
+                    tracing::warn!("TransportActorBridge timed out to deliver a message");
+                }
+            }
+        }
+        tracing::debug!("TransportActorBridge forwarding has finished");
+    }
+}
+
+impl<A: Actor> Clone for TransportActorBridge<A> {
+    fn clone(&self) -> Self {
+        Self {
+            addr: self.addr.clone(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl<A: Actor> fmt::Debug for TransportActorBridge<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransportActorBridge")
+            .field("connected", &self.is_connected())
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
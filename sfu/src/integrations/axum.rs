@@ -0,0 +1,28 @@
+use ::axum::{
+    extract::State,
+    http::{Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::admin::AdminApi;
+
+/// Build an axum router which serves [`AdminApi`]. Please nest it under a path and add authentication layers of your server.
+///
+/// ```ignore
+/// let admin = AdminApi::new();
+/// admin.register(&router).await;
+/// let app = axum::Router::new().nest("/admin", admin_router(admin));
+/// ```
+pub fn admin_router(api: AdminApi) -> ::axum::Router {
+    ::axum::Router::new().fallback(handle).with_state(api)
+}
+
+async fn handle(State(api): State<AdminApi>, method: Method, uri: Uri) -> Response {
+    let res = api.handle(method.as_str(), uri.path()).await;
+    let status = StatusCode::from_u16(res.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    match res.body {
+        Some(body) => (status, Json(body)).into_response(),
+        None => status.into_response(),
+    }
+}
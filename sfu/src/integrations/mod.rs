@@ -0,0 +1,8 @@
+/// Bridge from transport callbacks to [actix](https://docs.rs/actix) actors.
+#[cfg(feature = "actix")]
+#[cfg_attr(docsrs, doc(cfg(feature = "actix")))]
+pub mod actix;
+/// Adapter of [`crate::admin::AdminApi`] for [axum](https://docs.rs/axum).
+#[cfg(feature = "axum")]
+#[cfg_attr(docsrs, doc(cfg(feature = "axum")))]
+pub mod axum;
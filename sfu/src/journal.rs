@@ -0,0 +1,182 @@
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, router::DuplicateTrackKind, storage::Storage};
+
+const JOURNAL_KEY_PREFIX: &str = "router_journal/";
+
+/// Number of entries which are kept in a journal by default. Older entries are dropped.
+pub const DEFAULT_JOURNAL_CAPACITY: usize = 1024;
+
+/// Lifecycle event of a [`crate::router::Router`] which is recorded in the journal.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum JournalEvent {
+    RouterCreated,
+    #[serde(rename_all = "camelCase")]
+    PublishTransportCreated {
+        transport_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    SubscribeTransportCreated {
+        transport_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    TrackPublished {
+        publisher_id: String,
+        ssrc: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    TrackRemoved {
+        publisher_id: String,
+        ssrc: u32,
+    },
+    /// A published track has the same SSRC or the same track id as an existing publisher.
+    #[serde(rename_all = "camelCase")]
+    DuplicateTrack {
+        kind: DuplicateTrackKind,
+        existing_publisher_id: String,
+        new_publisher_id: String,
+    },
+    /// A subscribe transport attempts to subscribe a publisher which is not in its [`crate::grant::SubscribeGrant`].
+    #[serde(rename_all = "camelCase")]
+    SubscriptionDenied {
+        transport_id: String,
+        publisher_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    DataPublished {
+        data_publisher_id: String,
+        label: String,
+    },
+    /// A data channel is closed by [`crate::router::DataLabelPolicy`].
+    #[serde(rename_all = "camelCase")]
+    DataRejected {
+        data_publisher_id: String,
+        label: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    DataRemoved {
+        data_publisher_id: String,
+    },
+    RouterClosed,
+}
+
+/// An event with the time when it is recorded.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalEntry {
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    pub event: JournalEvent,
+}
+
+/// Bounded journal of lifecycle events of a [`crate::router::Router`] for audit logs. It is kept in memory, and it can be exported to a [`Storage`].
+#[derive(Clone, Debug)]
+pub struct Journal {
+    entries: VecDeque<JournalEntry>,
+    capacity: usize,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self::new(DEFAULT_JOURNAL_CAPACITY)
+    }
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity.min(DEFAULT_JOURNAL_CAPACITY)),
+            capacity,
+        }
+    }
+
+    pub(crate) fn record(&mut self, event: JournalEvent) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.push(JournalEntry {
+            timestamp_ms,
+            event,
+        });
+    }
+
+    fn push(&mut self, entry: JournalEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Change the capacity. The oldest entries are dropped if the journal has more entries than the capacity.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// This returns entries from the oldest to the newest.
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        self.entries.iter().cloned().collect()
+    }
+
+    /// Save entries of the router to the storage. Entries which have been saved before are overwritten.
+    pub async fn save(&self, storage: &impl Storage, router_id: &str) -> Result<(), Error> {
+        let value = serde_json::to_vec(&self.entries)?;
+        storage
+            .put(&format!("{}{}", JOURNAL_KEY_PREFIX, router_id), value)
+            .await
+    }
+
+    pub async fn load(
+        storage: &impl Storage,
+        router_id: &str,
+    ) -> Result<Option<Vec<JournalEntry>>, Error> {
+        match storage
+            .get(&format!("{}{}", JOURNAL_KEY_PREFIX, router_id))
+            .await?
+        {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bounded_journal() {
+        let mut journal = Journal::new(2);
+        journal.record(JournalEvent::RouterCreated);
+        journal.record(JournalEvent::DataRemoved {
+            data_publisher_id: "a".to_string(),
+        });
+        journal.record(JournalEvent::RouterClosed);
+
+        let events: Vec<JournalEvent> = journal.entries().into_iter().map(|e| e.event).collect();
+        assert_eq!(
+            events,
+            vec![
+                JournalEvent::DataRemoved {
+                    data_publisher_id: "a".to_string(),
+                },
+                JournalEvent::RouterClosed,
+            ]
+        );
+
+        journal.set_capacity(1);
+        assert_eq!(journal.entries().len(), 1);
+    }
+}
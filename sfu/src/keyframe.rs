@@ -0,0 +1,407 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use webrtc::{
+    api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9},
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+};
+
+use crate::runtime::{self, sleep_until};
+use crate::transport::RtcpSender;
+
+/// Keyframe requests to a publisher are not sent more often than this, because every keyframe costs a lot of bandwidth.
+const MIN_KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Handle to request a keyframe from a [`crate::publisher::Publisher`]. Subscribers and internal consumers like recorders share this path.
+/// Requests are aggregated, so the publisher receives at most one PLI per interval even if many consumers request keyframes at the same time.
+#[derive(Clone, Debug)]
+pub struct KeyframeRequester {
+    sender: mpsc::UnboundedSender<()>,
+}
+
+impl KeyframeRequester {
+    pub(crate) fn new(media_ssrc: u32, rtcp_sender: Arc<RtcpSender>) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        runtime::spawn(
+            async move {
+                Self::keyframe_request_loop(media_ssrc, rtcp_sender, receiver).await;
+            }
+            .in_current_span(),
+        );
+        Self { sender }
+    }
+
+    /// Request a keyframe. If a keyframe has been requested recently, the request is sent after the interval.
+    pub fn request(&self) {
+        let _ = self.sender.send(());
+    }
+
+    async fn keyframe_request_loop(
+        media_ssrc: u32,
+        rtcp_sender: Arc<RtcpSender>,
+        mut receiver: mpsc::UnboundedReceiver<()>,
+    ) {
+        let mut next_available = Instant::now();
+        let mut pending = false;
+
+        loop {
+            tokio::select! {
+                req = receiver.recv() => {
+                    if req.is_none() {
+                        break;
+                    }
+                    if Instant::now() < next_available {
+                        pending = true;
+                        continue;
+                    }
+                }
+                _ = sleep_until(next_available), if pending => {}
+            }
+
+            pending = false;
+            next_available = Instant::now() + MIN_KEYFRAME_REQUEST_INTERVAL;
+            match rtcp_sender.send(Box::new(PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc,
+            })) {
+                Ok(_) => tracing::trace!("send rtcp: pli, media_ssrc={}", media_ssrc),
+                Err(_) => break,
+            }
+        }
+
+        tracing::debug!(
+            "Keyframe request loop for media_ssrc={} has finished",
+            media_ssrc
+        );
+    }
+}
+
+/// KeyframeDetector knows the payload format of a codec and finds keyframes in RTP packets without decoding them.
+pub trait KeyframeDetector: Send + Sync {
+    /// This returns true if the RTP payload contains the beginning of a keyframe.
+    fn is_keyframe(&self, payload: &[u8]) -> bool;
+}
+
+/// Keyframe detectors per MIME type. VP8, VP9, H264 and AV1 detectors are registered by default, and detectors for other codecs can be registered in [`crate::config::MediaConfig`].
+#[derive(Clone)]
+pub struct KeyframeDetectors {
+    detectors: HashMap<String, Arc<dyn KeyframeDetector>>,
+}
+
+impl KeyframeDetectors {
+    /// Register a detector for the MIME type. A detector which has already been registered for the MIME type is replaced.
+    pub fn register(&mut self, mime_type: &str, detector: Arc<dyn KeyframeDetector>) {
+        self.detectors.insert(mime_type.to_lowercase(), detector);
+    }
+
+    /// Detectors without any codec. Use this when media payloads are end-to-end encrypted, because the SFU can't read them.
+    pub fn empty() -> Self {
+        Self {
+            detectors: HashMap::new(),
+        }
+    }
+
+    /// MIME types which have a detector, in lowercase.
+    pub fn mime_types(&self) -> Vec<String> {
+        let mut mime_types: Vec<String> = self.detectors.keys().cloned().collect();
+        mime_types.sort();
+        mime_types
+    }
+
+    /// This returns None if no detector is registered for the MIME type.
+    pub fn is_keyframe(&self, mime_type: &str, payload: &[u8]) -> Option<bool> {
+        self.detectors
+            .get(&mime_type.to_lowercase())
+            .map(|detector| detector.is_keyframe(payload))
+    }
+}
+
+impl Default for KeyframeDetectors {
+    fn default() -> Self {
+        let mut detectors = Self::empty();
+        detectors.register(MIME_TYPE_VP8, Arc::new(Vp8KeyframeDetector));
+        detectors.register(MIME_TYPE_VP9, Arc::new(Vp9KeyframeDetector));
+        detectors.register(MIME_TYPE_H264, Arc::new(H264KeyframeDetector));
+        detectors.register(MIME_TYPE_AV1, Arc::new(Av1KeyframeDetector));
+        detectors
+    }
+}
+
+impl fmt::Debug for KeyframeDetectors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyframeDetectors")
+            .field("mime_types", &self.mime_types())
+            .finish()
+    }
+}
+
+/// Keyframe detector for VP8 (RFC 7741).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Vp8KeyframeDetector;
+
+impl KeyframeDetector for Vp8KeyframeDetector {
+    fn is_keyframe(&self, payload: &[u8]) -> bool {
+        let Some(&descriptor) = payload.first() else {
+            return false;
+        };
+        // Only the first packet of partition 0 has the VP8 payload header.
+        if descriptor & 0x10 == 0 || descriptor & 0x0f != 0 {
+            return false;
+        }
+        let mut index = 1;
+        if descriptor & 0x80 != 0 {
+            let Some(&extension) = payload.get(index) else {
+                return false;
+            };
+            index += 1;
+            if extension & 0x80 != 0 {
+                // PictureID is 15 bits if the M bit is set.
+                match payload.get(index) {
+                    Some(picture_id) if picture_id & 0x80 != 0 => index += 2,
+                    Some(_) => index += 1,
+                    None => return false,
+                }
+            }
+            if extension & 0x40 != 0 {
+                index += 1;
+            }
+            if extension & 0x30 != 0 {
+                index += 1;
+            }
+        }
+        // P bit of the payload header is 0 for keyframes.
+        payload.get(index).map(|h| h & 0x01 == 0).unwrap_or(false)
+    }
+}
+
+/// Keyframe detector for VP9 (RFC 9628). A keyframe is the beginning of a frame which doesn't refer other pictures, in the base spatial layer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Vp9KeyframeDetector;
+
+impl KeyframeDetector for Vp9KeyframeDetector {
+    fn is_keyframe(&self, payload: &[u8]) -> bool {
+        let Some(&descriptor) = payload.first() else {
+            return false;
+        };
+        let inter_picture = descriptor & 0x40 != 0;
+        let start_of_frame = descriptor & 0x08 != 0;
+        if inter_picture || !start_of_frame {
+            return false;
+        }
+        if descriptor & 0x20 == 0 {
+            return true;
+        }
+        let mut index = 1;
+        if descriptor & 0x80 != 0 {
+            match payload.get(index) {
+                Some(picture_id) if picture_id & 0x80 != 0 => index += 2,
+                Some(_) => index += 1,
+                None => return false,
+            }
+        }
+        // Spatial layer ID is in the layer indices.
+        payload
+            .get(index)
+            .map(|layer| (layer >> 1) & 0x07 == 0)
+            .unwrap_or(false)
+    }
+}
+
+/// Keyframe detector for H264 (RFC 6184). IDR and SPS NAL units are treated as keyframes, in single NAL unit, STAP-A and FU-A packets.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct H264KeyframeDetector;
+
+const H264_NALU_IDR: u8 = 5;
+const H264_NALU_SPS: u8 = 7;
+const H264_NALU_STAP_A: u8 = 24;
+const H264_NALU_FU_A: u8 = 28;
+
+impl KeyframeDetector for H264KeyframeDetector {
+    fn is_keyframe(&self, payload: &[u8]) -> bool {
+        let Some(&header) = payload.first() else {
+            return false;
+        };
+        match header & 0x1f {
+            H264_NALU_IDR | H264_NALU_SPS => true,
+            H264_NALU_STAP_A => {
+                let mut index = 1;
+                while index + 2 < payload.len() {
+                    let size = u16::from_be_bytes([payload[index], payload[index + 1]]) as usize;
+                    let nalu_type = payload[index + 2] & 0x1f;
+                    if nalu_type == H264_NALU_IDR || nalu_type == H264_NALU_SPS {
+                        return true;
+                    }
+                    index += 2 + size;
+                }
+                false
+            }
+            H264_NALU_FU_A => match payload.get(1) {
+                // Only the start fragment of an IDR.
+                Some(fu_header) => fu_header & 0x80 != 0 && fu_header & 0x1f == H264_NALU_IDR,
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Keyframe detector for AV1. The N bit of the aggregation header is set on the first packet of a coded video sequence, which starts with a keyframe.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Av1KeyframeDetector;
+
+impl KeyframeDetector for Av1KeyframeDetector {
+    fn is_keyframe(&self, payload: &[u8]) -> bool {
+        payload.first().map(|h| h & 0x08 != 0).unwrap_or(false)
+    }
+}
+
+/// Settings of the keyframe-only mode of a [`crate::subscriber::Subscriber`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct KeyframeOnly {
+    pub(crate) enabled: bool,
+    /// Keyframes are dropped until this interval has passed since the last forwarded keyframe.
+    pub(crate) min_interval: Option<Duration>,
+}
+
+/// KeyframeFilter decides which RTP packets are forwarded in the keyframe-only mode. All packets of a frame have the same RTP timestamp, so the frame is kept or dropped by its first packet.
+#[derive(Debug)]
+pub(crate) struct KeyframeFilter {
+    detectors: KeyframeDetectors,
+    mime_type: String,
+    // RTP timestamp of the current frame, and whether the frame is forwarded.
+    frame: Option<(u32, bool)>,
+    last_keyframe: Option<Instant>,
+}
+
+impl KeyframeFilter {
+    pub(crate) fn new(detectors: KeyframeDetectors, mime_type: String) -> Self {
+        Self {
+            detectors,
+            mime_type,
+            frame: None,
+            last_keyframe: None,
+        }
+    }
+
+    /// Packets of codecs without a detector are always accepted, because keyframes can't be found in them.
+    pub(crate) fn accept(
+        &mut self,
+        mode: KeyframeOnly,
+        timestamp: u32,
+        payload: &[u8],
+        now: Instant,
+    ) -> bool {
+        if !mode.enabled {
+            self.frame = None;
+            self.last_keyframe = None;
+            return true;
+        }
+        if let Some((frame_timestamp, forward)) = self.frame {
+            if frame_timestamp == timestamp {
+                return forward;
+            }
+        }
+        let Some(keyframe) = self.detectors.is_keyframe(&self.mime_type, payload) else {
+            return true;
+        };
+        let due = match (mode.min_interval, self.last_keyframe) {
+            (Some(interval), Some(last)) => now.duration_since(last) >= interval,
+            _ => true,
+        };
+        let forward = keyframe && due;
+        if forward {
+            self.last_keyframe = Some(now);
+        }
+        self.frame = Some((timestamp, forward));
+        forward
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vp8_keyframe() {
+        let detector = Vp8KeyframeDetector;
+        // X=1, S=1, I=1 with 15 bit PictureID, then the payload header.
+        assert!(detector.is_keyframe(&[0x90, 0x80, 0x81, 0x23, 0x10]));
+        assert!(!detector.is_keyframe(&[0x90, 0x80, 0x81, 0x23, 0x11]));
+        // Not the start of partition 0.
+        assert!(!detector.is_keyframe(&[0x80, 0x80, 0x81, 0x23, 0x10]));
+        assert!(!detector.is_keyframe(&[0x90, 0x80]));
+    }
+
+    #[test]
+    fn test_vp9_keyframe() {
+        let detector = Vp9KeyframeDetector;
+        // I=1, L=1, B=1 with 7 bit PictureID, spatial layer 0.
+        assert!(detector.is_keyframe(&[0xa8, 0x01, 0x00]));
+        // Spatial layer 1.
+        assert!(!detector.is_keyframe(&[0xa8, 0x01, 0x02]));
+        // Inter-picture predicted.
+        assert!(!detector.is_keyframe(&[0xc8, 0x01]));
+    }
+
+    #[test]
+    fn test_h264_keyframe() {
+        let detector = H264KeyframeDetector;
+        assert!(detector.is_keyframe(&[0x65, 0x88]));
+        assert!(!detector.is_keyframe(&[0x41, 0x9a]));
+        // STAP-A with SPS and PPS.
+        assert!(detector.is_keyframe(&[0x78, 0x00, 0x02, 0x67, 0x42, 0x00, 0x02, 0x68, 0xce]));
+        // FU-A start and middle fragments of an IDR.
+        assert!(detector.is_keyframe(&[0x7c, 0x85, 0x88]));
+        assert!(!detector.is_keyframe(&[0x7c, 0x05, 0x88]));
+    }
+
+    #[test]
+    fn test_custom_detector() {
+        struct FirstByteDetector;
+        impl KeyframeDetector for FirstByteDetector {
+            fn is_keyframe(&self, payload: &[u8]) -> bool {
+                payload.first() == Some(&1)
+            }
+        }
+
+        let mut detectors = KeyframeDetectors::default();
+        assert_eq!(detectors.is_keyframe("video/H265", &[1]), None);
+        detectors.register("video/H265", Arc::new(FirstByteDetector));
+        assert_eq!(detectors.is_keyframe("video/h265", &[1]), Some(true));
+        assert_eq!(detectors.is_keyframe("video/AV1", &[0x08]), Some(true));
+    }
+
+    #[test]
+    fn test_keyframe_filter() {
+        let mut filter = KeyframeFilter::new(KeyframeDetectors::default(), "video/VP8".to_string());
+        let mode = KeyframeOnly {
+            enabled: true,
+            min_interval: Some(Duration::from_secs(1)),
+        };
+        let keyframe = [0x90, 0x80, 0x81, 0x23, 0x10];
+        let delta = [0x90, 0x80, 0x81, 0x23, 0x11];
+        let continuation = [0x80, 0x80, 0x81, 0x23];
+        let now = Instant::now();
+
+        assert!(!filter.accept(mode, 100, &delta, now));
+        assert!(!filter.accept(mode, 100, &continuation, now));
+        assert!(filter.accept(mode, 200, &keyframe, now));
+        assert!(filter.accept(mode, 200, &continuation, now));
+        assert!(!filter.accept(mode, 300, &delta, now));
+        // Keyframes are rate limited.
+        assert!(!filter.accept(mode, 400, &keyframe, now + Duration::from_millis(500)));
+        assert!(filter.accept(mode, 500, &keyframe, now + Duration::from_secs(1)));
+
+        assert!(filter.accept(KeyframeOnly::default(), 600, &delta, now));
+
+        let mut filter =
+            KeyframeFilter::new(KeyframeDetectors::default(), "audio/opus".to_string());
+        assert!(filter.accept(mode, 100, &[0x00], now));
+    }
+}
@@ -6,7 +6,22 @@
 //!
 //! ## Usage
 //! Please refer the [official README](https://github.com/h3poteto/rheomesh/blob/master/sfu/README.md#usage).
+//!
+//! ## Features
+//! The forwarding path is always available. Other subsystems are optional.
+//! - `actix` (default): Bridge transport callbacks to actix actors. Please refer [`integrations`].
+//! - `axum`: Serve [`admin::AdminApi`] with axum. Please refer [`integrations`].
+//! - `cluster` (default): Replicate router topology to a standby process. Please refer `replication`.
+//! - `cpu-affinity`: Pin forwarding tasks to CPU cores.
+//! - `server`: Build the `rheomesh-server` binary, a standalone SFU process which loads a JSON configuration file and serves the signaling WebSocket.
+//!
+//! Features of a build can be inspected at runtime with [`features()`].
 
+/// Administrative API to manage routers, which is independent of HTTP frameworks.
+pub mod admin;
+/// Ranking of audio publishers by the audio level header extension.
+pub mod audio_level;
+mod bandwidth;
 /// Configuration for [`router::Router`], [`publish_transport::PublishTransport`] and [`subscribe_transport::SubscribeTransport`].
 pub mod config;
 /// DataChannel methods for publisher.
@@ -14,15 +29,56 @@ pub mod data_publisher;
 /// DataChannel methods for subscriber.
 pub mod data_subscriber;
 pub mod error;
+/// Optional subsystems which are compiled in this build.
+pub mod features;
+mod feedback;
+/// Grants which restrict publishers that a subscribe transport can subscribe.
+pub mod grant;
+/// Integrations with other frameworks, which are enabled by features.
+pub mod integrations;
+/// Bounded journal of router lifecycle events for audit logs.
+pub mod journal;
+/// Aggregated keyframe requests to publishers and codec-specific keyframe detection.
+pub mod keyframe;
+/// In-process media sources which are published as publishers.
+pub mod media_source;
+/// Global memory budget for buffered RTP packets and data channel messages.
+pub mod memory;
+/// Network diagnostics for ICE servers, and QoS marking and sharing of UDP sockets.
+pub mod net;
+mod packet_channel;
+/// Per-packet metadata of publishers for analytics.
+pub mod packet_metadata;
 mod prober;
 /// [`webrtc::peer_connection::RTCPeerConnection`] methods for publisher.
 pub mod publish_transport;
 /// Audio and video methods for publisher.
 pub mod publisher;
+/// Connection quality scores of transports for quality indicators.
+pub mod quality;
+/// Experimental replication of router topology to a standby process.
+#[cfg(feature = "cluster")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cluster")))]
+pub mod replication;
 /// Router is a module that determines which media to distribute to whom.
 pub mod router;
+mod rtp_extension;
+/// Runtime abstraction to spawn background tasks on runtimes other than tokio.
+pub mod runtime;
+/// Counters of received RTCP packets, clock drift of publishers, results of bandwidth probing and router-wide snapshots.
+pub mod stats;
+/// Pluggable key-value storage to persist state.
+pub mod storage;
 /// [`webrtc::peer_connection::RTCPeerConnection`] methods for subscriber.
 pub mod subscribe_transport;
 /// Audio and video methods for subscriber.
 pub mod subscriber;
+/// Operations for many subscribers at once.
+pub mod subscriber_group;
+/// Helper to switch between direct P2P and SFU forwarding depending on the room size.
+pub mod topology;
 pub mod transport;
+/// Worker to run CPU heavy work out of the async tasks which forward media.
+pub mod worker;
+
+pub use features::features;
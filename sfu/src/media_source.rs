@@ -0,0 +1,83 @@
+use std::{fmt, future::Future, pin::Pin};
+
+use webrtc::{
+    rtp,
+    rtp_transceiver::{rtp_codec::RTCRtpCodecCapability, RTCRtpHeaderExtensionParameters},
+};
+
+/// In-process source of media, such as synthetic video, bridged protocols or generated audio.
+/// It is published with [`crate::router::Router::publish_source`], and becomes a normal [`crate::publisher::Publisher`] which can be subscribed like tracks from a [`crate::publish_transport::PublishTransport`].
+pub trait MediaSource: Send + Sync + fmt::Debug {
+    /// Track ID of the source. It is used as the ID of the publisher.
+    fn id(&self) -> String;
+
+    fn stream_id(&self) -> String;
+
+    fn ssrc(&self) -> u32;
+
+    /// Codec of RTP packets. The payload type of packets is rewritten to the negotiated one for each subscriber.
+    fn codec(&self) -> RTCRtpCodecCapability;
+
+    /// Read the next RTP packet. When this returns `None`, the source is finished and the publisher is removed from the router.
+    fn read_rtp(&self) -> Pin<Box<dyn Future<Output = Option<rtp::packet::Packet>> + Send + '_>>;
+
+    /// This is called when subscribers request a keyframe with PLI or FIR. Requests are already aggregated by [`crate::keyframe::KeyframeRequester`].
+    fn request_keyframe(&self) {}
+
+    /// Audio level of the packet in -dBov, from 0 (loudest) to 127 (silence). If the packet doesn't have the audio level extension (RFC 6464), it is attached with this value, so subscribers and [`crate::audio_level::AudioLevelObserver`] can detect speakers in server-originated audio.
+    /// Payloads are not decoded by the SFU, so please compute it from samples before encoding, for example with [`crate::audio_level::compute_audio_level`]. This is only called for audio sources.
+    fn audio_level(&self, _packet: &rtp::packet::Packet) -> Option<u8> {
+        None
+    }
+
+    /// RTP header extensions which are written in packets of the source.
+    fn header_extensions(&self) -> Vec<RTCRtpHeaderExtensionParameters> {
+        Vec::new()
+    }
+}
+
+/// Audio source which never sends packets, for tests which need publishers.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct SilentSource {
+    id: String,
+    ssrc: u32,
+}
+
+#[cfg(test)]
+impl SilentSource {
+    pub(crate) fn new(id: &str, ssrc: u32) -> Self {
+        Self {
+            id: id.to_string(),
+            ssrc,
+        }
+    }
+}
+
+#[cfg(test)]
+impl MediaSource for SilentSource {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn stream_id(&self) -> String {
+        "stream".to_string()
+    }
+
+    fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    fn codec(&self) -> RTCRtpCodecCapability {
+        RTCRtpCodecCapability {
+            mime_type: "audio/opus".to_string(),
+            clock_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        }
+    }
+
+    fn read_rtp(&self) -> Pin<Box<dyn Future<Output = Option<rtp::packet::Packet>> + Send + '_>> {
+        Box::pin(std::future::pending())
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// What [`MemoryBudget`] does when a new publisher doesn't fit in the budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverBudgetPolicy {
+    /// Buffers of video publishers are shrunk to the minimum, lowest [`BufferPriority`] first, to make room for the new publisher. The publisher is refused only when it is not enough.
+    #[default]
+    ShrinkVideo,
+    /// Existing buffers are kept, and the new publisher is refused.
+    RefusePublishers,
+}
+
+/// Priority of buffers of a publisher under [`OverBudgetPolicy::ShrinkVideo`]. Please refer [`crate::publisher::Publisher::set_buffer_priority`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum BufferPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl BufferPriority {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => BufferPriority::Low,
+            1 => BufferPriority::Normal,
+            _ => BufferPriority::High,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BufferKind {
+    Audio,
+    Video,
+    Data,
+}
+
+/// Global budget of memory for buffered RTP packets and data channel messages.
+/// Subscribers which stall keep packets in the buffers of publishers, so the budget protects the process from running out of memory. Share one budget with [`crate::config::MediaConfig::memory_budget`] of all routers in the process.
+///
+/// Buffers are accounted by their capacity, because packets are kept in them until the slowest subscriber reads them. Each slot is estimated at the size of the largest RTP packet or data channel message which is expected.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    policy: OverBudgetPolicy,
+    state: Mutex<BudgetState>,
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    used: usize,
+    next_id: u64,
+    buffers: HashMap<u64, Arc<Buffer>>,
+}
+
+#[derive(Debug)]
+struct Buffer {
+    kind: BufferKind,
+    slot_size: usize,
+    min_capacity: usize,
+    // Number of slots which are reserved in the budget. It is only changed while the budget is locked.
+    capacity: AtomicUsize,
+    priority: AtomicU8,
+}
+
+impl Buffer {
+    fn bytes(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed) * self.slot_size
+    }
+}
+
+impl MemoryBudget {
+    /// Create a budget of `limit` bytes.
+    pub fn new(limit: usize, policy: OverBudgetPolicy) -> Self {
+        Self {
+            limit,
+            policy,
+            state: Mutex::new(BudgetState::default()),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn policy(&self) -> OverBudgetPolicy {
+        self.policy
+    }
+
+    /// Bytes which are reserved by buffers now.
+    pub fn used(&self) -> usize {
+        self.state.lock().unwrap().used
+    }
+
+    /// Reserve a buffer of `capacity` slots. If it doesn't fit, the buffer is reserved with `min_capacity` slots, and `None` is returned when even it doesn't fit.
+    pub(crate) fn reserve(
+        self: &Arc<Self>,
+        kind: BufferKind,
+        slot_size: usize,
+        capacity: usize,
+        min_capacity: usize,
+    ) -> Option<Reservation> {
+        let mut state = self.state.lock().unwrap();
+        let fits = |state: &BudgetState, slots: usize| state.used + slots * slot_size <= self.limit;
+
+        let capacity = if fits(&state, capacity) {
+            capacity
+        } else {
+            if self.policy == OverBudgetPolicy::ShrinkVideo {
+                self.shrink_video(&mut state, min_capacity * slot_size);
+            }
+            if !fits(&state, min_capacity) {
+                tracing::warn!(
+                    "Memory budget is exhausted, used={} limit={}, {:?} buffer is refused",
+                    state.used,
+                    self.limit,
+                    kind
+                );
+                return None;
+            }
+            min_capacity
+        };
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.used += capacity * slot_size;
+        let buffer = Arc::new(Buffer {
+            kind,
+            slot_size,
+            min_capacity,
+            capacity: AtomicUsize::new(capacity),
+            priority: AtomicU8::new(BufferPriority::default() as u8),
+        });
+        state.buffers.insert(id, buffer.clone());
+
+        Some(Reservation {
+            budget: self.clone(),
+            id,
+            buffer,
+        })
+    }
+
+    /// Shrink video buffers, lowest priority first, until `needed` bytes are available.
+    fn shrink_video(&self, state: &mut BudgetState, needed: usize) {
+        let mut videos: Vec<Arc<Buffer>> = state
+            .buffers
+            .values()
+            .filter(|b| {
+                b.kind == BufferKind::Video && b.capacity.load(Ordering::Relaxed) > b.min_capacity
+            })
+            .cloned()
+            .collect();
+        videos.sort_by_key(|b| b.priority.load(Ordering::Relaxed));
+
+        for buffer in videos {
+            if state.used + needed <= self.limit {
+                break;
+            }
+            let freed = buffer.bytes() - buffer.min_capacity * buffer.slot_size;
+            buffer
+                .capacity
+                .store(buffer.min_capacity, Ordering::Relaxed);
+            state.used -= freed;
+            tracing::warn!(
+                "Memory budget is exhausted, a video buffer of {:?} priority is shrunk by {} bytes",
+                BufferPriority::from_u8(buffer.priority.load(Ordering::Relaxed)),
+                freed
+            );
+        }
+    }
+
+    /// Resize the buffer to `capacity` slots, as long as the budget allows. This returns the capacity which is reserved.
+    fn resize(&self, buffer: &Buffer, capacity: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let current = buffer.capacity.load(Ordering::Relaxed);
+        let capacity = if capacity <= current {
+            capacity
+        } else {
+            let available = (self.limit.saturating_sub(state.used)) / buffer.slot_size;
+            current + (capacity - current).min(available)
+        };
+        state.used = state.used + capacity * buffer.slot_size - current * buffer.slot_size;
+        buffer.capacity.store(capacity, Ordering::Relaxed);
+        capacity
+    }
+
+    fn release(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(buffer) = state.buffers.remove(&id) {
+            state.used -= buffer.bytes();
+        }
+    }
+}
+
+/// Buffer which is reserved in a [`MemoryBudget`]. The reservation is released when it is dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    budget: Arc<MemoryBudget>,
+    id: u64,
+    buffer: Arc<Buffer>,
+}
+
+impl Reservation {
+    /// Number of slots which the buffer may use. The budget decreases it when video buffers are shrunk.
+    pub(crate) fn capacity(&self) -> usize {
+        self.buffer.capacity.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn resize(&self, capacity: usize) -> usize {
+        self.budget.resize(&self.buffer, capacity)
+    }
+
+    pub(crate) fn set_priority(&self, priority: BufferPriority) {
+        self.buffer
+            .priority
+            .store(priority as u8, Ordering::Relaxed);
+    }
+
+    pub(crate) fn priority(&self) -> BufferPriority {
+        BufferPriority::from_u8(self.buffer.priority.load(Ordering::Relaxed))
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shrink_video() {
+        let budget = Arc::new(MemoryBudget::new(1000, OverBudgetPolicy::ShrinkVideo));
+        let low = budget.reserve(BufferKind::Video, 1, 400, 100).unwrap();
+        low.set_priority(BufferPriority::Low);
+        let high = budget.reserve(BufferKind::Video, 1, 400, 100).unwrap();
+        high.set_priority(BufferPriority::High);
+        let audio = budget.reserve(BufferKind::Audio, 1, 100, 100).unwrap();
+        assert_eq!(budget.used(), 900);
+
+        // The video buffer of the low priority is shrunk first.
+        let next = budget.reserve(BufferKind::Audio, 1, 200, 200).unwrap();
+        assert_eq!(low.capacity(), 100);
+        assert_eq!(high.capacity(), 400);
+        assert_eq!(budget.used(), 800);
+
+        // Buffers don't grow over the budget.
+        assert_eq!(audio.resize(500), 300);
+        assert_eq!(budget.used(), 1000);
+
+        // All video buffers have been shrunk, but it is not enough.
+        assert!(budget.reserve(BufferKind::Data, 1, 400, 400).is_none());
+        assert_eq!(high.capacity(), 100);
+
+        drop(next);
+        assert_eq!(budget.used(), 500);
+    }
+
+    #[test]
+    fn test_refuse_publishers() {
+        let budget = Arc::new(MemoryBudget::new(1000, OverBudgetPolicy::RefusePublishers));
+        let video = budget.reserve(BufferKind::Video, 1, 800, 100).unwrap();
+
+        // The new buffer is reserved with the minimum capacity.
+        let audio = budget.reserve(BufferKind::Audio, 1, 300, 100).unwrap();
+        assert_eq!(audio.capacity(), 100);
+        assert!(budget.reserve(BufferKind::Audio, 1, 300, 200).is_none());
+        assert_eq!(video.capacity(), 800);
+    }
+}
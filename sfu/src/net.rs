@@ -0,0 +1,566 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::{lookup_host, TcpStream, UdpSocket},
+    time::timeout,
+};
+use webrtc::{
+    ice_transport::ice_server::RTCIceServer,
+    stun::{
+        agent::TransactionId,
+        message::{Getter, Message, BINDING_REQUEST},
+        xoraddr::XorMappedAddress,
+    },
+};
+use webrtc_ice::{
+    udp_mux::{UDPMux, UDPMuxDefault, UDPMuxParams},
+    url::{ProtoType, SchemeType, Url},
+};
+use webrtc_util::Conn;
+
+use crate::config::{MediaConfig, PortRange};
+
+/// Result of probing a URL of an ICE server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IceServerProbe {
+    pub url: String,
+    /// Round trip time of the STUN binding request, or time to connect for TCP servers.
+    pub rtt: Option<Duration>,
+    /// Address of this host which is seen by the server. This is only known for UDP servers.
+    pub mapped_address: Option<SocketAddr>,
+    /// Reason why the server is not reachable.
+    pub error: Option<String>,
+}
+
+impl IceServerProbe {
+    pub fn is_reachable(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Probe reachability of STUN and TURN servers. A STUN binding request is sent to UDP servers, and only the connection is checked for TCP and TLS servers.
+/// TURN credentials are not verified, because it requires an allocation on the server.
+pub async fn probe_ice_servers(
+    ice_servers: &[RTCIceServer],
+    probe_timeout: Duration,
+) -> Vec<IceServerProbe> {
+    let mut probes = Vec::new();
+    for server in ice_servers.iter() {
+        for url in server.urls.iter() {
+            let res = match Url::parse_url(url) {
+                Ok(parsed) => timeout(probe_timeout, probe(&parsed))
+                    .await
+                    .unwrap_or_else(|_| Err("timed out".to_string())),
+                Err(err) => Err(format!("invalid url: {}", err)),
+            };
+            let probe = match res {
+                Ok((rtt, mapped_address)) => IceServerProbe {
+                    url: url.clone(),
+                    rtt: Some(rtt),
+                    mapped_address,
+                    error: None,
+                },
+                Err(error) => IceServerProbe {
+                    url: url.clone(),
+                    rtt: None,
+                    mapped_address: None,
+                    error: Some(error),
+                },
+            };
+            tracing::debug!("ICE server is probed: {:?}", probe);
+            probes.push(probe);
+        }
+    }
+    probes
+}
+
+async fn probe(url: &Url) -> Result<(Duration, Option<SocketAddr>), String> {
+    let addr = lookup_host((url.host.as_str(), url.port))
+        .await
+        .map_err(|e| format!("failed to resolve {}: {}", url.host, e))?
+        .next()
+        .ok_or_else(|| format!("failed to resolve {}", url.host))?;
+
+    let secure = url.scheme == SchemeType::Stuns || url.scheme == SchemeType::Turns;
+    if secure || url.proto == ProtoType::Tcp {
+        let started = Instant::now();
+        TcpStream::connect(addr)
+            .await
+            .map_err(|e| format!("failed to connect to {}: {}", addr, e))?;
+        return Ok((started.elapsed(), None));
+    }
+
+    let (rtt, mapped_address) = binding_request(addr).await?;
+    Ok((rtt, Some(mapped_address)))
+}
+
+async fn binding_request(addr: SocketAddr) -> Result<(Duration, SocketAddr), String> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local).await.map_err(|e| e.to_string())?;
+
+    let mut request = Message::new();
+    request
+        .build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)])
+        .map_err(|e| e.to_string())?;
+
+    let started = Instant::now();
+    socket
+        .send_to(&request.raw, addr)
+        .await
+        .map_err(|e| format!("failed to send binding request to {}: {}", addr, e))?;
+
+    let mut buf = vec![0u8; 1500];
+    loop {
+        let (n, from) = socket
+            .recv_from(&mut buf)
+            .await
+            .map_err(|e| e.to_string())?;
+        if from != addr {
+            continue;
+        }
+        let mut response = Message::new();
+        response.raw = buf[..n].to_vec();
+        if response.decode().is_err() || response.transaction_id != request.transaction_id {
+            continue;
+        }
+        let mut mapped = XorMappedAddress::default();
+        mapped
+            .get_from(&response)
+            .map_err(|e| format!("invalid binding response: {}", e))?;
+        return Ok((started.elapsed(), SocketAddr::new(mapped.ip, mapped.port)));
+    }
+}
+
+/// Differentiated services code point which is marked on egress packets, so managed networks can prioritize media. Recommended values per media kind are defined in RFC 8837.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dscp(pub u8);
+
+impl Dscp {
+    /// Default forwarding, which means packets are not prioritized.
+    pub const DF: Dscp = Dscp(0);
+    /// Expedited forwarding, which is recommended for audio.
+    pub const EF: Dscp = Dscp(46);
+    /// Assured forwarding class 4 with low drop precedence, which is recommended for video.
+    pub const AF41: Dscp = Dscp(34);
+    pub const AF42: Dscp = Dscp(36);
+    pub const AF43: Dscp = Dscp(38);
+
+    fn tos(&self) -> u32 {
+        (self.0 as u32) << 2
+    }
+}
+
+/// Mark packets which are sent from the socket with the DSCP. Only IPv4 sockets are supported.
+/// The code point applies to every media kind of the socket. Please use [`DscpConfig`] to mark packets per media kind.
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+)))]
+pub fn set_dscp(socket: &UdpSocket, dscp: Dscp) -> io::Result<()> {
+    if socket.local_addr()?.is_ipv6() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "DSCP marking is not supported for IPv6 sockets",
+        ));
+    }
+    socket.set_tos(dscp.tos())
+}
+
+/// Mark packets which are sent from the socket with the DSCP. This platform doesn't support it.
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+))]
+pub fn set_dscp(_socket: &UdpSocket, _dscp: Dscp) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DSCP marking is not supported on this platform",
+    ))
+}
+
+/// Code points per media kind which are marked on packets of each transport, please refer [`crate::config::WebRTCTransportConfig::dscp`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DscpConfig {
+    /// Code point of RTP packets of audio codecs.
+    pub audio: Option<Dscp>,
+    /// Code point of RTP packets of video codecs, including retransmissions.
+    pub video: Option<Dscp>,
+    /// Code point of other packets, such as RTCP, STUN, DTLS and data channels.
+    pub other: Option<Dscp>,
+}
+
+impl DscpConfig {
+    /// Code points which are recommended for interactive audio and video in RFC 8837.
+    pub fn recommended() -> Self {
+        Self {
+            audio: Some(Dscp::EF),
+            video: Some(Dscp::AF41),
+            other: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.audio.is_none() && self.video.is_none() && self.other.is_none()
+    }
+}
+
+/// This chooses the code point of each packet with [`DscpConfig`]. Media kinds of RTP packets are found from payload types of [`MediaConfig`].
+#[derive(Clone, Debug)]
+pub(crate) struct DscpMarker {
+    config: DscpConfig,
+    audio_payload_types: Vec<u8>,
+    video_payload_types: Vec<u8>,
+}
+
+// Payload types of `MediaEngine::register_default_codecs`, which is used when no codec is configured. webrtc doesn't expose the registered codecs, so they are copied here.
+const DEFAULT_AUDIO_PAYLOAD_TYPES: [u8; 4] = [111, 9, 0, 8];
+const DEFAULT_VIDEO_PAYLOAD_TYPES: [u8; 11] = [96, 98, 100, 102, 127, 125, 108, 123, 41, 126, 116];
+
+impl DscpMarker {
+    pub(crate) fn new(config: DscpConfig, media_config: &MediaConfig) -> Self {
+        if media_config.codec.audio.is_empty() && media_config.codec.video.is_empty() {
+            return Self {
+                config,
+                audio_payload_types: DEFAULT_AUDIO_PAYLOAD_TYPES.to_vec(),
+                video_payload_types: DEFAULT_VIDEO_PAYLOAD_TYPES.to_vec(),
+            };
+        }
+        Self {
+            config,
+            audio_payload_types: media_config
+                .codec
+                .audio
+                .iter()
+                .map(|codec| codec.payload_type)
+                .collect(),
+            video_payload_types: media_config
+                .codec
+                .video
+                .iter()
+                .map(|codec| codec.payload_type)
+                .collect(),
+        }
+    }
+
+    /// RTP is distinguished from other protocols with the first byte as RFC 7983, and from RTCP with the packet type as RFC 5761. SRTP doesn't encrypt RTP headers, so the payload type can be read.
+    fn classify(&self, packet: &[u8]) -> Dscp {
+        let dscp = match packet {
+            [first, second, ..] if (128..=191).contains(first) && !(192..=223).contains(second) => {
+                let payload_type = second & 0x7f;
+                if self.audio_payload_types.contains(&payload_type) {
+                    self.config.audio
+                } else if self.video_payload_types.contains(&payload_type) {
+                    self.config.video
+                } else {
+                    self.config.other
+                }
+            }
+            _ => self.config.other,
+        };
+        dscp.unwrap_or(Dscp::DF)
+    }
+}
+
+/// UDP socket of a transport, which marks each packet with the code point of its media kind.
+/// Media kinds are bundled on one socket, so the code point of the socket is switched before sending a packet of another kind.
+struct DscpSocket {
+    socket: UdpSocket,
+    marker: DscpMarker,
+    // Code point which is set on the socket now. It is locked while sending, so another packet doesn't switch it before the packet is sent.
+    current: std::sync::Mutex<Option<Dscp>>,
+}
+
+impl DscpSocket {
+    fn try_send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let dscp = self.marker.classify(buf);
+        let mut current = self.current.lock().unwrap();
+        if *current != Some(dscp) {
+            if let Err(err) = set_dscp(&self.socket, dscp) {
+                tracing::warn!("failed to mark packets with {:?}: {}", dscp, err);
+            }
+            *current = Some(dscp);
+        }
+        self.socket.try_send_to(buf, target)
+    }
+}
+
+#[async_trait]
+impl Conn for DscpSocket {
+    async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+        Ok(self.socket.connect(addr).await?)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        Ok(self.socket.recv(buf).await?)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        Ok(self.socket.recv_from(buf).await?)
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        let target = self.socket.peer_addr()?;
+        self.send_to(buf, target).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        loop {
+            self.socket.writable().await?;
+            match self.try_send_to(buf, target) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return Ok(res?),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Send + Sync) {
+        self
+    }
+}
+
+/// UDP mux which is used only by one transport. The ICE agent removes its connection when it is closed, so the mux and the socket are closed with it.
+struct TransportUdpMux(Arc<UDPMuxDefault>);
+
+#[async_trait]
+impl UDPMux for TransportUdpMux {
+    async fn close(&self) -> Result<(), webrtc_util::Error> {
+        self.0.close().await
+    }
+
+    async fn get_conn(
+        self: Arc<Self>,
+        ufrag: &str,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, webrtc_util::Error> {
+        self.0.clone().get_conn(ufrag).await
+    }
+
+    async fn remove_conn_by_ufrag(&self, ufrag: &str) {
+        self.0.remove_conn_by_ufrag(ufrag).await;
+        let _ = self.0.close().await;
+    }
+}
+
+/// Bind a UDP socket for a transport, which marks packets with [`DscpConfig`]. A port in the range is chosen at random if it is given.
+/// The socket is bound to the IPv4 unspecified address, because DSCP marking is supported only for IPv4. This must be called in a tokio runtime.
+pub(crate) fn bind_dscp_socket(
+    marker: DscpMarker,
+    port_range: Option<&PortRange>,
+) -> io::Result<Arc<dyn UDPMux + Send + Sync>> {
+    let socket = match port_range {
+        Some(port_range) => bind_in_range(port_range)?,
+        None => std::net::UdpSocket::bind("0.0.0.0:0")?,
+    };
+    socket.set_nonblocking(true)?;
+    let socket = DscpSocket {
+        socket: UdpSocket::from_std(socket)?,
+        marker,
+        current: std::sync::Mutex::new(None),
+    };
+    tracing::debug!(
+        "UDP socket with DSCP marking is bound to {:?}",
+        socket.socket.local_addr()
+    );
+    let mux = UDPMuxDefault::new(UDPMuxParams::new(socket));
+    Ok(Arc::new(TransportUdpMux(mux)))
+}
+
+fn bind_in_range(port_range: &PortRange) -> io::Result<std::net::UdpSocket> {
+    let count = port_range.max.saturating_sub(port_range.min) as usize + 1;
+    let start = rand::random::<usize>() % count;
+    for i in 0..count {
+        let port = port_range.min + ((start + i) % count) as u16;
+        if let Ok(socket) = std::net::UdpSocket::bind(("0.0.0.0", port)) {
+            return Ok(socket);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!(
+            "no port is available in {}-{}",
+            port_range.min, port_range.max
+        ),
+    ))
+}
+
+/// Options of the UDP socket which is shared by all transports with [`SharedUdpSocket`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpSocketOptions {
+    /// Address to bind. Bind a specific IP address to receive media only on the interface. Host candidates are gathered only for the IP address in that case.
+    pub bind_address: SocketAddr,
+    /// Set SO_REUSEPORT, so multiple processes can bind the same port and the kernel shards the traffic among them. This is supported only on unix.
+    pub reuse_port: bool,
+    /// Size of the receive buffer of the kernel in bytes. The kernel may limit it, for example with `net.core.rmem_max` on Linux.
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the send buffer of the kernel in bytes.
+    pub send_buffer_size: Option<usize>,
+    pub dscp: Option<Dscp>,
+}
+
+impl UdpSocketOptions {
+    pub fn new(bind_address: SocketAddr) -> Self {
+        Self {
+            bind_address,
+            reuse_port: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            dscp: None,
+        }
+    }
+}
+
+/// UDP socket which is shared by all transports that have it in [`crate::config::WebRTCTransportConfig::udp_socket`]. ICE traffic of the transports is demultiplexed with the ICE username fragment, so every transport uses the same port.
+#[derive(Clone)]
+pub struct SharedUdpSocket {
+    pub(crate) mux: Arc<UDPMuxDefault>,
+    local_addr: SocketAddr,
+}
+
+impl SharedUdpSocket {
+    /// Bind a socket with the options. This must be called in a tokio runtime.
+    pub fn bind(options: &UdpSocketOptions) -> io::Result<Self> {
+        let domain = Domain::for_address(options.bind_address);
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        if options.reuse_port {
+            set_reuse_port(&socket)?;
+        }
+        if let Some(size) = options.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = options.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&options.bind_address.into())?;
+
+        let socket = UdpSocket::from_std(socket.into())?;
+        if let Some(dscp) = options.dscp {
+            set_dscp(&socket, dscp)?;
+        }
+        let local_addr = socket.local_addr()?;
+        tracing::debug!("shared UDP socket is bound to {}", local_addr);
+
+        let mux = UDPMuxDefault::new(UDPMuxParams::new(socket));
+        Ok(Self { mux, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl fmt::Debug for SharedUdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedUdpSocket")
+            .field("local_addr", &self.local_addr)
+            .finish()
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecParameters;
+
+    fn marker() -> DscpMarker {
+        let codec = |payload_type| RTCRtpCodecParameters {
+            payload_type,
+            ..Default::default()
+        };
+        let mut media_config = MediaConfig::default();
+        media_config.codec.audio = vec![codec(111)];
+        media_config.codec.video = vec![codec(96), codec(97)];
+        DscpMarker::new(DscpConfig::recommended(), &media_config)
+    }
+
+    #[test]
+    fn test_classify() {
+        let marker = marker();
+        // RTP with the marker bit.
+        assert_eq!(marker.classify(&[0x80, 111, 0, 1]), Dscp::EF);
+        assert_eq!(marker.classify(&[0x80, 0x80 | 96, 0, 1]), Dscp::AF41);
+        // Retransmission of video.
+        assert_eq!(marker.classify(&[0x80, 97, 0, 1]), Dscp::AF41);
+        // Unknown payload type, RTCP receiver report, STUN and DTLS.
+        assert_eq!(marker.classify(&[0x80, 100, 0, 1]), Dscp::DF);
+        assert_eq!(marker.classify(&[0x81, 201, 0, 7]), Dscp::DF);
+        assert_eq!(marker.classify(&[0x00, 0x01, 0, 0]), Dscp::DF);
+        assert_eq!(marker.classify(&[22, 254, 253]), Dscp::DF);
+    }
+
+    #[test]
+    fn test_classify_default_codecs() {
+        let marker = DscpMarker::new(DscpConfig::recommended(), &MediaConfig::default());
+        // Opus and PCMU.
+        assert_eq!(marker.classify(&[0x80, 111, 0, 1]), Dscp::EF);
+        assert_eq!(marker.classify(&[0x80, 0, 0, 1]), Dscp::EF);
+        // VP8 and H264.
+        assert_eq!(marker.classify(&[0x80, 96, 0, 1]), Dscp::AF41);
+        assert_eq!(marker.classify(&[0x80, 102, 0, 1]), Dscp::AF41);
+        assert_eq!(marker.classify(&[0x80, 50, 0, 1]), Dscp::DF);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_mark_socket() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = receiver.local_addr().unwrap();
+        let socket = DscpSocket {
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            marker: marker(),
+            current: std::sync::Mutex::new(None),
+        };
+        let tos = |socket: &DscpSocket| socket2::SockRef::from(&socket.socket).tos().unwrap();
+
+        socket.send_to(&[0x80, 111, 0, 1], target).await.unwrap();
+        assert_eq!(tos(&socket), Dscp::EF.tos());
+        socket.send_to(&[0x80, 96, 0, 2], target).await.unwrap();
+        assert_eq!(tos(&socket), Dscp::AF41.tos());
+        socket.send_to(&[0x81, 201, 0, 7], target).await.unwrap();
+        assert_eq!(tos(&socket), Dscp::DF.tos());
+
+        let mut buf = [0u8; 4];
+        for _ in 0..3 {
+            receiver.recv_from(&mut buf).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{broadcast, watch};
+
+use crate::{memory::Reservation, publisher::ReceivedPacket};
+
+/// Packets are buffered for this duration at the measured packet rate, so slow subscribers can catch up with bursts.
+const BUFFERED_DURATION: Duration = Duration::from_secs(2);
+pub(crate) const MIN_CAPACITY: usize = 64;
+const MAX_CAPACITY: usize = 8192;
+/// The packet rate is measured over this window before the capacity is changed.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// The old channel is closed after this even if some subscribers haven't moved to the new channel yet.
+const RETIRE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Capacity of RTP packet channels of audio publishers before the packet rate is measured. It is enough for 20 ms frames.
+pub(crate) const AUDIO_CAPACITY: usize = 128;
+/// Capacity of RTP packet channels of video publishers before the packet rate is measured.
+pub(crate) const VIDEO_CAPACITY: usize = 1024;
+/// Each slot of RTP packet channels is accounted at the MTU in [`crate::memory::MemoryBudget`].
+pub(crate) const PACKET_SIZE: usize = 1500;
+
+#[derive(Clone, Debug)]
+struct IndexedPacket {
+    index: u64,
+    packet: ReceivedPacket,
+}
+
+/// Broadcast channel of RTP packets from a [`crate::publisher::Publisher`] to its subscribers.
+/// A broadcast channel can't be resized, so the capacity is changed by replacing the channel. Receivers move to the new channel after they drain the old one, and packets which are sent to both channels are delivered once.
+#[derive(Clone, Debug)]
+pub(crate) struct PacketChannel {
+    current: Arc<watch::Sender<broadcast::Sender<IndexedPacket>>>,
+}
+
+impl PacketChannel {
+    /// When the channel is reserved in a memory budget, the capacity never exceeds the reservation.
+    pub(crate) fn new(
+        capacity: usize,
+        reservation: Option<Arc<Reservation>>,
+    ) -> (Self, PacketSender) {
+        let (sender, _) = broadcast::channel(capacity);
+        let current = Arc::new(watch::channel(sender.clone()).0);
+        let packet_sender = PacketSender {
+            current: current.clone(),
+            sender,
+            capacity,
+            reservation,
+            retiring: None,
+            next_index: 0,
+            window_start: Instant::now(),
+            window_packets: 0,
+        };
+        (Self { current }, packet_sender)
+    }
+
+    pub(crate) fn subscribe(&self) -> PacketReceiver {
+        let channel = self.current.subscribe();
+        let receiver = channel.borrow().subscribe();
+        PacketReceiver {
+            receiver,
+            next: None,
+            channel,
+            last_index: None,
+        }
+    }
+}
+
+/// Sending side of a [`PacketChannel`], which is owned by the RTP event loop of the publisher.
+#[derive(Debug)]
+pub(crate) struct PacketSender {
+    current: Arc<watch::Sender<broadcast::Sender<IndexedPacket>>>,
+    sender: broadcast::Sender<IndexedPacket>,
+    capacity: usize,
+    reservation: Option<Arc<Reservation>>,
+    // Previous channel, and the time when it is closed regardless of receivers.
+    retiring: Option<(broadcast::Sender<IndexedPacket>, Instant)>,
+    next_index: u64,
+    window_start: Instant,
+    window_packets: u64,
+}
+
+impl PacketSender {
+    pub(crate) fn send(&mut self, packet: ReceivedPacket) {
+        let now = packet.received_at;
+        self.measure(now);
+        if let Some(reservation) = &self.reservation {
+            // The memory budget has shrunk this buffer to make room for other publishers.
+            let allowed = reservation.capacity();
+            if allowed < self.capacity && self.retiring.is_none() {
+                self.replace(allowed, now);
+            }
+        }
+
+        let packet = IndexedPacket {
+            index: self.next_index,
+            packet,
+        };
+        self.next_index += 1;
+
+        if let Some((retiring, deadline)) = &self.retiring {
+            if self.sender.receiver_count() >= retiring.receiver_count() || now >= *deadline {
+                // Dropping the old sender closes the old channel, so receivers move to the new one after draining it.
+                self.retiring = None;
+            } else {
+                let _ = retiring.send(packet.clone());
+            }
+        }
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(packet);
+        }
+    }
+
+    fn measure(&mut self, now: Instant) {
+        self.window_packets += 1;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let packet_rate = self.window_packets as f64 / elapsed.as_secs_f64();
+        self.window_start = now;
+        self.window_packets = 0;
+
+        let capacity = channel_capacity(packet_rate);
+        let resize = capacity > self.capacity || capacity * 4 <= self.capacity;
+        if !resize || self.retiring.is_some() {
+            return;
+        }
+        let capacity = match &self.reservation {
+            Some(reservation) => reservation.resize(capacity),
+            None => capacity,
+        };
+        if capacity != self.capacity {
+            tracing::debug!(
+                "RTP packet channel is resized from {} to {}, packet_rate={:.1}",
+                self.capacity,
+                capacity,
+                packet_rate
+            );
+            self.replace(capacity, now);
+        }
+    }
+
+    fn replace(&mut self, capacity: usize, now: Instant) {
+        let (sender, _) = broadcast::channel(capacity);
+        self.current.send_replace(sender.clone());
+        let old = std::mem::replace(&mut self.sender, sender);
+        self.retiring = Some((old, now + RETIRE_TIMEOUT));
+        self.capacity = capacity;
+    }
+}
+
+/// Receiving side of a [`PacketChannel`]. It follows the channel when it is replaced.
+#[derive(Debug)]
+pub(crate) struct PacketReceiver {
+    receiver: broadcast::Receiver<IndexedPacket>,
+    next: Option<broadcast::Receiver<IndexedPacket>>,
+    channel: watch::Receiver<broadcast::Sender<IndexedPacket>>,
+    last_index: Option<u64>,
+}
+
+impl PacketReceiver {
+    pub(crate) async fn recv(&mut self) -> Result<ReceivedPacket, broadcast::error::RecvError> {
+        loop {
+            tokio::select! {
+                res = self.receiver.recv() => match res {
+                    Ok(IndexedPacket { index, packet }) => {
+                        if self.last_index.is_some_and(|last| index <= last) {
+                            continue;
+                        }
+                        self.last_index = Some(index);
+                        return Ok(packet);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => match self.next.take() {
+                        Some(next) => self.receiver = next,
+                        // The old channel has been closed before this receiver noticed the new one.
+                        None if self.channel.has_changed().unwrap_or(false) => {
+                            self.receiver = self.channel.borrow_and_update().subscribe();
+                        }
+                        None => return Err(broadcast::error::RecvError::Closed),
+                    },
+                    Err(err) => return Err(err),
+                },
+                Ok(()) = self.channel.changed(), if self.next.is_none() => {
+                    // Subscribe the new channel before the old one is closed, so no packet is lost.
+                    self.next = Some(self.channel.borrow_and_update().subscribe());
+                }
+            }
+        }
+    }
+}
+
+/// Capacity which buffers [`BUFFERED_DURATION`] of packets at the packet rate.
+fn channel_capacity(packet_rate: f64) -> usize {
+    let packets = (packet_rate * BUFFERED_DURATION.as_secs_f64()).ceil() as usize;
+    packets
+        .next_power_of_two()
+        .clamp(MIN_CAPACITY, MAX_CAPACITY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_channel_capacity() {
+        // Opus with 20 ms frames.
+        assert_eq!(channel_capacity(50.0), 128);
+        // Silence suppressed audio.
+        assert_eq!(channel_capacity(5.0), MIN_CAPACITY);
+        // 2.5 Mbps video with about 1100 bytes packets.
+        assert_eq!(channel_capacity(280.0), 1024);
+        // High bitrate screen share.
+        assert_eq!(channel_capacity(2000.0), 4096);
+        assert_eq!(channel_capacity(100000.0), MAX_CAPACITY);
+    }
+}
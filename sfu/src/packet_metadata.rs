@@ -0,0 +1,76 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use webrtc::{rtp, rtp_transceiver::RTCRtpHeaderExtensionParameters, sdp::extmap};
+
+/// Metadata of an RTP packet which is received from a publisher. This doesn't contain the payload.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PacketMetadata {
+    /// Arrival time in microseconds since UNIX epoch.
+    pub arrival_time_us: u64,
+    pub ssrc: u32,
+    pub sequence_number: u16,
+    /// RTP timestamp which is set by the publisher.
+    pub rtp_timestamp: u32,
+    pub payload_type: u8,
+    pub marker: bool,
+    /// Payload size in bytes.
+    pub payload_size: usize,
+    /// 24 bit abs-send-time value.
+    pub abs_send_time: Option<u32>,
+    /// Transport-wide sequence number for TWCC.
+    pub transport_sequence_number: Option<u16>,
+    /// Audio level in -dBov.
+    pub audio_level: Option<u8>,
+}
+
+/// Extension IDs which are negotiated with the publisher.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExtensionIds {
+    abs_send_time: Option<u8>,
+    transport_cc: Option<u8>,
+    audio_level: Option<u8>,
+}
+
+impl ExtensionIds {
+    pub(crate) fn new(extensions: &[RTCRtpHeaderExtensionParameters]) -> Self {
+        let find = |uri: &str| extensions.iter().find(|e| e.uri == uri).map(|e| e.id as u8);
+        Self {
+            abs_send_time: find(extmap::ABS_SEND_TIME_URI),
+            transport_cc: find(extmap::TRANSPORT_CC_URI),
+            audio_level: find(extmap::AUDIO_LEVEL_URI),
+        }
+    }
+}
+
+impl PacketMetadata {
+    pub(crate) fn new(packet: &rtp::packet::Packet, ids: &ExtensionIds) -> Self {
+        let header = &packet.header;
+        let extension = |id: Option<u8>| id.and_then(|id| header.get_extension(id));
+
+        let arrival_time_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+
+        Self {
+            arrival_time_us,
+            ssrc: header.ssrc,
+            sequence_number: header.sequence_number,
+            rtp_timestamp: header.timestamp,
+            payload_type: header.payload_type,
+            marker: header.marker,
+            payload_size: packet.payload.len(),
+            abs_send_time: extension(ids.abs_send_time)
+                .filter(|v| v.len() >= 3)
+                .map(|v| ((v[0] as u32) << 16) | ((v[1] as u32) << 8) | v[2] as u32),
+            transport_sequence_number: extension(ids.transport_cc)
+                .filter(|v| v.len() >= 2)
+                .map(|v| ((v[0] as u16) << 8) | v[1] as u16),
+            audio_level: extension(ids.audio_level)
+                .filter(|v| !v.is_empty())
+                .map(|v| v[0] & 0x7f),
+        }
+    }
+}
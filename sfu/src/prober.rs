@@ -1,34 +1,98 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use tokio::time::sleep;
+use tokio::sync::watch;
+use tracing::Instrument;
 use uuid::Uuid;
 use webrtc::{
-    media::Sample, track::track_local::track_local_static_sample::TrackLocalStaticSample,
+    media::Sample,
+    rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+    rtp_transceiver::rtp_sender::RTCRtpSender,
+    track::track_local::track_local_static_sample::TrackLocalStaticSample,
 };
 
-use crate::error::Error;
+use crate::{
+    error::Error,
+    runtime::{self, sleep_until},
+    stats::{ProbeResult, ProbeState},
+};
 
 pub(crate) struct Prober {
     pub _id: String,
 }
 
 impl Prober {
-    pub(crate) fn new(track: Arc<TrackLocalStaticSample>) -> Self {
+    /// `result` is updated with the estimate which the client reports for the probe track. Headroom is not set here, because it depends on subscribers of the transport.
+    pub(crate) fn new(
+        track: Arc<TrackLocalStaticSample>,
+        rtp_sender: Arc<RTCRtpSender>,
+        result: Arc<Mutex<ProbeResult>>,
+    ) -> Self {
         let id = Uuid::new_v4().to_string();
+        result.lock().unwrap().state = ProbeState::Probing;
+        let (finished_sender, finished_receiver) = watch::channel(false);
 
-        tokio::spawn(async move {
-            let _ = Self::write_rtp(track).await;
-        });
+        {
+            let result = result.clone();
+            runtime::spawn(
+                async move {
+                    let _ = Self::write_rtp(track).await;
+                    let mut result = result.lock().unwrap();
+                    result.state = match result.estimate {
+                        Some(_) => ProbeState::Succeeded,
+                        None => ProbeState::Failed,
+                    };
+                    tracing::debug!("Probing has finished: {:?}", result);
+                    let _ = finished_sender.send(true);
+                }
+                .in_current_span(),
+            );
+        }
+
+        runtime::spawn(
+            async move {
+                Self::read_rtcp(rtp_sender, result, finished_receiver).await;
+            }
+            .in_current_span(),
+        );
 
         Self { _id: id }
     }
 
+    async fn read_rtcp(
+        rtp_sender: Arc<RTCRtpSender>,
+        result: Arc<Mutex<ProbeResult>>,
+        mut finished: watch::Receiver<bool>,
+    ) {
+        loop {
+            tokio::select! {
+                _ = finished.changed() => break,
+                res = rtp_sender.read_rtcp() => {
+                    match res {
+                        Ok((rtcp_packets, _)) => {
+                            for rtcp in rtcp_packets.iter() {
+                                if let Some(remb) = rtcp.as_any().downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                                    result.lock().unwrap().estimate = Some(remb.bitrate);
+                                }
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+        }
+    }
+
     pub(crate) async fn write_rtp(track: Arc<TrackLocalStaticSample>) -> Result<(), Error> {
         tracing::debug!("Starting prober rtp packets");
 
         let black_frame = vec![0u8; 640 * 480 * 3 / 2];
         let black_frame_bytes = bytes::Bytes::from(black_frame);
         let duration = Duration::from_millis(33);
+        // Frames are paced by their send times, so time to write a frame doesn't accumulate as drift.
+        let mut next_send = Instant::now();
         for _ in 0..900 {
             let sample = Sample {
                 data: black_frame_bytes.clone(),
@@ -39,7 +103,8 @@ impl Prober {
                 eprintln!("Error sending black screen frame: {}", err);
                 break;
             }
-            sleep(duration).await;
+            next_send += duration;
+            sleep_until(next_send).await;
         }
 
         tracing::debug!("Finished sending prober rtp packets");
@@ -1,23 +1,40 @@
 use crate::{
-    config::{MediaConfig, WebRTCTransportConfig},
-    data_publisher::DataPublisher,
+    config::{CongestionFeedback, MediaConfig, SdpHints, WebRTCTransportConfig},
+    data_publisher::{DataPublisher, DATA_CAPACITY, DATA_MESSAGE_SIZE},
     error::{Error, PublisherErrorKind, TransportErrorKind},
-    publisher::Publisher,
+    memory::{BufferKind, MemoryBudget},
+    publisher::{Publisher, PublisherContext},
+    quality::{ConnectionQuality, OnQualityChangeFn, QualityMonitor},
     router::RouterEvent,
-    transport::{OnIceCandidateFn, OnTrackFn, PeerConnection, RtcpReceiver, RtcpSender, Transport},
+    runtime::{self, Runtime},
+    stats::{RtcpStats, TransportKind, TransportStats, TransportStatsSource},
+    transport::{
+        add_sdp_hints, analyze_offer, filter_congestion_feedback, filter_local_candidates,
+        sdp_cache_key, NegotiationQueue, NegotiationState, OnIceCandidateFn, OnLocalCandidateFn,
+        OnNegotiationStateChangeFn, OnTrackFn, OnTransportFailedFn, PeerConnection,
+        RemoteCandidates, RemoteOffer, ResumeHint, RtcpReceiver, RtcpSender, SdpCache, Transport,
+        DEFAULT_MAX_MESSAGE_SIZE,
+    },
+    worker::BlockingWorker,
 };
 use derivative::Derivative;
 use enclose::enc;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Weak,
 };
-use tokio::sync::{broadcast, mpsc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
+use tracing::Instrument;
 use uuid::Uuid;
 use webrtc::{
     data_channel::RTCDataChannel,
-    ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+    ice_transport::{
+        ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_server::RTCIceServer,
+    },
     peer_connection::{
+        peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription, signaling_state::RTCSignalingState,
         RTCPeerConnection,
     },
@@ -25,29 +42,62 @@ use webrtc::{
     track::track_remote::TrackRemote,
 };
 
+/// Data channel which is closed without a [`DataPublisher`].
+#[derive(Clone, Debug)]
+struct RefusedData {
+    label: String,
+    kind: PublisherErrorKind,
+}
+
 /// This handle [`webrtc::peer_connection::RTCPeerConnection`] methods for publisher.
 #[derive(Derivative)]
 #[derivative(Clone, Debug)]
 pub struct PublishTransport {
     pub id: String,
     peer_connection: Arc<RTCPeerConnection>,
-    pending_candidates: Arc<Mutex<Vec<RTCIceCandidateInit>>>,
-    published_sender: broadcast::Sender<Arc<Publisher>>,
-    published_receiver: Arc<Mutex<broadcast::Receiver<Arc<Publisher>>>>,
-    data_published_sender: broadcast::Sender<Arc<DataPublisher>>,
-    data_published_receiver: Arc<Mutex<broadcast::Receiver<Arc<DataPublisher>>>>,
+    remote_candidates: RemoteCandidates,
+    // Track IDs and labels of refused publishers are sent as errors.
+    published_sender: broadcast::Sender<Result<Arc<Publisher>, String>>,
+    published_receiver: Arc<Mutex<broadcast::Receiver<Result<Arc<Publisher>, String>>>>,
+    // Labels of refused data channels are sent as errors with the reason.
+    data_published_sender: broadcast::Sender<Result<Arc<DataPublisher>, RefusedData>>,
+    data_published_receiver:
+        Arc<Mutex<broadcast::Receiver<Result<Arc<DataPublisher>, RefusedData>>>>,
     router_event_sender: mpsc::UnboundedSender<RouterEvent>,
     // For RTCP writer
     rtcp_sender_channel: Arc<RtcpSender>,
-    rtcp_receiver_channel: Arc<Mutex<RtcpReceiver>>,
-    stop_sender_channel: Arc<Mutex<mpsc::UnboundedSender<()>>>,
-    stop_receiver_channel: Arc<Mutex<mpsc::UnboundedReceiver<()>>>,
+    rtcp_writer: RtcpWriter,
     // For callback fn
     #[derivative(Debug = "ignore")]
     on_ice_candidate_fn: Arc<Mutex<OnIceCandidateFn>>,
     #[derivative(Debug = "ignore")]
+    on_local_candidate_fn: Arc<Mutex<OnLocalCandidateFn>>,
+    #[derivative(Debug = "ignore")]
     on_track_fn: Arc<Mutex<OnTrackFn>>,
-    signaling_pending: Arc<AtomicBool>,
+    #[derivative(Debug = "ignore")]
+    on_transport_failed_fn: Arc<Mutex<OnTransportFailedFn>>,
+    #[derivative(Debug = "ignore")]
+    on_negotiation_state_change_fn: Arc<Mutex<OnNegotiationStateChangeFn>>,
+    #[derivative(Debug = "ignore")]
+    on_quality_change_fn: Arc<Mutex<OnQualityChangeFn>>,
+    quality: QualityMonitor,
+    negotiation: NegotiationQueue,
+    max_message_size: Arc<AtomicUsize>,
+    congestion_feedback: CongestionFeedback,
+    sdp_hints: Option<SdpHints>,
+    span: tracing::Span,
+    rtcp_stats: Arc<std::sync::Mutex<RtcpStats>>,
+    forwarding_runtime: Option<Arc<dyn Runtime>>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    // Weak references, so the transport doesn't keep removed publishers alive.
+    publishers: Arc<std::sync::Mutex<Vec<Weak<Publisher>>>>,
+    ice_servers: Vec<RTCIceServer>,
+    resume_hint: Arc<std::sync::Mutex<Option<ResumeHint>>>,
+    // Bitrates in bps per mid which the client announces in the offer.
+    bandwidth_hints: Arc<std::sync::Mutex<Vec<(String, u32)>>>,
+    // SDP is parsed on the blocking worker of the router, so negotiation bursts don't delay RTP forwarding.
+    blocking_worker: BlockingWorker,
+    offer_cache: SdpCache<RemoteOffer>,
 }
 
 impl PublishTransport {
@@ -55,37 +105,74 @@ impl PublishTransport {
         router_event_sender: mpsc::UnboundedSender<RouterEvent>,
         media_config: MediaConfig,
         transport_config: WebRTCTransportConfig,
+        blocking_worker: BlockingWorker,
     ) -> Self {
         let id = Uuid::new_v4().to_string();
         let (s, r) = mpsc::unbounded_channel();
-        let (stop_sender, stop_receiver) = mpsc::unbounded_channel();
         let (published_sender, published_receiver) = broadcast::channel(1024);
         let (data_published_sender, data_published_receiver) = broadcast::channel(1024);
+        let span = transport_config.log_context.span("PublishTransport", &id);
+        let congestion_feedback = media_config.congestion_feedback;
+        let forwarding_runtime = media_config.forwarding_runtime.clone();
+        let memory_budget = media_config.memory_budget.clone();
+        let sdp_hints = transport_config.sdp_hints.clone();
+        let ice_servers = transport_config.configuration.ice_servers.clone();
 
         let peer_connection = Self::generate_peer_connection(media_config, transport_config)
             .await
             .unwrap();
+        let peer_connection = Arc::new(peer_connection);
+
+        let rtcp_writer = RtcpWriter {
+            peer_connection: Arc::downgrade(&peer_connection),
+            rtcp_receiver: Arc::new(Mutex::new(r)),
+            closed: Arc::new(watch::channel(false).0),
+            started: Arc::new(AtomicBool::new(false)),
+            health: Arc::new(std::sync::Mutex::new(RtcpWriterHealth::default())),
+            span: span.clone(),
+        };
 
         let mut transport = Self {
             id,
-            peer_connection: Arc::new(peer_connection),
+            peer_connection,
             router_event_sender,
             published_sender,
             published_receiver: Arc::new(Mutex::new(published_receiver)),
             data_published_sender,
             data_published_receiver: Arc::new(Mutex::new(data_published_receiver)),
-            pending_candidates: Arc::new(Mutex::new(Vec::new())),
+            remote_candidates: RemoteCandidates::default(),
             rtcp_sender_channel: Arc::new(s),
-            rtcp_receiver_channel: Arc::new(Mutex::new(r)),
-            stop_sender_channel: Arc::new(Mutex::new(stop_sender)),
-            stop_receiver_channel: Arc::new(Mutex::new(stop_receiver)),
+            rtcp_writer,
             on_ice_candidate_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            on_local_candidate_fn: Arc::new(Mutex::new(Box::new(Some))),
             on_track_fn: Arc::new(Mutex::new(Box::new(|_, _, _| {}))),
-            signaling_pending: Arc::new(AtomicBool::new(false)),
+            on_transport_failed_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            on_negotiation_state_change_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            on_quality_change_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            quality: QualityMonitor::new(),
+            negotiation: NegotiationQueue::default(),
+            max_message_size: Arc::new(AtomicUsize::new(DEFAULT_MAX_MESSAGE_SIZE)),
+            congestion_feedback,
+            sdp_hints,
+            span,
+            rtcp_stats: Arc::new(std::sync::Mutex::new(RtcpStats::default())),
+            forwarding_runtime,
+            memory_budget,
+            publishers: Arc::new(std::sync::Mutex::new(Vec::new())),
+            ice_servers,
+            resume_hint: Arc::new(std::sync::Mutex::new(None)),
+            bandwidth_hints: Arc::new(std::sync::Mutex::new(Vec::new())),
+            blocking_worker,
+            offer_cache: SdpCache::new(),
         };
 
-        transport.rtcp_writer_loop();
+        transport.rtcp_writer.start();
         transport.ice_state_hooks().await;
+        transport.quality.start(
+            Arc::downgrade(&transport.peer_connection),
+            TransportKind::Publish,
+            transport.on_quality_change_fn.clone(),
+        );
 
         tracing::debug!("PublishTransport {} is created", transport.id);
 
@@ -93,6 +180,7 @@ impl PublishTransport {
     }
 
     /// This sets the offer to the [`webrtc::peer_connection::RTCPeerConnection`] and creates answer sdp for it.
+    /// Plan-B offers which have multiple tracks in one media section are rejected with [`crate::error::TransportErrorKind::PlanBNotSupportedError`].
     pub async fn get_answer(
         &self,
         sdp: RTCSessionDescription,
@@ -101,11 +189,19 @@ impl PublishTransport {
         Ok(answer)
     }
 
+    /// The track is refused with [`crate::error::PublisherErrorKind::MemoryBudgetExceededError`] when the [`crate::config::MediaConfig::memory_budget`] is exhausted.
     pub async fn publish(&self, track_id: String) -> Result<Arc<Publisher>, Error> {
         let receiver = self.published_receiver.clone();
-        while let Ok(publisher) = receiver.lock().await.recv().await {
-            if publisher.id == track_id {
-                return Ok(publisher);
+        while let Ok(published) = receiver.lock().await.recv().await {
+            match published {
+                Ok(publisher) if publisher.id == track_id => return Ok(publisher),
+                Err(id) if id == track_id => {
+                    return Err(Error::new_publisher(
+                        format!("Track {} is refused by the memory budget", id),
+                        PublisherErrorKind::MemoryBudgetExceededError,
+                    ))
+                }
+                _ => {}
             }
         }
         Err(Error::new_publisher(
@@ -114,11 +210,20 @@ impl PublishTransport {
         ))
     }
 
+    /// This returns the [`DataPublisher`] of the label when the data channel is opened.
+    /// It fails with [`PublisherErrorKind::MemoryBudgetExceededError`] when the memory budget is exhausted, and with [`PublisherErrorKind::DataLabelRejectedError`] when the label is rejected by [`crate::router::DataLabelPolicy::Reject`].
     pub async fn data_publish(&self, label: String) -> Result<Arc<DataPublisher>, Error> {
         let receiver = self.data_published_receiver.clone();
-        while let Ok(data_publisher) = receiver.lock().await.recv().await {
-            if data_publisher.label == label {
-                return Ok(data_publisher);
+        while let Ok(published) = receiver.lock().await.recv().await {
+            match published {
+                Ok(data_publisher) if data_publisher.label == label => return Ok(data_publisher),
+                Err(refused) if refused.label == label => {
+                    return Err(Error::new_publisher(
+                        format!("DataChannel {} is refused: {}", refused.label, refused.kind),
+                        refused.kind,
+                    ))
+                }
+                _ => {}
             }
         }
         Err(Error::new_publisher(
@@ -140,25 +245,49 @@ impl PublishTransport {
                 TransportErrorKind::SignalingStateInvalidError,
             ));
         }
-        self.signaling_pending.store(true, Ordering::Relaxed);
+        let key = sdp_cache_key(&offer.sdp, ());
+        let remote = offer.clone();
+        let remote_offer = self
+            .offer_cache
+            .get_or_run(&self.blocking_worker, key, move || analyze_offer(&remote))
+            .await?;
+        self.negotiation.start();
+        let res = self.answer_offer(offer, remote_offer).await;
+        self.finish_on_error(res)
+    }
+
+    async fn answer_offer(
+        &self,
+        offer: RTCSessionDescription,
+        remote_offer: RemoteOffer,
+    ) -> Result<RTCSessionDescription, Error> {
+        let RemoteOffer {
+            max_message_size,
+            stopped_mids,
+            bandwidths,
+        } = remote_offer;
         tracing::debug!("publisher set remote description");
-        self.peer_connection.set_remote_description(offer).await?;
-        let pendings = self.pending_candidates.lock().await;
-        for candidate in pendings.iter() {
-            tracing::debug!("Adding pending ICE candidate: {:#?}", candidate);
-            if let Err(err) = self
-                .peer_connection
-                .add_ice_candidate(candidate.clone())
-                .await
-            {
-                tracing::error!("failed to add_ice_candidate: {}", err);
-            }
-        }
+        self.remote_candidates
+            .set_remote_description(&self.peer_connection, offer)
+            .await?;
+        self.max_message_size
+            .store(max_message_size, Ordering::Relaxed);
+        self.close_stopped_publishers(&stopped_mids).await;
+        self.update_bandwidth_hints(bandwidths);
 
         let answer = self.peer_connection.create_answer(None).await?;
         self.peer_connection.set_local_description(answer).await?;
         match self.peer_connection.local_description().await {
-            Some(answer) => Ok(answer),
+            Some(answer) => {
+                let answer =
+                    filter_local_candidates(answer, &*self.on_local_candidate_fn.lock().await);
+                let feedback = self.congestion_feedback;
+                let answer = self
+                    .blocking_worker
+                    .run(move || filter_congestion_feedback(answer, feedback))
+                    .await?;
+                Ok(add_sdp_hints(answer, self.sdp_hints.as_ref()))
+            }
             None => Err(Error::new_transport(
                 "Failed to set local description".to_string(),
                 TransportErrorKind::LocalDescriptionError,
@@ -166,51 +295,92 @@ impl PublishTransport {
         }
     }
 
-    fn rtcp_writer_loop(&self) {
-        let rtcp_receiver = self.rtcp_receiver_channel.clone();
-        let stop_receiver = self.stop_receiver_channel.clone();
-        let pc = self.peer_connection.clone();
-        tokio::spawn(async move {
-            tracing::info!("RTCP writer loop");
-            loop {
-                let mut rtcp_receiver = rtcp_receiver.lock().await;
-                let mut stop_receiver = stop_receiver.lock().await;
-                tokio::select! {
-                    data = rtcp_receiver.recv() => {
-                        if let Some(data) = data {
-                            if let Err(err) = pc.write_rtcp(&[data]).await {
-                                tracing::error!("Error writing RTCP: {}", err);
-                            }
-                        }
-                    }
-                    _data = stop_receiver.recv() => {
-                        tracing::info!("RTCP writer loop stopped");
-                        return;
-                    }
-                };
+    /// Finish the negotiation which has been started when the answer can't be created, otherwise following negotiations wait forever.
+    fn finish_on_error<T>(&self, res: Result<T, Error>) -> Result<T, Error> {
+        if res.is_err() {
+            self.negotiation.finish();
+        }
+        res
+    }
+
+    /// Apply bitrates which the client announces with `b=TIAS` or `b=AS` to publishers. Publishers which are published later get them in `on_track`.
+    fn update_bandwidth_hints(&self, bandwidth_hints: Vec<(String, u32)>) {
+        for publisher in self.publishers.lock().unwrap().iter() {
+            if let Some(publisher) = publisher.upgrade() {
+                publisher
+                    .set_bandwidth_hint(find_bandwidth_hint(&bandwidth_hints, publisher.mid()));
             }
-        });
+        }
+        *self.bandwidth_hints.lock().unwrap() = bandwidth_hints;
+    }
+
+    /// Close publishers whose senders have been removed by the client. Subscribers are notified through [`crate::router::Router::watch_publishers`] when the RTP event loop of the publisher finishes.
+    async fn close_stopped_publishers(&self, stopped_mids: &[String]) {
+        if stopped_mids.is_empty() {
+            return;
+        }
+        let stopped: Vec<Arc<Publisher>> = {
+            let mut publishers = self.publishers.lock().unwrap();
+            let mut stopped = Vec::new();
+            publishers.retain(|p| match p.upgrade() {
+                Some(publisher) => match publisher.mid() {
+                    Some(mid) if stopped_mids.contains(&mid) => {
+                        stopped.push(publisher);
+                        false
+                    }
+                    _ => true,
+                },
+                None => false,
+            });
+            stopped
+        };
+        for publisher in stopped.iter() {
+            tracing::info!("Track removed by the client: id={}", publisher.id);
+            publisher.close().await;
+        }
+    }
+
+    /// This returns the health of the RTCP writer loop, which writes RTCP packets from subscribers to the publisher.
+    pub fn rtcp_writer_health(&self) -> RtcpWriterHealth {
+        self.rtcp_writer.health.lock().unwrap().clone()
+    }
+
+    /// Start the RTCP writer loop again if it has stopped, for example after an ICE restart. The loop is also restarted automatically when the peer connection is connected again.
+    /// This returns true if the loop is started.
+    pub fn restart_rtcp_writer(&self) -> bool {
+        self.rtcp_writer.start()
     }
 
     // ICE events
     async fn ice_state_hooks(&mut self) {
         let peer = self.peer_connection.clone();
         let on_ice_candidate = Arc::clone(&self.on_ice_candidate_fn);
+        let on_local_candidate = Arc::clone(&self.on_local_candidate_fn);
+        let span = self.span.clone();
 
         // This callback is called after initializing PeerConnection with ICE servers.
-        peer.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
-            Box::pin({
-                let func = on_ice_candidate.clone();
-                async move {
-                    let locked = func.lock().await;
-                    if let Some(candidate) = candidate {
+        peer.on_ice_candidate(Box::new(
+            enc!((span) move |candidate: Option<RTCIceCandidate>| {
+                Box::pin({
+                    let func = on_ice_candidate.clone();
+                    let filter = on_local_candidate.clone();
+                    async move {
+                        let Some(candidate) = candidate else {
+                            return;
+                        };
+                        let Some(candidate) = (filter.lock().await)(candidate) else {
+                            tracing::debug!("local ICE candidate is dropped by the filter");
+                            return;
+                        };
+                        let locked = func.lock().await;
                         tracing::info!("on ice candidate: {}", candidate);
                         // Call on_ice_candidate_fn as callback.
                         (locked)(candidate);
                     }
-                }
-            })
-        }));
+                    .instrument(span.clone())
+                })
+            }),
+        ));
 
         peer.on_negotiation_needed(Box::new(move || {
             Box::pin(async move {
@@ -222,23 +392,48 @@ impl PublishTransport {
         let router_sender = self.router_event_sender.clone();
         let rtcp_sender = self.rtcp_sender_channel.clone();
         let published_sender = self.published_sender.clone();
-        peer.on_track(Box::new(enc!( (on_track, router_sender, rtcp_sender, published_sender)
+        let publisher_context = PublisherContext {
+            rtcp_stats: self.rtcp_stats.clone(),
+            forwarding_runtime: self.forwarding_runtime.clone(),
+            memory_budget: self.memory_budget.clone(),
+        };
+        let publishers = self.publishers.clone();
+        let bandwidth_hints = self.bandwidth_hints.clone();
+        peer.on_track(Box::new(enc!( (on_track, router_sender, rtcp_sender, published_sender, publisher_context, publishers, bandwidth_hints, span)
             move |track: Arc<TrackRemote>,
                   receiver: Arc<RTCRtpReceiver>,
                   transceiver: Arc<RTCRtpTransceiver>| {
-                Box::pin(enc!( (on_track, router_sender, rtcp_sender, published_sender) async move {
+                // Publisher is created in the span, so its loops inherit the log context of the transport.
+                Box::pin(enc!( (on_track, router_sender, rtcp_sender, published_sender, publisher_context, publishers, bandwidth_hints) async move {
                     let locked = on_track.lock().await;
                     let id = track.id();
                     let ssrc = track.ssrc();
                     tracing::info!("Track published: id={}, ssrc={}", id, ssrc);
 
-                    let publisher = Arc::new(Publisher::new(track.clone(), receiver.clone(), transceiver.clone(), rtcp_sender, router_sender.clone()));
+                    let publisher = match Publisher::new(track.clone(), receiver.clone(), transceiver.clone(), rtcp_sender, router_sender.clone(), publisher_context) {
+                        Ok(publisher) => Arc::new(publisher),
+                        Err(err) => {
+                            tracing::warn!("Track is refused: id={}, ssrc={}, {}", id, ssrc, err);
+                            // Stop reading RTP, so packets of the refused track are not buffered.
+                            if let Err(err) = receiver.stop().await {
+                                tracing::error!("Failed to stop the receiver of refused track: {}", err);
+                            }
+                            let _ = published_sender.send(Err(id));
+                            return;
+                        }
+                    };
+                    publisher.set_bandwidth_hint(find_bandwidth_hint(&bandwidth_hints.lock().unwrap(), publisher.mid()));
 
-                    published_sender.send(publisher.clone()).expect("could not send published track id to publisher");
+                    {
+                        let mut publishers = publishers.lock().unwrap();
+                        publishers.retain(|p| p.strong_count() > 0);
+                        publishers.push(Arc::downgrade(&publisher));
+                    }
+                    published_sender.send(Ok(publisher.clone())).expect("could not send published track id to publisher");
                     let _ = router_sender.send(RouterEvent::TrackPublished(publisher));
 
                     (locked)(track, receiver, transceiver);
-                }))
+                }).instrument(span.clone()))
             }
         )));
 
@@ -250,32 +445,131 @@ impl PublishTransport {
 
         let router_sender = self.router_event_sender.clone();
         let data_published_sender = self.data_published_sender.clone();
+        let memory_budget = self.memory_budget.clone();
         peer.on_data_channel(Box::new(
-            enc!((router_sender, data_published_sender) move |dc: Arc<RTCDataChannel>| {
-                Box::pin(enc!((router_sender, data_published_sender) async move {
+            enc!((router_sender, data_published_sender, memory_budget, span) move |dc: Arc<RTCDataChannel>| {
+                Box::pin(enc!((router_sender, data_published_sender, memory_budget, span) async move {
                     let channel = dc.clone();
-                    dc.on_open(Box::new(enc!((channel, router_sender, data_published_sender) move || {
+                    dc.on_open(Box::new(enc!((channel, router_sender, data_published_sender, memory_budget, span) move || {
                         let id = channel.id().to_string();
+                        let _enter = span.enter();
                         tracing::info!("DataChannel is opened: id={}, label={}, readyState={}", id, channel.label(), channel.ready_state());
                         Box::pin(async move {
-                            let data_publisher = Arc::new(DataPublisher::new(channel, router_sender.clone()));
-                            data_published_sender.send(data_publisher.clone()).expect("could not send data published to publisher");
-                            let _ = router_sender.send(RouterEvent::DataPublished(data_publisher));
-                        })
+                            let reservation = match memory_budget.map(|budget| budget.reserve(BufferKind::Data, DATA_MESSAGE_SIZE, DATA_CAPACITY, DATA_CAPACITY)) {
+                                Some(None) => {
+                                    tracing::warn!("DataChannel is refused: id={}, label={}", id, channel.label());
+                                    if let Err(err) = channel.close().await {
+                                        tracing::error!("Failed to close refused DataChannel: {}", err);
+                                    }
+                                    let _ = data_published_sender.send(Err(RefusedData {
+                                        label: channel.label().to_string(),
+                                        kind: PublisherErrorKind::MemoryBudgetExceededError,
+                                    }));
+                                    return;
+                                }
+                                Some(reservation) => reservation,
+                                None => None,
+                            };
+                            let label = channel.label().to_string();
+                            let data_publisher = Arc::new(DataPublisher::new(channel, router_sender.clone(), reservation));
+                            let (reply_sender, reply_receiver) = oneshot::channel();
+                            let _ = router_sender.send(RouterEvent::DataPublished(data_publisher.clone(), reply_sender));
+                            let published = match reply_receiver.await {
+                                Ok(true) => Ok(data_publisher),
+                                Ok(false) => Err(RefusedData { label, kind: PublisherErrorKind::DataLabelRejectedError }),
+                                // The router has been closed.
+                                Err(_) => Err(RefusedData { label, kind: PublisherErrorKind::DataChannelNotPublishedError }),
+                            };
+                            let _ = data_published_sender.send(published);
+                        }.instrument(span.clone()))
                     })));
                 }))
             }),
         ));
 
-        let signaling_pending = self.signaling_pending.clone();
-        peer.on_signaling_state_change(Box::new(enc!((signaling_pending) move |state| {
+        let rtcp_writer = self.rtcp_writer.clone();
+        let transport_id = self.id.clone();
+        let publishers = self.publishers.clone();
+        let ice_servers = self.ice_servers.clone();
+        let resume_hint = self.resume_hint.clone();
+        let on_transport_failed = Arc::clone(&self.on_transport_failed_fn);
+        peer.on_peer_connection_state_change(Box::new(move |state| {
+            tracing::debug!("Peer connection state changed: {}", state);
+            match state {
+                RTCPeerConnectionState::Connected => {
+                    rtcp_writer.start();
+                }
+                RTCPeerConnectionState::Failed => {
+                    let publications = publishers
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter_map(|p| p.upgrade().map(|p| p.id.clone()))
+                        .collect();
+                    let hint = ResumeHint::new(
+                        transport_id.clone(),
+                        vec![],
+                        publications,
+                        ice_servers.clone(),
+                    );
+                    *resume_hint.lock().unwrap() = Some(hint.clone());
+                    let on_transport_failed = on_transport_failed.clone();
+                    return Box::pin(async move {
+                        let locked = on_transport_failed.lock().await;
+                        (locked)(hint);
+                    });
+                }
+                RTCPeerConnectionState::Closed => rtcp_writer.stop(),
+                _ => {}
+            }
+            Box::pin(async {})
+        }));
+
+        let negotiation = self.negotiation.clone();
+        let on_negotiation_state_change = Arc::clone(&self.on_negotiation_state_change_fn);
+        peer.on_signaling_state_change(Box::new(move |state| {
             tracing::debug!("Signaling state changed: {}", state);
             if state == RTCSignalingState::Stable {
-                signaling_pending.store(false, Ordering::Relaxed);
+                negotiation.finish();
             }
 
-            Box::pin(async {})
-        })));
+            let negotiation_state = negotiation.state(state);
+            Box::pin(enc!((on_negotiation_state_change) async move {
+                let locked = on_negotiation_state_change.lock().await;
+                (locked)(negotiation_state);
+            }))
+        }));
+    }
+
+    /// This returns the span which has the [`crate::config::LogContext`] of this transport. Applications can use it to emit their own events with the same context.
+    pub fn span(&self) -> tracing::Span {
+        self.span.clone()
+    }
+
+    /// This returns the SCTP max message size which is negotiated with the client. `0` means there is no limit.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.load(Ordering::Relaxed)
+    }
+
+    /// This returns counters of RTCP packets which are received from the client for all publishers of this transport.
+    pub fn rtcp_stats(&self) -> RtcpStats {
+        *self.rtcp_stats.lock().unwrap()
+    }
+
+    /// Collect stats of this transport for [`crate::router::Router::stats_snapshot`]. The source doesn't keep the transport alive.
+    pub(crate) fn stats_source(&self) -> TransportStatsSource {
+        let id = self.id.clone();
+        let rtcp_stats = Arc::downgrade(&self.rtcp_stats);
+        Box::new(move || {
+            let rtcp = *rtcp_stats.upgrade()?.lock().unwrap();
+            Some(TransportStats {
+                id: id.clone(),
+                kind: TransportKind::Publish,
+                rtcp,
+                probe: None,
+                subscribers: vec![],
+            })
+        })
     }
 
     // Hooks
@@ -285,37 +579,204 @@ impl PublishTransport {
         *callback = f;
     }
 
+    /// Set a filter which drops or rewrites local ICE candidates before they are passed to the `on_ice_candidate` callback. For example, exclude internal interfaces or rewrite addresses to anycast IPs.
+    pub async fn on_local_candidate(&self, f: OnLocalCandidateFn) {
+        let mut filter = self.on_local_candidate_fn.lock().await;
+        *filter = f;
+    }
+
     /// Set callback function when the [`webrtc::peer_connection::RTCPeerConnection`] receives `on_track` events.
     pub async fn on_track(&mut self, f: OnTrackFn) {
         let mut callback = self.on_track_fn.lock().await;
         *callback = f;
     }
 
+    /// Set callback function when the [`webrtc::peer_connection::RTCPeerConnection`] has failed. The [`ResumeHint`] can be handed to the reconnecting client.
+    pub async fn on_transport_failed(&self, f: OnTransportFailedFn) {
+        let mut callback = self.on_transport_failed_fn.lock().await;
+        *callback = f;
+    }
+
+    /// Set callback function when the signaling state of the [`webrtc::peer_connection::RTCPeerConnection`] changes.
+    pub async fn on_negotiation_state_change(&self, f: OnNegotiationStateChangeFn) {
+        let mut callback = self.on_negotiation_state_change_fn.lock().await;
+        *callback = f;
+    }
+
+    /// This returns the current negotiation state, for example to find a negotiation which is stuck waiting for the client.
+    pub fn negotiation_state(&self) -> NegotiationState {
+        self.negotiation
+            .state(self.peer_connection.signaling_state())
+    }
+
+    /// Set callback function when the score of [`ConnectionQuality`] changes.
+    pub async fn on_quality_change(&self, f: OnQualityChangeFn) {
+        let mut callback = self.on_quality_change_fn.lock().await;
+        *callback = f;
+    }
+
+    /// This returns the latest connection quality. It is `None` until stats are collected twice after the transport is connected.
+    pub fn quality(&self) -> Option<ConnectionQuality> {
+        self.quality.quality()
+    }
+
+    /// This returns the [`ResumeHint`] which is generated when the transport has failed.
+    pub fn resume_hint(&self) -> Option<ResumeHint> {
+        self.resume_hint.lock().unwrap().clone()
+    }
+
     pub async fn close(&self) -> Result<(), Error> {
-        if let Err(err) = self.stop_sender_channel.lock().await.send(()) {
-            tracing::error!("failed to stop rtcp writer loop: {}", err);
-        }
+        self.rtcp_writer.stop();
         self.peer_connection.close().await?;
         Ok(())
     }
 }
 
+/// Time to wait for more RTCP packets before writing a compound packet. It is short enough for keyframe requests.
+const RTCP_BATCH_DELAY: Duration = Duration::from_millis(5);
+/// Compound packets are kept in the MTU with the SRTCP overhead.
+const MAX_COMPOUND_SIZE: usize = 1200;
+
+type RtcpPacket = Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>;
+
+/// Health of the RTCP writer loop of [`PublishTransport`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RtcpWriterHealth {
+    pub running: bool,
+    /// Number of times the loop has been started again after it stopped.
+    pub restarts: u64,
+    pub written_packets: u64,
+    /// Number of compound packets which are written. Feedback which is queued at the same time is batched in one compound packet.
+    pub compound_packets: u64,
+    pub write_errors: u64,
+}
+
+/// RTCP writer loop of [`PublishTransport`]. Only one loop runs at a time, and it can be started again after it stops.
+#[derive(Clone, Debug)]
+struct RtcpWriter {
+    peer_connection: Weak<RTCPeerConnection>,
+    rtcp_receiver: Arc<Mutex<RtcpReceiver>>,
+    closed: Arc<watch::Sender<bool>>,
+    started: Arc<AtomicBool>,
+    health: Arc<std::sync::Mutex<RtcpWriterHealth>>,
+    span: tracing::Span,
+}
+
+impl RtcpWriter {
+    /// This starts the loop unless it is running or the transport has been closed. This returns true if the loop is started.
+    fn start(&self) -> bool {
+        if *self.closed.borrow() {
+            return false;
+        }
+        let Some(pc) = self.peer_connection.upgrade() else {
+            return false;
+        };
+        {
+            let mut health = self.health.lock().unwrap();
+            if health.running {
+                return false;
+            }
+            health.running = true;
+            if self.started.swap(true, Ordering::Relaxed) {
+                health.restarts += 1;
+            }
+        }
+
+        let rtcp_receiver = self.rtcp_receiver.clone();
+        let mut closed = self.closed.subscribe();
+        let health = self.health.clone();
+        runtime::spawn(
+            async move {
+                tracing::info!("RTCP writer loop");
+                let mut rtcp_receiver = rtcp_receiver.lock().await;
+                // Packet which didn't fit in the previous compound packet.
+                let mut pending = None;
+                loop {
+                    let first = match pending.take() {
+                        Some(packet) => packet,
+                        None => tokio::select! {
+                            res = closed.changed() => {
+                                if res.is_err() || *closed.borrow() {
+                                    break;
+                                }
+                                continue;
+                            }
+                            data = rtcp_receiver.recv() => {
+                                let Some(data) = data else {
+                                    break;
+                                };
+                                data
+                            }
+                        },
+                    };
+                    let (batch, next) = collect_rtcp_batch(first, &mut rtcp_receiver).await;
+                    pending = next;
+                    match pc.write_rtcp(&batch).await {
+                        Ok(_) => {
+                            let mut health = health.lock().unwrap();
+                            health.written_packets += batch.len() as u64;
+                            health.compound_packets += 1;
+                        }
+                        Err(webrtc::Error::ErrConnectionClosed) => {
+                            tracing::warn!(
+                                "RTCP writer loop stops, because the connection is closed"
+                            );
+                            break;
+                        }
+                        Err(err) => {
+                            health.lock().unwrap().write_errors += 1;
+                            tracing::error!("Error writing RTCP: {}", err);
+                        }
+                    }
+                }
+                health.lock().unwrap().running = false;
+                tracing::info!("RTCP writer loop stopped");
+            }
+            .instrument(self.span.clone()),
+        );
+        true
+    }
+
+    /// Stop the loop. It is never started again.
+    fn stop(&self) {
+        self.closed.send_replace(true);
+    }
+}
+
+/// Collect RTCP packets which are queued within [`RTCP_BATCH_DELAY`], so they are written in one compound packet. Feedback from big rooms, like NACKs and PLIs for many subscribers, costs one SRTCP header and authentication tag instead of one for each packet.
+/// This returns the packet which doesn't fit in the compound packet too.
+async fn collect_rtcp_batch(
+    first: RtcpPacket,
+    receiver: &mut RtcpReceiver,
+) -> (Vec<RtcpPacket>, Option<RtcpPacket>) {
+    let mut size = first.raw_size();
+    let mut batch = vec![first];
+    let delay = runtime::sleep(RTCP_BATCH_DELAY);
+    tokio::pin!(delay);
+    loop {
+        let packet = tokio::select! {
+            _ = &mut delay => break,
+            packet = receiver.recv() => match packet {
+                Some(packet) => packet,
+                None => break,
+            },
+        };
+        if size + packet.raw_size() > MAX_COMPOUND_SIZE {
+            return (batch, Some(packet));
+        }
+        size += packet.raw_size();
+        batch.push(packet);
+    }
+    (batch, None)
+}
+
 impl PeerConnection for PublishTransport {}
 
 impl Transport for PublishTransport {
     async fn add_ice_candidate(&self, candidate: RTCIceCandidateInit) -> Result<(), Error> {
-        if let Some(_rd) = self.peer_connection.remote_description().await {
-            tracing::debug!("Adding ICE candidate for {:#?}", candidate);
-            let _ = self
-                .peer_connection
-                .add_ice_candidate(candidate.clone())
-                .await?;
-        } else {
-            tracing::debug!("Pending ICE candidate for {:#?}", candidate);
-            self.pending_candidates.lock().await.push(candidate.clone());
-        }
-
-        Ok(())
+        self.remote_candidates
+            .add(&self.peer_connection, candidate)
+            .await
     }
 }
 
@@ -324,3 +785,117 @@ impl Drop for PublishTransport {
         tracing::debug!("PublishTransport {} is dropped", self.id);
     }
 }
+
+fn find_bandwidth_hint(bandwidth_hints: &[(String, u32)], mid: Option<String>) -> Option<u32> {
+    let mid = mid?;
+    bandwidth_hints
+        .iter()
+        .find(|(m, _)| *m == mid)
+        .map(|(_, bitrate)| *bitrate)
+}
+
+#[cfg(test)]
+mod test {
+    use webrtc::rtp_transceiver::{
+        rtp_codec::RTPCodecType, rtp_transceiver_direction::RTCRtpTransceiverDirection,
+        RTCRtpTransceiverInit,
+    };
+
+    use super::*;
+    use crate::{
+        router::Router,
+        transport::{client_candidate, client_local_description, client_peer_connection},
+    };
+
+    async fn publish_transport() -> (Arc<Mutex<Router>>, PublishTransport) {
+        let router = Router::new(MediaConfig::default());
+        let transport = router
+            .lock()
+            .await
+            .create_publish_transport(WebRTCTransportConfig::default())
+            .await;
+        (router, transport)
+    }
+
+    async fn client_offer(client: &RTCPeerConnection) -> RTCSessionDescription {
+        client
+            .add_transceiver_from_kind(
+                RTPCodecType::Audio,
+                Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Sendonly,
+                    send_encodings: vec![],
+                }),
+            )
+            .await
+            .unwrap();
+        let offer = client.create_offer(None).await.unwrap();
+        client_local_description(client, offer).await
+    }
+
+    #[tokio::test]
+    async fn test_candidates_before_offer() {
+        let (_router, transport) = publish_transport().await;
+        let client = client_peer_connection().await;
+
+        let offer = client_offer(&client).await;
+        transport
+            .add_ice_candidate(client_candidate())
+            .await
+            .unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 1);
+
+        transport.get_answer(offer).await.unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_candidates_during_get_answer() {
+        let (_router, transport) = publish_transport().await;
+        let client = client_peer_connection().await;
+
+        let offer = client_offer(&client).await;
+        let (answered, added) = tokio::join!(
+            transport.get_answer(offer),
+            transport.add_ice_candidate(client_candidate())
+        );
+        answered.unwrap();
+        added.unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_finish_negotiation_on_invalid_offer() {
+        let (_router, transport) = publish_transport().await;
+        let client = client_peer_connection().await;
+
+        let offer: RTCSessionDescription =
+            serde_json::from_value(serde_json::json!({ "type": "offer", "sdp": "invalid" }))
+                .unwrap();
+        assert!(transport.get_answer(offer).await.is_err());
+        assert!(!transport.negotiation_state().pending);
+
+        let offer = client_offer(&client).await;
+        transport.get_answer(offer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_candidates_during_renegotiation() {
+        let (_router, transport) = publish_transport().await;
+        let client = client_peer_connection().await;
+
+        let offer = client_offer(&client).await;
+        let answer = transport.get_answer(offer).await.unwrap();
+        client.set_remote_description(answer).await.unwrap();
+
+        // The client publishes another track. The remote description of the previous negotiation is used.
+        let offer = client_offer(&client).await;
+        transport
+            .add_ice_candidate(client_candidate())
+            .await
+            .unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 0);
+        let answer = transport.get_answer(offer).await.unwrap();
+        client.set_remote_description(answer).await.unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 0);
+    }
+}
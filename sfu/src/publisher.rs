@@ -1,28 +1,243 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::Instant;
+use std::{collections::HashSet, fmt};
 
+use bytes::Bytes;
 use enclose::enc;
-use tokio::sync::{broadcast, mpsc, Mutex};
-use tokio::time::sleep;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tracing::Instrument;
 use webrtc::rtp;
 use webrtc::{
-    rtp_transceiver::{rtp_receiver::RTCRtpReceiver, RTCRtpTransceiver},
+    rtcp::payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+    },
+    rtp_transceiver::{
+        rtp_codec::RTCRtpCodecCapability, rtp_receiver::RTCRtpReceiver,
+        RTCRtpHeaderExtensionParameters, RTCRtpTransceiver,
+    },
+    sdp::extmap,
     track::track_remote::TrackRemote,
 };
 
+use crate::error::{Error, PublisherErrorKind};
+use crate::keyframe::KeyframeRequester;
+use crate::media_source::MediaSource;
+use crate::memory::{BufferKind, BufferPriority, MemoryBudget, Reservation};
+use crate::packet_channel::{self, PacketChannel, PacketSender};
+use crate::packet_metadata::{ExtensionIds, PacketMetadata};
 use crate::router::RouterEvent;
+use crate::runtime::{self, Runtime};
+use crate::stats::{ClockDrift, ClockDriftEstimator, RtcpCounter, RtcpStats};
 use crate::transport;
 
 #[derive(Clone, Debug)]
 pub struct Publisher {
     /// The ID is the same as published track_id.
     pub id: String,
-    pub track: Arc<TrackRemote>,
-    _rtp_receiver: Arc<RTCRtpReceiver>,
-    _rtp_transceiver: Arc<RTCRtpTransceiver>,
+    input: PublisherInput,
+    ssrc: u32,
+    stream_id: String,
+    codec: RTCRtpCodecCapability,
     pub(crate) rtcp_sender: Arc<transport::RtcpSender>,
     closed_sender: Arc<mpsc::UnboundedSender<bool>>,
-    pub(crate) rtp_packet_sender: broadcast::Sender<rtp::packet::Packet>,
+    pub(crate) packet_channel: PacketChannel,
+    pub(crate) forwarding_policy: Arc<Mutex<ForwardingPolicy>>,
+    pub(crate) forwarding_policy_changed: watch::Sender<()>,
+    metadata_sender: broadcast::Sender<PacketMetadata>,
+    keyframe_requester: KeyframeRequester,
+    rtcp_counter: RtcpCounter,
+    clock_drift: Arc<std::sync::Mutex<ClockDriftEstimator>>,
+    // Bitrate in bps which is announced in the offer of the publishing client. 0 means it is not announced.
+    bandwidth_hint: Arc<AtomicU32>,
+    role: Arc<std::sync::Mutex<Option<String>>>,
+    memory_reservation: Option<Arc<Reservation>>,
+}
+
+/// Resources of the router and the transport which are shared with [`Publisher`]s.
+#[derive(Clone, Debug)]
+pub(crate) struct PublisherContext {
+    pub(crate) rtcp_stats: Arc<std::sync::Mutex<RtcpStats>>,
+    pub(crate) forwarding_runtime: Option<Arc<dyn Runtime>>,
+    pub(crate) memory_budget: Option<Arc<MemoryBudget>>,
+}
+
+/// RTP packet with the time when the publisher reads it, to measure the forwarding latency of subscribers.
+#[derive(Clone, Debug)]
+pub(crate) struct ReceivedPacket {
+    pub(crate) packet: rtp::packet::Packet,
+    pub(crate) received_at: Instant,
+}
+
+/// Where RTP packets of a [`Publisher`] come from.
+#[derive(Clone, Debug)]
+enum PublisherInput {
+    /// A track which is published by a client over a [`crate::publish_transport::PublishTransport`].
+    Track {
+        track: Arc<TrackRemote>,
+        rtp_receiver: Arc<RTCRtpReceiver>,
+        rtp_transceiver: Arc<RTCRtpTransceiver>,
+    },
+    /// An in-process source which is published by [`crate::router::Router::publish_source`].
+    Source {
+        source: Arc<dyn MediaSource>,
+        /// Extension id to attach audio levels which are reported by [`MediaSource::audio_level`]. This is only set for audio sources.
+        audio_level_id: Option<u8>,
+    },
+}
+
+impl PublisherInput {
+    fn source(source: Arc<dyn MediaSource>) -> Self {
+        let audio_level_id =
+            if MediaType::from_mime_type(&source.codec().mime_type) == MediaType::Audio {
+                let extensions = source.header_extensions();
+                match extensions.iter().find(|e| e.uri == extmap::AUDIO_LEVEL_URI) {
+                    Some(ext) => Some(ext.id as u8),
+                    // One-byte header extension ids are from 1 to 14.
+                    None => (1..=14).find(|id| extensions.iter().all(|e| e.id != *id as isize)),
+                }
+            } else {
+                None
+            };
+        PublisherInput::Source {
+            source,
+            audio_level_id,
+        }
+    }
+
+    fn id(&self) -> String {
+        match self {
+            PublisherInput::Track { track, .. } => track.id(),
+            PublisherInput::Source { source, .. } => source.id(),
+        }
+    }
+
+    fn ssrc(&self) -> u32 {
+        match self {
+            PublisherInput::Track { track, .. } => track.ssrc(),
+            PublisherInput::Source { source, .. } => source.ssrc(),
+        }
+    }
+
+    fn stream_id(&self) -> String {
+        match self {
+            PublisherInput::Track { track, .. } => track.stream_id(),
+            PublisherInput::Source { source, .. } => source.stream_id(),
+        }
+    }
+
+    fn codec(&self) -> RTCRtpCodecCapability {
+        match self {
+            PublisherInput::Track { track, .. } => track.codec().capability,
+            PublisherInput::Source { source, .. } => source.codec(),
+        }
+    }
+
+    async fn header_extensions(&self) -> Vec<RTCRtpHeaderExtensionParameters> {
+        match self {
+            PublisherInput::Track { rtp_receiver, .. } => {
+                rtp_receiver.get_parameters().await.header_extensions
+            }
+            PublisherInput::Source {
+                source,
+                audio_level_id,
+            } => {
+                let mut extensions = source.header_extensions();
+                if let Some(id) = audio_level_id {
+                    if !extensions.iter().any(|e| e.uri == extmap::AUDIO_LEVEL_URI) {
+                        extensions.push(RTCRtpHeaderExtensionParameters {
+                            uri: extmap::AUDIO_LEVEL_URI.to_owned(),
+                            id: *id as isize,
+                        });
+                    }
+                }
+                extensions
+            }
+        }
+    }
+
+    /// Read the next RTP packet. This returns `None` when the input is closed.
+    async fn read_rtp(&self, id: &str) -> Option<rtp::packet::Packet> {
+        let track = match self {
+            PublisherInput::Track { track, .. } => track,
+            PublisherInput::Source {
+                source,
+                audio_level_id,
+            } => {
+                let mut packet = source.read_rtp().await?;
+                // Server-originated audio has no level unless the source reports it, so speaker detection of subscribers keeps working.
+                if let Some(extension_id) = audio_level_id {
+                    if packet.header.get_extension(*extension_id).is_none() {
+                        if let Some(level) = source.audio_level(&packet) {
+                            let payload = Bytes::from(vec![audio_level_payload(level)]);
+                            if let Err(err) = packet.header.set_extension(*extension_id, payload) {
+                                tracing::warn!(
+                                    "Publisher id={} failed to set audio level: {}",
+                                    id,
+                                    err
+                                );
+                            }
+                        }
+                    }
+                }
+                return Some(packet);
+            }
+        };
+        match track.read_rtp().await {
+            Ok((rtp, _attr)) => Some(rtp),
+            Err(webrtc::error::Error::ErrDataChannelNotOpen) => None,
+            Err(webrtc::error::Error::ErrClosedPipe) => None,
+            Err(webrtc::error::Error::Interceptor(webrtc::interceptor::Error::Srtp(
+                webrtc_srtp::Error::Util(webrtc_util::Error::ErrBufferClosed),
+            ))) => None,
+            Err(err) => {
+                tracing::error!("Publisher id={} failed to read rtp: {:#?}", id, err);
+                None
+            }
+        }
+    }
+}
+
+pub type ForwardingPredicate =
+    Arc<dyn Fn(String) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+/// Policy which decides [`crate::subscribe_transport::SubscribeTransport`]s that the publisher is forwarded to. Each rule receives the transport ID.
+#[derive(Clone, Default)]
+pub enum ForwardingPolicy {
+    /// Forward to every subscriber in the router.
+    #[default]
+    AllowAll,
+    /// Forward only to these transports.
+    Allow(HashSet<String>),
+    /// Forward to every transport except these.
+    Deny(HashSet<String>),
+    /// Forward if the predicate returns true.
+    Predicate(ForwardingPredicate),
+}
+
+impl ForwardingPolicy {
+    pub(crate) async fn is_allowed(&self, transport_id: &str) -> bool {
+        match self {
+            ForwardingPolicy::AllowAll => true,
+            ForwardingPolicy::Allow(ids) => ids.contains(transport_id),
+            ForwardingPolicy::Deny(ids) => !ids.contains(transport_id),
+            ForwardingPolicy::Predicate(predicate) => predicate(transport_id.to_string()).await,
+        }
+    }
+}
+
+impl fmt::Debug for ForwardingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardingPolicy::AllowAll => f.write_str("AllowAll"),
+            ForwardingPolicy::Allow(ids) => f.debug_tuple("Allow").field(ids).finish(),
+            ForwardingPolicy::Deny(ids) => f.debug_tuple("Deny").field(ids).finish(),
+            ForwardingPolicy::Predicate(_) => f.write_str("Predicate"),
+        }
+    }
 }
 
 impl Publisher {
@@ -32,70 +247,230 @@ impl Publisher {
         rtp_transceiver: Arc<RTCRtpTransceiver>,
         rtcp_sender: Arc<transport::RtcpSender>,
         router_sender: mpsc::UnboundedSender<RouterEvent>,
-    ) -> Self {
-        let id = track.id();
-        let ssrc = track.ssrc();
+        context: PublisherContext,
+    ) -> Result<Self, Error> {
+        let input = PublisherInput::Track {
+            track,
+            rtp_receiver,
+            rtp_transceiver,
+        };
+        Self::create(input, rtcp_sender, router_sender, context)
+    }
+
+    /// Create a publisher for the in-process source. RTCP packets to the publisher are not sent anywhere, but keyframe requests are delivered to [`MediaSource::request_keyframe`].
+    pub(crate) fn from_source(
+        source: Arc<dyn MediaSource>,
+        router_sender: mpsc::UnboundedSender<RouterEvent>,
+        forwarding_runtime: Option<Arc<dyn Runtime>>,
+        memory_budget: Option<Arc<MemoryBudget>>,
+    ) -> Result<Self, Error> {
+        let (rtcp_sender, rtcp_receiver) = mpsc::unbounded_channel();
+        runtime::spawn(
+            enc!((source) async move {
+                Self::source_rtcp_loop(source, rtcp_receiver).await;
+            })
+            .in_current_span(),
+        );
 
-        let (sender, _reader) = broadcast::channel::<rtp::packet::Packet>(1024);
+        Self::create(
+            PublisherInput::source(source),
+            Arc::new(rtcp_sender),
+            router_sender,
+            PublisherContext {
+                rtcp_stats: Arc::new(std::sync::Mutex::new(RtcpStats::default())),
+                forwarding_runtime,
+                memory_budget,
+            },
+        )
+    }
+
+    fn create(
+        input: PublisherInput,
+        rtcp_sender: Arc<transport::RtcpSender>,
+        router_sender: mpsc::UnboundedSender<RouterEvent>,
+        context: PublisherContext,
+    ) -> Result<Self, Error> {
+        let PublisherContext {
+            rtcp_stats,
+            forwarding_runtime,
+            memory_budget,
+        } = context;
+        let id = input.id();
+        let ssrc = input.ssrc();
+        let codec = input.codec();
+
+        let (kind, capacity) = match MediaType::from_mime_type(&codec.mime_type) {
+            MediaType::Audio => (BufferKind::Audio, packet_channel::AUDIO_CAPACITY),
+            MediaType::Video => (BufferKind::Video, packet_channel::VIDEO_CAPACITY),
+        };
+        let memory_reservation = match memory_budget {
+            Some(budget) => {
+                let reservation = budget
+                    .reserve(
+                        kind,
+                        packet_channel::PACKET_SIZE,
+                        capacity,
+                        packet_channel::MIN_CAPACITY,
+                    )
+                    .ok_or_else(|| {
+                        Error::new_publisher(
+                            format!("Memory budget is exhausted, publisher {} is refused", id),
+                            PublisherErrorKind::MemoryBudgetExceededError,
+                        )
+                    })?;
+                Some(Arc::new(reservation))
+            }
+            None => None,
+        };
+        let capacity = memory_reservation
+            .as_ref()
+            .map_or(capacity, |reservation| reservation.capacity());
+        let (packet_channel, packet_sender) =
+            PacketChannel::new(capacity, memory_reservation.clone());
+        let (metadata_sender, _) = broadcast::channel::<PacketMetadata>(1024);
         let (tx, rx) = mpsc::unbounded_channel();
 
         {
             let id = id.clone();
             let closed_receiver = Arc::new(Mutex::new(rx));
-            tokio::spawn(enc!((sender, track) async move {
-                Self::rtp_event_loop(id.clone(), ssrc, sender, track, closed_receiver).await;
-                let _ = router_sender.send(RouterEvent::TrackRemoved(id));
-            }));
+            runtime::spawn_on(
+                forwarding_runtime.as_ref(),
+                enc!((input, metadata_sender) async move {
+                    let extension_ids = ExtensionIds::new(&input.header_extensions().await);
+                    Self::rtp_event_loop(id.clone(), ssrc, packet_sender, input, closed_receiver, metadata_sender, extension_ids).await;
+                    let _ = router_sender.send(RouterEvent::TrackRemoved(id, ssrc));
+                })
+                .in_current_span(),
+            );
+        }
+
+        let rtcp_counter = RtcpCounter::new(rtcp_stats);
+        let clock_drift = Arc::new(std::sync::Mutex::new(ClockDriftEstimator::new(
+            codec.clock_rate,
+        )));
+        if let PublisherInput::Track { rtp_receiver, .. } = &input {
+            let id = id.clone();
+            runtime::spawn_on(
+                forwarding_runtime.as_ref(),
+                enc!((rtp_receiver, rtcp_counter, clock_drift) async move {
+                    Self::rtcp_event_loop(id, ssrc, rtp_receiver, rtcp_counter, clock_drift).await;
+                })
+                .in_current_span(),
+            );
         }
 
         tracing::debug!("Publisher id={} is created for ssrc={}", id, ssrc);
 
+        let keyframe_requester = KeyframeRequester::new(ssrc, rtcp_sender.clone());
         let publisher = Self {
             id,
-            track,
-            _rtp_receiver: rtp_receiver,
-            _rtp_transceiver: rtp_transceiver,
+            stream_id: input.stream_id(),
+            input,
+            ssrc,
+            codec,
             rtcp_sender,
             closed_sender: Arc::new(tx),
-            rtp_packet_sender: sender,
+            packet_channel,
+            forwarding_policy: Arc::new(Mutex::new(ForwardingPolicy::default())),
+            forwarding_policy_changed: watch::channel(()).0,
+            metadata_sender,
+            keyframe_requester,
+            rtcp_counter,
+            clock_drift,
+            bandwidth_hint: Arc::new(AtomicU32::new(0)),
+            role: Arc::new(std::sync::Mutex::new(None)),
+            memory_reservation,
         };
 
-        publisher
+        Ok(publisher)
+    }
+
+    /// RTCP packets to a source are dropped except keyframe requests.
+    async fn source_rtcp_loop(
+        source: Arc<dyn MediaSource>,
+        mut rtcp_receiver: mpsc::UnboundedReceiver<
+            Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>,
+        >,
+    ) {
+        while let Some(packet) = rtcp_receiver.recv().await {
+            let packet = packet.as_any();
+            if packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>() {
+                source.request_keyframe();
+            }
+        }
+        tracing::debug!(
+            "Publisher id={} RTCP loop of the source has finished",
+            source.id()
+        );
+    }
+
+    /// RTCP packets from the publishing client are read to count them. Feedback for the SFU, such as sender reports, is handled by interceptors.
+    async fn rtcp_event_loop(
+        id: String,
+        ssrc: u32,
+        rtp_receiver: Arc<RTCRtpReceiver>,
+        rtcp_counter: RtcpCounter,
+        clock_drift: Arc<std::sync::Mutex<ClockDriftEstimator>>,
+    ) {
+        tracing::debug!(
+            "Publisher id={} ssrc={} RTCP event loop has started",
+            id,
+            ssrc
+        );
+
+        loop {
+            match rtp_receiver.read_rtcp().await {
+                Ok((rtcp_packets, _)) => {
+                    rtcp_counter.record(&rtcp_packets);
+                    clock_drift
+                        .lock()
+                        .unwrap()
+                        .record_packets(ssrc, &rtcp_packets);
+                }
+                Err(webrtc::Error::ErrClosedPipe) => break,
+                Err(err) => {
+                    tracing::error!("Publisher id={} failed to read rtcp: {}", id, err);
+                    break;
+                }
+            }
+        }
+
+        tracing::debug!(
+            "Publisher id={} ssrc={} RTCP event loop has finished",
+            id,
+            ssrc
+        );
     }
 
     async fn rtp_event_loop(
         id: String,
         ssrc: u32,
-        rtp_sender: broadcast::Sender<rtp::packet::Packet>,
-        track: Arc<TrackRemote>,
+        mut packet_sender: PacketSender,
+        input: PublisherInput,
         publisher_closed: Arc<Mutex<mpsc::UnboundedReceiver<bool>>>,
+        metadata_sender: broadcast::Sender<PacketMetadata>,
+        extension_ids: ExtensionIds,
     ) {
         tracing::debug!(
-            "Publisher id={} ssrc={} RTP event loop has started, payload_type={}, mime_type={}",
+            "Publisher id={} ssrc={} RTP event loop has started, mime_type={}",
             id,
             ssrc,
-            track.payload_type(),
-            track.codec().capability.mime_type
+            input.codec().mime_type
         );
 
-        let mut last_timestamp = 0;
-
         loop {
             let mut publisher_closed = publisher_closed.lock().await;
             tokio::select! {
                 _closed = publisher_closed.recv() => {
                     break;
                 }
-                res = track.read_rtp() => {
+                res = input.read_rtp(&id) => {
                     match res {
-                        Ok((mut rtp, _attr)) => {
-                            let old_timestamp = rtp.header.timestamp;
-                            if last_timestamp == 0 {
-                                rtp.header.timestamp = 0
-                            } else {
-                                rtp.header.timestamp -= last_timestamp;
+                        Some(rtp) => {
+                            let received_at = Instant::now();
+                            if metadata_sender.receiver_count() > 0 {
+                                let _ = metadata_sender.send(PacketMetadata::new(&rtp, &extension_ids));
                             }
-                            last_timestamp = old_timestamp;
 
                             tracing::trace!(
                                 "Publisher id={} received RTP ssrc={} seq={} timestamp={}",
@@ -105,29 +480,14 @@ impl Publisher {
                                 rtp.header.timestamp
                             );
 
-                            if rtp_sender.receiver_count() > 0 {
-                                if let Err(err) = rtp_sender.send(rtp) {
-                                    tracing::error!("Publisher id={} failed to send rtp: {}", id, err);
-                                }
-                            }
-                        }
-                        Err(webrtc::error::Error::ErrDataChannelNotOpen) => {
-                            break;
+                            packet_sender.send(ReceivedPacket { packet: rtp, received_at });
                         }
-                        Err(webrtc::error::Error::ErrClosedPipe) =>{
-                            break;
-                        }
-                        Err(webrtc::error::Error::Interceptor(webrtc::interceptor::Error::Srtp(webrtc_srtp::Error::Util(webrtc_util::Error::ErrBufferClosed)))) => {
-                            break;
-                        }
-                        Err(err) => {
-                            tracing::error!("Publisher id={} failed to read rtp: {:#?}", id, err);
+                        None => {
                             break;
                         }
                     }
                 }
             }
-            sleep(Duration::from_millis(1)).await;
         }
 
         tracing::debug!(
@@ -137,24 +497,169 @@ impl Publisher {
         );
     }
 
+    /// Change the forwarding policy. Existing subscribers which are no longer allowed are closed.
+    pub async fn set_forwarding_policy(&self, policy: ForwardingPolicy) {
+        *self.forwarding_policy.lock().await = policy;
+        self.forwarding_policy_changed.send_replace(());
+    }
+
+    pub(crate) async fn is_allowed(&self, transport_id: &str) -> bool {
+        let policy = self.forwarding_policy.lock().await.clone();
+        policy.is_allowed(transport_id).await
+    }
+
+    /// This returns a stream of metadata of received RTP packets, such as arrival time, size and header extension values. Payloads are not included.
+    /// Metadata is only collected while at least one receiver exists.
+    pub fn metadata_tap(&self) -> broadcast::Receiver<PacketMetadata> {
+        self.metadata_sender.subscribe()
+    }
+
+    /// This returns RTP header extensions which are negotiated with the publisher.
+    pub(crate) async fn header_extensions(&self) -> Vec<RTCRtpHeaderExtensionParameters> {
+        self.input.header_extensions().await
+    }
+
+    /// This returns a handle to request keyframes from the publisher. Consumers of RTP packets, such as recorders, should use it instead of sending PLI by themselves.
+    pub fn keyframe_requester(&self) -> KeyframeRequester {
+        self.keyframe_requester.clone()
+    }
+
+    /// This returns counters of RTCP packets which are received from the publishing client for this publisher.
+    pub fn rtcp_stats(&self) -> RtcpStats {
+        self.rtcp_counter.stats()
+    }
+
+    /// This returns the drift of the clocks of the publishing client, which is derived from RTCP sender reports.
+    pub fn clock_drift(&self) -> ClockDrift {
+        self.clock_drift.lock().unwrap().drift()
+    }
+
+    /// This returns a snapshot of the publisher which is safe to share with signaling layers.
+    pub fn info(&self) -> PublisherInfo {
+        PublisherInfo {
+            id: self.id.clone(),
+            stream_id: self.stream_id.clone(),
+            ssrc: self.ssrc,
+            mime_type: self.codec.mime_type.clone(),
+        }
+    }
+
+    /// This returns the bitrate in bps which the publishing client announces with `b=TIAS` or `b=AS`. It is the initial bandwidth estimate of new subscribers.
+    pub fn bandwidth_hint(&self) -> Option<u32> {
+        match self.bandwidth_hint.load(Ordering::Relaxed) {
+            0 => None,
+            bitrate => Some(bitrate),
+        }
+    }
+
+    pub(crate) fn set_bandwidth_hint(&self, bandwidth_hint: Option<u32>) {
+        self.bandwidth_hint
+            .store(bandwidth_hint.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Buffers of publishers with lower priority are shrunk first when the [`crate::config::MediaConfig::memory_budget`] is exhausted. It has no effect without a budget.
+    pub fn set_buffer_priority(&self, priority: BufferPriority) {
+        if let Some(reservation) = &self.memory_reservation {
+            reservation.set_priority(priority);
+        }
+    }
+
+    pub fn buffer_priority(&self) -> BufferPriority {
+        self.memory_reservation
+            .as_ref()
+            .map_or(BufferPriority::default(), |reservation| {
+                reservation.priority()
+            })
+    }
+
+    /// Set the role of the participant who publishes the track, such as `speaker` or `backstage`. It is matched with [`crate::grant::SubscribeGrant::roles`].
+    pub fn set_role(&self, role: Option<String>) {
+        *self.role.lock().unwrap() = role;
+    }
+
+    pub fn role(&self) -> Option<String> {
+        self.role.lock().unwrap().clone()
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    pub fn stream_id(&self) -> String {
+        self.stream_id.clone()
+    }
+
+    pub fn codec(&self) -> RTCRtpCodecCapability {
+        self.codec.clone()
+    }
+
+    pub fn kind(&self) -> MediaType {
+        MediaType::from_mime_type(&self.codec.mime_type)
+    }
+
+    /// This returns the remote track if the publisher is published over a [`crate::publish_transport::PublishTransport`]. Publishers of [`MediaSource`]s don't have it.
+    pub fn track(&self) -> Option<Arc<TrackRemote>> {
+        match &self.input {
+            PublisherInput::Track { track, .. } => Some(track.clone()),
+            PublisherInput::Source { .. } => None,
+        }
+    }
+
+    /// This returns the mid of the media section which the track is published in.
+    pub(crate) fn mid(&self) -> Option<String> {
+        match &self.input {
+            PublisherInput::Track {
+                rtp_transceiver, ..
+            } => rtp_transceiver.mid().map(|mid| mid.to_string()),
+            PublisherInput::Source { .. } => None,
+        }
+    }
+
     pub async fn close(&self) {
-        self.closed_sender.send(true).unwrap();
+        // The RTP event loop may have finished already.
+        let _ = self.closed_sender.send(true);
     }
 }
 
-pub(crate) fn detect_mime_type(mime_type: String) -> MediaType {
-    if mime_type.contains("video") || mime_type.contains("Video") {
-        MediaType::Video
+/// Summary of a [`Publisher`] which is delivered by [`crate::router::Router::watch_publishers`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublisherInfo {
+    pub id: String,
+    pub stream_id: String,
+    pub ssrc: u32,
+    pub mime_type: String,
+}
+
+/// Payload of the audio level extension (RFC 6464). The voice activity flag is set unless the level is silence.
+fn audio_level_payload(level: u8) -> u8 {
+    let level = level.min(127);
+    if level < 127 {
+        0x80 | level
     } else {
-        MediaType::Audio
+        level
     }
 }
 
-pub(crate) enum MediaType {
+/// Kind of media of a [`Publisher`] or a [`crate::subscriber::Subscriber`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaType {
     Video,
     Audio,
 }
 
+impl MediaType {
+    /// Kind of the mime type, such as `video/VP8` or `audio/opus`. Mime types which are not video are audio.
+    pub fn from_mime_type(mime_type: &str) -> Self {
+        if mime_type.to_ascii_lowercase().starts_with("video") {
+            MediaType::Video
+        } else {
+            MediaType::Audio
+        }
+    }
+}
+
 impl Drop for Publisher {
     fn drop(&mut self) {
         tracing::debug!("Publisher id={} is dropped", self.id);
@@ -0,0 +1,275 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::{watch, Mutex};
+use tracing::Instrument;
+use webrtc::{
+    peer_connection::{peer_connection_state::RTCPeerConnectionState, RTCPeerConnection},
+    stats::{StatsReport, StatsReportType},
+};
+
+use crate::{runtime, stats::TransportKind};
+
+/// Interval to collect stats of the peer connection.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+/// Number of samples which the score is computed from.
+const WINDOW: usize = 5;
+
+pub type OnQualityChangeFn = Box<dyn Fn(ConnectionQuality) + Send + Sync>;
+
+/// Connection quality of a transport, which is computed from stats of the peer connection every 2 seconds over the last 10 seconds.
+///
+/// The score is the worst of three factors, so it tells which factor to blame:
+///
+/// | score | loss   | round trip time | bitrate variation |
+/// |-------|--------|-----------------|-------------------|
+/// | 5     | < 2%   | < 150 ms        | < 0.15            |
+/// | 4     | < 5%   | < 300 ms        | < 0.3             |
+/// | 3     | < 10%  | < 500 ms        | < 0.5             |
+/// | 2     | < 20%  | < 1 s           | < 0.8             |
+/// | 1     | others | others          | others            |
+///
+/// Loss of subscribe transports is the fraction lost which clients report in receiver reports. webrtc doesn't count lost packets of received streams, so loss of publish transports is estimated from NACKs which the SFU sends.
+/// The bitrate variation is the coefficient of variation (standard deviation / mean) of bitrates which are sent or received in the window. A transport without media is stable.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionQuality {
+    /// From 1 (bad) to 5 (excellent).
+    pub score: u8,
+    /// Fraction of lost packets from 0.0 to 1.0.
+    pub loss: f64,
+    /// Round trip time of the selected ICE candidate pair. It is `None` until it is measured.
+    pub rtt_ms: Option<u64>,
+    pub bitrate_variation: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sample {
+    loss: f64,
+    rtt: Option<Duration>,
+    // Bits per second.
+    bitrate: f64,
+}
+
+/// Scores the last samples of a transport.
+#[derive(Debug, Default)]
+struct QualityEstimator {
+    samples: VecDeque<Sample>,
+}
+
+impl QualityEstimator {
+    fn record(&mut self, sample: Sample) -> ConnectionQuality {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+
+        let count = self.samples.len() as f64;
+        let loss = self.samples.iter().map(|s| s.loss).sum::<f64>() / count;
+        let rtt = self.samples.iter().rev().find_map(|s| s.rtt);
+        let mean = self.samples.iter().map(|s| s.bitrate).sum::<f64>() / count;
+        let bitrate_variation = if mean > 0.0 {
+            let variance = self
+                .samples
+                .iter()
+                .map(|s| (s.bitrate - mean).powi(2))
+                .sum::<f64>()
+                / count;
+            variance.sqrt() / mean
+        } else {
+            0.0
+        };
+
+        ConnectionQuality {
+            score: score(loss, rtt, bitrate_variation),
+            loss,
+            rtt_ms: rtt.map(|rtt| rtt.as_millis() as u64),
+            bitrate_variation,
+        }
+    }
+}
+
+fn score(loss: f64, rtt: Option<Duration>, bitrate_variation: f64) -> u8 {
+    let grade = |value: f64, thresholds: [f64; 4]| {
+        5 - thresholds.iter().take_while(|t| value >= **t).count() as u8
+    };
+    let loss_score = grade(loss, [0.02, 0.05, 0.1, 0.2]);
+    let rtt_score = rtt.map_or(5, |rtt| grade(rtt.as_secs_f64(), [0.15, 0.3, 0.5, 1.0]));
+    let stability_score = grade(bitrate_variation, [0.15, 0.3, 0.5, 0.8]);
+    loss_score.min(rtt_score).min(stability_score)
+}
+
+/// Cumulative counters in a stats report.
+#[derive(Clone, Copy, Debug, Default)]
+struct Counters {
+    packets: u64,
+    nacks: u64,
+    bytes: u64,
+    // Average fraction lost which is reported by the remote peer.
+    fraction_lost: Option<f64>,
+    rtt: Option<Duration>,
+}
+
+impl Counters {
+    fn new(report: &StatsReport, kind: TransportKind) -> Self {
+        let mut counters = Self::default();
+        let mut fractions = Vec::new();
+        for stats in report.reports.values() {
+            match (stats, kind) {
+                (StatsReportType::InboundRTP(inbound), TransportKind::Publish) => {
+                    counters.packets += inbound.packets_received;
+                    counters.nacks += inbound.nack_count;
+                    counters.bytes += inbound.bytes_received;
+                }
+                (StatsReportType::OutboundRTP(outbound), TransportKind::Subscribe) => {
+                    counters.packets += outbound.packets_sent;
+                    counters.bytes += outbound.bytes_sent;
+                }
+                (StatsReportType::RemoteInboundRTP(remote), TransportKind::Subscribe) => {
+                    fractions.push(remote.fraction_lost);
+                }
+                (StatsReportType::CandidatePair(pair), _)
+                    if pair.nominated && pair.current_round_trip_time > 0.0 =>
+                {
+                    counters.rtt = Some(Duration::from_secs_f64(pair.current_round_trip_time));
+                }
+                _ => {}
+            }
+        }
+        if !fractions.is_empty() {
+            counters.fraction_lost = Some(fractions.iter().sum::<f64>() / fractions.len() as f64);
+        }
+        counters
+    }
+
+    fn sample(&self, previous: &Counters, elapsed: Duration) -> Sample {
+        let packets = self.packets.saturating_sub(previous.packets);
+        let nacks = self.nacks.saturating_sub(previous.nacks);
+        let loss = match self.fraction_lost {
+            Some(fraction_lost) => fraction_lost,
+            None if packets + nacks > 0 => nacks as f64 / (packets + nacks) as f64,
+            None => 0.0,
+        };
+        let bytes = self.bytes.saturating_sub(previous.bytes);
+        Sample {
+            loss,
+            rtt: self.rtt,
+            bitrate: bytes as f64 * 8.0 / elapsed.as_secs_f64(),
+        }
+    }
+}
+
+/// Connection quality of a transport, which is updated by [`QualityMonitor::start`].
+#[derive(Clone, Debug)]
+pub(crate) struct QualityMonitor {
+    quality: Arc<watch::Sender<Option<ConnectionQuality>>>,
+}
+
+impl QualityMonitor {
+    pub(crate) fn new() -> Self {
+        Self {
+            quality: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    pub(crate) fn quality(&self) -> Option<ConnectionQuality> {
+        self.quality.borrow().clone()
+    }
+
+    /// Collect stats until the peer connection is closed. The callback is called when the score is changed.
+    pub(crate) fn start(
+        &self,
+        peer_connection: Weak<RTCPeerConnection>,
+        kind: TransportKind,
+        on_quality_change: Arc<Mutex<OnQualityChangeFn>>,
+    ) {
+        let quality = Arc::downgrade(&self.quality);
+        runtime::spawn(async move {
+            let mut estimator = QualityEstimator::default();
+            let mut previous: Option<(Counters, Instant)> = None;
+            loop {
+                runtime::sleep(SAMPLE_INTERVAL).await;
+                let (Some(peer_connection), Some(quality)) =
+                    (peer_connection.upgrade(), quality.upgrade())
+                else {
+                    break;
+                };
+                if peer_connection.connection_state() == RTCPeerConnectionState::Closed {
+                    break;
+                }
+                if peer_connection.connection_state() != RTCPeerConnectionState::Connected {
+                    continue;
+                }
+
+                let report = peer_connection.get_stats().await;
+                let now = Instant::now();
+                let counters = Counters::new(&report, kind);
+                let Some((last, last_at)) = previous.replace((counters, now)) else {
+                    continue;
+                };
+                let current = estimator.record(counters.sample(&last, now - last_at));
+                let changed = quality.send_if_modified(|q| {
+                    let changed = q.as_ref().map(|q| q.score) != Some(current.score);
+                    *q = Some(current.clone());
+                    changed
+                });
+                if changed {
+                    tracing::debug!(
+                        "Connection quality is changed to {}, loss={:.3}, rtt_ms={:?}, bitrate_variation={:.2}",
+                        current.score,
+                        current.loss,
+                        current.rtt_ms,
+                        current.bitrate_variation
+                    );
+                    let callback = on_quality_change.lock().await;
+                    (callback)(current);
+                }
+            }
+            tracing::debug!("Connection quality monitor has finished");
+        }
+        .in_current_span());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score(0.0, None, 0.0), 5);
+        assert_eq!(score(0.01, Some(Duration::from_millis(80)), 0.1), 5);
+        assert_eq!(score(0.03, Some(Duration::from_millis(80)), 0.1), 4);
+        // The worst factor decides the score.
+        assert_eq!(score(0.01, Some(Duration::from_millis(600)), 0.1), 2);
+        assert_eq!(score(0.01, Some(Duration::from_millis(80)), 0.6), 2);
+        assert_eq!(score(0.5, None, 0.0), 1);
+    }
+
+    #[test]
+    fn test_quality_estimator() {
+        let mut estimator = QualityEstimator::default();
+        let sample = |loss, bitrate| Sample {
+            loss,
+            rtt: Some(Duration::from_millis(50)),
+            bitrate,
+        };
+        for _ in 0..WINDOW {
+            assert_eq!(estimator.record(sample(0.0, 1_000_000.0)).score, 5);
+        }
+        // A burst of loss is averaged in the window.
+        let quality = estimator.record(sample(0.2, 1_000_000.0));
+        assert_eq!(quality.score, 4);
+        assert!((quality.loss - 0.04).abs() < 1e-9);
+        assert_eq!(quality.rtt_ms, Some(50));
+
+        // Bitrate drops to a half.
+        let quality = estimator.record(sample(0.0, 500_000.0));
+        assert!(quality.bitrate_variation > 0.15);
+        assert_eq!(quality.score, 4);
+    }
+}
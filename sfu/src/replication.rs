@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data_publisher::{DataChannelReliability, DataPublisher},
+    error::Error,
+    publisher::PublisherInfo,
+    storage::Storage,
+};
+
+const SNAPSHOT_KEY_PREFIX: &str = "router_snapshot/";
+
+/// Topology of a [`crate::router::Router`] which is replicated to a standby process. This is experimental.
+/// A standby process can restore the room with [`crate::router::Router::restore`] and advise clients to publish and subscribe the same IDs again, because [`crate::publisher::Publisher`] IDs are the same as track IDs.
+/// Media is not replicated, and DTLS and SRTP sessions can't be moved to another process, so clients have to create new transports after failover.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouterSnapshot {
+    pub router_id: String,
+    pub publishers: Vec<PublisherInfo>,
+    pub data_publishers: Vec<DataPublisherInfo>,
+    /// Audio programs and [`crate::publisher::Publisher`] IDs which are assigned to them.
+    pub audio_programs: HashMap<String, String>,
+}
+
+impl RouterSnapshot {
+    /// Save the snapshot to the storage, so a standby process can load it.
+    pub async fn save(&self, storage: &impl Storage) -> Result<(), Error> {
+        let value = serde_json::to_vec(self)?;
+        storage
+            .put(&format!("{}{}", SNAPSHOT_KEY_PREFIX, self.router_id), value)
+            .await
+    }
+
+    pub async fn load(storage: &impl Storage, router_id: &str) -> Result<Option<Self>, Error> {
+        match storage
+            .get(&format!("{}{}", SNAPSHOT_KEY_PREFIX, router_id))
+            .await?
+        {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// This returns router IDs whose snapshots are saved in the storage.
+    pub async fn router_ids(storage: &impl Storage) -> Result<Vec<String>, Error> {
+        let keys = storage.keys(SNAPSHOT_KEY_PREFIX).await?;
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(SNAPSHOT_KEY_PREFIX).map(str::to_string))
+            .collect())
+    }
+
+    pub async fn delete(storage: &impl Storage, router_id: &str) -> Result<(), Error> {
+        storage
+            .delete(&format!("{}{}", SNAPSHOT_KEY_PREFIX, router_id))
+            .await
+    }
+}
+
+/// Summary of a [`crate::data_publisher::DataPublisher`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataPublisherInfo {
+    pub id: String,
+    pub channel_id: u16,
+    pub label: String,
+    /// This is missing in snapshots of older versions.
+    #[serde(default)]
+    pub reliability: DataChannelReliability,
+}
+
+impl From<&DataPublisher> for DataPublisherInfo {
+    fn from(data_publisher: &DataPublisher) -> Self {
+        Self {
+            id: data_publisher.id.clone(),
+            channel_id: data_publisher.channel_id,
+            label: data_publisher.label.clone(),
+            reliability: data_publisher.reliability.clone(),
+        }
+    }
+}
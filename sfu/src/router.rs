@@ -1,29 +1,96 @@
 use std::{collections::HashMap, sync::Arc};
 
+#[cfg(feature = "cluster")]
+use crate::replication::{DataPublisherInfo, RouterSnapshot};
 use crate::{
+    audio_level::AudioLevelObserver,
     config::{MediaConfig, WebRTCTransportConfig},
     data_publisher::DataPublisher,
+    error::Error,
+    journal::{Journal, JournalEntry, JournalEvent},
+    media_source::MediaSource,
     publish_transport::PublishTransport,
-    publisher::Publisher,
+    publisher::{MediaType, Publisher, PublisherInfo},
+    runtime,
+    stats::{unix_time_ms, PublisherStats, RouterStatsSnapshot, TransportStatsSource},
+    storage::Storage,
     subscribe_transport::SubscribeTransport,
+    worker::BlockingWorker,
 };
-use tokio::sync::{mpsc, oneshot, Mutex};
+use derivative::Derivative;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use uuid::Uuid;
 
+pub type OnDuplicateTrackFn = Box<dyn Fn(DuplicateTrack) + Send + Sync>;
+
 /// Router accommodates multiple transports and they can communicate with each other. That means transports belonging to the same Router can send/receive their media. Router is like a meeting room.
-#[derive(Clone, Debug)]
+#[derive(Derivative)]
+#[derivative(Clone, Debug)]
 pub struct Router {
     pub id: String,
     publishers: Vec<(String, Arc<Publisher>)>,
     data_publishers: HashMap<String, Arc<DataPublisher>>,
     router_event_sender: mpsc::UnboundedSender<RouterEvent>,
     media_config: MediaConfig,
+    publishers_sender: Arc<watch::Sender<Vec<PublisherInfo>>>,
+    audio_programs_sender: Arc<watch::Sender<HashMap<String, String>>>,
+    data_label_policy: DataLabelPolicy,
+    blocking_worker: BlockingWorker,
+    data_group_watchers: Vec<(String, mpsc::UnboundedSender<Arc<DataPublisher>>)>,
+    journal: Arc<std::sync::Mutex<Journal>>,
+    audio_level_observer: AudioLevelObserver,
+    #[derivative(Debug = "ignore")]
+    stats_sources: Arc<std::sync::Mutex<Vec<TransportStatsSource>>>,
+    #[derivative(Debug = "ignore")]
+    on_duplicate_track_fn: Arc<Mutex<OnDuplicateTrackFn>>,
+}
+
+/// Policy for a [`crate::data_publisher::DataPublisher`] whose label is the same as an existing one in the router.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DataLabelPolicy {
+    /// Close the new data channel and keep the existing one. [`crate::publish_transport::PublishTransport::data_publish`] of the new data channel returns [`crate::error::PublisherErrorKind::DataLabelRejectedError`].
+    Reject,
+    /// Close the existing data channels and keep the new one.
+    Replace,
+    /// Keep all of them.
+    #[default]
+    AllowMultiple,
+}
+
+/// Reason why a published track is treated as a duplicate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicateTrackKind {
+    /// Another publisher is already delivering the same SSRC.
+    Ssrc,
+    /// The same track id has been published again.
+    TrackId,
+}
+
+/// Event which is fired when a newly published track conflicts with a [`crate::publisher::Publisher`] that already exists in the router.
+#[derive(Clone, Debug)]
+pub struct DuplicateTrack {
+    pub kind: DuplicateTrackKind,
+    pub ssrc: u32,
+    /// ID of the publisher which has already been published in the router.
+    pub existing_publisher_id: String,
+    /// ID of the publisher which has just been published.
+    pub new_publisher_id: String,
 }
 
 impl Router {
     pub fn new(media_config: MediaConfig) -> Arc<Mutex<Router>> {
         let id = Uuid::new_v4().to_string();
         let (tx, rx) = mpsc::unbounded_channel::<RouterEvent>();
+        let (publishers_sender, _) = watch::channel(Vec::new());
+        let (audio_programs_sender, _) = watch::channel(HashMap::new());
+        let blocking_worker = match media_config.max_blocking_tasks {
+            Some(max) => BlockingWorker::new(max),
+            None => BlockingWorker::default(),
+        };
+        let audio_level_observer =
+            AudioLevelObserver::new(media_config.audio_level_observer.clone());
 
         let r = Router {
             id: id.clone(),
@@ -31,13 +98,23 @@ impl Router {
             data_publishers: HashMap::new(),
             router_event_sender: tx,
             media_config,
+            publishers_sender: Arc::new(publishers_sender),
+            audio_programs_sender: Arc::new(audio_programs_sender),
+            data_label_policy: DataLabelPolicy::default(),
+            blocking_worker,
+            data_group_watchers: Vec::new(),
+            journal: Arc::new(std::sync::Mutex::new(Journal::default())),
+            audio_level_observer,
+            stats_sources: Arc::new(std::sync::Mutex::new(Vec::new())),
+            on_duplicate_track_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
         };
 
         tracing::debug!("Router {} is created", id);
+        r.record(JournalEvent::RouterCreated);
 
         let router = Arc::new(Mutex::new(r));
         let copied = Arc::clone(&router);
-        tokio::spawn(async move {
+        runtime::spawn(async move {
             Router::router_event_loop(id, copied, rx).await;
         });
 
@@ -53,6 +130,28 @@ impl Router {
             .collect()
     }
 
+    /// This returns the [`crate::publisher::Publisher`] of the ID in this router.
+    pub fn publisher(&self, publisher_id: &str) -> Option<Arc<Publisher>> {
+        self.publishers
+            .iter()
+            .find(|(id, _)| id == publisher_id)
+            .map(|(_, publisher)| publisher.clone())
+    }
+
+    /// This returns a receiver which is updated every time a [`crate::publisher::Publisher`] is published or removed in this router. It is useful to push room state changes to clients without polling [`Router::publisher_ids`].
+    pub fn watch_publishers(&self) -> watch::Receiver<Vec<PublisherInfo>> {
+        self.publishers_sender.subscribe()
+    }
+
+    fn notify_publishers(&self) {
+        let infos = self
+            .publishers
+            .iter()
+            .map(|(_, publisher)| publisher.info())
+            .collect();
+        self.publishers_sender.send_replace(infos);
+    }
+
     /// This returns [`crate::data_publisher::DataPublisher`] IDs that has already been published in this router. It is useful when a new user connect to the router and get already published data channels.
     pub fn data_publisher_ids(&self) -> Vec<String> {
         self.data_publishers
@@ -62,12 +161,172 @@ impl Router {
             .collect()
     }
 
+    /// This returns [`crate::data_publisher::DataPublisher`] IDs which have the label. Clients address data channels by label, so it is useful to resolve a label to IDs.
+    pub fn find_data_publisher_by_label(&self, label: &str) -> Vec<String> {
+        self.data_publishers
+            .iter()
+            .filter(|(_, data_publisher)| data_publisher.label == label)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// This returns [`crate::data_publisher::DataPublisher`] IDs which belong to the group. Please refer [`crate::data_publisher::DataPublisher::group`].
+    pub fn find_data_publishers_by_group(&self, group: &str) -> Vec<String> {
+        self.data_publishers
+            .iter()
+            .filter(|(_, data_publisher)| data_publisher.group() == group)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Set the policy which is applied when a data channel with a duplicated label is published.
+    pub fn set_data_label_policy(&mut self, policy: DataLabelPolicy) {
+        self.data_label_policy = policy;
+    }
+
+    /// Assign an audio [`crate::publisher::Publisher`] to the audio program, for example "floor" or an interpreter language like "es".
+    /// Subscribers which follow the program are switched to the new publisher without renegotiation, so interpreters can hand over the program.
+    pub fn set_audio_program(&mut self, program: String, publisher_id: String) {
+        tracing::debug!(
+            "Router {} assigns publisher {} to audio program {}",
+            self.id,
+            publisher_id,
+            program
+        );
+        self.audio_programs_sender.send_modify(|programs| {
+            programs.insert(program, publisher_id);
+        });
+    }
+
+    /// Remove the audio program. Subscribers which follow the program keep receiving the last publisher.
+    pub fn remove_audio_program(&mut self, program: &str) {
+        self.audio_programs_sender.send_modify(|programs| {
+            programs.remove(program);
+        });
+    }
+
+    /// This returns audio programs and [`crate::publisher::Publisher`] IDs which are assigned to them.
+    pub fn audio_programs(&self) -> HashMap<String, String> {
+        self.audio_programs_sender.borrow().clone()
+    }
+
+    /// This returns the topology of this router to replicate it to a standby process.
+    #[cfg(feature = "cluster")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "cluster")))]
+    pub fn snapshot(&self) -> RouterSnapshot {
+        RouterSnapshot {
+            router_id: self.id.clone(),
+            publishers: self
+                .publishers
+                .iter()
+                .map(|(_, publisher)| publisher.info())
+                .collect(),
+            data_publishers: self
+                .data_publishers
+                .values()
+                .map(|data_publisher| DataPublisherInfo::from(data_publisher.as_ref()))
+                .collect(),
+            audio_programs: self.audio_programs(),
+        }
+    }
+
+    /// Restore the state of a [`RouterSnapshot`] which is taken in the primary process. Publishers are not restored until clients publish their tracks again, but audio programs are assigned to the same IDs, so subscribers can follow them after failover.
+    #[cfg(feature = "cluster")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "cluster")))]
+    pub fn restore(&mut self, snapshot: &RouterSnapshot) {
+        tracing::debug!(
+            "Router {} restores snapshot of router {}",
+            self.id,
+            snapshot.router_id
+        );
+        self.audio_programs_sender
+            .send_replace(snapshot.audio_programs.clone());
+    }
+
+    /// This returns entries of the journal from the oldest to the newest. Please refer [`crate::journal::Journal`].
+    pub fn journal(&self) -> Vec<JournalEntry> {
+        self.journal.lock().unwrap().entries()
+    }
+
+    /// Save the journal to the storage, so audit logs remain after the router is closed.
+    pub async fn save_journal(&self, storage: &impl Storage) -> Result<(), Error> {
+        let journal = self.journal.lock().unwrap().clone();
+        journal.save(storage, &self.id).await
+    }
+
+    /// Change the max number of entries in the journal. The default is [`crate::journal::DEFAULT_JOURNAL_CAPACITY`].
+    pub fn set_journal_capacity(&mut self, capacity: usize) {
+        self.journal.lock().unwrap().set_capacity(capacity);
+    }
+
+    fn record(&self, event: JournalEvent) {
+        self.journal.lock().unwrap().record(event);
+    }
+
+    /// Collect stats of publishers, transports and subscribers of this router in one pass with a single timestamp.
+    /// The snapshot is serializable, so it can be shipped to analytics pipelines periodically.
+    pub fn stats_snapshot(&self) -> RouterStatsSnapshot {
+        let timestamp_ms = unix_time_ms();
+        let publishers = self
+            .publishers
+            .iter()
+            .map(|(_, publisher)| PublisherStats {
+                info: publisher.info(),
+                kind: publisher.kind(),
+                rtcp: publisher.rtcp_stats(),
+                clock_drift: publisher.clock_drift(),
+            })
+            .collect();
+        let mut transports = Vec::new();
+        // Sources of dropped transports are removed here.
+        self.stats_sources
+            .lock()
+            .unwrap()
+            .retain(|source| match source() {
+                Some(stats) => {
+                    transports.push(stats);
+                    true
+                }
+                None => false,
+            });
+        RouterStatsSnapshot {
+            router_id: self.id.clone(),
+            timestamp_ms,
+            publishers,
+            transports,
+        }
+    }
+
+    /// This returns the observer which ranks audio publishers of this router by audio level.
+    pub fn audio_level_observer(&self) -> AudioLevelObserver {
+        self.audio_level_observer.clone()
+    }
+
+    /// This returns the worker to run CPU heavy work for this router.
+    pub fn blocking_worker(&self) -> BlockingWorker {
+        self.blocking_worker.clone()
+    }
+
     pub async fn create_publish_transport(
         &self,
         transport_config: WebRTCTransportConfig,
     ) -> PublishTransport {
         let tx = self.router_event_sender.clone();
-        PublishTransport::new(tx, self.media_config.clone(), transport_config).await
+        let transport = PublishTransport::new(
+            tx,
+            self.media_config.clone(),
+            transport_config,
+            self.blocking_worker.clone(),
+        )
+        .await;
+        self.record(JournalEvent::PublishTransportCreated {
+            transport_id: transport.id.clone(),
+        });
+        self.stats_sources
+            .lock()
+            .unwrap()
+            .push(transport.stats_source());
+        transport
     }
 
     pub async fn create_subscribe_transport(
@@ -75,7 +334,158 @@ impl Router {
         transport_config: WebRTCTransportConfig,
     ) -> SubscribeTransport {
         let tx = self.router_event_sender.clone();
-        SubscribeTransport::new(tx, self.media_config.clone(), transport_config).await
+        let transport = SubscribeTransport::new(
+            tx,
+            self.audio_programs_sender.subscribe(),
+            self.audio_level_observer.watch(),
+            self.media_config.clone(),
+            transport_config,
+            self.blocking_worker.clone(),
+        )
+        .await;
+        self.record(JournalEvent::SubscribeTransportCreated {
+            transport_id: transport.id.clone(),
+        });
+        self.stats_sources
+            .lock()
+            .unwrap()
+            .push(transport.stats_source());
+        transport
+    }
+
+    /// Publish the in-process source as a [`crate::publisher::Publisher`]. It can be subscribed with the ID of the source, in the same way as tracks from a [`PublishTransport`].
+    /// The publisher is removed when [`MediaSource::read_rtp`] returns `None` or the publisher is closed.
+    /// It is refused with [`crate::error::PublisherErrorKind::MemoryBudgetExceededError`] when the [`MediaConfig::memory_budget`] is exhausted.
+    pub fn publish_source(
+        &self,
+        source: impl MediaSource + 'static,
+    ) -> Result<Arc<Publisher>, Error> {
+        let publisher = Arc::new(Publisher::from_source(
+            Arc::new(source),
+            self.router_event_sender.clone(),
+            self.media_config.forwarding_runtime.clone(),
+            self.media_config.memory_budget.clone(),
+        )?);
+        tracing::info!(
+            "Router {} publishes source: id={}, ssrc={}",
+            self.id,
+            publisher.id,
+            publisher.ssrc()
+        );
+        let _ = self
+            .router_event_sender
+            .send(RouterEvent::TrackPublished(publisher.clone()));
+        Ok(publisher)
+    }
+
+    /// Set callback function when a published track has the same SSRC or the same track id as an existing [`crate::publisher::Publisher`].
+    pub async fn on_duplicate_track(&self, f: OnDuplicateTrackFn) {
+        let mut callback = self.on_duplicate_track_fn.lock().await;
+        *callback = f;
+    }
+
+    fn find_duplicates(&self, publisher: &Arc<Publisher>) -> Vec<DuplicateTrack> {
+        let ssrc = publisher.ssrc();
+        self.publishers
+            .iter()
+            .filter_map(|(id, existing)| {
+                let kind = if *id == publisher.id {
+                    DuplicateTrackKind::TrackId
+                } else if existing.ssrc() == ssrc {
+                    DuplicateTrackKind::Ssrc
+                } else {
+                    return None;
+                };
+                Some(DuplicateTrack {
+                    kind,
+                    ssrc,
+                    existing_publisher_id: id.clone(),
+                    new_publisher_id: publisher.id.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Add the publisher and return tracks which it duplicates. A republished track replaces the old one, otherwise subscribers would keep finding the stale publisher.
+    fn add_publisher(&mut self, publisher: Arc<Publisher>) -> Vec<DuplicateTrack> {
+        let track_id = publisher.id.clone();
+        let duplicates = self.find_duplicates(&publisher);
+        for duplicate in duplicates.iter() {
+            tracing::warn!(
+                "Router {} detected duplicated track: kind={:?}, ssrc={}, existing={}, new={}",
+                self.id,
+                duplicate.kind,
+                duplicate.ssrc,
+                duplicate.existing_publisher_id,
+                duplicate.new_publisher_id
+            );
+            self.record(JournalEvent::DuplicateTrack {
+                kind: duplicate.kind.clone(),
+                existing_publisher_id: duplicate.existing_publisher_id.clone(),
+                new_publisher_id: duplicate.new_publisher_id.clone(),
+            });
+        }
+        self.publishers.retain(|(id, _)| *id != track_id);
+        self.record(JournalEvent::TrackPublished {
+            publisher_id: track_id.clone(),
+            ssrc: publisher.ssrc(),
+        });
+        if publisher.kind() == MediaType::Audio {
+            self.audio_level_observer.observe(&publisher);
+        }
+        self.publishers.push((track_id, publisher));
+        self.notify_publishers();
+        duplicates
+    }
+
+    /// Add the data publisher according to the [`DataLabelPolicy`]. This returns false when the data publisher is rejected and closed.
+    async fn add_data_publisher(&mut self, data_publisher: Arc<DataPublisher>) -> bool {
+        let data_id = data_publisher.id.clone();
+        let duplicated = self.find_data_publisher_by_label(&data_publisher.label);
+        if !duplicated.is_empty() {
+            match self.data_label_policy {
+                DataLabelPolicy::Reject => {
+                    tracing::warn!(
+                        "Router {} rejects DataPublisher {}, label={} is already published",
+                        self.id,
+                        data_id,
+                        data_publisher.label
+                    );
+                    self.record(JournalEvent::DataRejected {
+                        data_publisher_id: data_id,
+                        label: data_publisher.label.clone(),
+                    });
+                    data_publisher.close().await;
+                    return false;
+                }
+                DataLabelPolicy::Replace => {
+                    for old_id in duplicated {
+                        if let Some(old) = self.data_publishers.remove(&old_id) {
+                            tracing::debug!(
+                                "Router {} replaces DataPublisher {} with {}",
+                                self.id,
+                                old_id,
+                                data_id
+                            );
+                            old.close().await;
+                        }
+                    }
+                }
+                DataLabelPolicy::AllowMultiple => {}
+            }
+        }
+        self.data_group_watchers.retain(|(group, watcher)| {
+            if group != data_publisher.group() {
+                return !watcher.is_closed();
+            }
+            watcher.send(data_publisher.clone()).is_ok()
+        });
+        self.record(JournalEvent::DataPublished {
+            data_publisher_id: data_id.clone(),
+            label: data_publisher.label.clone(),
+        });
+        self.data_publishers.insert(data_id, data_publisher);
+        true
     }
 
     pub(crate) async fn router_event_loop(
@@ -86,13 +496,27 @@ impl Router {
         while let Some(event) = event_receiver.recv().await {
             match event {
                 RouterEvent::TrackPublished(publisher) => {
-                    let mut r = router.lock().await;
-                    let track_id = publisher.id.clone();
-                    r.publishers.push((track_id, publisher));
+                    // The callback is called after the router is unlocked, so it can use the router.
+                    let (duplicates, on_duplicate_track) = {
+                        let mut r = router.lock().await;
+                        (r.add_publisher(publisher), r.on_duplicate_track_fn.clone())
+                    };
+                    if !duplicates.is_empty() {
+                        let callback = on_duplicate_track.lock().await;
+                        for duplicate in duplicates {
+                            (callback)(duplicate);
+                        }
+                    }
                 }
-                RouterEvent::TrackRemoved(track_id) => {
+                RouterEvent::TrackRemoved(track_id, ssrc) => {
                     let mut r = router.lock().await;
-                    r.publishers.retain(|(id, _)| *id != track_id);
+                    r.publishers
+                        .retain(|(id, publisher)| *id != track_id || publisher.ssrc() != ssrc);
+                    r.record(JournalEvent::TrackRemoved {
+                        publisher_id: track_id,
+                        ssrc,
+                    });
+                    r.notify_publishers();
                 }
                 RouterEvent::GetPublisher(track_id, reply_sender) => {
                     let r = router.lock().await;
@@ -104,14 +528,26 @@ impl Router {
                     let data = track.cloned();
                     let _ = reply_sender.send(data);
                 }
-                RouterEvent::DataPublished(data_publisher) => {
-                    let mut r = router.lock().await;
-                    let data_id = data_publisher.id.clone();
-                    r.data_publishers.insert(data_id, data_publisher);
+                RouterEvent::SubscriptionDenied {
+                    transport_id,
+                    publisher_id,
+                } => {
+                    router
+                        .lock()
+                        .await
+                        .record(JournalEvent::SubscriptionDenied {
+                            transport_id,
+                            publisher_id,
+                        });
+                }
+                RouterEvent::DataPublished(data_publisher, reply_sender) => {
+                    let accepted = router.lock().await.add_data_publisher(data_publisher).await;
+                    let _ = reply_sender.send(accepted);
                 }
                 RouterEvent::DataRemoved(data_publisher_id) => {
                     let mut r = router.lock().await;
                     r.data_publishers.remove(&data_publisher_id);
+                    r.record(JournalEvent::DataRemoved { data_publisher_id });
                 }
                 RouterEvent::GetDataPublisher(data_publisher_id, reply_sender) => {
                     let r = router.lock().await;
@@ -119,7 +555,19 @@ impl Router {
                     let data = channel.cloned();
                     let _ = reply_sender.send(data);
                 }
+                RouterEvent::WatchDataGroup(group, watcher, reply_sender) => {
+                    let mut r = router.lock().await;
+                    let data_publishers = r
+                        .data_publishers
+                        .values()
+                        .filter(|data_publisher| data_publisher.group() == group)
+                        .cloned()
+                        .collect();
+                    r.data_group_watchers.push((group, watcher));
+                    let _ = reply_sender.send(data_publishers);
+                }
                 RouterEvent::Closed => {
+                    router.lock().await.record(JournalEvent::RouterClosed);
                     break;
                 }
             }
@@ -135,11 +583,22 @@ impl Router {
 #[derive(Debug)]
 pub(crate) enum RouterEvent {
     TrackPublished(Arc<Publisher>),
-    TrackRemoved(String),
-    DataPublished(Arc<DataPublisher>),
+    TrackRemoved(String, u32),
+    /// Reply whether the data publisher is accepted by the [`DataLabelPolicy`].
+    DataPublished(Arc<DataPublisher>, oneshot::Sender<bool>),
     DataRemoved(String),
     GetPublisher(String, oneshot::Sender<Option<Arc<Publisher>>>),
     GetDataPublisher(String, oneshot::Sender<Option<Arc<DataPublisher>>>),
+    SubscriptionDenied {
+        transport_id: String,
+        publisher_id: String,
+    },
+    /// Reply data publishers of the group, and send data publishers which are published to the group later.
+    WatchDataGroup(
+        String,
+        mpsc::UnboundedSender<Arc<DataPublisher>>,
+        oneshot::Sender<Vec<Arc<DataPublisher>>>,
+    ),
     Closed,
 }
 
@@ -148,3 +607,70 @@ impl Drop for Router {
         tracing::debug!("Router {} is dropped", self.id);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::media_source::SilentSource;
+    use std::time::Duration;
+    use webrtc::data_channel::RTCDataChannel;
+
+    #[tokio::test]
+    async fn test_duplicate_track_callback_can_lock_router() {
+        let router = Router::new(MediaConfig::default());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let weak = Arc::downgrade(&router);
+        router
+            .lock()
+            .await
+            .on_duplicate_track(Box::new(move |duplicate| {
+                let unlocked = weak
+                    .upgrade()
+                    .is_some_and(|router| router.try_lock().is_ok());
+                let _ = tx.send((duplicate, unlocked));
+            }))
+            .await;
+
+        {
+            let r = router.lock().await;
+            r.publish_source(SilentSource::new("first", 1)).unwrap();
+            r.publish_source(SilentSource::new("second", 1)).unwrap();
+        }
+
+        let (duplicate, unlocked) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(duplicate.kind, DuplicateTrackKind::Ssrc);
+        assert_eq!(duplicate.existing_publisher_id, "first");
+        assert_eq!(duplicate.new_publisher_id, "second");
+        assert!(unlocked);
+        assert_eq!(router.lock().await.publisher_ids(), vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_reject_data_label() {
+        let router = Router::new(MediaConfig::default());
+        let mut r = router.lock().await;
+        r.set_data_label_policy(DataLabelPolicy::Reject);
+        let data_publisher = || {
+            Arc::new(DataPublisher::new(
+                Arc::new(RTCDataChannel::default()),
+                r.router_event_sender.clone(),
+                None,
+            ))
+        };
+        let first = data_publisher();
+        let second = data_publisher();
+
+        assert!(r.add_data_publisher(first.clone()).await);
+        assert!(!r.add_data_publisher(second.clone()).await);
+        assert!(second.is_closed());
+        assert!(!first.is_closed());
+        assert_eq!(r.data_publisher_ids(), vec![first.id.clone()]);
+        assert!(r
+            .journal()
+            .iter()
+            .any(|entry| matches!(entry.event, JournalEvent::DataRejected { .. })));
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use webrtc::{
+    rtp::{self, header::Extension},
+    rtp_transceiver::RTCRtpHeaderExtensionParameters,
+    sdp::extmap,
+};
+
+use crate::config::find_extmap_order;
+
+/// What to do with a header extension which is received from a publisher.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ExtensionAction {
+    /// Forward the value with the subscriber's extension id.
+    Remap(u8),
+    /// Replace the value with the subscriber's mid.
+    ReplaceMid(u8),
+    /// Drop the extension, because the value is only meaningful between the publisher and the SFU.
+    Strip,
+}
+
+/// ExtensionRewriter has an explicit table to rewrite RTP header extensions per subscriber.
+/// Publisher's extension ids are negotiated by the publisher, but subscribers negotiate ids which are decided in [`crate::config`], so the ids have to be translated.
+/// And rid, repaired-rid and mid values describe the publisher's stream, so they are stripped or rewritten instead of being forwarded verbatim.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExtensionRewriter {
+    // Actions which are decided from the publisher's extensions. They are kept to restrict the table again on renegotiation.
+    actions: HashMap<u8, ExtensionAction>,
+    table: HashMap<u8, ExtensionAction>,
+}
+
+impl ExtensionRewriter {
+    pub(crate) fn new(publisher_extensions: &[RTCRtpHeaderExtensionParameters]) -> Self {
+        let mut table = HashMap::new();
+        for ext in publisher_extensions.iter() {
+            let action = if ext.uri == extmap::SDES_RTP_STREAM_ID_URI
+                || ext.uri == extmap::SDES_REPAIR_RTP_STREAM_ID_URI
+            {
+                ExtensionAction::Strip
+            } else {
+                match find_extmap_order(&ext.uri) {
+                    Some(id) if ext.uri == extmap::SDES_MID_URI => {
+                        ExtensionAction::ReplaceMid(id as u8)
+                    }
+                    Some(id) => ExtensionAction::Remap(id as u8),
+                    None => ExtensionAction::Strip,
+                }
+            };
+            table.insert(ext.id as u8, action);
+        }
+        Self {
+            actions: table.clone(),
+            table,
+        }
+    }
+
+    /// Rewrite extensions of the packet. `mid` is the mid of the subscriber's transceiver, which is known after negotiation.
+    pub(crate) fn rewrite(&self, header: &mut rtp::header::Header, mid: Option<&str>) {
+        if !header.extension {
+            return;
+        }
+
+        let mut extensions = Vec::with_capacity(header.extensions.len());
+        for ext in header.extensions.drain(..) {
+            match self.table.get(&ext.id) {
+                Some(ExtensionAction::Remap(id)) => extensions.push(Extension {
+                    id: *id,
+                    payload: ext.payload,
+                }),
+                Some(ExtensionAction::ReplaceMid(id)) => {
+                    if let Some(mid) = mid {
+                        extensions.push(Extension {
+                            id: *id,
+                            payload: Bytes::copy_from_slice(mid.as_bytes()),
+                        });
+                    }
+                }
+                Some(ExtensionAction::Strip) | None => {}
+            }
+        }
+
+        header.extension = !extensions.is_empty();
+        header.extensions = extensions;
+    }
+
+    /// Strip extensions which the subscriber didn't negotiate. `negotiated` is the list of extension ids in the subscriber's answer.
+    /// Some browsers silently drop packets which have extension ids that are not negotiated, so they are never forwarded.
+    /// The table is computed from the publisher's extensions every time, so extensions which are negotiated again are forwarded again.
+    pub(crate) fn restrict(&mut self, negotiated: &[u8]) {
+        self.table = self
+            .actions
+            .iter()
+            .map(|(publisher_id, action)| {
+                let action = match action {
+                    ExtensionAction::Remap(id) | ExtensionAction::ReplaceMid(id)
+                        if !negotiated.contains(id) =>
+                    {
+                        tracing::debug!(
+                            "header extension id={} is not negotiated by the subscriber, so it is stripped",
+                            id
+                        );
+                        ExtensionAction::Strip
+                    }
+                    action => action.clone(),
+                };
+                (*publisher_id, action)
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(uri: &str, id: isize) -> RTCRtpHeaderExtensionParameters {
+        RTCRtpHeaderExtensionParameters {
+            uri: uri.to_owned(),
+            id,
+        }
+    }
+
+    #[test]
+    fn test_rewrite_extensions() {
+        let rewriter = ExtensionRewriter::new(&[
+            params(extmap::SDES_MID_URI, 9),
+            params(extmap::SDES_RTP_STREAM_ID_URI, 5),
+            params(extmap::ABS_SEND_TIME_URI, 7),
+        ]);
+        let mut header = rtp::header::Header {
+            extension: true,
+            extension_profile: rtp::header::EXTENSION_PROFILE_ONE_BYTE,
+            extensions: vec![
+                Extension {
+                    id: 9,
+                    payload: Bytes::from_static(b"0"),
+                },
+                Extension {
+                    id: 5,
+                    payload: Bytes::from_static(b"h"),
+                },
+                Extension {
+                    id: 7,
+                    payload: Bytes::from_static(&[1, 2, 3]),
+                },
+            ],
+            ..Default::default()
+        };
+
+        rewriter.rewrite(&mut header, Some("3"));
+
+        assert_eq!(
+            header.extensions,
+            vec![
+                Extension {
+                    id: 4,
+                    payload: Bytes::from_static(b"3"),
+                },
+                Extension {
+                    id: 2,
+                    payload: Bytes::from_static(&[1, 2, 3]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restrict_extensions() {
+        let mut rewriter = ExtensionRewriter::new(&[
+            params(extmap::SDES_MID_URI, 9),
+            params(extmap::ABS_SEND_TIME_URI, 7),
+        ]);
+        rewriter.restrict(&[4]);
+        let mut header = rtp::header::Header {
+            extension: true,
+            extension_profile: rtp::header::EXTENSION_PROFILE_ONE_BYTE,
+            extensions: vec![
+                Extension {
+                    id: 9,
+                    payload: Bytes::from_static(b"0"),
+                },
+                Extension {
+                    id: 7,
+                    payload: Bytes::from_static(&[1, 2, 3]),
+                },
+            ],
+            ..Default::default()
+        };
+
+        rewriter.rewrite(&mut header, Some("3"));
+
+        assert_eq!(
+            header.extensions,
+            vec![Extension {
+                id: 4,
+                payload: Bytes::from_static(b"3"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_restrict_extensions_again() {
+        let mut rewriter = ExtensionRewriter::new(&[
+            params(extmap::SDES_MID_URI, 9),
+            params(extmap::ABS_SEND_TIME_URI, 7),
+        ]);
+        rewriter.restrict(&[4]);
+        // The subscriber negotiates abs-send-time in the next negotiation.
+        rewriter.restrict(&[4, 2]);
+        let mut header = rtp::header::Header {
+            extension: true,
+            extension_profile: rtp::header::EXTENSION_PROFILE_ONE_BYTE,
+            extensions: vec![Extension {
+                id: 7,
+                payload: Bytes::from_static(&[1, 2, 3]),
+            }],
+            ..Default::default()
+        };
+
+        rewriter.rewrite(&mut header, Some("3"));
+
+        assert_eq!(
+            header.extensions,
+            vec![Extension {
+                id: 2,
+                payload: Bytes::from_static(&[1, 2, 3]),
+            }]
+        );
+    }
+}
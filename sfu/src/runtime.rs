@@ -0,0 +1,155 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "cpu-affinity")]
+use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Future which is spawned or returned by [`Runtime`].
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Async runtime which runs background tasks of the SFU, like RTP and RTCP forwarding loops.
+/// Channels of the SFU are runtime agnostic, so a runtime only has to spawn tasks and sleep.
+///
+/// Note that [`webrtc`] itself still requires a tokio reactor to drive sockets and its own timers, so a custom runtime has to run inside a tokio context, for example with `tokio::runtime::Handle::enter`.
+pub trait Runtime: Send + Sync + fmt::Debug {
+    /// Run the future in the background. The task is detached.
+    fn spawn(&self, future: BoxFuture);
+    /// Return a future which completes after the duration.
+    fn sleep(&self, duration: Duration) -> BoxFuture;
+}
+
+/// [`Runtime`] which uses the current tokio runtime. This is the default runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+    fn spawn(&self, future: BoxFuture) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// [`Runtime`] which runs tasks on threads that are pinned to CPU cores. Set it to [`crate::config::MediaConfig::forwarding_runtime`], so forwarding tasks of a router stay on the cores.
+/// It reduces cache thrash on machines which run many routers, and keeps jitter of each room low.
+#[cfg(feature = "cpu-affinity")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cpu-affinity")))]
+#[derive(Debug)]
+pub struct PinnedRuntime {
+    handles: Vec<tokio::runtime::Handle>,
+    next: AtomicUsize,
+    // Threads stop when these are dropped.
+    _shutdown: Vec<tokio::sync::oneshot::Sender<()>>,
+}
+
+#[cfg(feature = "cpu-affinity")]
+impl PinnedRuntime {
+    /// Start a thread per core. Each thread runs a single threaded tokio runtime, and tasks are distributed to the threads in round robin.
+    pub fn new(cores: &[usize]) -> io::Result<Self> {
+        let available: Vec<usize> = core_affinity::get_core_ids()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|core| core.id)
+            .collect();
+        if cores.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "at least one core is required",
+            ));
+        }
+
+        let mut handles = Vec::with_capacity(cores.len());
+        let mut shutdown = Vec::with_capacity(cores.len());
+        for &core in cores.iter() {
+            if !available.contains(&core) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("core {} is not available", core),
+                ));
+            }
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+            handles.push(runtime.handle().clone());
+            shutdown.push(tx);
+            std::thread::Builder::new()
+                .name(format!("rheomesh-core-{}", core))
+                .spawn(move || {
+                    if !core_affinity::set_for_current(core_affinity::CoreId { id: core }) {
+                        tracing::warn!("failed to pin thread to core {}", core);
+                    }
+                    let _ = runtime.block_on(rx);
+                })?;
+        }
+
+        Ok(Self {
+            handles,
+            next: AtomicUsize::new(0),
+            _shutdown: shutdown,
+        })
+    }
+}
+
+#[cfg(feature = "cpu-affinity")]
+impl Runtime for PinnedRuntime {
+    fn spawn(&self, future: BoxFuture) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.handles.len();
+        self.handles[index].spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+static RUNTIME: OnceLock<Box<dyn Runtime>> = OnceLock::new();
+
+/// Set the runtime of the SFU. This must be called before creating any [`crate::router::Router`], and it can be called only once.
+/// If this is not called, [`TokioRuntime`] is used.
+pub fn set_runtime(runtime: Box<dyn Runtime>) -> Result<(), Box<dyn Runtime>> {
+    RUNTIME.set(runtime)
+}
+
+fn runtime() -> &'static dyn Runtime {
+    RUNTIME.get_or_init(default_runtime).as_ref()
+}
+
+fn default_runtime() -> Box<dyn Runtime> {
+    Box::new(TokioRuntime)
+}
+
+pub(crate) fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    runtime().spawn(Box::pin(future));
+}
+
+/// Spawn the future on the runtime if it is given, otherwise on the global runtime.
+pub(crate) fn spawn_on<F>(runtime: Option<&Arc<dyn Runtime>>, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    match runtime {
+        Some(runtime) => runtime.spawn(Box::pin(future)),
+        None => spawn(future),
+    }
+}
+
+pub(crate) async fn sleep(duration: Duration) {
+    runtime().sleep(duration).await
+}
+
+pub(crate) async fn sleep_until(deadline: Instant) {
+    sleep(deadline.saturating_duration_since(Instant::now())).await
+}
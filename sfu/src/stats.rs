@@ -0,0 +1,477 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use webrtc::rtcp::{
+    self,
+    header::{PacketType, FORMAT_FIR, FORMAT_PLI, FORMAT_REMB, FORMAT_TCC, FORMAT_TLN},
+    sender_report::SenderReport,
+};
+
+use crate::publisher::{MediaType, PublisherInfo};
+
+/// Number of received packets of an RTCP packet type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RtcpPacketCounter {
+    pub count: u64,
+    /// Arrival time of the last packet in microseconds since UNIX epoch.
+    pub last_received_us: Option<u64>,
+}
+
+/// Counters of received RTCP packets per packet type. It is useful to check whether feedback is flowing.
+/// For [`crate::publisher::Publisher`]s, packets are received from the publishing client. For [`crate::subscriber::Subscriber`]s, packets are received from the subscribing client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RtcpStats {
+    pub sender_report: RtcpPacketCounter,
+    pub receiver_report: RtcpPacketCounter,
+    pub pli: RtcpPacketCounter,
+    pub fir: RtcpPacketCounter,
+    pub nack: RtcpPacketCounter,
+    pub remb: RtcpPacketCounter,
+    pub twcc: RtcpPacketCounter,
+}
+
+impl RtcpStats {
+    fn counter_mut(&mut self, kind: RtcpPacketKind) -> &mut RtcpPacketCounter {
+        match kind {
+            RtcpPacketKind::SenderReport => &mut self.sender_report,
+            RtcpPacketKind::ReceiverReport => &mut self.receiver_report,
+            RtcpPacketKind::Pli => &mut self.pli,
+            RtcpPacketKind::Fir => &mut self.fir,
+            RtcpPacketKind::Nack => &mut self.nack,
+            RtcpPacketKind::Remb => &mut self.remb,
+            RtcpPacketKind::Twcc => &mut self.twcc,
+        }
+    }
+}
+
+/// Number of the latest packets which latency percentiles are computed from.
+const LATENCY_WINDOW: usize = 1024;
+
+/// Percentiles of the forwarding latency inside the SFU, from reading an RTP packet of the publisher to writing it to the subscriber. They are computed from the latest packets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LatencyStats {
+    /// Number of packets which the percentiles are computed from.
+    pub samples: usize,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// LatencyRecorder keeps forwarding latency of the latest packets in a ring buffer.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LatencyRecorder {
+    window: Arc<Mutex<LatencyWindow>>,
+}
+
+#[derive(Debug, Default)]
+struct LatencyWindow {
+    samples: Vec<u64>,
+    next: usize,
+}
+
+impl LatencyRecorder {
+    pub(crate) fn record(&self, latency: Duration) {
+        let latency = latency.as_micros() as u64;
+        let mut window = self.window.lock().unwrap();
+        if window.samples.len() < LATENCY_WINDOW {
+            window.samples.push(latency);
+        } else {
+            let next = window.next;
+            window.samples[next] = latency;
+        }
+        window.next = (window.next + 1) % LATENCY_WINDOW;
+    }
+
+    pub(crate) fn stats(&self) -> LatencyStats {
+        let mut samples = self.window.lock().unwrap().samples.clone();
+        if samples.is_empty() {
+            return LatencyStats::default();
+        }
+        samples.sort_unstable();
+        let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+        LatencyStats {
+            samples: samples.len(),
+            p50_us: percentile(0.5),
+            p90_us: percentile(0.9),
+            p99_us: percentile(0.99),
+            max_us: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// State of bandwidth probing of a [`crate::subscribe_transport::SubscribeTransport`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProbeState {
+    /// The probe track has not been added yet.
+    #[default]
+    NotStarted,
+    Probing,
+    /// The client has reported a bandwidth estimate while probing.
+    Succeeded,
+    /// The client has not reported any estimate, for example because REMB is not negotiated.
+    Failed,
+}
+
+/// Result of bandwidth probing of a [`crate::subscribe_transport::SubscribeTransport`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeResult {
+    pub state: ProbeState,
+    /// The latest bandwidth estimate in bps which the client reports with REMB.
+    pub estimate: Option<f32>,
+    /// Estimate minus the bitrate which is forwarded to subscribers now. Negative value means the transport is congested.
+    pub headroom: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RtcpPacketKind {
+    SenderReport,
+    ReceiverReport,
+    Pli,
+    Fir,
+    Nack,
+    Remb,
+    Twcc,
+}
+
+impl RtcpPacketKind {
+    pub(crate) fn from_header(header: &rtcp::header::Header) -> Option<Self> {
+        match (header.packet_type, header.count) {
+            (PacketType::SenderReport, _) => Some(Self::SenderReport),
+            (PacketType::ReceiverReport, _) => Some(Self::ReceiverReport),
+            (PacketType::PayloadSpecificFeedback, FORMAT_PLI) => Some(Self::Pli),
+            (PacketType::PayloadSpecificFeedback, FORMAT_FIR) => Some(Self::Fir),
+            (PacketType::PayloadSpecificFeedback, FORMAT_REMB) => Some(Self::Remb),
+            (PacketType::TransportSpecificFeedback, FORMAT_TLN) => Some(Self::Nack),
+            (PacketType::TransportSpecificFeedback, FORMAT_TCC) => Some(Self::Twcc),
+            _ => None,
+        }
+    }
+}
+
+/// RtcpCounter records received RTCP packets to the stats of a publisher or subscriber, and to the stats of its transport.
+#[derive(Clone, Debug)]
+pub(crate) struct RtcpCounter {
+    stats: Arc<Mutex<RtcpStats>>,
+    transport_stats: Arc<Mutex<RtcpStats>>,
+}
+
+impl RtcpCounter {
+    pub(crate) fn new(transport_stats: Arc<Mutex<RtcpStats>>) -> Self {
+        Self {
+            stats: Arc::new(Mutex::new(RtcpStats::default())),
+            transport_stats,
+        }
+    }
+
+    pub(crate) fn record(&self, packets: &[Box<dyn rtcp::packet::Packet + Send + Sync>]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        let kinds: Vec<RtcpPacketKind> = packets
+            .iter()
+            .filter_map(|p| RtcpPacketKind::from_header(&p.header()))
+            .collect();
+        if kinds.is_empty() {
+            return;
+        }
+
+        for stats in [&self.stats, &self.transport_stats] {
+            let mut stats = stats.lock().unwrap();
+            for kind in kinds.iter() {
+                let counter = stats.counter_mut(*kind);
+                counter.count += 1;
+                counter.last_received_us = Some(now);
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> RtcpStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+/// Drift of the clocks of a publisher which is derived from successive RTCP sender reports. Drift is in parts per million against the wall clock of the SFU, and positive value means the clock of the publisher runs faster.
+/// Devices with badly skewed clocks cause lip sync errors and jitter buffer growth on subscribers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClockDrift {
+    /// Number of sender reports which are used for the estimation.
+    pub sender_reports: u64,
+    /// Time between the first and the last sender report in milliseconds.
+    pub elapsed_ms: u64,
+    /// Drift of the RTP clock, which is the media clock of the publisher.
+    pub rtp_drift_ppm: Option<f64>,
+    /// Drift of the NTP clock, which is the wall clock of the publisher.
+    pub ntp_drift_ppm: Option<f64>,
+}
+
+/// Drift is not reported until sender reports span this window, because arrival times contain network jitter.
+const MIN_DRIFT_WINDOW_SECS: f64 = 5.0;
+/// Sender reports which jump more than this from the expected time are treated as a restart of the stream.
+const MAX_DRIFT_JUMP_SECS: f64 = 2.0;
+
+#[derive(Clone, Copy, Debug)]
+struct SenderReportSample {
+    ntp_secs: f64,
+    rtp_time: u32,
+    arrival: Instant,
+}
+
+/// ClockDriftEstimator compares elapsed time of RTP timestamps and NTP timestamps in sender reports with elapsed time of arrival.
+#[derive(Clone, Debug)]
+pub(crate) struct ClockDriftEstimator {
+    clock_rate: u32,
+    first: Option<SenderReportSample>,
+    last: Option<SenderReportSample>,
+    /// Unwrapped RTP ticks between the first and the last sender report.
+    rtp_ticks: i64,
+    sender_reports: u64,
+}
+
+impl ClockDriftEstimator {
+    pub(crate) fn new(clock_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            first: None,
+            last: None,
+            rtp_ticks: 0,
+            sender_reports: 0,
+        }
+    }
+
+    pub(crate) fn record_packets(
+        &mut self,
+        ssrc: u32,
+        packets: &[Box<dyn rtcp::packet::Packet + Send + Sync>],
+    ) {
+        let now = Instant::now();
+        for packet in packets.iter() {
+            if let Some(sr) = packet.as_any().downcast_ref::<SenderReport>() {
+                if sr.ssrc == ssrc {
+                    self.record(sr.ntp_time, sr.rtp_time, now);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn record(&mut self, ntp_time: u64, rtp_time: u32, arrival: Instant) {
+        let sample = SenderReportSample {
+            ntp_secs: (ntp_time >> 32) as f64
+                + (ntp_time & 0xffff_ffff) as f64 / (1u64 << 32) as f64,
+            rtp_time,
+            arrival,
+        };
+        let Some(last) = self.last else {
+            self.reset(sample);
+            return;
+        };
+        if self.clock_rate == 0 || sample.arrival <= last.arrival {
+            return;
+        }
+
+        let ticks = sample.rtp_time.wrapping_sub(last.rtp_time) as i32 as i64;
+        let rtp_elapsed = ticks as f64 / self.clock_rate as f64;
+        let wall_elapsed = sample.arrival.duration_since(last.arrival).as_secs_f64();
+        if (rtp_elapsed - wall_elapsed).abs() > MAX_DRIFT_JUMP_SECS {
+            tracing::debug!(
+                "sender report jumps {}s from the expected time, restart drift estimation",
+                rtp_elapsed - wall_elapsed
+            );
+            self.reset(sample);
+            return;
+        }
+
+        self.rtp_ticks += ticks;
+        self.sender_reports += 1;
+        self.last = Some(sample);
+    }
+
+    fn reset(&mut self, sample: SenderReportSample) {
+        self.first = Some(sample);
+        self.last = Some(sample);
+        self.rtp_ticks = 0;
+        self.sender_reports = 1;
+    }
+
+    pub(crate) fn drift(&self) -> ClockDrift {
+        let (Some(first), Some(last)) = (self.first, self.last) else {
+            return ClockDrift::default();
+        };
+        let wall_elapsed = last.arrival.duration_since(first.arrival).as_secs_f64();
+        let mut drift = ClockDrift {
+            sender_reports: self.sender_reports,
+            elapsed_ms: (wall_elapsed * 1000.0) as u64,
+            ..Default::default()
+        };
+        if wall_elapsed < MIN_DRIFT_WINDOW_SECS {
+            return drift;
+        }
+
+        let ppm = |elapsed: f64| (elapsed - wall_elapsed) / wall_elapsed * 1_000_000.0;
+        drift.rtp_drift_ppm = Some(ppm(self.rtp_ticks as f64 / self.clock_rate as f64));
+        drift.ntp_drift_ppm = Some(ppm(last.ntp_secs - first.ntp_secs));
+        drift
+    }
+}
+
+/// Stats of all entities of a [`crate::router::Router`] which are collected in one pass. Please refer [`crate::router::Router::stats_snapshot`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouterStatsSnapshot {
+    pub router_id: String,
+    /// Time when the snapshot is taken in milliseconds since UNIX epoch.
+    pub timestamp_ms: u64,
+    pub publishers: Vec<PublisherStats>,
+    pub transports: Vec<TransportStats>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublisherStats {
+    #[serde(flatten)]
+    pub info: PublisherInfo,
+    pub kind: MediaType,
+    pub rtcp: RtcpStats,
+    pub clock_drift: ClockDrift,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransportKind {
+    Publish,
+    Subscribe,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransportStats {
+    pub id: String,
+    pub kind: TransportKind,
+    pub rtcp: RtcpStats,
+    /// This is only available for subscribe transports.
+    pub probe: Option<ProbeResult>,
+    pub subscribers: Vec<SubscriberStats>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriberStats {
+    pub id: String,
+    pub publisher_id: String,
+    pub kind: MediaType,
+    pub rtcp: RtcpStats,
+    pub forwarding_latency: LatencyStats,
+}
+
+/// Function which collects stats of a transport for [`RouterStatsSnapshot`]. It returns None after the transport is dropped.
+pub(crate) type TransportStatsSource = Box<dyn Fn() -> Option<TransportStats> + Send + Sync>;
+
+pub(crate) fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use webrtc::rtcp::{
+        payload_feedbacks::picture_loss_indication::PictureLossIndication,
+        receiver_report::ReceiverReport,
+        transport_feedbacks::transport_layer_nack::TransportLayerNack,
+    };
+
+    #[test]
+    fn test_record_rtcp_packets() {
+        let transport_stats = Arc::new(Mutex::new(RtcpStats::default()));
+        let first = RtcpCounter::new(transport_stats.clone());
+        let second = RtcpCounter::new(transport_stats.clone());
+
+        first.record(&[
+            Box::new(ReceiverReport::default()),
+            Box::new(PictureLossIndication::default()),
+        ]);
+        second.record(&[
+            Box::new(PictureLossIndication::default()),
+            Box::new(TransportLayerNack::default()),
+        ]);
+
+        let stats = first.stats();
+        assert_eq!(stats.receiver_report.count, 1);
+        assert_eq!(stats.pli.count, 1);
+        assert_eq!(stats.nack.count, 0);
+        assert!(stats.pli.last_received_us.is_some());
+        assert_eq!(stats.nack.last_received_us, None);
+
+        let stats = *transport_stats.lock().unwrap();
+        assert_eq!(stats.receiver_report.count, 1);
+        assert_eq!(stats.pli.count, 2);
+        assert_eq!(stats.nack.count, 1);
+        assert_eq!(stats.remb.count, 0);
+    }
+
+    #[test]
+    fn test_clock_drift() {
+        let clock_rate = 90000;
+        let mut estimator = ClockDriftEstimator::new(clock_rate);
+        let start = Instant::now();
+        assert_eq!(estimator.drift(), ClockDrift::default());
+
+        // The RTP clock runs 100ppm faster and the NTP clock is accurate. RTP timestamp wraps around.
+        let rtp_start = u32::MAX - 100_000;
+        for i in 0..=10u64 {
+            let secs = i as f64;
+            let ntp_time = (1000 + i) << 32;
+            let rtp_time =
+                rtp_start.wrapping_add((secs * clock_rate as f64 * 1.0001).round() as u32);
+            estimator.record(ntp_time, rtp_time, start + Duration::from_secs(i));
+        }
+
+        let drift = estimator.drift();
+        assert_eq!(drift.sender_reports, 11);
+        assert_eq!(drift.elapsed_ms, 10_000);
+        assert!((drift.rtp_drift_ppm.unwrap() - 100.0).abs() < 1.0);
+        assert!(drift.ntp_drift_ppm.unwrap().abs() < 1.0);
+
+        // A jump of the RTP timestamp restarts the estimation.
+        estimator.record(0, 0, start + Duration::from_secs(11));
+        let drift = estimator.drift();
+        assert_eq!(drift.sender_reports, 1);
+        assert_eq!(drift.rtp_drift_ppm, None);
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let recorder = LatencyRecorder::default();
+        assert_eq!(recorder.stats(), LatencyStats::default());
+
+        for i in 1..=100 {
+            recorder.record(Duration::from_micros(i));
+        }
+        let stats = recorder.stats();
+        assert_eq!(stats.samples, 100);
+        assert_eq!(stats.p50_us, 51);
+        assert_eq!(stats.p90_us, 90);
+        assert_eq!(stats.p99_us, 99);
+        assert_eq!(stats.max_us, 100);
+
+        // Old samples are overwritten by new ones.
+        for _ in 0..LATENCY_WINDOW {
+            recorder.record(Duration::from_micros(5));
+        }
+        let stats = recorder.stats();
+        assert_eq!(stats.samples, LATENCY_WINDOW);
+        assert_eq!(stats.max_us, 5);
+    }
+}
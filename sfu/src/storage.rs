@@ -0,0 +1,59 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, RwLock},
+};
+
+use crate::error::Error;
+
+/// Storage is a key-value store to persist state of rheomesh, such as [`crate::replication::RouterSnapshot`].
+/// [`MemoryStorage`] is used by default. Please implement this trait for your database to make the state persistent.
+pub trait Storage: Send + Sync {
+    fn put(&self, key: &str, value: Vec<u8>) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>, Error>> + Send;
+
+    fn delete(&self, key: &str) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// This returns keys which start with the prefix.
+    fn keys(&self, prefix: &str) -> impl Future<Output = Result<Vec<String>, Error>> + Send;
+}
+
+/// Storage in the process memory. The state is lost when the process exits.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    entries: Arc<RwLock<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        self.entries.write().unwrap().insert(key.to_string(), value);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.entries.read().unwrap().get(key).cloned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.entries.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .entries
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
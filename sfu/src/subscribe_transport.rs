@@ -1,34 +1,58 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
 use derivative::Derivative;
 use enclose::enc;
-use tokio::sync::{mpsc, oneshot, Mutex};
-use tokio::time::sleep;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tracing::Instrument;
 use uuid::Uuid;
-use webrtc::api::media_engine::MIME_TYPE_VP8;
+use webrtc::api::media_engine::{MIME_TYPE_OPUS, MIME_TYPE_VP8};
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::peer_connection::{
     offer_answer_options::RTCOfferOptions, sdp::session_description::RTCSessionDescription,
 };
+use webrtc::rtcp::goodbye::Goodbye;
 use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::rtp_transceiver::RTCRtpTransceiver;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 use webrtc_sdp::attribute_type::{SdpAttribute, SdpAttributeType};
-use webrtc_sdp::parse_sdp;
+use webrtc_sdp::{parse_sdp, SdpSession};
 
-use crate::config::{find_extmap_order, MediaConfig, WebRTCTransportConfig};
+use crate::bandwidth::BandwidthAllocator;
+use crate::config::{
+    find_extmap_order, CongestionFeedback, MediaConfig, RembPolicy, SdpHints, WebRTCTransportConfig,
+};
 use crate::data_publisher::DataPublisher;
-use crate::data_subscriber::DataSubscriber;
+use crate::data_subscriber::{DataGroupSubscriber, DataSubscriber};
 use crate::prober::Prober;
-use crate::subscriber::Subscriber;
-use crate::transport::{OnIceCandidateFn, OnNegotiationNeededFn, PeerConnection, Transport};
+use crate::quality::{ConnectionQuality, OnQualityChangeFn, QualityMonitor};
+use crate::rtp_extension::ExtensionRewriter;
+use crate::runtime::{self, sleep, Runtime};
+use crate::stats::{
+    ProbeResult, RtcpStats, SubscriberStats, TransportKind, TransportStats, TransportStatsSource,
+};
+use crate::subscriber::{InitialSource, Subscriber};
+use crate::transport::{
+    add_sdp_hints, filter_local_candidates, filter_session_congestion_feedback,
+    remote_max_message_size, sdp_cache_key, set_session_bandwidths, NegotiationQueue,
+    NegotiationState, OnIceCandidateFn, OnLocalCandidateFn, OnNegotiationNeededFn,
+    OnNegotiationStateChangeFn, OnTransportFailedFn, PeerConnection, RemoteCandidates, ResumeHint,
+    SdpCache, Transport, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use crate::worker::BlockingWorker;
 use crate::{
     error::{Error, SubscriberErrorKind},
-    publisher::Publisher,
+    grant::{GrantGuard, SubscribeGrant},
+    keyframe::KeyframeDetectors,
+    publisher::{MediaType, Publisher},
     router::RouterEvent,
 };
 
@@ -38,27 +62,94 @@ use crate::{
 pub struct SubscribeTransport {
     pub id: String,
     peer_connection: Arc<RTCPeerConnection>,
-    pending_candidates: Arc<Mutex<Vec<RTCIceCandidateInit>>>,
+    remote_candidates: RemoteCandidates,
     router_event_sender: mpsc::UnboundedSender<RouterEvent>,
+    audio_programs: watch::Receiver<HashMap<String, String>>,
+    loudest_speakers: watch::Receiver<Vec<String>>,
     offer_options: RTCOfferOptions,
     // For callback fn
     #[derivative(Debug = "ignore")]
     on_ice_candidate_fn: Arc<Mutex<OnIceCandidateFn>>,
     #[derivative(Debug = "ignore")]
+    on_local_candidate_fn: Arc<Mutex<OnLocalCandidateFn>>,
+    #[derivative(Debug = "ignore")]
     on_negotiation_needed_fn: Arc<Mutex<OnNegotiationNeededFn>>,
+    #[derivative(Debug = "ignore")]
+    on_transport_failed_fn: Arc<Mutex<OnTransportFailedFn>>,
+    #[derivative(Debug = "ignore")]
+    on_negotiation_state_change_fn: Arc<Mutex<OnNegotiationStateChangeFn>>,
+    #[derivative(Debug = "ignore")]
+    on_quality_change_fn: Arc<Mutex<OnQualityChangeFn>>,
+    quality: QualityMonitor,
     // rtp event
     closed_sender: Arc<mpsc::UnboundedSender<bool>>,
     closed_receiver: Arc<Mutex<mpsc::UnboundedReceiver<bool>>>,
-    signaling_pending: Arc<AtomicBool>,
+    negotiation: NegotiationQueue,
+    negotiation_deferred: Arc<AtomicBool>,
+    subscriber_context: SubscriberContext,
+    max_message_size: Arc<AtomicUsize>,
+    sdp_hints: Option<SdpHints>,
+    span: tracing::Span,
+    subscribed_tracks: Arc<std::sync::Mutex<Vec<SubscribedTrack>>>,
+    probe_result: Arc<std::sync::Mutex<ProbeResult>>,
+    ice_servers: Vec<RTCIceServer>,
+    resume_hint: Arc<std::sync::Mutex<Option<ResumeHint>>>,
+    // Interval of scheduled ICE credential rotation.
+    credential_rotation: Arc<watch::Sender<Option<Duration>>>,
+    // True while a loop of the credential rotation is running, so toggling the rotation doesn't start another loop.
+    credential_rotation_running: Arc<AtomicBool>,
+    // SDP is parsed on the blocking worker of the router, so negotiation bursts don't delay RTP forwarding.
+    blocking_worker: BlockingWorker,
+    offer_cache: SdpCache<RTCSessionDescription>,
+    // Codec of slots of the loudest speakers which wait for a publisher.
+    speaker_codec: RTCRtpCodecCapability,
+}
+
+/// Subscriber and its RTP sender, which are closed in order when the transport is closed.
+#[derive(Clone, Debug)]
+struct SubscribedTrack {
+    subscriber: Subscriber,
+    rtp_sender: Arc<RTCRtpSender>,
+}
+
+/// Time to wait for audio packets which have already been queued, before closing audio subscribers.
+const AUDIO_FLUSH_DELAY: Duration = Duration::from_millis(20);
+/// RTCP BYE can contain up to 31 SSRCs.
+const MAX_GOODBYE_SOURCES: usize = 31;
+
+/// Transport-wide state which is shared with [`crate::subscriber::Subscriber`]s of the transport.
+#[derive(Clone, Debug)]
+pub(crate) struct SubscriberContext {
+    pub(crate) transport_id: String,
+    pub(crate) bandwidth_allocator: Arc<std::sync::Mutex<BandwidthAllocator>>,
+    pub(crate) remb_policy: RembPolicy,
+    pub(crate) congestion_feedback: CongestionFeedback,
+    pub(crate) rtcp_stats: Arc<std::sync::Mutex<RtcpStats>>,
+    pub(crate) forwarding_runtime: Option<Arc<dyn Runtime>>,
+    pub(crate) grant: GrantGuard,
+    pub(crate) keyframe_detectors: KeyframeDetectors,
+    /// Origin of RTP timestamps which are written to the subscriber.
+    pub(crate) timestamp_epoch: Instant,
 }
 
 impl SubscribeTransport {
     pub(crate) async fn new(
         router_event_sender: mpsc::UnboundedSender<RouterEvent>,
+        audio_programs: watch::Receiver<HashMap<String, String>>,
+        loudest_speakers: watch::Receiver<Vec<String>>,
         media_config: MediaConfig,
         transport_config: WebRTCTransportConfig,
+        blocking_worker: BlockingWorker,
     ) -> Self {
         let id = Uuid::new_v4().to_string();
+        let remb_policy = media_config.remb_policy.clone();
+        let congestion_feedback = media_config.congestion_feedback;
+        let forwarding_runtime = media_config.forwarding_runtime.clone();
+        let keyframe_detectors = media_config.keyframe_detectors.clone();
+        let sdp_hints = transport_config.sdp_hints.clone();
+        let span = transport_config.log_context.span("SubscribeTransport", &id);
+        let ice_servers = transport_config.configuration.ice_servers.clone();
+        let speaker_codec = speaker_codec(&media_config);
 
         let peer_connection = Self::generate_peer_connection(media_config, transport_config)
             .await
@@ -66,23 +157,61 @@ impl SubscribeTransport {
 
         let (closed_sender, closed_receiver) = mpsc::unbounded_channel();
 
+        let subscriber_context = SubscriberContext {
+            transport_id: id.clone(),
+            bandwidth_allocator: Arc::new(std::sync::Mutex::new(BandwidthAllocator::default())),
+            remb_policy,
+            congestion_feedback,
+            rtcp_stats: Arc::new(std::sync::Mutex::new(RtcpStats::default())),
+            forwarding_runtime,
+            grant: GrantGuard::new(id.clone(), router_event_sender.clone()),
+            keyframe_detectors,
+            timestamp_epoch: Instant::now(),
+        };
+
         let mut transport = Self {
             id,
             peer_connection: Arc::new(peer_connection),
             router_event_sender,
+            audio_programs,
+            loudest_speakers,
             offer_options: RTCOfferOptions {
                 ice_restart: false,
                 voice_activity_detection: false,
             },
-            pending_candidates: Arc::new(Mutex::new(Vec::new())),
+            remote_candidates: RemoteCandidates::default(),
             on_ice_candidate_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            on_local_candidate_fn: Arc::new(Mutex::new(Box::new(Some))),
             on_negotiation_needed_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            on_transport_failed_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            on_negotiation_state_change_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            on_quality_change_fn: Arc::new(Mutex::new(Box::new(|_| {}))),
+            quality: QualityMonitor::new(),
             closed_sender: Arc::new(closed_sender),
             closed_receiver: Arc::new(Mutex::new(closed_receiver)),
-            signaling_pending: Arc::new(AtomicBool::new(false)),
+            negotiation: NegotiationQueue::default(),
+            negotiation_deferred: Arc::new(AtomicBool::new(false)),
+            subscriber_context,
+            max_message_size: Arc::new(AtomicUsize::new(DEFAULT_MAX_MESSAGE_SIZE)),
+            sdp_hints,
+            span,
+            subscribed_tracks: Arc::new(std::sync::Mutex::new(Vec::new())),
+            probe_result: Arc::new(std::sync::Mutex::new(ProbeResult::default())),
+            ice_servers,
+            resume_hint: Arc::new(std::sync::Mutex::new(None)),
+            credential_rotation: Arc::new(watch::channel(None).0),
+            credential_rotation_running: Arc::new(AtomicBool::new(false)),
+            blocking_worker,
+            offer_cache: SdpCache::new(),
+            speaker_codec,
         };
 
         transport.ice_state_hooks().await;
+        transport.quality.start(
+            Arc::downgrade(&transport.peer_connection),
+            TransportKind::Subscribe,
+            transport.on_quality_change_fn.clone(),
+        );
 
         tracing::debug!("SubscribeTransport {} is created", transport.id);
 
@@ -97,29 +226,438 @@ impl SubscribeTransport {
         // We have to add a track before creating offer.
         // https://datatracker.ietf.org/doc/html/rfc3264
         // https://github.com/webrtc-rs/webrtc/issues/115#issuecomment-1958137875
+        let publisher = self.get_publisher(&publisher_id).await?;
+        self.negotiation.wait().await;
+        self.negotiation.start();
+        let subscriber = self.finish_on_error(self.subscribe_track(publisher.clone()).await)?;
+
+        // The subscriber is bound before creating the offer, so the offer is not handed out for a subscription which fails.
+        let res = match self
+            .follow_replaced_publisher(&subscriber, &publisher)
+            .await
+        {
+            Ok(()) => self.create_offer().await,
+            Err(err) => Err(err),
+        };
+        match res {
+            Ok(offer) => Ok((subscriber, offer)),
+            Err(err) => {
+                self.rollback_subscriptions(&[subscriber]).await;
+                self.finish_on_error(Err(err))
+            }
+        }
+    }
+
+    /// This starts subscribing the published media without creating an offer sdp. The track is negotiated when [`SubscribeTransport::negotiate`] is called.
+    /// Applications can batch many subscription changes, for example on a layout switch, into one negotiation.
+    pub async fn subscribe_deferred(&self, publisher_id: String) -> Result<Subscriber, Error> {
+        let publisher = self.get_publisher(&publisher_id).await?;
+        self.negotiation.wait().await;
+        // Negotiation needed events are ignored until negotiate is called.
+        self.negotiation_deferred.store(true, Ordering::Relaxed);
+        let subscriber = self.subscribe_track(publisher.clone()).await?;
+        self.follow_replaced_publisher(&subscriber, &publisher)
+            .await?;
+        Ok(subscriber)
+    }
+
+    /// This creates an offer sdp for subscriptions which are added by [`SubscribeTransport::subscribe_deferred`].
+    pub async fn negotiate(&self) -> Result<RTCSessionDescription, Error> {
+        self.negotiation.wait().await;
+        self.negotiation.start();
+        let res = self.create_offer().await;
+        self.finish_on_error(res)
+    }
+
+    /// Rotate the ICE ufrag and password with an ICE restart, and return an offer sdp which has the new credentials. Please send it to the client and set the answer with [`SubscribeTransport::set_answer`].
+    /// Media keeps flowing on the current candidate pair until the client answers.
+    pub async fn rotate_credentials(&self) -> Result<RTCSessionDescription, Error> {
+        self.negotiation.wait().await;
+        self.negotiation.start();
+        let res = self
+            .create_offer_with(RTCOfferOptions {
+                ice_restart: true,
+                ..self.offer_options.clone()
+            })
+            .await;
+        let offer = self.finish_on_error(res)?;
+        self.remote_candidates.restart().await;
+        tracing::info!("SubscribeTransport {} has rotated ICE credentials", self.id);
+        Ok(offer)
+    }
+
+    /// Rotate ICE credentials at the interval, for long-lived sessions whose security policy requires periodic refresh. Offers are delivered to the callback of [`SubscribeTransport::on_negotiation_needed`]. `None` stops the rotation.
+    pub fn set_credential_rotation(&self, interval: Option<Duration>) {
+        self.credential_rotation.send_replace(interval);
+        // A running loop follows the new interval, even if it has been stopped and is not finished yet.
+        if interval.is_none()
+            || self
+                .credential_rotation_running
+                .swap(true, Ordering::SeqCst)
+        {
+            return;
+        }
+
+        let transport = self.clone();
+        let running = self.credential_rotation_running.clone();
+        let mut rotation = self.credential_rotation.subscribe();
+        runtime::spawn(
+            async move {
+                'rotation: loop {
+                    loop {
+                        let Some(interval) = *rotation.borrow_and_update() else {
+                            break;
+                        };
+                        tokio::select! {
+                            res = rotation.changed() => {
+                                if res.is_err() {
+                                    break 'rotation;
+                                }
+                                continue;
+                            }
+                            _ = sleep(interval) => {}
+                        }
+                        if transport.peer_connection.connection_state()
+                            == RTCPeerConnectionState::Closed
+                        {
+                            break 'rotation;
+                        }
+                        match transport.rotate_credentials().await {
+                            Ok(offer) => {
+                                let locked = transport.on_negotiation_needed_fn.lock().await;
+                                (locked)(offer);
+                            }
+                            Err(err) => tracing::error!(
+                                "SubscribeTransport id={} failed to rotate ICE credentials: {}",
+                                transport.id,
+                                err
+                            ),
+                        }
+                    }
+                    running.store(false, Ordering::SeqCst);
+                    // The rotation may be started again before the flag is cleared, and then no loop is spawned for it.
+                    if rotation.borrow_and_update().is_none()
+                        || running.swap(true, Ordering::SeqCst)
+                    {
+                        break;
+                    }
+                }
+                tracing::debug!("ICE credential rotation has finished");
+            }
+            .instrument(self.span.clone()),
+        );
+    }
+
+    /// Finish the negotiation which has been started when it fails before an offer is handed out, otherwise following negotiations wait forever.
+    fn finish_on_error<T>(&self, res: Result<T, Error>) -> Result<T, Error> {
+        if res.is_err() {
+            self.negotiation.finish();
+        }
+        res
+    }
+
+    /// Close subscribers whose offer is not handed out, and remove their tracks so they are not negotiated later.
+    async fn rollback_subscriptions(&self, subscribers: &[Subscriber]) {
+        for subscriber in subscribers.iter() {
+            subscriber.close().await;
+            let rtp_sender = {
+                let mut tracks = self.subscribed_tracks.lock().unwrap();
+                tracks
+                    .iter()
+                    .position(|t| t.subscriber.id == subscriber.id)
+                    .map(|index| tracks.remove(index).rtp_sender)
+            };
+            if let Some(rtp_sender) = rtp_sender {
+                if let Err(err) = self.peer_connection.remove_track(&rtp_sender).await {
+                    tracing::warn!(
+                        "failed to remove the track of subscriber {}: {}",
+                        subscriber.id,
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns true if there are subscriptions which have not been negotiated by [`SubscribeTransport::negotiate`].
+    pub fn negotiation_deferred(&self) -> bool {
+        self.negotiation_deferred.load(Ordering::Relaxed)
+    }
+
+    /// The publisher may be removed and published again with the same ID while the subscription is negotiated.
+    /// In that case the subscriber is bound to the new publisher, otherwise it would wait for packets from the removed one forever.
+    async fn follow_replaced_publisher(
+        &self,
+        subscriber: &Subscriber,
+        publisher: &Arc<Publisher>,
+    ) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .router_event_sender
+            .send(RouterEvent::GetPublisher(publisher.id.clone(), tx));
+        match rx.await {
+            Ok(Some(current)) if !Arc::ptr_eq(&current, publisher) => {
+                tracing::debug!(
+                    "Publisher {} is replaced while subscribing, so subscriber {} is bound to the new one",
+                    publisher.id,
+                    subscriber.id
+                );
+                subscriber.bind_publisher(&current).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn get_publisher(&self, publisher_id: &str) -> Result<Arc<Publisher>, Error> {
         let (tx, rx) = oneshot::channel();
 
         let _ = self
             .router_event_sender
-            .send(RouterEvent::GetPublisher(publisher_id.clone(), tx));
+            .send(RouterEvent::GetPublisher(publisher_id.to_string(), tx));
 
         let reply = rx.await.unwrap();
         match reply {
-            None => {
-                return Err(Error::new_subscriber(
-                    format!("Publisher for {} is not found", publisher_id),
-                    SubscriberErrorKind::TrackNotFoundError,
-                ))
-            }
+            None => Err(Error::new_subscriber(
+                format!("Publisher for {} is not found", publisher_id),
+                SubscriberErrorKind::TrackNotFoundError,
+            )),
             Some(publisher) => {
-                while self.signaling_pending.load(Ordering::Relaxed) {
-                    sleep(Duration::from_millis(10)).await;
+                if !publisher.is_allowed(&self.id).await {
+                    return Err(Error::new_subscriber(
+                        format!(
+                            "Publisher {} is not allowed to be forwarded to {}",
+                            publisher_id, self.id
+                        ),
+                        SubscriberErrorKind::ForwardingNotAllowedError,
+                    ));
                 }
-                self.signaling_pending.store(true, Ordering::Relaxed);
-                let subscriber = self.subscribe_track(publisher).await?;
+                self.subscriber_context.grant.check(&publisher)?;
+                Ok(publisher)
+            }
+        }
+    }
 
-                let offer = self.create_offer().await?;
-                Ok((subscriber, offer))
+    /// Restrict publishers which can be subscribed by this transport, for example with a grant in the join token of the participant. Denied attempts fail with [`SubscriberErrorKind::SubscriptionNotGrantedError`] and are recorded in the journal of the router.
+    /// Existing subscribers are not closed, but they can't be switched to publishers which are not granted. `None` allows all publishers, which is the default.
+    pub fn set_grant(&self, grant: Option<SubscribeGrant>) {
+        self.subscriber_context.grant.set(grant);
+    }
+
+    pub fn grant(&self) -> Option<SubscribeGrant> {
+        self.subscriber_context.grant.get()
+    }
+
+    /// This starts subscribing the audio program which is assigned by [`crate::router::Router::set_audio_program`], and returns an offer sdp.
+    /// The returned [`crate::subscriber::Subscriber`] follows the program, so it is switched to another publisher without renegotiation when the program is reassigned.
+    pub async fn subscribe_audio_program(
+        &self,
+        program: String,
+    ) -> Result<(Subscriber, RTCSessionDescription), Error> {
+        let publisher_id = self.find_audio_program(&program)?;
+        let (subscriber, offer) = self.subscribe(publisher_id).await?;
+        subscriber.audio_program.send_replace(Some(program));
+
+        let router_event_sender = self.router_event_sender.clone();
+        let audio_programs = self.audio_programs.clone();
+        runtime::spawn(
+            enc!((subscriber) async move {
+                Self::audio_program_event_loop(subscriber, audio_programs, router_event_sender).await;
+            })
+            .instrument(self.span.clone()),
+        );
+
+        Ok((subscriber, offer))
+    }
+
+    /// Switch the audio program of the subscriber which is created by [`SubscribeTransport::subscribe_audio_program`], for example from the floor to an interpreter. This doesn't need renegotiation.
+    pub async fn switch_audio_program(
+        &self,
+        subscriber: &Subscriber,
+        program: String,
+    ) -> Result<(), Error> {
+        self.find_audio_program(&program)?;
+        subscriber.audio_program.send_replace(Some(program));
+        Ok(())
+    }
+
+    /// This starts subscribing the loudest audio publishers in the router with `slots` subscribers, and returns an offer sdp.
+    /// Each subscriber is switched to another publisher without renegotiation when the ranking of [`crate::audio_level::AudioLevelObserver`] is changed, so only top-N speakers are forwarded even if the room has hundreds of audio publishers.
+    /// All slots are negotiated at first. Slots which don't have a speaker yet, for example in an empty room, wait until a publisher enters the ranking.
+    /// Publishers in `excluded`, for example the client's own microphone, are not subscribed. All audio publishers must have the same codec.
+    pub async fn subscribe_loudest_speakers(
+        &self,
+        slots: usize,
+        excluded: Vec<String>,
+    ) -> Result<(Vec<Subscriber>, RTCSessionDescription), Error> {
+        let ranking: Vec<String> = self
+            .loudest_speakers
+            .borrow()
+            .iter()
+            .filter(|id| !excluded.contains(id))
+            .cloned()
+            .collect();
+        let mut publishers = Vec::with_capacity(slots);
+        for publisher_id in ranking.iter() {
+            if publishers.len() >= slots {
+                break;
+            }
+            match self.get_publisher(publisher_id).await {
+                Ok(publisher) => publishers.push(publisher),
+                Err(err) => tracing::debug!("publisher {} is skipped: {}", publisher_id, err),
+            }
+        }
+        let codec = match publishers.first() {
+            Some(publisher) => publisher.codec(),
+            None => self.speaker_codec.clone(),
+        };
+
+        self.negotiation.wait().await;
+        self.negotiation.start();
+        let mut publishers = publishers.into_iter();
+        let mut subscribers = Vec::with_capacity(slots);
+        for _ in 0..slots {
+            let res = match publishers.next() {
+                Some(publisher) => self.subscribe_track(publisher).await,
+                None => self.subscribe_idle_track(codec.clone()).await,
+            };
+            match res {
+                Ok(subscriber) => subscribers.push(subscriber),
+                Err(err) => {
+                    self.rollback_subscriptions(&subscribers).await;
+                    return self.finish_on_error(Err(err));
+                }
+            }
+        }
+        let offer = match self.create_offer().await {
+            Ok(offer) => offer,
+            Err(err) => {
+                self.rollback_subscriptions(&subscribers).await;
+                return self.finish_on_error(Err(err));
+            }
+        };
+
+        let loudest_speakers = self.loudest_speakers.clone();
+        let router_event_sender = self.router_event_sender.clone();
+        runtime::spawn(
+            enc!((subscribers) async move {
+                Self::loudest_speakers_event_loop(subscribers, loudest_speakers, excluded, router_event_sender).await;
+            })
+            .instrument(self.span.clone()),
+        );
+
+        Ok((subscribers, offer))
+    }
+
+    /// This switches subscribers of [`SubscribeTransport::subscribe_loudest_speakers`] to publishers which enter the top of the ranking.
+    async fn loudest_speakers_event_loop(
+        subscribers: Vec<Subscriber>,
+        mut loudest_speakers: watch::Receiver<Vec<String>>,
+        excluded: Vec<String>,
+        router_event_sender: mpsc::UnboundedSender<RouterEvent>,
+    ) {
+        while loudest_speakers.changed().await.is_ok() {
+            if subscribers.iter().all(|s| s.is_closed()) {
+                break;
+            }
+            let top: Vec<String> = loudest_speakers
+                .borrow_and_update()
+                .iter()
+                .filter(|id| !excluded.contains(id))
+                .take(subscribers.len())
+                .cloned()
+                .collect();
+            let current: Vec<String> = subscribers.iter().map(|s| s.publisher_id()).collect();
+            for (slot, publisher_id) in assign_slots(&current, &top) {
+                let subscriber = &subscribers[slot];
+                if subscriber.is_closed() {
+                    continue;
+                }
+                let (tx, rx) = oneshot::channel();
+                let _ =
+                    router_event_sender.send(RouterEvent::GetPublisher(publisher_id.clone(), tx));
+                let Ok(Some(publisher)) = rx.await else {
+                    continue;
+                };
+                if let Err(err) = subscriber.switch_publisher(&publisher).await {
+                    tracing::warn!(
+                        "Subscriber id={} failed to switch to speaker {}: {}",
+                        subscriber.id,
+                        publisher_id,
+                        err
+                    );
+                }
+            }
+        }
+        tracing::debug!("loudest speakers event loop finished");
+    }
+
+    fn find_audio_program(&self, program: &str) -> Result<String, Error> {
+        match self.audio_programs.borrow().get(program) {
+            Some(publisher_id) => Ok(publisher_id.clone()),
+            None => Err(Error::new_subscriber(
+                format!("Audio program {} is not found", program),
+                SubscriberErrorKind::AudioProgramNotFoundError,
+            )),
+        }
+    }
+
+    /// This switches the publisher of the subscriber when the subscriber selects another program, or the program is reassigned to another publisher.
+    async fn audio_program_event_loop(
+        subscriber: Subscriber,
+        mut audio_programs: watch::Receiver<HashMap<String, String>>,
+        router_event_sender: mpsc::UnboundedSender<RouterEvent>,
+    ) {
+        let mut selected = subscriber.audio_program.subscribe();
+        let mut subscriber_closed = subscriber.subscribe_closed();
+
+        loop {
+            tokio::select! {
+                _ = subscriber_closed.recv() => {
+                    break;
+                }
+                res = audio_programs.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                }
+                res = selected.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            let Some(program) = selected.borrow_and_update().clone() else {
+                continue;
+            };
+            let Some(publisher_id) = audio_programs.borrow_and_update().get(&program).cloned()
+            else {
+                continue;
+            };
+            if publisher_id == subscriber.publisher_id() {
+                continue;
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let _ = router_event_sender.send(RouterEvent::GetPublisher(publisher_id.clone(), tx));
+            match rx.await {
+                Ok(Some(publisher)) => {
+                    if let Err(err) = subscriber.switch_publisher(&publisher).await {
+                        tracing::error!(
+                            "Subscriber id={} failed to switch to audio program {}: {}",
+                            subscriber.id,
+                            program,
+                            err
+                        );
+                    }
+                }
+                _ => {
+                    tracing::warn!(
+                        "Publisher {} for audio program {} is not found",
+                        publisher_id,
+                        program
+                    );
+                }
             }
         }
     }
@@ -150,13 +688,89 @@ impl SubscribeTransport {
         }
     }
 
+    /// This starts subscribing every data channel in the group, and returns an offer sdp. Please refer [`crate::data_publisher::DataPublisher::group`] for groups.
+    /// Data channels which are published to the group later are subscribed automatically, and they are added to the returned [`DataGroupSubscriber`].
+    pub async fn data_subscribe_group(
+        &self,
+        group: String,
+    ) -> Result<(DataGroupSubscriber, RTCSessionDescription), Error> {
+        let (watcher, mut published) = mpsc::unbounded_channel();
+        let (tx, rx) = oneshot::channel();
+
+        let _ =
+            self.router_event_sender
+                .send(RouterEvent::WatchDataGroup(group.clone(), watcher, tx));
+
+        let data_publishers = rx.await.unwrap();
+        let group_subscriber = DataGroupSubscriber::new(group);
+        for data_publisher in data_publishers {
+            let data_subscriber = self.subscribe_data(data_publisher).await?;
+            group_subscriber.push(data_subscriber);
+        }
+
+        // The loop doesn't keep the transport alive, so it finishes when the transport is dropped.
+        let transport_id = self.id.clone();
+        let peer_connection = Arc::downgrade(&self.peer_connection);
+        let closed_receiver = Arc::downgrade(&self.closed_receiver);
+        let max_message_size = Arc::downgrade(&self.max_message_size);
+        let span = self.span.clone();
+        let mut closed = group_subscriber.subscribe_closed();
+        runtime::spawn(
+            enc!((group_subscriber) async move {
+                loop {
+                    tokio::select! {
+                        _ = closed.changed() => {
+                            break;
+                        }
+                        data_publisher = published.recv() => {
+                            let Some(data_publisher) = data_publisher else {
+                                break;
+                            };
+                            let (Some(peer_connection), Some(closed_receiver), Some(max_message_size)) =
+                                (peer_connection.upgrade(), closed_receiver.upgrade(), max_message_size.upgrade())
+                            else {
+                                break;
+                            };
+                            if peer_connection.connection_state() == RTCPeerConnectionState::Closed {
+                                break;
+                            }
+                            match Self::create_data_subscriber(&peer_connection, closed_receiver, max_message_size, &span, data_publisher).await {
+                                Ok(data_subscriber) => group_subscriber.push(data_subscriber),
+                                Err(err) => tracing::error!(
+                                    "SubscribeTransport id={} failed to subscribe data group {}: {}",
+                                    transport_id,
+                                    group_subscriber.group,
+                                    err
+                                ),
+                            }
+                        }
+                    }
+                }
+                tracing::debug!("data group {} subscription has finished", group_subscriber.group);
+            })
+            .instrument(self.span.clone()),
+        );
+
+        let offer = self.create_offer().await?;
+        Ok((group_subscriber, offer))
+    }
+
     async fn create_offer(&self) -> Result<RTCSessionDescription, Error> {
+        self.create_offer_with(self.offer_options.clone()).await
+    }
+
+    async fn create_offer_with(
+        &self,
+        offer_options: RTCOfferOptions,
+    ) -> Result<RTCSessionDescription, Error> {
         tracing::debug!("subscriber creates offer");
 
         let offer = self
             .peer_connection
-            .create_offer(Some(self.offer_options.clone()))
+            .create_offer(Some(offer_options))
             .await?;
+        // The offer includes every deferred subscription, so they don't need another negotiation.
+        self.negotiation_deferred.store(false, Ordering::Relaxed);
 
         let mut gathering_complete = self.peer_connection.gathering_complete_promise().await;
         self.peer_connection.set_local_description(offer).await?;
@@ -164,7 +778,19 @@ impl SubscribeTransport {
 
         match self.peer_connection.local_description().await {
             Some(offer) => {
-                let offer = Self::adjust_extmap(offer)?;
+                let offer =
+                    filter_local_candidates(offer, &*self.on_local_candidate_fn.lock().await);
+                let bandwidths =
+                    Self::bandwidth_hints(&self.peer_connection, &self.subscribed_tracks).await;
+                let offer = Self::rewrite_offer_on_worker(
+                    &self.blocking_worker,
+                    &self.offer_cache,
+                    offer,
+                    self.subscriber_context.congestion_feedback,
+                    bandwidths,
+                )
+                .await?;
+                let offer = add_sdp_hints(offer, self.sdp_hints.as_ref());
                 Ok(offer)
             }
             None => Err(Error::new_transport(
@@ -177,46 +803,100 @@ impl SubscribeTransport {
     /// This sets the answer to the [`webrtc::peer_connection::RTCPeerConnection`].
     pub async fn set_answer(&self, answer: RTCSessionDescription) -> Result<(), Error> {
         tracing::debug!("subscriber set answer");
-        self.peer_connection.set_remote_description(answer).await?;
-
-        self.signaling_pending.store(false, Ordering::Relaxed);
-        let pendings = self.pending_candidates.lock().await;
-        for candidate in pendings.iter() {
-            tracing::debug!("Adding pending ICE candidate: {:#?}", candidate);
-            if let Err(err) = self
-                .peer_connection
-                .add_ice_candidate(candidate.clone())
-                .await
-            {
-                tracing::error!("failed to add_ice_candidate: {}", err);
-            }
-        }
+        let remote = answer.clone();
+        let max_message_size = self
+            .blocking_worker
+            .run(move || remote_max_message_size(&remote))
+            .await?;
+        self.remote_candidates
+            .set_remote_description(&self.peer_connection, answer)
+            .await?;
+        self.max_message_size
+            .store(max_message_size, Ordering::Relaxed);
+        self.repair_extensions().await;
+
+        self.negotiation.finish();
 
         Ok(())
     }
 
+    /// Publishers may send header extensions which the subscriber didn't negotiate. Those are stripped from egress packets, instead of sending packets which don't match the SDP.
+    async fn repair_extensions(&self) {
+        let tracks = self.subscribed_tracks.lock().unwrap().clone();
+        for track in tracks.iter().filter(|t| !t.subscriber.is_closed()) {
+            let parameters = track.rtp_sender.get_parameters().await;
+            let negotiated = parameters
+                .rtp_parameters
+                .header_extensions
+                .iter()
+                .map(|ext| ext.id as u8)
+                .collect();
+            track.subscriber.set_negotiated_extensions(negotiated);
+        }
+    }
+
     async fn subscribe_track(&self, publisher: Arc<Publisher>) -> Result<Subscriber, Error> {
-        let publisher_rtcp_sender = publisher.rtcp_sender.clone();
-        let mime_type = publisher.track.codec().capability.mime_type;
+        let local_track = Arc::new(TrackLocalStaticRTP::new(
+            publisher.codec(),
+            publisher.id.clone(),
+            publisher.stream_id(),
+        ));
+        let extension_rewriter = ExtensionRewriter::new(&publisher.header_extensions().await);
+        self.add_subscriber(
+            local_track,
+            InitialSource::Publisher(&publisher),
+            extension_rewriter,
+        )
+        .await
+    }
 
+    /// This adds a track which waits for a publisher with the codec. The subscriber is fed after it is switched to a publisher.
+    async fn subscribe_idle_track(
+        &self,
+        codec: RTCRtpCodecCapability,
+    ) -> Result<Subscriber, Error> {
+        let track_id = Uuid::new_v4().to_string();
         let local_track = Arc::new(TrackLocalStaticRTP::new(
-            publisher.track.codec().capability,
-            publisher.track.id(),
-            publisher.track.stream_id(),
+            codec.clone(),
+            track_id.clone(),
+            track_id,
         ));
+        self.add_subscriber(
+            local_track,
+            InitialSource::Idle(codec),
+            ExtensionRewriter::default(),
+        )
+        .await
+    }
 
-        let rtcp_sender = self.peer_connection.add_track(local_track.clone()).await?;
-        let media_ssrc = publisher.track.ssrc();
-        let rtp_sender = publisher.rtp_packet_sender.clone();
+    async fn add_subscriber(
+        &self,
+        local_track: Arc<TrackLocalStaticRTP>,
+        initial_source: InitialSource<'_>,
+        extension_rewriter: ExtensionRewriter,
+    ) -> Result<Subscriber, Error> {
+        let rtp_sender = self.peer_connection.add_track(local_track.clone()).await?;
+        let transceiver = self.find_transceiver(&rtp_sender).await;
+
+        let subscriber = self.span.in_scope(|| {
+            Subscriber::new(
+                local_track,
+                rtp_sender.clone(),
+                initial_source,
+                transceiver,
+                extension_rewriter,
+                self.subscriber_context.clone(),
+            )
+        });
 
-        let subscriber = Subscriber::new(
-            local_track,
-            rtp_sender,
-            rtcp_sender,
-            publisher_rtcp_sender,
-            mime_type,
-            media_ssrc,
-        );
+        {
+            let mut tracks = self.subscribed_tracks.lock().unwrap();
+            tracks.retain(|t| !t.subscriber.is_closed());
+            tracks.push(SubscribedTrack {
+                subscriber: subscriber.clone(),
+                rtp_sender,
+            });
+        }
 
         if let None = self.peer_connection.current_local_description().await {
             let _ = self.add_probe().await?;
@@ -225,24 +905,83 @@ impl SubscribeTransport {
         Ok(subscriber)
     }
 
+    /// This returns bandwidth hints of subscribers per mid, which are written to offers.
+    async fn bandwidth_hints(
+        peer_connection: &RTCPeerConnection,
+        subscribed_tracks: &std::sync::Mutex<Vec<SubscribedTrack>>,
+    ) -> Vec<(String, u32)> {
+        let hints: Vec<(Arc<RTCRtpSender>, u32)> = subscribed_tracks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| !t.subscriber.is_closed())
+            .filter_map(|t| Some((t.rtp_sender.clone(), t.subscriber.bandwidth_hint()?)))
+            .collect();
+        if hints.is_empty() {
+            return Vec::new();
+        }
+        let mut bandwidths = Vec::new();
+        for transceiver in peer_connection.get_transceivers().await {
+            let sender = transceiver.sender().await;
+            let Some((_, bitrate)) = hints.iter().find(|(s, _)| Arc::ptr_eq(s, &sender)) else {
+                continue;
+            };
+            if let Some(mid) = transceiver.mid() {
+                bandwidths.push((mid.to_string(), *bitrate));
+            }
+        }
+        bandwidths
+    }
+
+    async fn find_transceiver(&self, sender: &Arc<RTCRtpSender>) -> Option<Arc<RTCRtpTransceiver>> {
+        for transceiver in self.peer_connection.get_transceivers().await {
+            if Arc::ptr_eq(&transceiver.sender().await, sender) {
+                return Some(transceiver);
+            }
+        }
+        None
+    }
+
     async fn subscribe_data(
         &self,
         data_publisher: Arc<DataPublisher>,
+    ) -> Result<DataSubscriber, Error> {
+        Self::create_data_subscriber(
+            &self.peer_connection,
+            self.closed_receiver.clone(),
+            self.max_message_size.clone(),
+            &self.span,
+            data_publisher,
+        )
+        .await
+    }
+
+    async fn create_data_subscriber(
+        peer_connection: &RTCPeerConnection,
+        closed_receiver: Arc<Mutex<mpsc::UnboundedReceiver<bool>>>,
+        max_message_size: Arc<AtomicUsize>,
+        span: &tracing::Span,
+        data_publisher: Arc<DataPublisher>,
     ) -> Result<DataSubscriber, Error> {
         let data_sender = data_publisher.data_sender.clone();
 
-        let data_channel = self
-            .peer_connection
-            .create_data_channel(data_publisher.id.as_str(), None)
+        let data_channel = peer_connection
+            .create_data_channel(
+                data_publisher.id.as_str(),
+                Some(data_publisher.reliability.init()),
+            )
             .await?;
 
-        let closed_receiver = self.closed_receiver.clone();
-        let data_subscriber = DataSubscriber::new(
-            data_publisher.id.clone(),
-            data_channel,
-            data_sender,
-            closed_receiver,
-        );
+        let data_subscriber = span.in_scope(|| {
+            DataSubscriber::new(
+                data_publisher.id.clone(),
+                data_channel,
+                data_sender,
+                data_publisher.subscribe_closed(),
+                closed_receiver,
+                max_message_size,
+            )
+        });
 
         Ok(data_subscriber)
     }
@@ -257,11 +996,10 @@ impl SubscribeTransport {
             "probator".to_owned(),
             "webrtc-rs".to_owned(),
         ));
-        {
-            let dummy_track = dummy_track.clone();
-            let _rtcp_sender = self.peer_connection.add_track(dummy_track).await?;
-        }
-        let _prober = Prober::new(dummy_track);
+        let rtp_sender = self.peer_connection.add_track(dummy_track.clone()).await?;
+        let _prober = self
+            .span
+            .in_scope(|| Prober::new(dummy_track, rtp_sender, self.probe_result.clone()));
 
         Ok(())
     }
@@ -269,51 +1007,74 @@ impl SubscribeTransport {
     async fn ice_state_hooks(&mut self) {
         let peer = self.peer_connection.clone();
         let on_ice_candidate = Arc::clone(&self.on_ice_candidate_fn);
+        let on_local_candidate = Arc::clone(&self.on_local_candidate_fn);
+        let span = self.span.clone();
 
         // This callback is called after initializing PeerConnection with ICE servers.
-        peer.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
-            Box::pin({
-                let func = on_ice_candidate.clone();
-                async move {
-                    let locked = func.lock().await;
-                    if let Some(candidate) = candidate {
+        peer.on_ice_candidate(Box::new(
+            enc!((span) move |candidate: Option<RTCIceCandidate>| {
+                Box::pin({
+                    let func = on_ice_candidate.clone();
+                    let filter = on_local_candidate.clone();
+                    async move {
+                        let Some(candidate) = candidate else {
+                            return;
+                        };
+                        let Some(candidate) = (filter.lock().await)(candidate) else {
+                            tracing::debug!("local ICE candidate is dropped by the filter");
+                            return;
+                        };
+                        let locked = func.lock().await;
                         tracing::info!("on ice candidate: {}", candidate);
                         // Call on_ice_candidate_fn as callback.
                         (locked)(candidate);
                     }
-                }
-            })
-        }));
+                    .instrument(span.clone())
+                })
+            }),
+        ));
 
         let downgraded_peer = Arc::downgrade(&peer);
         let on_negotiation_needed = Arc::clone(&self.on_negotiation_needed_fn);
-        let signaling_pending = self.signaling_pending.clone();
+        let on_local_candidate = Arc::clone(&self.on_local_candidate_fn);
+        let negotiation = self.negotiation.clone();
+        let negotiation_deferred = self.negotiation_deferred.clone();
         let offer_options = self.offer_options.clone();
-        peer.on_negotiation_needed(Box::new(enc!( (downgraded_peer, on_negotiation_needed, signaling_pending) move || {
-                Box::pin(enc!( (downgraded_peer, on_negotiation_needed, signaling_pending) async move {
+        let congestion_feedback = self.subscriber_context.congestion_feedback;
+        let sdp_hints = self.sdp_hints.clone();
+        let subscribed_tracks = self.subscribed_tracks.clone();
+        let blocking_worker = self.blocking_worker.clone();
+        let offer_cache = self.offer_cache.clone();
+        peer.on_negotiation_needed(Box::new(enc!( (downgraded_peer, on_negotiation_needed, on_local_candidate, negotiation, negotiation_deferred, sdp_hints, subscribed_tracks, blocking_worker, offer_cache, span) move || {
+                Box::pin(enc!( (downgraded_peer, on_negotiation_needed, on_local_candidate, negotiation, negotiation_deferred, sdp_hints, subscribed_tracks, blocking_worker, offer_cache) async move {
                     tracing::info!("on negotiation needed");
-                    while signaling_pending.load(Ordering::Relaxed) {
-                        sleep(Duration::from_millis(10)).await;
+                    negotiation.wait().await;
+                    if negotiation_deferred.load(Ordering::Relaxed) {
+                        tracing::debug!("negotiation is deferred until negotiate is called");
+                        return;
                     }
                     let locked = on_negotiation_needed.lock().await;
                     if let Some(pc) = downgraded_peer.upgrade() {
                         if pc.connection_state() == RTCPeerConnectionState::Closed {
                                 return;
                         }
-                        signaling_pending.store(true, Ordering::Relaxed);
+                        negotiation.start();
                         let offer = pc.create_offer(Some(offer_options)).await.expect("could not create subscriber offer:");
-                        let offer = Self::adjust_extmap(offer).expect("could not adjust sdp");
+                        let bandwidths = Self::bandwidth_hints(&pc, &subscribed_tracks).await;
+                        let offer = Self::rewrite_offer_on_worker(&blocking_worker, &offer_cache, offer, congestion_feedback, bandwidths).await.expect("could not rewrite sdp");
 
                         let mut gathering_complete = pc.gathering_complete_promise().await;
                         pc.set_local_description(offer).await.expect("could not set local description");
                         let _ = gathering_complete.recv().await;
 
                         let offer = pc.local_description().await.unwrap();
+                        let offer = filter_local_candidates(offer, &*on_local_candidate.lock().await);
+                        let offer = add_sdp_hints(offer, sdp_hints.as_ref());
 
                         tracing::info!("peer sending offer");
                         (locked)(offer);
                     }
-                }))
+                }).instrument(span.clone()))
             })));
 
         peer.on_ice_gathering_state_change(Box::new(move |state| {
@@ -321,6 +1082,121 @@ impl SubscribeTransport {
                 tracing::debug!("ICE gathering state changed: {}", state);
             })
         }));
+
+        let negotiation = self.negotiation.clone();
+        let on_negotiation_state_change = Arc::clone(&self.on_negotiation_state_change_fn);
+        peer.on_signaling_state_change(Box::new(move |state| {
+            tracing::debug!("Signaling state changed: {}", state);
+            let negotiation_state = negotiation.state(state);
+            Box::pin(enc!((on_negotiation_state_change) async move {
+                let locked = on_negotiation_state_change.lock().await;
+                (locked)(negotiation_state);
+            }))
+        }));
+
+        let transport_id = self.id.clone();
+        let subscribed_tracks = self.subscribed_tracks.clone();
+        let ice_servers = self.ice_servers.clone();
+        let resume_hint = self.resume_hint.clone();
+        let on_transport_failed = Arc::clone(&self.on_transport_failed_fn);
+        peer.on_peer_connection_state_change(Box::new(move |state| {
+            tracing::debug!("Peer connection state changed: {}", state);
+            if state != RTCPeerConnectionState::Failed {
+                return Box::pin(async {});
+            }
+            let mut subscriptions: Vec<String> = subscribed_tracks
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|t| !t.subscriber.is_closed())
+                .map(|t| t.subscriber.publisher_id())
+                // Slots which wait for a publisher don't have to be restored.
+                .filter(|publisher_id| !publisher_id.is_empty())
+                .collect();
+            subscriptions.sort();
+            subscriptions.dedup();
+            let hint = ResumeHint::new(
+                transport_id.clone(),
+                subscriptions,
+                vec![],
+                ice_servers.clone(),
+            );
+            *resume_hint.lock().unwrap() = Some(hint.clone());
+            let on_transport_failed = on_transport_failed.clone();
+            Box::pin(async move {
+                let locked = on_transport_failed.lock().await;
+                (locked)(hint);
+            })
+        }));
+    }
+
+    /// This returns the span which has the [`crate::config::LogContext`] of this transport. Applications can use it to emit their own events with the same context.
+    pub fn span(&self) -> tracing::Span {
+        self.span.clone()
+    }
+
+    /// This returns the SCTP max message size which is negotiated with the client. `0` means there is no limit.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.load(Ordering::Relaxed)
+    }
+
+    /// This returns the result of bandwidth probing, which starts when the first track is subscribed.
+    pub fn probe_result(&self) -> ProbeResult {
+        Self::headroom(
+            &self.probe_result,
+            &self.subscriber_context.bandwidth_allocator,
+        )
+    }
+
+    fn headroom(
+        probe_result: &std::sync::Mutex<ProbeResult>,
+        bandwidth_allocator: &std::sync::Mutex<BandwidthAllocator>,
+    ) -> ProbeResult {
+        let mut result = *probe_result.lock().unwrap();
+        result.headroom = result.estimate.map(|estimate| {
+            let forwarded = bandwidth_allocator.lock().unwrap().total_bitrate();
+            estimate - forwarded
+        });
+        result
+    }
+
+    /// Collect stats of this transport for [`crate::router::Router::stats_snapshot`]. The source doesn't keep the transport alive.
+    pub(crate) fn stats_source(&self) -> TransportStatsSource {
+        let id = self.id.clone();
+        let rtcp_stats = Arc::downgrade(&self.subscriber_context.rtcp_stats);
+        let probe_result = Arc::downgrade(&self.probe_result);
+        let bandwidth_allocator = Arc::downgrade(&self.subscriber_context.bandwidth_allocator);
+        let subscribed_tracks = Arc::downgrade(&self.subscribed_tracks);
+        Box::new(move || {
+            let rtcp = *rtcp_stats.upgrade()?.lock().unwrap();
+            let probe = Self::headroom(&probe_result.upgrade()?, &bandwidth_allocator.upgrade()?);
+            let subscribers = subscribed_tracks
+                .upgrade()?
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|t| !t.subscriber.is_closed())
+                .map(|t| SubscriberStats {
+                    id: t.subscriber.id.clone(),
+                    publisher_id: t.subscriber.publisher_id(),
+                    kind: t.subscriber.kind(),
+                    rtcp: t.subscriber.rtcp_stats(),
+                    forwarding_latency: t.subscriber.forwarding_latency(),
+                })
+                .collect();
+            Some(TransportStats {
+                id: id.clone(),
+                kind: TransportKind::Subscribe,
+                rtcp,
+                probe: Some(probe),
+                subscribers,
+            })
+        })
+    }
+
+    /// This returns counters of RTCP packets which are received from the client for all subscribers of this transport.
+    pub fn rtcp_stats(&self) -> RtcpStats {
+        *self.subscriber_context.rtcp_stats.lock().unwrap()
     }
 
     // Hooks
@@ -330,22 +1206,130 @@ impl SubscribeTransport {
         *callback = f;
     }
 
+    /// Set a filter which drops or rewrites local ICE candidates before they are passed to the `on_ice_candidate` callback. For example, exclude internal interfaces or rewrite addresses to anycast IPs.
+    pub async fn on_local_candidate(&self, f: OnLocalCandidateFn) {
+        let mut filter = self.on_local_candidate_fn.lock().await;
+        *filter = f;
+    }
+
     /// Set callback function when the [`webrtc::peer_connection::RTCPeerConnection`] receives `on_negotiation_needed` events.
     pub async fn on_negotiation_needed(&self, f: OnNegotiationNeededFn) {
         let mut callback = self.on_negotiation_needed_fn.lock().await;
         *callback = f;
     }
 
+    /// Set callback function when the [`webrtc::peer_connection::RTCPeerConnection`] has failed. The [`ResumeHint`] can be handed to the reconnecting client.
+    pub async fn on_transport_failed(&self, f: OnTransportFailedFn) {
+        let mut callback = self.on_transport_failed_fn.lock().await;
+        *callback = f;
+    }
+
+    /// Set callback function when the signaling state of the [`webrtc::peer_connection::RTCPeerConnection`] changes.
+    pub async fn on_negotiation_state_change(&self, f: OnNegotiationStateChangeFn) {
+        let mut callback = self.on_negotiation_state_change_fn.lock().await;
+        *callback = f;
+    }
+
+    /// This returns the current negotiation state, for example to find a negotiation which is stuck waiting for the client.
+    pub fn negotiation_state(&self) -> NegotiationState {
+        self.negotiation
+            .state(self.peer_connection.signaling_state())
+    }
+
+    /// Set callback function when the score of [`ConnectionQuality`] changes.
+    pub async fn on_quality_change(&self, f: OnQualityChangeFn) {
+        let mut callback = self.on_quality_change_fn.lock().await;
+        *callback = f;
+    }
+
+    /// This returns the latest connection quality. It is `None` until stats are collected twice after the transport is connected.
+    pub fn quality(&self) -> Option<ConnectionQuality> {
+        self.quality.quality()
+    }
+
+    /// This returns the [`ResumeHint`] which is generated when the transport has failed.
+    pub fn resume_hint(&self) -> Option<ResumeHint> {
+        self.resume_hint.lock().unwrap().clone()
+    }
+
+    /// Close the transport. Video subscribers are stopped first and audio subscribers are stopped last, and RTCP BYE is sent for each SSRC.
+    /// So clients don't see a frozen last frame while the audio is still playing, and the audio is not cut in the middle of a word.
     pub async fn close(&self) -> Result<(), Error> {
-        self.closed_sender.send(true).unwrap();
+        let tracks = std::mem::take(&mut *self.subscribed_tracks.lock().unwrap());
+        let (video, audio): (Vec<_>, Vec<_>) = tracks
+            .into_iter()
+            .filter(|t| !t.subscriber.is_closed())
+            .partition(|t| t.subscriber.media_type == MediaType::Video);
+
+        self.close_subscribed_tracks(&video).await;
+        if !audio.is_empty() {
+            sleep(AUDIO_FLUSH_DELAY).await;
+            self.close_subscribed_tracks(&audio).await;
+        }
+
+        let _ = self.closed_sender.send(true);
+        self.credential_rotation.send_replace(None);
 
         self.peer_connection.close().await?;
         Ok(())
     }
 
-    fn adjust_extmap(mut sdp: RTCSessionDescription) -> Result<RTCSessionDescription, Error> {
+    async fn close_subscribed_tracks(&self, tracks: &[SubscribedTrack]) {
+        let mut sources = Vec::with_capacity(tracks.len());
+        for track in tracks.iter() {
+            track.subscriber.close().await;
+            let parameters = track.rtp_sender.get_parameters().await;
+            sources.extend(parameters.encodings.iter().map(|e| e.ssrc));
+        }
+
+        for chunk in sources.chunks(MAX_GOODBYE_SOURCES) {
+            let goodbye = Goodbye {
+                sources: chunk.to_vec(),
+                reason: Bytes::from_static(b"transport closed"),
+            };
+            tracing::debug!(
+                "SubscribeTransport {} sends RTCP BYE for ssrcs={:?}",
+                self.id,
+                chunk
+            );
+            if let Err(err) = self.peer_connection.write_rtcp(&[Box::new(goodbye)]).await {
+                tracing::warn!("failed to send RTCP BYE: {}", err);
+            }
+        }
+    }
+
+    /// Run [`SubscribeTransport::rewrite_offer`] on the blocking worker, because parsing and serializing SDP would stall RTP forwarding tasks of the executor.
+    async fn rewrite_offer_on_worker(
+        worker: &BlockingWorker,
+        cache: &SdpCache<RTCSessionDescription>,
+        offer: RTCSessionDescription,
+        congestion_feedback: CongestionFeedback,
+        bandwidths: Vec<(String, u32)>,
+    ) -> Result<RTCSessionDescription, Error> {
+        let key = sdp_cache_key(&offer.sdp, (congestion_feedback, &bandwidths));
+        cache
+            .get_or_run(worker, key, move || {
+                Self::rewrite_offer(offer, congestion_feedback, &bandwidths)
+            })
+            .await
+    }
+
+    /// Rewrite the offer before it is sent to the client. SDP is parsed and serialized only once for all steps, because it is a hot path when many clients join at once.
+    fn rewrite_offer(
+        mut sdp: RTCSessionDescription,
+        congestion_feedback: CongestionFeedback,
+        bandwidths: &[(String, u32)],
+    ) -> Result<RTCSessionDescription, Error> {
         let mut session = parse_sdp(&sdp.sdp, false)?;
+        Self::adjust_extmap(&mut session)?;
+        filter_session_congestion_feedback(&mut session, congestion_feedback)?;
+        set_session_bandwidths(&mut session, bandwidths);
+        tracing::trace!("updated session: {:#?}", session);
+        sdp.sdp = session.to_string();
+        Ok(sdp)
+    }
 
+    fn adjust_extmap(session: &mut SdpSession) -> Result<(), Error> {
         for media in session.media.iter_mut() {
             let mut found_attr = vec![];
             for attr in media.get_attributes() {
@@ -365,28 +1349,42 @@ impl SubscribeTransport {
                 };
             }
         }
-        tracing::trace!("updated session: {:#?}", session);
-        sdp.sdp = session.to_string();
-        Ok(sdp)
+        Ok(())
+    }
+}
+
+/// The first audio codec of the router. Opus is registered first when codecs are not configured.
+fn speaker_codec(media_config: &MediaConfig) -> RTCRtpCodecCapability {
+    match media_config.codec.audio.first() {
+        Some(codec) => codec.capability.clone(),
+        None => RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_string(),
+            clock_rate: 48000,
+            channels: 2,
+            sdp_fmtp_line: "minptime=10;useinbandfec=1".to_string(),
+            rtcp_feedback: vec![],
+        },
     }
 }
 
+/// Decide which slots are switched to which publishers. Slots which already forward a publisher in `top` are kept, so speakers are not moved between slots.
+fn assign_slots(current: &[String], top: &[String]) -> Vec<(usize, String)> {
+    let mut entering = top.iter().filter(|id| !current.contains(id));
+    current
+        .iter()
+        .enumerate()
+        .filter(|(_, id)| !top.contains(id))
+        .filter_map(|(slot, _)| entering.next().map(|id| (slot, id.clone())))
+        .collect()
+}
+
 impl PeerConnection for SubscribeTransport {}
 
 impl Transport for SubscribeTransport {
     async fn add_ice_candidate(&self, candidate: RTCIceCandidateInit) -> Result<(), Error> {
-        if let Some(_rd) = self.peer_connection.remote_description().await {
-            tracing::debug!("Adding ICE candidate for {:#?}", candidate);
-            let _ = self
-                .peer_connection
-                .add_ice_candidate(candidate.clone())
-                .await?;
-        } else {
-            tracing::debug!("Pending ICE candidate for {:#?}", candidate);
-            self.pending_candidates.lock().await.push(candidate.clone());
-        }
-
-        Ok(())
+        self.remote_candidates
+            .add(&self.peer_connection, candidate)
+            .await
     }
 }
 
@@ -403,6 +1401,23 @@ mod test {
     use webrtc_sdp::attribute_type::SdpAttributeExtmap;
 
     use super::*;
+    use crate::{
+        media_source::SilentSource,
+        router::Router,
+        transport::{client_candidate, client_local_description, client_peer_connection},
+    };
+
+    async fn subscribe_transport() -> (Arc<Mutex<Router>>, SubscribeTransport) {
+        let router = Router::new(MediaConfig::default());
+        let transport = {
+            let r = router.lock().await;
+            r.publish_source(SilentSource::new("first", 1)).unwrap();
+            r.publish_source(SilentSource::new("second", 2)).unwrap();
+            r.create_subscribe_transport(WebRTCTransportConfig::default())
+                .await
+        };
+        (router, transport)
+    }
 
     fn check_extmap_index(original_sdp_path: &str, correct_sdp_path: &str) {
         let original = fs::read_to_string(original_sdp_path)
@@ -411,7 +1426,8 @@ mod test {
             .expect(format!("failed to open {}", correct_sdp_path).as_str());
         let mut original_sdp = RTCSessionDescription::default();
         original_sdp.sdp = original;
-        let res = SubscribeTransport::adjust_extmap(original_sdp).expect("failed to adjust extmap");
+        let res = SubscribeTransport::rewrite_offer(original_sdp, CongestionFeedback::Both, &[])
+            .expect("failed to adjust extmap");
 
         let correct_session = parse_sdp(&correct, false).expect("failed to parse correct sdp");
         let response_session = parse_sdp(&res.sdp, false).expect("failed to parse response sdp");
@@ -497,4 +1513,192 @@ mod test {
             "./test_data/sdp_audio_video_correct",
         );
     }
+
+    #[test]
+    fn test_assign_slots() {
+        let ids = |ids: &[&str]| -> Vec<String> { ids.iter().map(|id| id.to_string()).collect() };
+        assert_eq!(
+            assign_slots(&ids(&["a", "b", "c"]), &ids(&["d", "b", "e"])),
+            vec![(0, "d".to_string()), (2, "e".to_string())]
+        );
+        assert_eq!(assign_slots(&ids(&["a", "b"]), &ids(&["b", "a"])), vec![]);
+        assert_eq!(
+            assign_slots(&ids(&["a", "b"]), &ids(&["c"])),
+            vec![(0, "c".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_after_deferred() {
+        let (_router, transport) = subscribe_transport().await;
+
+        transport
+            .subscribe_deferred("first".to_string())
+            .await
+            .unwrap();
+        assert!(transport.negotiation_deferred());
+
+        let (_, offer) = tokio::time::timeout(
+            Duration::from_secs(10),
+            transport.subscribe("second".to_string()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(!transport.negotiation_deferred());
+        // The deferred subscription is negotiated with the offer.
+        assert_eq!(offer.sdp.matches("m=audio").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_offer_without_filtered_candidates() {
+        let (_router, transport) = subscribe_transport().await;
+        transport.on_local_candidate(Box::new(|_| None)).await;
+
+        let (_, offer) = tokio::time::timeout(
+            Duration::from_secs(10),
+            transport.subscribe("first".to_string()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(!offer.sdp.contains("a=candidate:"));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_loudest_speakers_without_ranking() {
+        let (_router, transport) = subscribe_transport().await;
+
+        let (subscribers, offer) = tokio::time::timeout(
+            Duration::from_secs(10),
+            transport.subscribe_loudest_speakers(2, vec![]),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        // Nobody has spoken yet, so both slots wait for speakers.
+        assert_eq!(subscribers.len(), 2);
+        assert!(subscribers.iter().all(|s| s.publisher_id().is_empty()));
+        assert!(subscribers.iter().all(|s| !s.is_closed()));
+        assert_eq!(offer.sdp.matches("m=audio").count(), 2);
+        assert!(offer.sdp.contains("opus/48000/2"));
+    }
+
+    async fn client_answer(
+        client: &RTCPeerConnection,
+        offer: RTCSessionDescription,
+    ) -> RTCSessionDescription {
+        client.set_remote_description(offer).await.unwrap();
+        let answer = client.create_answer(None).await.unwrap();
+        client_local_description(client, answer).await
+    }
+
+    #[tokio::test]
+    async fn test_candidates_in_have_local_offer() {
+        let (_router, transport) = subscribe_transport().await;
+        let client = client_peer_connection().await;
+
+        let (_, offer) = transport.subscribe("first".to_string()).await.unwrap();
+        transport
+            .add_ice_candidate(client_candidate())
+            .await
+            .unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 1);
+
+        let answer = client_answer(&client, offer).await;
+        transport.set_answer(answer).await.unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_candidates_during_set_answer() {
+        let (_router, transport) = subscribe_transport().await;
+        let client = client_peer_connection().await;
+
+        let (_, offer) = transport.subscribe("first".to_string()).await.unwrap();
+        let answer = client_answer(&client, offer).await;
+        let (answered, added) = tokio::join!(
+            transport.set_answer(answer),
+            transport.add_ice_candidate(client_candidate())
+        );
+        answered.unwrap();
+        added.unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_candidates_during_renegotiation() {
+        let (_router, transport) = subscribe_transport().await;
+        let client = client_peer_connection().await;
+
+        let (_, offer) = transport.subscribe("first".to_string()).await.unwrap();
+        let answer = client_answer(&client, offer).await;
+        transport.set_answer(answer).await.unwrap();
+
+        // The remote description of the previous negotiation is used.
+        let (_, offer) = transport.subscribe("second".to_string()).await.unwrap();
+        transport
+            .add_ice_candidate(client_candidate())
+            .await
+            .unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 0);
+        let answer = client_answer(&client, offer).await;
+        transport.set_answer(answer).await.unwrap();
+
+        // Candidates of new credentials wait for the answer of the ICE restart.
+        let offer = transport.rotate_credentials().await.unwrap();
+        transport
+            .add_ice_candidate(client_candidate())
+            .await
+            .unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 1);
+        let answer = client_answer(&client, offer).await;
+        transport.set_answer(answer).await.unwrap();
+        assert_eq!(transport.remote_candidates.pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_error_finishes_negotiation() {
+        let (_router, transport) = subscribe_transport().await;
+        transport.peer_connection.close().await.unwrap();
+
+        assert!(transport.subscribe("first".to_string()).await.is_err());
+        assert!(!transport.negotiation.is_pending());
+        assert!(transport.subscribed_tracks.lock().unwrap().is_empty());
+
+        // The next negotiation doesn't wait for the failed one.
+        let res = tokio::time::timeout(
+            Duration::from_secs(10),
+            transport.subscribe("second".to_string()),
+        )
+        .await
+        .unwrap();
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_toggle_credential_rotation() {
+        let (_router, transport) = subscribe_transport().await;
+        let interval = Some(Duration::from_secs(3600));
+
+        // Each loop of the rotation holds a receiver of the interval.
+        transport.set_credential_rotation(interval);
+        transport.set_credential_rotation(None);
+        transport.set_credential_rotation(interval);
+        assert_eq!(transport.credential_rotation.receiver_count(), 1);
+
+        transport.set_credential_rotation(None);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while transport.credential_rotation.receiver_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!transport.credential_rotation_running.load(Ordering::SeqCst));
+
+        transport.set_credential_rotation(interval);
+        assert_eq!(transport.credential_rotation.receiver_count(), 1);
+        transport.set_credential_rotation(None);
+    }
 }
@@ -1,68 +1,449 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use chrono::Utc;
 use enclose::enc;
-use tokio::{sync::broadcast, time::sleep};
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tracing::Instrument;
 use uuid::Uuid;
 use webrtc::{
     rtcp::{
         self,
         header::{PacketType, FORMAT_PLI, FORMAT_REMB},
-        payload_feedbacks::picture_loss_indication::PictureLossIndication,
+        raw_packet::RawPacket,
     },
     rtp,
-    rtp_transceiver::rtp_sender::RTCRtpSender,
+    rtp_transceiver::{
+        rtp_codec::RTCRtpCodecCapability, rtp_sender::RTCRtpSender, RTCRtpTransceiver,
+    },
     track::track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocalWriter},
 };
 
 use crate::{
-    publisher::{detect_mime_type, MediaType},
+    bandwidth::{BandwidthAllocator, RembShaper},
+    error::{Error, SubscriberErrorKind},
+    feedback::{rewrite_media_ssrc, FORMAT_LRR},
+    grant::GrantGuard,
+    keyframe::{KeyframeFilter, KeyframeOnly, KeyframeRequester},
+    packet_channel::{self, PacketChannel, PacketReceiver, PacketSender},
+    publisher::{ForwardingPolicy, MediaType, Publisher, ReceivedPacket},
+    rtp_extension::ExtensionRewriter,
+    runtime,
+    stats::{LatencyRecorder, LatencyStats, RtcpCounter, RtcpStats},
+    subscribe_transport::SubscriberContext,
     transport,
 };
 
+/// Max number of duplicated audio packets which wait for their spacing. It covers a spacing of 1 second at 50 packets per second, and more packets are dropped instead of buffering without bound.
+const DUPLICATE_CAPACITY: usize = 64;
+
+type DuplicatePacket = (Instant, rtp::packet::Packet);
+
 #[derive(Clone, Debug)]
 pub struct Subscriber {
     pub id: String,
     closed_sender: broadcast::Sender<bool>,
+    // Set when the subscriber is closed or forwarding has finished, so it doesn't depend on when event loops drop receivers.
+    closed: Arc<AtomicBool>,
+    bandwidth_allocator: Arc<std::sync::Mutex<BandwidthAllocator>>,
+    pub(crate) media_type: MediaType,
+    codec: RTCRtpCodecCapability,
+    transport_id: String,
+    source: watch::Sender<SubscriberSource>,
+    switch_sender: mpsc::UnboundedSender<SourceSwitch>,
+    paused: Arc<AtomicBool>,
+    negotiated_extensions: watch::Sender<Option<Vec<u8>>>,
+    pub(crate) audio_program: watch::Sender<Option<String>>,
+    rtcp_counter: RtcpCounter,
+    forwarding_latency: LatencyRecorder,
+    // Bitrate in bps which is written to offers as b= lines. 0 means no hint.
+    bandwidth_hint: Arc<AtomicU32>,
+    grant: GrantGuard,
+    keyframe_only: watch::Sender<KeyframeOnly>,
+    // Spacing of duplicated audio packets. None means packets are sent once.
+    duplication: watch::Sender<Option<Duration>>,
+    // Channels of the placeholder source while the subscriber waits for a publisher.
+    _idle: Option<Arc<IdleSource>>,
+}
+
+/// What feeds a [`Subscriber`] when it is created.
+pub(crate) enum InitialSource<'a> {
+    Publisher(&'a Publisher),
+    /// The subscriber waits for a publisher with the codec, for example an empty slot of the loudest speakers. Nothing is forwarded until it is switched to a publisher.
+    Idle(RTCRtpCodecCapability),
+}
+
+/// Channels of a placeholder source. They are kept open, so the subscriber is not finished while it waits for a publisher.
+#[derive(Debug)]
+struct IdleSource {
+    _packet_sender: PacketSender,
+    _rtcp_receiver: transport::RtcpReceiver,
+}
+
+/// Publisher which feeds the subscriber now. This doesn't keep the RTP sender of the publisher, so the subscriber is finished when the publisher is dropped.
+#[derive(Clone, Debug)]
+pub(crate) struct SubscriberSource {
+    pub(crate) publisher_id: String,
+    media_ssrc: u32,
+    rtcp_sender: Arc<transport::RtcpSender>,
+    keyframe_requester: KeyframeRequester,
+    forwarding_policy: Arc<Mutex<ForwardingPolicy>>,
+    forwarding_policy_changed: watch::Sender<()>,
+}
+
+impl SubscriberSource {
+    fn idle() -> (Self, PacketReceiver, IdleSource) {
+        let (packet_channel, packet_sender) =
+            PacketChannel::new(packet_channel::MIN_CAPACITY, None);
+        let (rtcp_sender, rtcp_receiver) = mpsc::unbounded_channel();
+        let rtcp_sender = Arc::new(rtcp_sender);
+        let source = Self {
+            publisher_id: String::new(),
+            media_ssrc: 0,
+            keyframe_requester: KeyframeRequester::new(0, rtcp_sender.clone()),
+            rtcp_sender,
+            forwarding_policy: Arc::new(Mutex::new(ForwardingPolicy::default())),
+            forwarding_policy_changed: watch::channel(()).0,
+        };
+        let idle = IdleSource {
+            _packet_sender: packet_sender,
+            _rtcp_receiver: rtcp_receiver,
+        };
+        (source, packet_channel.subscribe(), idle)
+    }
+
+    fn new(publisher: &Publisher) -> Self {
+        Self {
+            publisher_id: publisher.id.clone(),
+            media_ssrc: publisher.ssrc(),
+            rtcp_sender: publisher.rtcp_sender.clone(),
+            keyframe_requester: publisher.keyframe_requester(),
+            forwarding_policy: publisher.forwarding_policy.clone(),
+            forwarding_policy_changed: publisher.forwarding_policy_changed.clone(),
+        }
+    }
+
+    fn request_keyframe(&self) {
+        self.keyframe_requester.request();
+    }
+}
+
+/// RTP packets of the new publisher, which is sent to the RTP event loop when the publisher is switched.
+pub(crate) struct SourceSwitch {
+    rtp_receiver: PacketReceiver,
+    extension_rewriter: ExtensionRewriter,
+}
+
+/// Priority of a [`Subscriber`] when the bandwidth of the [`crate::subscribe_transport::SubscribeTransport`] is shared among video subscribers.
+/// It is the weight of the share which is allocated to the subscriber, and the share is applied through REMB to the publisher. Packets are not reordered or paced by priority.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SubscriberPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl SubscriberPriority {
+    pub(crate) fn weight(&self) -> f32 {
+        match self {
+            SubscriberPriority::Low => 1.0,
+            SubscriberPriority::Normal => 2.0,
+            SubscriberPriority::High => 4.0,
+        }
+    }
+}
+
+/// Per-subscriber state to rewrite RTP packets from the publisher and write them to the local track.
+pub(crate) struct RtpForwarder {
+    local_track: Arc<TrackLocalStaticRTP>,
+    transceiver: Option<Arc<RTCRtpTransceiver>>,
+    mid: Option<String>,
+    extension_rewriter: ExtensionRewriter,
+    // Extension ids which are negotiated by the subscriber. It is known after the subscriber answers.
+    negotiated_extensions: watch::Receiver<Option<Vec<u8>>>,
+    sent_bytes: Arc<AtomicU64>,
+    timestamp_rewriter: TimestampRewriter,
+    paused: Arc<AtomicBool>,
+    // Difference between sequence numbers of the publisher and the subscriber, to keep sequence numbers continuous when packets are not forwarded or the publisher is switched.
+    sequence_offset: u16,
+    last_sequence_number: Option<u16>,
+    source_switched: bool,
+    forwarding_latency: LatencyRecorder,
+    keyframe_only: watch::Receiver<KeyframeOnly>,
+    keyframe_filter: KeyframeFilter,
+    duplication: watch::Receiver<Option<Duration>>,
+    // Duplicated packets and the time when they are sent. It is None for video subscribers.
+    duplicate_sender: Option<mpsc::Sender<DuplicatePacket>>,
+}
+
+impl RtpForwarder {
+    async fn forward(&mut self, received: ReceivedPacket) -> Result<(), webrtc::Error> {
+        let ReceivedPacket {
+            mut packet,
+            received_at,
+        } = received;
+        packet.header.timestamp = self
+            .timestamp_rewriter
+            .rewrite(packet.header.timestamp, received_at);
+
+        if self.source_switched {
+            self.source_switched = false;
+            if let Some(last) = self.last_sequence_number {
+                self.sequence_offset = packet
+                    .header
+                    .sequence_number
+                    .wrapping_sub(last.wrapping_add(1));
+            }
+        }
+        let keyframe_only = *self.keyframe_only.borrow();
+        if self.paused.load(Ordering::Relaxed)
+            || !self.keyframe_filter.accept(
+                keyframe_only,
+                packet.header.timestamp,
+                &packet.payload,
+                received_at,
+            )
+        {
+            self.sequence_offset = self.sequence_offset.wrapping_add(1);
+            return Ok(());
+        }
+        packet.header.sequence_number = packet
+            .header
+            .sequence_number
+            .wrapping_sub(self.sequence_offset);
+        self.last_sequence_number = Some(packet.header.sequence_number);
+
+        if self.mid.is_none() {
+            self.mid = self
+                .transceiver
+                .as_ref()
+                .and_then(|t| t.mid())
+                .map(|mid| mid.to_string());
+        }
+        if self.negotiated_extensions.has_changed().unwrap_or(false) {
+            if let Some(negotiated) = self.negotiated_extensions.borrow_and_update().as_ref() {
+                self.extension_rewriter.restrict(negotiated);
+            }
+        }
+        self.extension_rewriter
+            .rewrite(&mut packet.header, self.mid.as_deref());
+
+        self.local_track.write_rtp(&packet).await?;
+        self.forwarding_latency.record(received_at.elapsed());
+        self.sent_bytes
+            .fetch_add(packet.payload.len() as u64, Ordering::Relaxed);
+        if let (Some(spacing), Some(duplicate_sender)) =
+            (*self.duplication.borrow(), &self.duplicate_sender)
+        {
+            schedule_duplicate(duplicate_sender, Instant::now() + spacing, packet);
+        }
+        Ok(())
+    }
+
+    fn switch_source(&mut self, extension_rewriter: ExtensionRewriter) {
+        self.extension_rewriter = extension_rewriter;
+        if let Some(negotiated) = self.negotiated_extensions.borrow().as_ref() {
+            self.extension_rewriter.restrict(negotiated);
+        }
+        self.source_switched = true;
+        self.timestamp_rewriter.reset();
+    }
+}
+
+/// Rewrite RTP timestamps of the publisher to the timeline of the subscriber.
+/// The timeline starts at the epoch of the subscribe transport and advances at the clock rate of the codec, so audio and video tracks of a participant stay aligned even if they are subscribed or switched at different times.
+#[derive(Debug)]
+pub(crate) struct TimestampRewriter {
+    clock_rate: u32,
+    epoch: Instant,
+    // Difference between timestamps of the subscriber and the publisher. It is anchored at the first packet after the source is bound.
+    offset: Option<u32>,
+    last_timestamp: Option<u32>,
+}
+
+impl TimestampRewriter {
+    pub(crate) fn new(clock_rate: u32, epoch: Instant) -> Self {
+        Self {
+            clock_rate,
+            epoch,
+            offset: None,
+            last_timestamp: None,
+        }
+    }
+
+    pub(crate) fn rewrite(&mut self, timestamp: u32, received_at: Instant) -> u32 {
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => {
+                let offset = self.anchor(received_at).wrapping_sub(timestamp);
+                self.offset = Some(offset);
+                offset
+            }
+        };
+        let rewritten = timestamp.wrapping_add(offset);
+        self.last_timestamp = Some(rewritten);
+        rewritten
+    }
+
+    /// Timestamps are anchored again at the next packet, because the new source has unrelated timestamps.
+    pub(crate) fn reset(&mut self) {
+        self.offset = None;
+    }
+
+    fn anchor(&self, received_at: Instant) -> u32 {
+        let elapsed = received_at.saturating_duration_since(self.epoch);
+        let anchor = (elapsed.as_micros() * self.clock_rate as u128 / 1_000_000) as u32;
+        match self.last_timestamp {
+            // The clock of the previous source may run faster than ours, so timestamps never go backwards.
+            Some(last) if (anchor.wrapping_sub(last) as i32) <= 0 => last.wrapping_add(1),
+            _ => anchor,
+        }
+    }
 }
 
 impl Subscriber {
     pub(crate) fn new(
         local_track: Arc<TrackLocalStaticRTP>,
-        rtp_sender: broadcast::Sender<rtp::packet::Packet>,
         rtcp_sender: Arc<RTCRtpSender>,
-        publisher_rtcp_sender: Arc<transport::RtcpSender>,
-        mime_type: String,
-        media_ssrc: u32,
+        initial_source: InitialSource,
+        transceiver: Option<Arc<RTCRtpTransceiver>>,
+        extension_rewriter: ExtensionRewriter,
+        context: SubscriberContext,
     ) -> Self {
         let id = Uuid::new_v4().to_string();
         let (tx, _rx) = broadcast::channel::<bool>(1);
+        let closed = Arc::new(AtomicBool::new(false));
+        let (initial, rtp_receiver, codec, bandwidth_hint, idle) = match initial_source {
+            InitialSource::Publisher(publisher) => (
+                SubscriberSource::new(publisher),
+                publisher.packet_channel.subscribe(),
+                publisher.codec(),
+                publisher.bandwidth_hint(),
+                None,
+            ),
+            InitialSource::Idle(codec) => {
+                let (source, rtp_receiver, idle) = SubscriberSource::idle();
+                (source, rtp_receiver, codec, None, Some(Arc::new(idle)))
+            }
+        };
+        let media_ssrc = initial.media_ssrc;
+        let media_type = MediaType::from_mime_type(&codec.mime_type);
+        let (source, _) = watch::channel(initial);
+        let (switch_sender, switch_receiver) = mpsc::unbounded_channel();
+        let bandwidth_allocator = context.bandwidth_allocator.clone();
+        let sent_bytes = {
+            let mut allocator = bandwidth_allocator.lock().unwrap();
+            let sent_bytes = allocator.register(id.clone(), media_type);
+            allocator.set_bandwidth_hint(&id, bandwidth_hint);
+            sent_bytes
+        };
+        let paused = Arc::new(AtomicBool::new(false));
+        let forwarding_latency = LatencyRecorder::default();
+        let (keyframe_only, keyframe_only_receiver) = watch::channel(KeyframeOnly::default());
+        let (negotiated_extensions, negotiated_extensions_receiver) = watch::channel(None);
+        let (duplication, duplication_receiver) = watch::channel(None);
+        let (duplicate_sender, duplicate_receiver) = if media_type == MediaType::Audio {
+            let (sender, receiver) = mpsc::channel(DUPLICATE_CAPACITY);
+            (Some(sender), Some(receiver))
+        } else {
+            (None, None)
+        };
+        if let Some(duplicate_receiver) = duplicate_receiver {
+            let local_track = local_track.clone();
+            let sent_bytes = sent_bytes.clone();
+            runtime::spawn_on(
+                context.forwarding_runtime.as_ref(),
+                async move {
+                    Self::duplicate_event_loop(local_track, duplicate_receiver, sent_bytes).await;
+                }
+                .in_current_span(),
+            );
+        }
+        let forwarder = RtpForwarder {
+            local_track,
+            transceiver,
+            mid: None,
+            extension_rewriter,
+            negotiated_extensions: negotiated_extensions_receiver,
+            sent_bytes,
+            timestamp_rewriter: TimestampRewriter::new(codec.clock_rate, context.timestamp_epoch),
+            paused: paused.clone(),
+            sequence_offset: 0,
+            last_sequence_number: None,
+            source_switched: false,
+            forwarding_latency: forwarding_latency.clone(),
+            keyframe_only: keyframe_only_receiver,
+            keyframe_filter: KeyframeFilter::new(
+                context.keyframe_detectors.clone(),
+                codec.mime_type.clone(),
+            ),
+            duplication: duplication_receiver,
+            duplicate_sender,
+        };
+        let remb_shaper = RembShaper::new(
+            id.clone(),
+            media_type,
+            bandwidth_allocator.clone(),
+            context.remb_policy.clone(),
+        );
+        // REMB is not forwarded to publishers when only TWCC is negotiated.
+        let forward_remb = context.congestion_feedback.remb();
+        let rtcp_counter = RtcpCounter::new(context.rtcp_stats.clone());
+
+        {
+            let tx = tx.clone();
+            let closed = closed.clone();
+            let id = id.clone();
+            let source = source.subscribe();
+            runtime::spawn_on(
+                context.forwarding_runtime.as_ref(),
+                async move {
+                    Self::rtp_event_loop(
+                        id,
+                        forwarder,
+                        rtp_receiver,
+                        switch_receiver,
+                        source,
+                        tx,
+                        closed,
+                    )
+                    .await;
+                }
+                .in_current_span(),
+            );
+        }
 
         {
             let tx = tx.clone();
             let id = id.clone();
-            let media_ssrc = media_ssrc.clone();
-            let publisher_rtcp_sender = publisher_rtcp_sender.clone();
-            tokio::spawn(async move {
-                Self::rtp_event_loop(
-                    id,
-                    media_ssrc,
-                    local_track,
-                    rtp_sender,
-                    tx,
-                    publisher_rtcp_sender,
-                )
-                .await;
-            });
+            let source = source.subscribe();
+            runtime::spawn_on(
+                context.forwarding_runtime.as_ref(),
+                enc!((rtcp_sender, rtcp_counter) async move {
+                    Self::rtcp_event_loop(id, rtcp_sender, source, remb_shaper, forward_remb, rtcp_counter, tx).await;
+                })
+                .in_current_span(),
+            );
         }
 
         {
             let tx = tx.clone();
+            let closed = closed.clone();
             let id = id.clone();
-            let media_ssrc = media_ssrc.clone();
-            tokio::spawn(enc!((rtcp_sender, publisher_rtcp_sender) async move {
-                Self::rtcp_event_loop(id, media_ssrc, rtcp_sender, publisher_rtcp_sender, mime_type, tx).await;
-            }));
+            let transport_id = context.transport_id.clone();
+            let source = source.subscribe();
+            runtime::spawn_on(
+                context.forwarding_runtime.as_ref(),
+                async move {
+                    Self::policy_event_loop(id, transport_id, source, tx, closed).await;
+                }
+                .in_current_span(),
+            );
         }
 
         tracing::debug!(
@@ -74,21 +455,57 @@ impl Subscriber {
         Self {
             id,
             closed_sender: tx,
+            closed,
+            bandwidth_allocator,
+            media_type,
+            codec,
+            transport_id: context.transport_id,
+            source,
+            switch_sender,
+            paused,
+            negotiated_extensions,
+            audio_program: watch::channel(None).0,
+            rtcp_counter,
+            forwarding_latency,
+            bandwidth_hint: Arc::new(AtomicU32::new(bandwidth_hint.unwrap_or(0))),
+            grant: context.grant,
+            keyframe_only,
+            duplication,
+            _idle: idle,
+        }
+    }
+
+    /// Send duplicated audio packets when they are due. The loop is finished when the RTP event loop is finished.
+    async fn duplicate_event_loop(
+        local_track: Arc<TrackLocalStaticRTP>,
+        mut duplicate_receiver: mpsc::Receiver<DuplicatePacket>,
+        sent_bytes: Arc<AtomicU64>,
+    ) {
+        while let Some((due, packet)) = duplicate_receiver.recv().await {
+            runtime::sleep_until(due).await;
+            match local_track.write_rtp(&packet).await {
+                Ok(_) => {
+                    sent_bytes.fetch_add(packet.payload.len() as u64, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    tracing::trace!("failed to write duplicated rtp: {}", err);
+                }
+            }
         }
     }
 
     pub(crate) async fn rtp_event_loop(
         id: String,
-        media_ssrc: u32,
-        local_track: Arc<TrackLocalStaticRTP>,
-        rtp_sender: broadcast::Sender<rtp::packet::Packet>,
+        mut forwarder: RtpForwarder,
+        mut rtp_receiver: PacketReceiver,
+        mut switch_receiver: mpsc::UnboundedReceiver<SourceSwitch>,
+        source: watch::Receiver<SubscriberSource>,
         subscriber_closed_sender: broadcast::Sender<bool>,
-        publisher_rtcp_sender: Arc<transport::RtcpSender>,
+        closed: Arc<AtomicBool>,
     ) {
-        let mut rtp_receiver = rtp_sender.subscribe();
-        drop(rtp_sender);
         let mut subscriber_closed = subscriber_closed_sender.subscribe();
         drop(subscriber_closed_sender);
+        let media_ssrc = source.borrow().media_ssrc;
 
         tracing::debug!(
             "Subscriber id={} publisher_ssrc={} RTP event loop has started",
@@ -96,32 +513,30 @@ impl Subscriber {
             media_ssrc
         );
 
-        let mut current_timestamp = 0;
-
         loop {
             tokio::select! {
                 _ = subscriber_closed.recv() => {
                     break;
                 }
+                Some(switch) = switch_receiver.recv() => {
+                    rtp_receiver = switch.rtp_receiver;
+                    forwarder.switch_source(switch.extension_rewriter);
+                }
                 res = rtp_receiver.recv() => {
-                    if publisher_rtcp_sender.is_closed() {
+                    if source.borrow().rtcp_sender.is_closed() {
                         break;
                     }
                     match res {
-                        Ok(mut packet) => {
-                            current_timestamp += packet.header.timestamp;
-                            packet.header.timestamp = current_timestamp;
-
+                        Ok(packet) => {
                             tracing::trace!(
                                 "Subscriber id={} write RTP ssrc={} seq={} timestamp={}",
                                 id,
-                                packet.header.ssrc,
-                                packet.header.sequence_number,
-                                packet.header.timestamp
+                                packet.packet.header.ssrc,
+                                packet.packet.header.sequence_number,
+                                packet.packet.header.timestamp
                             );
 
-
-                            if let Err(err) = local_track.write_rtp(&packet).await {
+                            if let Err(err) = forwarder.forward(packet).await {
                                 tracing::error!("Subscriber id={} failed to write rtp: {}", id, err)
                             }
                         }
@@ -132,11 +547,13 @@ impl Subscriber {
                             tracing::error!("Subscriber id={} failed to read rtp: {}", id, err);
                         }
                     }
-                    sleep(Duration::from_millis(1)).await;
                 }
             }
         }
 
+        // Nothing is forwarded anymore, for example the publisher has been closed.
+        closed.store(true, Ordering::Relaxed);
+
         tracing::debug!(
             "Subscriber id={} publisher_ssrc={} RTP event loop has finished",
             id,
@@ -146,21 +563,20 @@ impl Subscriber {
 
     pub(crate) async fn rtcp_event_loop(
         id: String,
-        media_ssrc: u32,
         rtcp_sender: Arc<RTCRtpSender>,
-        publisher_rtcp_sender: Arc<transport::RtcpSender>,
-        mime_type: String,
+        source: watch::Receiver<SubscriberSource>,
+        mut remb_shaper: RembShaper,
+        forward_remb: bool,
+        rtcp_counter: RtcpCounter,
         subscriber_closed_sender: broadcast::Sender<bool>,
     ) {
         let mut subscriber_closed = subscriber_closed_sender.subscribe();
         drop(subscriber_closed_sender);
 
-        let media_type = detect_mime_type(mime_type);
-        let start_timestamp = Utc::now();
         tracing::debug!(
             "Subscriber id={} publisher_ssrc={} RTCP event loop has started",
             id,
-            media_ssrc
+            source.borrow().media_ssrc
         );
 
         loop {
@@ -169,11 +585,14 @@ impl Subscriber {
                     break;
                 }
                 res = rtcp_sender.read_rtcp() => {
+                    // RTCP is sent to the publisher which feeds the subscriber now.
+                    let SubscriberSource { rtcp_sender: publisher_rtcp_sender, keyframe_requester, media_ssrc, .. } = source.borrow().clone();
                     if publisher_rtcp_sender.is_closed() {
                         break;
                     }
                     match res {
                         Ok((rtcp_packets, attr)) => {
+                            rtcp_counter.record(&rtcp_packets);
                             for rtcp in rtcp_packets.into_iter() {
                                 tracing::trace!("Receive RTCP subscriber={} rtcp={:#?}, attr={:#?}", id, rtcp, attr);
 
@@ -194,35 +613,26 @@ impl Subscriber {
                                     PacketType::PayloadSpecificFeedback => match header.count {
                                         FORMAT_PLI => {
                                             if let Some(_pli) = rtcp.as_any().downcast_ref::<rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication>() {
-                                                match publisher_rtcp_sender.send(Box::new(PictureLossIndication {
-                                                    sender_ssrc: 0,
-                                                    media_ssrc,
-                                                })) {
-                                                    Ok(_) => tracing::trace!("send rtcp: pli"),
-                                                    Err(err) => tracing::error!("Subscriber id ={} failed to send rtcp pli: {}", id, err)
+                                                keyframe_requester.request();
+                                            }
+                                        }
+                                        // Loss notifications let the encoder choose a reference frame which the subscriber has received, instead of sending a keyframe.
+                                        // They share the format with REMB, and they are kept as raw packets by the interceptor.
+                                        FORMAT_REMB if rtcp.as_any().is::<RawPacket>() => {
+                                            if let Some(raw) = rtcp.as_any().downcast_ref::<RawPacket>() {
+                                                if let Some(lntf) = rewrite_media_ssrc(raw, media_ssrc) {
+                                                    match publisher_rtcp_sender.send(Box::new(lntf)) {
+                                                        Ok(_) => tracing::trace!("send rtcp: lntf"),
+                                                        Err(err) => tracing::error!("Subscriber id={} failed to send rtcp lntf: {}", id, err),
+                                                    }
                                                 }
                                             }
                                         }
-                                        FORMAT_REMB => {
+                                        FORMAT_REMB if forward_remb => {
                                             if let Some(remb) = rtcp.as_any().downcast_ref::<rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate>() {
 
                                                 let mut remb = remb.clone();
-                                                let diff = Utc::now() - start_timestamp;
-                                                if diff.num_seconds() < 30 {
-                                                    // Min bitrate is 128kbps if it is video and first 30seconds.
-                                                    match media_type {
-                                                        MediaType::Video => {
-                                                            if remb.bitrate < 128000.0 {
-                                                                remb.bitrate = 128000.0;
-                                                            }
-                                                        }
-                                                        MediaType::Audio => {
-                                                            if remb.bitrate < 64000.0 {
-                                                                remb.bitrate = 640000.0
-                                                            }
-                                                        }
-                                                    }
-                                                }
+                                                remb.bitrate = remb_shaper.shape(remb.bitrate);
 
                                                 match publisher_rtcp_sender.send(Box::new(remb)) {
                                                     Ok(_) => tracing::trace!("send rtcp: remb"),
@@ -230,6 +640,17 @@ impl Subscriber {
                                                 }
                                             }
                                         }
+                                        // Layer refresh requests let the encoder refresh only the lost layer, instead of sending a full keyframe.
+                                        FORMAT_LRR => {
+                                            if let Some(raw) = rtcp.as_any().downcast_ref::<RawPacket>() {
+                                                if let Some(lrr) = rewrite_media_ssrc(raw, media_ssrc) {
+                                                    match publisher_rtcp_sender.send(Box::new(lrr)) {
+                                                        Ok(_) => tracing::trace!("send rtcp: lrr"),
+                                                        Err(err) => tracing::error!("Subscriber id={} failed to send rtcp lrr: {}", id, err),
+                                                    }
+                                                }
+                                            }
+                                        }
                                         _ => {}
                                     },
                                     _ => {}
@@ -254,12 +675,220 @@ impl Subscriber {
         tracing::debug!(
             "Subscriber id={} publisher_ssrc={} RTCP event loop finished",
             id,
-            media_ssrc
+            source.borrow().media_ssrc
         );
     }
 
+    /// This closes the subscriber when the forwarding policy of the publisher is changed and the transport is no longer allowed.
+    pub(crate) async fn policy_event_loop(
+        id: String,
+        transport_id: String,
+        mut source: watch::Receiver<SubscriberSource>,
+        subscriber_closed_sender: broadcast::Sender<bool>,
+        closed: Arc<AtomicBool>,
+    ) {
+        let mut subscriber_closed = subscriber_closed_sender.subscribe();
+        let mut policy_changed = source.borrow().forwarding_policy_changed.subscribe();
+
+        loop {
+            tokio::select! {
+                _ = subscriber_closed.recv() => {
+                    break;
+                }
+                res = source.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                    policy_changed = source.borrow_and_update().forwarding_policy_changed.subscribe();
+                }
+                res = policy_changed.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                    let policy = source.borrow().forwarding_policy.clone();
+                    let policy = policy.lock().await.clone();
+                    if !policy.is_allowed(&transport_id).await {
+                        tracing::debug!("Subscriber id={} is closed by forwarding policy", id);
+                        closed.store(true, Ordering::Relaxed);
+                        let _ = subscriber_closed_sender.send(true);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Set the priority which is used to share the bandwidth of the transport among video subscribers.
+    pub fn set_priority(&self, priority: SubscriberPriority) {
+        self.bandwidth_allocator
+            .lock()
+            .unwrap()
+            .set_priority(&self.id, priority);
+    }
+
+    /// Set the bitrate in bps which is expected for the subscriber. It is the initial bandwidth estimate of the subscriber before the forwarded bitrate is measured, and it caps the share of the transport bandwidth.
+    /// It is also written as `b=AS` and `b=TIAS` lines to the media section of the subscriber in the next offer of the transport. By default, the bitrate which the publisher announces is used.
+    pub fn set_bandwidth_hint(&self, bandwidth_hint: Option<u32>) {
+        self.bandwidth_hint
+            .store(bandwidth_hint.unwrap_or(0), Ordering::Relaxed);
+        self.bandwidth_allocator
+            .lock()
+            .unwrap()
+            .set_bandwidth_hint(&self.id, bandwidth_hint);
+    }
+
+    pub fn bandwidth_hint(&self) -> Option<u32> {
+        match self.bandwidth_hint.load(Ordering::Relaxed) {
+            0 => None,
+            bitrate => Some(bitrate),
+        }
+    }
+
+    /// Stop or restart forwarding media to the subscriber without renegotiation. A keyframe is requested to the publisher when a video subscriber is resumed.
+    pub fn set_paused(&self, paused: bool) {
+        let was_paused = self.paused.swap(paused, Ordering::Relaxed);
+        if was_paused && !paused && self.media_type == MediaType::Video {
+            self.source.borrow().request_keyframe();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Forward only keyframes to the subscriber, for example for thumbnails of a grid overview, without an extra simulcast layer. A keyframe is requested when it is enabled.
+    /// Keyframes are found by [`crate::keyframe::KeyframeDetectors`] of the router, so audio subscribers and codecs without a detector are not affected.
+    pub fn set_keyframe_only(&self, keyframe_only: bool) {
+        let was_keyframe_only = self.keyframe_only.borrow().enabled;
+        self.keyframe_only
+            .send_modify(|mode| mode.enabled = keyframe_only);
+        if !was_keyframe_only && keyframe_only && self.media_type == MediaType::Video {
+            self.source.borrow().request_keyframe();
+        }
+    }
+
+    pub fn is_keyframe_only(&self) -> bool {
+        self.keyframe_only.borrow().enabled
+    }
+
+    /// Set the minimum interval between keyframes in the keyframe-only mode, for example 1 second to limit thumbnails to 1 fps.
+    /// Keyframes are not requested periodically, so thumbnails are refreshed at the keyframe interval of the publisher.
+    pub fn set_keyframe_only_interval(&self, min_interval: Option<Duration>) {
+        self.keyframe_only
+            .send_modify(|mode| mode.min_interval = min_interval);
+    }
+
+    /// Send each audio packet twice, the second time `spacing` after the first one, for subscribers on very lossy links.
+    /// A burst of loss shorter than the spacing doesn't drop the audio, at the cost of doubling the audio bitrate. Duplicated packets have the same sequence number, so the client discards the second one when the first one arrives.
+    /// The spacing should be shorter than the jitter buffer of the client, for example 40 ms. Video subscribers are not affected.
+    pub fn set_audio_duplication(&self, spacing: Option<Duration>) {
+        self.duplication.send_replace(spacing);
+    }
+
+    pub fn audio_duplication(&self) -> Option<Duration> {
+        *self.duplication.borrow()
+    }
+
+    pub fn kind(&self) -> MediaType {
+        self.media_type
+    }
+
+    /// This returns the [`crate::publisher::Publisher`] ID which feeds the subscriber now.
+    pub fn publisher_id(&self) -> String {
+        self.source.borrow().publisher_id.clone()
+    }
+
+    /// Switch the publisher which feeds the subscriber without renegotiation. The new publisher must have the same codec as the current one, because the negotiated track of the subscriber is not changed.
+    /// Sequence numbers and timestamps are kept continuous for the subscriber, and a keyframe is requested when the subscriber is video.
+    pub async fn switch_publisher(&self, publisher: &Publisher) -> Result<(), Error> {
+        if self.publisher_id() == publisher.id {
+            return Ok(());
+        }
+        self.bind_publisher(publisher).await
+    }
+
+    /// Bind the subscriber to channels of the publisher. Unlike [`Subscriber::switch_publisher`], this is done even if the publisher has the same ID, because a publisher can be removed and published again with the same ID.
+    pub(crate) async fn bind_publisher(&self, publisher: &Publisher) -> Result<(), Error> {
+        let codec = publisher.codec();
+        if !codec.mime_type.eq_ignore_ascii_case(&self.codec.mime_type)
+            || codec.clock_rate != self.codec.clock_rate
+            || codec.channels != self.codec.channels
+        {
+            return Err(Error::new_subscriber(
+                format!(
+                    "Publisher {} has {}, but subscriber {} has {}",
+                    publisher.id, codec.mime_type, self.id, self.codec.mime_type
+                ),
+                SubscriberErrorKind::CodecMismatchError,
+            ));
+        }
+        if !publisher.is_allowed(&self.transport_id).await {
+            return Err(Error::new_subscriber(
+                format!(
+                    "Publisher {} is not allowed to be forwarded to {}",
+                    publisher.id, self.transport_id
+                ),
+                SubscriberErrorKind::ForwardingNotAllowedError,
+            ));
+        }
+        self.grant.check(publisher)?;
+
+        let switch = SourceSwitch {
+            rtp_receiver: publisher.packet_channel.subscribe(),
+            extension_rewriter: ExtensionRewriter::new(&publisher.header_extensions().await),
+        };
+        if self.switch_sender.send(switch).is_err() {
+            return Err(Error::new_subscriber(
+                format!("Subscriber {} has already been closed", self.id),
+                SubscriberErrorKind::TrackNotFoundError,
+            ));
+        }
+        let source = SubscriberSource::new(publisher);
+        if self.media_type == MediaType::Video {
+            source.request_keyframe();
+        }
+        self.source.send_replace(source);
+
+        tracing::debug!(
+            "Subscriber id={} is switched to publisher id={}",
+            self.id,
+            publisher.id
+        );
+        Ok(())
+    }
+
+    /// This returns the audio program which the subscriber follows, if it is subscribed by [`crate::subscribe_transport::SubscribeTransport::subscribe_audio_program`].
+    pub fn audio_program(&self) -> Option<String> {
+        self.audio_program.borrow().clone()
+    }
+
+    /// This returns counters of RTCP packets which are received from the subscribing client for this subscriber.
+    pub fn rtcp_stats(&self) -> RtcpStats {
+        self.rtcp_counter.stats()
+    }
+
+    /// This returns percentiles of the forwarding latency inside the SFU, from reading RTP packets of the publisher to writing them for this subscriber.
+    pub fn forwarding_latency(&self) -> LatencyStats {
+        self.forwarding_latency.stats()
+    }
+
+    /// This returns true if the subscriber has already been closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Set extension ids which are negotiated by the subscriber, so extensions which are not negotiated are stripped from egress packets.
+    pub(crate) fn set_negotiated_extensions(&self, negotiated: Vec<u8>) {
+        self.negotiated_extensions.send_replace(Some(negotiated));
+    }
+
+    pub(crate) fn subscribe_closed(&self) -> broadcast::Receiver<bool> {
+        self.closed_sender.subscribe()
+    }
+
     pub async fn close(&self) {
-        self.closed_sender.send(true).unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.closed_sender.send(true);
     }
 }
 
@@ -268,3 +897,88 @@ impl Drop for Subscriber {
         tracing::debug!("Subscriber id={} is dropped", self.id);
     }
 }
+
+/// Queue the duplicate of the packet to be sent at `due`. It returns false if the packet is dropped, because the queue is full or the subscriber is finished.
+fn schedule_duplicate(
+    duplicate_sender: &mpsc::Sender<DuplicatePacket>,
+    due: Instant,
+    packet: rtp::packet::Packet,
+) -> bool {
+    match duplicate_sender.try_send((due, packet)) {
+        Ok(()) => true,
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            tracing::trace!("duplicated rtp is dropped because the queue is full");
+            false
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_rewriter() {
+        let epoch = Instant::now();
+        let mut video = TimestampRewriter::new(90000, epoch);
+        let mut audio = TimestampRewriter::new(48000, epoch);
+
+        // Video is subscribed at 1s and audio at 2s, but both are on the same timeline.
+        let at = epoch + Duration::from_secs(1);
+        assert_eq!(video.rewrite(3000, at), 90000);
+        assert_eq!(video.rewrite(6000, at + Duration::from_millis(33)), 93000);
+        let at = epoch + Duration::from_secs(2);
+        assert_eq!(audio.rewrite(u32::MAX - 479, at), 96000);
+        assert_eq!(audio.rewrite(480, at + Duration::from_millis(20)), 96960);
+
+        // The new source is anchored at the wall clock.
+        video.reset();
+        assert_eq!(video.rewrite(123, epoch + Duration::from_secs(3)), 270000);
+        // Timestamps don't go backwards even if the wall clock is behind them.
+        video.reset();
+        assert_eq!(video.rewrite(456, epoch + Duration::from_secs(2)), 270001);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_scheduling() {
+        let local_track = Arc::new(TrackLocalStaticRTP::new(
+            RTCRtpCodecCapability {
+                mime_type: "audio/opus".to_string(),
+                clock_rate: 48000,
+                channels: 2,
+                ..Default::default()
+            },
+            "audio".to_string(),
+            "stream".to_string(),
+        ));
+        let sent_bytes = Arc::new(AtomicU64::new(0));
+        let (sender, receiver) = mpsc::channel(DUPLICATE_CAPACITY);
+        runtime::spawn(enc!((local_track, sent_bytes) async move {
+            Subscriber::duplicate_event_loop(local_track, receiver, sent_bytes).await;
+        }));
+        let packet = rtp::packet::Packet {
+            payload: bytes::Bytes::from_static(&[0; 10]),
+            ..Default::default()
+        };
+
+        // The duplicate is sent after the spacing.
+        assert!(schedule_duplicate(
+            &sender,
+            Instant::now() + Duration::from_millis(100),
+            packet.clone()
+        ));
+        runtime::sleep(Duration::from_millis(20)).await;
+        assert_eq!(sent_bytes.load(Ordering::Relaxed), 0);
+        runtime::sleep(Duration::from_millis(300)).await;
+        assert_eq!(sent_bytes.load(Ordering::Relaxed), 10);
+
+        // Packets are dropped instead of being buffered without bound, when the event loop can't keep up.
+        let (sender, _receiver) = mpsc::channel(DUPLICATE_CAPACITY);
+        let due = Instant::now() + Duration::from_secs(60);
+        for _ in 0..DUPLICATE_CAPACITY {
+            assert!(schedule_duplicate(&sender, due, packet.clone()));
+        }
+        assert!(!schedule_duplicate(&sender, due, packet.clone()));
+    }
+}
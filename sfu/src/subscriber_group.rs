@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use crate::{
+    publisher::MediaType,
+    subscriber::{Subscriber, SubscriberPriority},
+};
+
+/// Aggregated result of an operation on a [`SubscriberGroup`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GroupResult {
+    /// Subscriber IDs which the operation is applied to.
+    pub applied: Vec<String>,
+    /// Subscriber IDs which are skipped, because they don't match the operation or have already been closed.
+    pub skipped: Vec<String>,
+}
+
+/// SubscriberGroup applies operations to many [`Subscriber`]s at once. It is useful for layout switches, for example "pause every video except the screenshare".
+#[derive(Clone, Debug, Default)]
+pub struct SubscriberGroup {
+    subscribers: HashMap<String, Subscriber>,
+}
+
+impl SubscriberGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, subscriber: Subscriber) {
+        self.subscribers.insert(subscriber.id.clone(), subscriber);
+    }
+
+    pub fn remove(&mut self, subscriber_id: &str) -> Option<Subscriber> {
+        self.subscribers.remove(subscriber_id)
+    }
+
+    pub fn subscriber_ids(&self) -> Vec<String> {
+        self.subscribers.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// Pause every video subscriber in the group.
+    pub fn pause_video(&self) -> GroupResult {
+        self.apply(|s| s.media_type == MediaType::Video, |s| s.set_paused(true))
+    }
+
+    /// Resume every video subscriber in the group.
+    pub fn resume_video(&self) -> GroupResult {
+        self.apply(
+            |s| s.media_type == MediaType::Video,
+            |s| s.set_paused(false),
+        )
+    }
+
+    /// Pause or resume every subscriber in the group.
+    pub fn set_paused(&self, paused: bool) -> GroupResult {
+        self.apply(|_| true, |s| s.set_paused(paused))
+    }
+
+    pub fn set_priority(&self, priority: SubscriberPriority) -> GroupResult {
+        self.apply(|_| true, |s| s.set_priority(priority))
+    }
+
+    /// Close every subscriber in the group and remove them from the group.
+    pub async fn close(&mut self) -> GroupResult {
+        let mut result = GroupResult::default();
+        for (id, subscriber) in self.subscribers.drain() {
+            if subscriber.is_closed() {
+                result.skipped.push(id);
+                continue;
+            }
+            subscriber.close().await;
+            result.applied.push(id);
+        }
+        result
+    }
+
+    fn apply<F, G>(&self, filter: F, operation: G) -> GroupResult
+    where
+        F: Fn(&Subscriber) -> bool,
+        G: Fn(&Subscriber),
+    {
+        let mut result = GroupResult::default();
+        for (id, subscriber) in self.subscribers.iter() {
+            if subscriber.is_closed() || !filter(subscriber) {
+                result.skipped.push(id.clone());
+                continue;
+            }
+            operation(subscriber);
+            result.applied.push(id.clone());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+    use webrtc::peer_connection::RTCPeerConnection;
+
+    use super::*;
+    use crate::{
+        config::{MediaConfig, WebRTCTransportConfig},
+        media_source::SilentSource,
+        router::Router,
+        subscribe_transport::SubscribeTransport,
+        transport::{client_local_description, client_peer_connection},
+    };
+
+    // Audio subscribers of silent sources. The router, the transport and the client are returned to keep them alive.
+    async fn subscribers(
+        ids: &[&str],
+    ) -> (
+        Arc<Mutex<Router>>,
+        SubscribeTransport,
+        RTCPeerConnection,
+        Vec<Subscriber>,
+    ) {
+        let router = Router::new(MediaConfig::default());
+        let transport = {
+            let r = router.lock().await;
+            for (i, id) in ids.iter().enumerate() {
+                r.publish_source(SilentSource::new(id, i as u32 + 1))
+                    .unwrap();
+            }
+            r.create_subscribe_transport(WebRTCTransportConfig::default())
+                .await
+        };
+        let client = client_peer_connection().await;
+        let mut subscribers = Vec::new();
+        for id in ids.iter() {
+            let (subscriber, offer) = transport.subscribe(id.to_string()).await.unwrap();
+            client.set_remote_description(offer).await.unwrap();
+            let answer = client.create_answer(None).await.unwrap();
+            let answer = client_local_description(&client, answer).await;
+            transport.set_answer(answer).await.unwrap();
+            subscribers.push(subscriber);
+        }
+        (router, transport, client, subscribers)
+    }
+
+    fn sorted(mut ids: Vec<String>) -> Vec<String> {
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove() {
+        let (_router, _transport, _client, subscribers) = subscribers(&["first", "second"]).await;
+        let ids: Vec<String> = subscribers.iter().map(|s| s.id.clone()).collect();
+        let mut group = SubscriberGroup::new();
+        assert!(group.is_empty());
+
+        for subscriber in subscribers {
+            group.add(subscriber);
+        }
+        assert_eq!(group.len(), 2);
+        assert_eq!(sorted(group.subscriber_ids()), sorted(ids.clone()));
+
+        let removed = group.remove(&ids[0]).unwrap();
+        assert_eq!(removed.id, ids[0]);
+        assert!(group.remove(&ids[0]).is_none());
+        assert_eq!(group.subscriber_ids(), vec![ids[1].clone()]);
+
+        // The removed subscriber is not affected by operations of the group.
+        let result = group.set_paused(true);
+        assert_eq!(result.applied, vec![ids[1].clone()]);
+        assert!(!removed.is_paused());
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_results() {
+        let (_router, _transport, _client, subscribers) =
+            subscribers(&["first", "second", "third"]).await;
+        let ids: Vec<String> = subscribers.iter().map(|s| s.id.clone()).collect();
+        let closed = subscribers[2].clone();
+        let mut group = SubscriberGroup::new();
+        for subscriber in subscribers.iter() {
+            group.add(subscriber.clone());
+        }
+        closed.close().await;
+
+        let result = group.set_paused(true);
+        assert_eq!(sorted(result.applied), sorted(ids[..2].to_vec()));
+        assert_eq!(result.skipped, vec![ids[2].clone()]);
+        assert!(subscribers[0].is_paused());
+        assert!(subscribers[1].is_paused());
+
+        // Every subscriber is audio, so no one matches.
+        let result = group.resume_video();
+        assert!(result.applied.is_empty());
+        assert_eq!(sorted(result.skipped), sorted(ids.clone()));
+        assert!(subscribers[0].is_paused());
+
+        let result = group.close().await;
+        assert_eq!(sorted(result.applied), sorted(ids[..2].to_vec()));
+        assert_eq!(result.skipped, vec![ids[2].clone()]);
+        assert!(group.is_empty());
+        assert!(subscribers.iter().all(|s| s.is_closed()));
+    }
+}
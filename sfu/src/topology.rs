@@ -0,0 +1,182 @@
+use std::fmt;
+
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+use crate::config::WebRTCTransportConfig;
+
+pub type OnUpgradeFn = Box<dyn Fn(Vec<String>) + Send + Sync>;
+
+/// Advice for a participant about how to exchange media in the room.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TopologyAdvice {
+    /// Connect to the other participants directly. Signaling of SDP and ICE candidates between peers must be relayed by the application.
+    Mesh {
+        ice_servers: Vec<RTCIceServer>,
+        /// Participants which the new participant has to connect to.
+        peers: Vec<String>,
+    },
+    /// Publish and subscribe media via [`crate::publish_transport::PublishTransport`] and [`crate::subscribe_transport::SubscribeTransport`].
+    Sfu,
+}
+
+/// HybridTopology advises direct P2P connections for tiny rooms and upgrades the room to SFU forwarding once the room grows beyond `max_mesh_participants`.
+/// The upgrade is one-way, so rooms don't flap between modes when participants come and go around the threshold.
+/// Because [`crate::publisher::Publisher`] IDs are the same as track IDs, clients can publish the same tracks to the SFU after the upgrade and keep using the IDs which they have shared in mesh mode.
+pub struct HybridTopology {
+    max_mesh_participants: usize,
+    ice_servers: Vec<RTCIceServer>,
+    participants: Vec<String>,
+    upgraded: bool,
+    on_upgrade_fn: OnUpgradeFn,
+}
+
+impl HybridTopology {
+    pub fn new(max_mesh_participants: usize, transport_config: &WebRTCTransportConfig) -> Self {
+        Self {
+            max_mesh_participants,
+            ice_servers: transport_config.configuration.ice_servers.clone(),
+            participants: Vec::new(),
+            upgraded: false,
+            on_upgrade_fn: Box::new(|_| {}),
+        }
+    }
+
+    /// Add a participant to the room and return how the participant should exchange media.
+    /// If this participant makes the room too large for mesh, the room is upgraded and `on_upgrade` callback is called with the participants which had been connected in mesh.
+    pub fn join(&mut self, participant_id: String) -> TopologyAdvice {
+        if !self.participants.contains(&participant_id) {
+            self.participants.push(participant_id.clone());
+        }
+
+        if !self.upgraded && self.participants.len() > self.max_mesh_participants {
+            self.upgraded = true;
+            let mesh_participants: Vec<String> = self
+                .participants
+                .iter()
+                .filter(|p| **p != participant_id)
+                .cloned()
+                .collect();
+            tracing::debug!(
+                "Room is upgraded to SFU, participants={}",
+                self.participants.len()
+            );
+            (self.on_upgrade_fn)(mesh_participants);
+        }
+
+        self.advice_for(&participant_id)
+    }
+
+    pub fn leave(&mut self, participant_id: &str) {
+        self.participants.retain(|p| p != participant_id);
+    }
+
+    /// This returns the current advice for a participant which has already joined.
+    pub fn advice_for(&self, participant_id: &str) -> TopologyAdvice {
+        if self.upgraded {
+            return TopologyAdvice::Sfu;
+        }
+        TopologyAdvice::Mesh {
+            ice_servers: self.ice_servers.clone(),
+            peers: self
+                .participants
+                .iter()
+                .filter(|p| *p != participant_id)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn is_upgraded(&self) -> bool {
+        self.upgraded
+    }
+
+    /// Set callback function when the room is upgraded from mesh to SFU. The argument is the participants which have to switch to SFU.
+    pub fn on_upgrade(&mut self, f: OnUpgradeFn) {
+        self.on_upgrade_fn = f;
+    }
+}
+
+impl fmt::Debug for HybridTopology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HybridTopology")
+            .field("max_mesh_participants", &self.max_mesh_participants)
+            .field("participants", &self.participants)
+            .field("upgraded", &self.upgraded)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn topology() -> (HybridTopology, Arc<Mutex<Vec<Vec<String>>>>) {
+        let mut transport_config = WebRTCTransportConfig::default();
+        transport_config.configuration.ice_servers = vec![RTCIceServer {
+            urls: vec!["stun:stun.example.com:3478".to_owned()],
+            ..Default::default()
+        }];
+        let mut topology = HybridTopology::new(3, &transport_config);
+        let upgrades = Arc::new(Mutex::new(Vec::new()));
+        let u = upgrades.clone();
+        topology.on_upgrade(Box::new(move |participants| {
+            u.lock().unwrap().push(participants);
+        }));
+        (topology, upgrades)
+    }
+
+    fn mesh(peers: &[&str]) -> TopologyAdvice {
+        TopologyAdvice::Mesh {
+            ice_servers: vec![RTCIceServer {
+                urls: vec!["stun:stun.example.com:3478".to_owned()],
+                ..Default::default()
+            }],
+            peers: peers.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_below_threshold() {
+        let (mut topology, upgrades) = topology();
+        assert_eq!(topology.join("a".to_string()), mesh(&[]));
+        assert_eq!(topology.join("b".to_string()), mesh(&["a"]));
+        // Joining again doesn't count the participant twice.
+        assert_eq!(topology.join("b".to_string()), mesh(&["a"]));
+        assert_eq!(topology.advice_for("a"), mesh(&["b"]));
+        assert!(!topology.is_upgraded());
+        assert!(upgrades.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_boundary() {
+        let (mut topology, upgrades) = topology();
+        topology.join("a".to_string());
+        topology.join("b".to_string());
+        assert_eq!(topology.join("c".to_string()), mesh(&["a", "b"]));
+        assert!(!topology.is_upgraded());
+        assert!(upgrades.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cross_threshold() {
+        let (mut topology, upgrades) = topology();
+        for participant in ["a", "b", "c"] {
+            topology.join(participant.to_string());
+        }
+        assert_eq!(topology.join("d".to_string()), TopologyAdvice::Sfu);
+        assert!(topology.is_upgraded());
+        assert_eq!(
+            *upgrades.lock().unwrap(),
+            vec![vec!["a".to_string(), "b".to_string(), "c".to_string()]]
+        );
+        assert_eq!(topology.advice_for("a"), TopologyAdvice::Sfu);
+
+        // The upgrade is one-way.
+        topology.leave("d");
+        topology.leave("c");
+        assert_eq!(topology.join("e".to_string()), TopologyAdvice::Sfu);
+        assert_eq!(upgrades.lock().unwrap().len(), 1);
+    }
+}
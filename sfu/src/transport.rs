@@ -1,34 +1,256 @@
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use serde::{Deserialize, Serialize};
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
 use webrtc::{
     api::{
-        interceptor_registry::register_default_interceptors, media_engine::MediaEngine, APIBuilder,
+        interceptor_registry::{
+            configure_nack, configure_rtcp_reports, register_default_interceptors,
+        },
+        media_engine::MediaEngine,
+        APIBuilder,
+    },
+    ice_transport::{
+        ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_server::RTCIceServer,
     },
-    ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
     interceptor::registry::Registry,
-    peer_connection::{sdp::session_description::RTCSessionDescription, RTCPeerConnection},
+    peer_connection::{
+        sdp::session_description::RTCSessionDescription, signaling_state::RTCSignalingState,
+        RTCPeerConnection,
+    },
     rtcp,
     rtp_transceiver::{
-        rtp_codec::{RTCRtpHeaderExtensionCapability, RTPCodecType},
+        rtp_codec::{RTCRtpCodecParameters, RTCRtpHeaderExtensionCapability, RTPCodecType},
         rtp_receiver::RTCRtpReceiver,
         RTCRtpTransceiver,
     },
     track::track_remote::TrackRemote,
 };
+use webrtc_ice::{
+    candidate::{candidate_base::unmarshal_candidate, Candidate},
+    network_type::NetworkType,
+    udp_network::UDPNetwork,
+};
+use webrtc_sdp::{
+    attribute_type::{SdpAttribute, SdpAttributeType},
+    media_type::SdpMedia,
+    parse_sdp, SdpBandwidth, SdpSession,
+};
 
 use crate::{
-    config::{MediaConfig, WebRTCTransportConfig},
-    error::Error,
+    config::{
+        codec_name, find_payload_type_owner, same_codec, CongestionFeedback, MediaConfig, SdpHints,
+        WebRTCTransportConfig,
+    },
+    error::{Error, TransportErrorKind},
+    feedback::LossNotificationInterceptorBuilder,
+    net::{bind_dscp_socket, DscpMarker},
+    runtime::sleep,
+    worker::BlockingWorker,
 };
 
+/// Max message size which is assumed when the remote SDP doesn't have `a=max-message-size`. See RFC 8841.
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 65536;
+
 pub(crate) type RtcpSender = mpsc::UnboundedSender<Box<dyn rtcp::packet::Packet + Send + Sync>>;
 pub(crate) type RtcpReceiver = mpsc::UnboundedReceiver<Box<dyn rtcp::packet::Packet + Send + Sync>>;
 
 pub type OnIceCandidateFn = Box<dyn Fn(RTCIceCandidate) + Send + Sync>;
+/// Filter for local ICE candidates. Return None to drop the candidate, or return a rewritten candidate.
+pub type OnLocalCandidateFn = Box<dyn Fn(RTCIceCandidate) -> Option<RTCIceCandidate> + Send + Sync>;
 pub type OnNegotiationNeededFn = Box<dyn Fn(RTCSessionDescription) + Send + Sync>;
+pub type OnTransportFailedFn = Box<dyn Fn(ResumeHint) + Send + Sync>;
+pub type OnNegotiationStateChangeFn = Box<dyn Fn(NegotiationState) + Send + Sync>;
 pub type OnTrackFn =
     Box<dyn Fn(Arc<TrackRemote>, Arc<RTCRtpReceiver>, Arc<RTCRtpTransceiver>) + Send + Sync>;
 
+/// Remote ICE candidates of a transport. Candidates which arrive before the remote description is set are buffered, and they are added right after the remote description is set.
+/// Setting the remote description and adding candidates are serialized by one lock, so a candidate which arrives while the remote description is being set is never dropped.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RemoteCandidates {
+    buffer: Arc<Mutex<CandidateBuffer>>,
+}
+
+impl RemoteCandidates {
+    pub(crate) async fn add(
+        &self,
+        peer_connection: &RTCPeerConnection,
+        candidate: RTCIceCandidateInit,
+    ) -> Result<(), Error> {
+        let mut buffer = self.buffer.lock().await;
+        match buffer.accept(candidate) {
+            Some(candidate) => {
+                tracing::debug!("Adding ICE candidate for {:#?}", candidate);
+                peer_connection.add_ice_candidate(candidate).await?;
+            }
+            None => tracing::debug!("ICE candidate is pending until the remote description is set"),
+        }
+        Ok(())
+    }
+
+    /// Set the remote description and add pending candidates.
+    pub(crate) async fn set_remote_description(
+        &self,
+        peer_connection: &RTCPeerConnection,
+        description: RTCSessionDescription,
+    ) -> Result<(), Error> {
+        let mut buffer = self.buffer.lock().await;
+        peer_connection.set_remote_description(description).await?;
+        for candidate in buffer.remote_description_set() {
+            tracing::debug!("Adding pending ICE candidate: {:#?}", candidate);
+            if let Err(err) = peer_connection.add_ice_candidate(candidate).await {
+                tracing::error!("failed to add_ice_candidate: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep candidates until the next remote description is set, because candidates of the new ICE credentials are rejected by the current ones.
+    /// Please call this when an offer with an ICE restart is sent.
+    pub(crate) async fn restart(&self) {
+        self.buffer.lock().await.restart();
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn pending(&self) -> usize {
+        self.buffer.lock().await.pending.len()
+    }
+}
+
+#[derive(Debug, Default)]
+struct CandidateBuffer {
+    remote_description_set: bool,
+    pending: Vec<RTCIceCandidateInit>,
+}
+
+impl CandidateBuffer {
+    /// This returns the candidate if it can be added now. Otherwise it is kept until the remote description is set.
+    fn accept(&mut self, candidate: RTCIceCandidateInit) -> Option<RTCIceCandidateInit> {
+        if self.remote_description_set {
+            return Some(candidate);
+        }
+        self.pending.push(candidate);
+        None
+    }
+
+    /// This returns pending candidates. They are taken, so they are not added again on renegotiation.
+    fn remote_description_set(&mut self) -> Vec<RTCIceCandidateInit> {
+        self.remote_description_set = true;
+        std::mem::take(&mut self.pending)
+    }
+
+    fn restart(&mut self) {
+        self.remote_description_set = false;
+    }
+}
+
+/// Negotiation state of a transport. Applications can use it to retry signaling and to diagnose stuck negotiations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NegotiationState {
+    pub signaling_state: RTCSignalingState,
+    /// True while an offer or an answer is exchanged with the client.
+    pub pending: bool,
+    /// Number of renegotiations which wait for the pending negotiation to finish.
+    pub queued: usize,
+}
+
+/// Serializes offer/answer exchanges of a transport. A new negotiation waits until the pending one finishes.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct NegotiationQueue {
+    pending: Arc<AtomicBool>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl NegotiationQueue {
+    /// Wait until the pending negotiation finishes. Waiters are counted as queued renegotiations.
+    pub(crate) async fn wait(&self) {
+        if !self.is_pending() {
+            return;
+        }
+        let _queued = QueuedGuard::new(&self.queued);
+        while self.is_pending() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    pub(crate) fn start(&self) {
+        self.pending.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn finish(&self) {
+        self.pending.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn state(&self, signaling_state: RTCSignalingState) -> NegotiationState {
+        NegotiationState {
+            signaling_state,
+            pending: self.is_pending(),
+            queued: self.queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Decrement the queue length even if the waiting future is dropped.
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl<'a> QueuedGuard<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Self(queued)
+    }
+}
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Payload for a client which reconnects after its transport has failed. The application hands it to the client, so the client can create new transports and restore its publications and subscriptions.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeHint {
+    /// Random token which identifies this hint. The application can ask the client to present it when it reconnects.
+    pub resume_token: String,
+    /// ID of the failed transport.
+    pub transport_id: String,
+    /// [`crate::publisher::Publisher`] IDs which were subscribed by the failed transport.
+    pub subscriptions: Vec<String>,
+    /// [`crate::publisher::Publisher`] IDs which were published by the failed transport. They are the same as track IDs, so the client can publish the same tracks again.
+    pub publications: Vec<String>,
+    /// ICE servers which are suggested for the new transports.
+    pub ice_servers: Vec<RTCIceServer>,
+}
+
+impl ResumeHint {
+    pub(crate) fn new(
+        transport_id: String,
+        subscriptions: Vec<String>,
+        publications: Vec<String>,
+        ice_servers: Vec<RTCIceServer>,
+    ) -> Self {
+        Self {
+            resume_token: Uuid::new_v4().to_string(),
+            transport_id,
+            subscriptions,
+            publications,
+            ice_servers,
+        }
+    }
+}
+
 pub(crate) trait PeerConnection {
     fn generate_peer_connection(
         media_config: MediaConfig,
@@ -37,18 +259,44 @@ pub(crate) trait PeerConnection {
         async move {
             let mut me = MediaEngine::default();
 
+            let feedback = media_config.congestion_feedback;
+            let dscp_marker = DscpMarker::new(transport_config.dscp, &media_config);
+
             if media_config.codec.audio.len() > 0 || media_config.codec.video.len() > 0 {
-                for codec in media_config.codec.audio {
-                    me.register_codec(codec, RTPCodecType::Audio)?;
-                }
-                for codec in media_config.codec.video {
-                    me.register_codec(codec, RTPCodecType::Video)?;
+                let mut registered: Vec<RTCRtpCodecParameters> = Vec::new();
+                for (typ, codecs) in [
+                    (RTPCodecType::Audio, media_config.codec.audio),
+                    (RTPCodecType::Video, media_config.codec.video),
+                ] {
+                    for mut codec in codecs {
+                        // Conflicting payload types are reported by config::validate.
+                        if let Some(other) = find_payload_type_owner(&registered, &codec) {
+                            if !same_codec(other, &codec) {
+                                tracing::warn!(
+                                    "{} is not registered, because payload type {} is used by {}",
+                                    codec_name(&codec),
+                                    codec.payload_type,
+                                    codec_name(other)
+                                );
+                            }
+                            continue;
+                        }
+                        codec
+                            .capability
+                            .rtcp_feedback
+                            .retain(|fb| feedback.allows(&fb.typ));
+                        me.register_codec(codec.clone(), typ)?;
+                        registered.push(codec);
+                    }
                 }
             } else {
                 me.register_default_codecs()?;
             }
 
             for extension in media_config.header_extension.audio {
+                if !feedback.allows(&extension) {
+                    continue;
+                }
                 me.register_header_extension(
                     RTCRtpHeaderExtensionCapability { uri: extension },
                     RTPCodecType::Audio,
@@ -57,6 +305,9 @@ pub(crate) trait PeerConnection {
             }
 
             for extension in media_config.header_extension.video {
+                if !feedback.allows(&extension) {
+                    continue;
+                }
                 me.register_header_extension(
                     RTCRtpHeaderExtensionCapability { uri: extension },
                     RTPCodecType::Video,
@@ -65,12 +316,36 @@ pub(crate) trait PeerConnection {
             }
 
             let mut registry = Registry::new();
-            registry = register_default_interceptors(registry, &mut me)?;
+            // This has to be the first, so it reads RTCP packets before other interceptors parse them.
+            registry.add(Box::new(LossNotificationInterceptorBuilder));
+            if feedback.twcc() {
+                registry = register_default_interceptors(registry, &mut me)?;
+            } else {
+                // Default interceptors register transport-cc, so only NACK and RTCP reports are configured.
+                registry = configure_nack(registry, &mut me);
+                registry = configure_rtcp_reports(registry);
+            }
+
+            let mut setting_engine = transport_config.setting_engine();
+            // A shared socket is marked with its own options.
+            if transport_config.udp_socket.is_none() && !transport_config.dscp.is_empty() {
+                let udp_mux = bind_dscp_socket(dscp_marker, transport_config.port_range.as_ref())
+                    .map_err(|err| {
+                    Error::new_transport(
+                        format!("failed to bind UDP socket: {}", err),
+                        TransportErrorKind::PeerConnectionError,
+                    )
+                })?;
+                setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux));
+                if transport_config.network_types.is_empty() {
+                    setting_engine.set_network_types(vec![NetworkType::Udp4]);
+                }
+            }
 
             let api = APIBuilder::new()
                 .with_media_engine(me)
                 .with_interceptor_registry(registry)
-                .with_setting_engine(transport_config.setting_engine())
+                .with_setting_engine(setting_engine)
                 .build();
 
             let peer_connection = api
@@ -88,3 +363,609 @@ pub trait Transport {
         candidate: RTCIceCandidateInit,
     ) -> impl std::future::Future<Output = Result<(), Error>> + Send;
 }
+
+/// This returns the SCTP max message size which the remote peer accepts. `0` means there is no limit.
+/// webrtc_sdp is stricter than webrtc-rs, so the default size is assumed when the SDP can't be parsed, instead of failing the negotiation.
+pub(crate) fn remote_max_message_size(remote: &RTCSessionDescription) -> usize {
+    match parse_sdp(&remote.sdp, false) {
+        Ok(session) => session_max_message_size(&session),
+        Err(err) => {
+            tracing::warn!(
+                "failed to parse the remote SDP, max message size is assumed to be {}: {}",
+                DEFAULT_MAX_MESSAGE_SIZE,
+                err
+            );
+            DEFAULT_MAX_MESSAGE_SIZE
+        }
+    }
+}
+
+fn session_max_message_size(session: &SdpSession) -> usize {
+    for media in session.media.iter() {
+        if let Some(SdpAttribute::MaxMessageSize(size)) =
+            media.get_attribute(SdpAttributeType::MaxMessageSize)
+        {
+            return *size as usize;
+        }
+    }
+    DEFAULT_MAX_MESSAGE_SIZE
+}
+
+/// This returns an error when the remote SDP is Plan-B, which has multiple tracks in one media section. Only Unified Plan is supported, so such an offer would produce a broken answer.
+/// Plan-B offers which have only one track in each media section are the same as Unified Plan, so they are accepted as they are.
+fn reject_plan_b(session: &SdpSession) -> Result<(), Error> {
+    for media in session.media.iter() {
+        let track_ids = media_track_ids(media);
+        if track_ids.len() > 1 {
+            let mid = match media.get_attribute(SdpAttributeType::Mid) {
+                Some(SdpAttribute::Mid(mid)) => Some(mid.clone()),
+                _ => None,
+            };
+            return Err(Error::new_transport(
+                format!(
+                    "Plan-B SDP is not supported, media section mid={:?} has {} tracks, please use Unified Plan (sdpSemantics: \"unified-plan\")",
+                    mid,
+                    track_ids.len()
+                ),
+                TransportErrorKind::PlanBNotSupportedError { mid, track_ids },
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// This returns mids of media sections which the remote side has stopped sending. The port is 0 when the transceiver is stopped, and the direction is recvonly or inactive when the sender is removed.
+fn stopped_sending_mids(session: &SdpSession) -> Vec<String> {
+    let mut mids = Vec::new();
+    for media in session.media.iter() {
+        let stopped = media.get_port() == 0
+            || media.get_attribute(SdpAttributeType::Recvonly).is_some()
+            || media.get_attribute(SdpAttributeType::Inactive).is_some();
+        if !stopped {
+            continue;
+        }
+        if let Some(SdpAttribute::Mid(mid)) = media.get_attribute(SdpAttributeType::Mid) {
+            mids.push(mid.clone());
+        }
+    }
+    mids
+}
+
+/// What the SFU reads from an offer of the client.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RemoteOffer {
+    pub(crate) max_message_size: usize,
+    pub(crate) stopped_mids: Vec<String>,
+    pub(crate) bandwidths: Vec<(String, u32)>,
+}
+
+impl Default for RemoteOffer {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            stopped_mids: Vec::new(),
+            bandwidths: Vec::new(),
+        }
+    }
+}
+
+/// Parse the offer once, and return an error when it is Plan-B. This is synchronous and heavy for large offers, so please run it on [`BlockingWorker`].
+/// webrtc_sdp is stricter than webrtc-rs, so an offer which can't be parsed is applied as it is with the default [`RemoteOffer`], instead of failing the negotiation.
+pub(crate) fn analyze_offer(remote: &RTCSessionDescription) -> Result<RemoteOffer, Error> {
+    let session = match parse_sdp(&remote.sdp, false) {
+        Ok(session) => session,
+        Err(err) => {
+            tracing::warn!(
+                "failed to parse the remote offer, it is applied without analysis: {}",
+                err
+            );
+            return Ok(RemoteOffer::default());
+        }
+    };
+    reject_plan_b(&session)?;
+    Ok(RemoteOffer {
+        max_message_size: session_max_message_size(&session),
+        stopped_mids: stopped_sending_mids(&session),
+        bandwidths: remote_bandwidths(&session),
+    })
+}
+
+/// Last result of SDP processing on [`BlockingWorker`], which is keyed by a hash of the input.
+/// Clients send the same SDP again when they retry signaling, and negotiation bursts often repeat the same offer, so the result is reused without parsing SDP again.
+#[derive(Debug)]
+pub(crate) struct SdpCache<T> {
+    last: Arc<std::sync::Mutex<Option<(u64, T)>>>,
+}
+
+impl<T> Clone for SdpCache<T> {
+    fn clone(&self) -> Self {
+        Self {
+            last: self.last.clone(),
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> SdpCache<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            last: Arc::new(std::sync::Mutex::new(None)),
+        }
+    }
+
+    /// Return the cached result when `key` is the same as the last one, otherwise run `f` on the worker. Errors are not cached.
+    pub(crate) async fn get_or_run<F>(
+        &self,
+        worker: &BlockingWorker,
+        key: u64,
+        f: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error> + Send + 'static,
+    {
+        if let Some((last_key, value)) = self.last.lock().unwrap().as_ref() {
+            if *last_key == key {
+                return Ok(value.clone());
+            }
+        }
+        let value = worker.run(f).await??;
+        *self.last.lock().unwrap() = Some((key, value.clone()));
+        Ok(value)
+    }
+}
+
+/// Key of [`SdpCache`] for the SDP and parameters which change the result.
+pub(crate) fn sdp_cache_key(sdp: &str, params: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    sdp.hash(&mut hasher);
+    params.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// This removes rtcp-fb lines and header extensions which are not used in the [`CongestionFeedback`] mode. Default codecs of the media engine have both of feedback, so they are removed from the SDP which is sent to the client.
+/// The SDP is returned unmodified when it can't be rewritten, because the client can still negotiate with both of feedback.
+pub(crate) fn filter_congestion_feedback(
+    sdp: RTCSessionDescription,
+    feedback: CongestionFeedback,
+) -> RTCSessionDescription {
+    if feedback == CongestionFeedback::Both {
+        return sdp;
+    }
+    let filtered = parse_sdp(&sdp.sdp, false)
+        .map_err(Error::from)
+        .and_then(|mut session| {
+            filter_session_congestion_feedback(&mut session, feedback)?;
+            Ok(session.to_string())
+        });
+    match filtered {
+        Ok(filtered) => RTCSessionDescription {
+            sdp: filtered,
+            ..sdp
+        },
+        Err(err) => {
+            tracing::warn!(
+                "failed to filter congestion feedback, SDP is sent unmodified: {}",
+                err
+            );
+            sdp
+        }
+    }
+}
+
+/// This is the same as [`filter_congestion_feedback`] for a session which has already been parsed, so callers which rewrite SDP in several steps parse it only once.
+pub(crate) fn filter_session_congestion_feedback(
+    session: &mut SdpSession,
+    feedback: CongestionFeedback,
+) -> Result<(), Error> {
+    if feedback == CongestionFeedback::Both {
+        return Ok(());
+    }
+    for media in session.media.iter_mut() {
+        let mut rtcp_fbs = vec![];
+        let mut extmaps = vec![];
+        for attr in media.get_attributes() {
+            match attr {
+                SdpAttribute::Rtcpfb(rtcp_fb) => rtcp_fbs.push(rtcp_fb.clone()),
+                SdpAttribute::Extmap(extmap) => extmaps.push(extmap.clone()),
+                _ => continue,
+            }
+        }
+        media.remove_attribute(SdpAttributeType::Rtcpfb);
+        media.remove_attribute(SdpAttributeType::Extmap);
+        for rtcp_fb in rtcp_fbs {
+            if feedback.allows(&rtcp_fb.feedback_type.to_string()) {
+                media.add_attribute(SdpAttribute::Rtcpfb(rtcp_fb))?;
+            }
+        }
+        for extmap in extmaps {
+            if feedback.allows(&extmap.url) {
+                media.add_attribute(SdpAttribute::Extmap(extmap))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// This returns bitrates in bps which the remote side announces with `b=TIAS` or `b=AS` per mid. TIAS is preferred, because AS includes the overhead of transports.
+fn remote_bandwidths(session: &SdpSession) -> Vec<(String, u32)> {
+    let mut bandwidths = Vec::new();
+    for media in session.media.iter() {
+        let Some(SdpAttribute::Mid(mid)) = media.get_attribute(SdpAttributeType::Mid) else {
+            continue;
+        };
+        if let Some(bitrate) = media_bandwidth(media) {
+            bandwidths.push((mid.clone(), bitrate));
+        }
+    }
+    bandwidths
+}
+
+fn media_bandwidth(media: &SdpMedia) -> Option<u32> {
+    let mut application_specific = None;
+    for bandwidth in media.get_bandwidth().iter() {
+        match bandwidth {
+            SdpBandwidth::Tias(bps) => return Some(*bps),
+            SdpBandwidth::As(kbps) => application_specific = Some(kbps.saturating_mul(1000)),
+            _ => {}
+        }
+    }
+    application_specific
+}
+
+/// This writes bitrates in bps as `b=AS` and `b=TIAS` lines to media sections of the mids.
+pub(crate) fn set_session_bandwidths(session: &mut SdpSession, bandwidths: &[(String, u32)]) {
+    if bandwidths.is_empty() {
+        return;
+    }
+    for media in session.media.iter_mut() {
+        let mid = match media.get_attribute(SdpAttributeType::Mid) {
+            Some(SdpAttribute::Mid(mid)) => mid.clone(),
+            _ => continue,
+        };
+        if let Some((_, bitrate)) = bandwidths.iter().find(|(m, _)| *m == mid) {
+            media.add_bandwidth(SdpBandwidth::As(bitrate.div_ceil(1000)));
+            media.add_bandwidth(SdpBandwidth::Tias(*bitrate));
+        }
+    }
+}
+
+/// This adds [`SdpHints`] as session attributes to the SDP which is sent to the client. It is done after parsing SDP with webrtc_sdp, because unknown attributes are not kept by the parser.
+pub(crate) fn add_sdp_hints(
+    mut sdp: RTCSessionDescription,
+    hints: Option<&SdpHints>,
+) -> RTCSessionDescription {
+    let Some(hints) = hints else {
+        return sdp;
+    };
+    let line_break = if sdp.sdp.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut lines: String = hints
+        .attributes()
+        .into_iter()
+        .map(|attribute| attribute + line_break)
+        .collect();
+    // Session attributes have to be placed before the first media section.
+    match sdp.sdp.find(&format!("{}m=", line_break)) {
+        Some(index) => {
+            let index = index + line_break.len();
+            sdp.sdp.insert_str(index, &lines);
+        }
+        None => {
+            if !sdp.sdp.ends_with(line_break) {
+                lines.insert_str(0, line_break);
+            }
+            sdp.sdp.push_str(&lines);
+        }
+    }
+    sdp
+}
+
+/// Apply the filter of `on_local_candidate` to candidates in the local SDP, so dropped candidates are not sent to the client through offers and answers either.
+/// Candidates which are rewritten by the filter are written again, and candidates which can't be parsed are kept as they are.
+pub(crate) fn filter_local_candidates(
+    mut sdp: RTCSessionDescription,
+    filter: &OnLocalCandidateFn,
+) -> RTCSessionDescription {
+    let line_break = if sdp.sdp.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut filtered = String::with_capacity(sdp.sdp.len());
+    for line in sdp.sdp.split_terminator(line_break) {
+        let line = match line.strip_prefix("a=candidate:") {
+            Some(raw) => match filter_candidate_line(raw, filter) {
+                Some(line) => line,
+                None => {
+                    tracing::debug!("local ICE candidate is dropped from the SDP by the filter");
+                    continue;
+                }
+            },
+            None => line.to_string(),
+        };
+        filtered.push_str(&line);
+        filtered.push_str(line_break);
+    }
+    sdp.sdp = filtered;
+    sdp
+}
+
+fn filter_candidate_line(raw: &str, filter: &OnLocalCandidateFn) -> Option<String> {
+    let original = format!("a=candidate:{}", raw);
+    let candidate: Arc<dyn Candidate + Send + Sync> = match unmarshal_candidate(raw) {
+        Ok(candidate) => Arc::new(candidate),
+        Err(err) => {
+            tracing::warn!("failed to parse local ICE candidate {}: {}", raw, err);
+            return Some(original);
+        }
+    };
+    let candidate = RTCIceCandidate::from(&candidate);
+    let filtered = filter(candidate.clone())?;
+    if filtered == candidate {
+        return Some(original);
+    }
+    match filtered.to_json() {
+        Ok(init) => Some(format!("a={}", init.candidate)),
+        Err(err) => {
+            tracing::warn!("failed to write rewritten ICE candidate: {}", err);
+            None
+        }
+    }
+}
+
+/// This returns track IDs in the media section from `a=msid` and `a=ssrc:<ssrc> msid` attributes.
+/// Attributes without the track ID, like `a=msid:<stream>`, only tell the stream, so they are skipped. A track can belong to multiple streams.
+fn media_track_ids(media: &SdpMedia) -> Vec<String> {
+    let mut track_ids: Vec<String> = Vec::new();
+    for attribute in media.get_attributes().iter() {
+        let track_id = match attribute {
+            SdpAttribute::Msid(msid) => match &msid.appdata {
+                Some(track_id) => track_id.clone(),
+                None => continue,
+            },
+            SdpAttribute::Ssrc(ssrc) if ssrc.attribute.as_deref() == Some("msid") => match ssrc
+                .value
+                .as_ref()
+                .and_then(|value| value.split_whitespace().nth(1))
+            {
+                Some(track_id) => track_id.to_string(),
+                None => continue,
+            },
+            _ => continue,
+        };
+        if !track_ids.contains(&track_id) {
+            track_ids.push(track_id);
+        }
+    }
+    track_ids
+}
+
+/// Peer connection of a client, for tests which negotiate with transports.
+#[cfg(test)]
+pub(crate) async fn client_peer_connection() -> RTCPeerConnection {
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs().unwrap();
+    let api = APIBuilder::new().with_media_engine(media_engine).build();
+    api.new_peer_connection(Default::default()).await.unwrap()
+}
+
+/// Set the local description of the client, and return it with gathered candidates.
+#[cfg(test)]
+pub(crate) async fn client_local_description(
+    peer_connection: &RTCPeerConnection,
+    description: RTCSessionDescription,
+) -> RTCSessionDescription {
+    let mut gathering_complete = peer_connection.gathering_complete_promise().await;
+    peer_connection
+        .set_local_description(description)
+        .await
+        .unwrap();
+    let _ = gathering_complete.recv().await;
+    peer_connection.local_description().await.unwrap()
+}
+
+/// Remote candidate which is sent by a client.
+#[cfg(test)]
+pub(crate) fn client_candidate() -> RTCIceCandidateInit {
+    RTCIceCandidateInit {
+        candidate: "candidate:1 1 udp 2130706431 192.0.2.1 50000 typ host".to_string(),
+        sdp_mid: Some("0".to_string()),
+        sdp_mline_index: Some(0),
+        username_fragment: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn session_description(path: &str) -> RTCSessionDescription {
+        let mut offer = RTCSessionDescription::default();
+        offer.sdp = fs::read_to_string(path).expect(format!("failed to open {}", path).as_str());
+        offer
+    }
+
+    #[test]
+    fn test_reject_plan_b() {
+        let offer = session_description("./test_data/sdp_plan_b");
+        match analyze_offer(&offer) {
+            Err(Error::TransportError(err)) => match err.kind {
+                TransportErrorKind::PlanBNotSupportedError { mid, track_ids } => {
+                    assert_eq!(mid, Some("video".to_string()));
+                    assert_eq!(track_ids, vec!["video1", "video2"]);
+                }
+                kind => panic!("unexpected error kind: {}", kind),
+            },
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_msid_without_track_id() {
+        let offer = session_description("./test_data/sdp_plan_b");
+        let mut msid_only = offer.clone();
+        msid_only.sdp = offer
+            .sdp
+            .replace("msid:stream1 video1", "msid:stream1")
+            .replace("msid:stream2 video2", "msid:stream2");
+        assert!(analyze_offer(&msid_only).is_ok());
+
+        // A track which belongs to two streams.
+        let offer = session_description("./test_data/sdp_audio_video_original");
+        let line_break = if offer.sdp.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        let mut streams = offer.clone();
+        streams.sdp = offer.sdp.replace(
+            "a=msid:- 9b7db7c1-b108-4c3e-aac8-b81301062ef6",
+            &format!("a=msid:stream1{}a=msid:stream2", line_break),
+        );
+        let session = parse_sdp(&streams.sdp, false).unwrap();
+        assert_eq!(
+            media_track_ids(&session.media[0]),
+            vec!["9b7db7c1-b108-4c3e-aac8-b81301062ef6"]
+        );
+        assert!(analyze_offer(&streams).is_ok());
+    }
+
+    #[test]
+    fn test_add_sdp_hints() {
+        let mut offer = RTCSessionDescription::default();
+        offer.sdp =
+            "v=0\r\ns=-\r\nt=0 0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n".to_string();
+        let hints = SdpHints {
+            control_channel_label: Some("control".to_string()),
+        };
+
+        let offer = add_sdp_hints(offer, Some(&hints));
+
+        assert_eq!(
+            offer.sdp,
+            format!(
+                "v=0\r\ns=-\r\nt=0 0\r\na=x-rheomesh:version={}\r\na=x-rheomesh:control-channel=control\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn test_filter_local_candidates() {
+        let mut offer = RTCSessionDescription::default();
+        offer.sdp = "v=0\r\ns=-\r\nt=0 0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na=candidate:1 1 udp 2130706431 10.0.0.1 50000 typ host\r\na=candidate:2 1 udp 2130706431 192.0.2.1 50001 typ host\r\na=end-of-candidates\r\n".to_string();
+        let filter: OnLocalCandidateFn = Box::new(|mut candidate| {
+            if candidate.address.starts_with("10.") {
+                return None;
+            }
+            if candidate.address == "192.0.2.1" {
+                candidate.address = "198.51.100.1".to_string();
+            }
+            Some(candidate)
+        });
+
+        let offer = filter_local_candidates(offer, &filter);
+
+        assert!(!offer.sdp.contains("10.0.0.1"));
+        assert!(!offer.sdp.contains("192.0.2.1"));
+        assert_eq!(offer.sdp.matches("a=candidate:").count(), 1);
+        assert!(offer.sdp.contains(" 198.51.100.1 50001 typ host"));
+        assert!(offer.sdp.ends_with("a=mid:0\r\na=candidate:2 1 udp 2130706431 198.51.100.1 50001 typ host\r\na=end-of-candidates\r\n"));
+
+        let pass: OnLocalCandidateFn = Box::new(Some);
+        let sdp = offer.sdp.clone();
+        assert_eq!(filter_local_candidates(offer, &pass).sdp, sdp);
+    }
+
+    #[test]
+    fn test_stopped_sending_mids() {
+        let offer = session_description("./test_data/sdp_audio_video_original");
+        let stopped = |sdp: &str| stopped_sending_mids(&parse_sdp(sdp, false).unwrap());
+        assert_eq!(stopped(&offer.sdp), Vec::<String>::new());
+
+        let removed = offer.sdp.replace("m=audio 27735", "m=audio 0");
+        assert_eq!(stopped(&removed), vec!["0"]);
+
+        let removed = offer.sdp.replace("a=sendonly", "a=recvonly");
+        assert_eq!(stopped(&removed), vec!["0", "1"]);
+    }
+
+    #[test]
+    fn test_remote_bandwidths() {
+        let offer = session_description("./test_data/sdp_audio_video_original");
+        assert_eq!(analyze_offer(&offer).unwrap().bandwidths, vec![]);
+
+        let line_break = if offer.sdp.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        let mut limited = offer.clone();
+        limited.sdp = offer.sdp.replace(
+            "c=IN IP4 0.0.0.0",
+            &format!("c=IN IP4 0.0.0.0{}b=AS:500", line_break),
+        );
+        assert_eq!(
+            analyze_offer(&limited).unwrap().bandwidths,
+            vec![("1".to_string(), 500_000)]
+        );
+
+        let mut session = parse_sdp(&offer.sdp, false).unwrap();
+        set_session_bandwidths(&mut session, &[("0".to_string(), 64_500)]);
+        let mut limited = offer.clone();
+        limited.sdp = session.to_string();
+        assert_eq!(
+            analyze_offer(&limited).unwrap().bandwidths,
+            vec![("0".to_string(), 64_500)]
+        );
+    }
+
+    fn candidate(name: &str) -> RTCIceCandidateInit {
+        RTCIceCandidateInit {
+            candidate: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_candidate_buffer() {
+        let mut buffer = CandidateBuffer::default();
+        // Before the offer or the answer is set, for example in have-local-offer of subscribe transports.
+        assert_eq!(buffer.accept(candidate("a")), None);
+        assert_eq!(buffer.accept(candidate("b")), None);
+
+        assert_eq!(
+            buffer.remote_description_set(),
+            vec![candidate("a"), candidate("b")]
+        );
+        // After the remote description is set, including renegotiations.
+        assert_eq!(buffer.accept(candidate("c")), Some(candidate("c")));
+        // Candidates which have been added are not added again on renegotiation.
+        assert_eq!(buffer.remote_description_set(), vec![]);
+        assert_eq!(buffer.accept(candidate("d")), Some(candidate("d")));
+
+        // Candidates of new ICE credentials wait for the answer after an ICE restart.
+        buffer.restart();
+        assert_eq!(buffer.accept(candidate("e")), None);
+        assert_eq!(buffer.remote_description_set(), vec![candidate("e")]);
+    }
+
+    #[test]
+    fn test_unparsable_sdp_falls_back() {
+        let mut offer = RTCSessionDescription::default();
+        offer.sdp = "v=0\r\nbroken\r\n".to_string();
+        assert_eq!(analyze_offer(&offer).unwrap(), RemoteOffer::default());
+        assert_eq!(remote_max_message_size(&offer), DEFAULT_MAX_MESSAGE_SIZE);
+        let answer = filter_congestion_feedback(offer.clone(), CongestionFeedback::Twcc);
+        assert_eq!(answer.sdp, offer.sdp);
+    }
+
+    #[test]
+    fn test_accept_unified_plan() {
+        let offer = session_description("./test_data/sdp_audio_video_original");
+        assert!(analyze_offer(&offer).is_ok());
+    }
+}
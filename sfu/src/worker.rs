@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use crate::error::Error;
+
+/// BlockingWorker runs CPU heavy work (muxing, encoding, decoding, SDP processing) on the blocking thread pool, so it never stalls the async tasks which forward RTP packets.
+/// The number of tasks running at the same time is limited per [`crate::router::Router`], so one busy router can't occupy the whole blocking pool.
+#[derive(Clone, Debug)]
+pub struct BlockingWorker {
+    semaphore: Arc<Semaphore>,
+    max_concurrency: usize,
+}
+
+impl BlockingWorker {
+    pub fn new(max_concurrency: usize) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrency)),
+            max_concurrency,
+        }
+    }
+
+    /// Run the closure on the blocking thread pool. This waits for a free slot if the limit has been reached.
+    pub async fn run<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("worker semaphore is never closed");
+        let res = tokio::task::spawn_blocking(move || {
+            let res = f();
+            drop(permit);
+            res
+        })
+        .await?;
+        Ok(res)
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// This returns the number of tasks which are running now.
+    pub fn running(&self) -> usize {
+        self.max_concurrency - self.semaphore.available_permits()
+    }
+}
+
+impl Default for BlockingWorker {
+    fn default() -> Self {
+        let parallelism = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self::new(parallelism)
+    }
+}